@@ -0,0 +1,169 @@
+// Copyright 2020 Lakin Wecker
+//
+// This file is part of lila-deepq.
+//
+// lila-deepq is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// lila-deepq is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with lila-deepq.  If not, see <https://www.gnu.org/licenses/>.
+
+// Micro-benchmarks of the hot paths between a fishnet submission and irwin,
+// on report sized batches of long games with lc0's multipv matrix analysis.
+// Run `cargo bench -- --save-baseline main` on main and
+// `cargo bench -- --baseline main` on a branch, then
+// `cargo run --example bench_regressions` to fail on regressions.
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use shakmaty::{san::San, uci::Uci, CastlingMode, Chess, MoveList, Position};
+
+use lila_deepq::deepq::model::{Game, GameAnalysis, GameId, MatrixAnalysis, PlyAnalysis, Provenance, Score};
+use lila_deepq::deepq::units::{Depth, MultiPv, NodeBudget, Nodes};
+use lila_deepq::deepq::verifier::verify;
+use lila_deepq::fishnet::model::JobId;
+use lila_deepq::irwin::{api::uci_from_san, job::assemble_games};
+use mongodb::bson::oid::ObjectId;
+
+const GAMES: usize = 30;
+const PLIES: usize = 200;
+const DEPTHS: usize = 4;
+const PV_LENGTH: usize = 8;
+
+/// A long, legal, deterministic game: always the n-th legal move, rotating.
+fn long_game(id: usize, plies: usize) -> (Game, Vec<San>) {
+    let mut pos = Chess::default();
+    let mut pgn = Vec::with_capacity(plies);
+    let mut sans = Vec::with_capacity(plies);
+    for ply in 0..plies {
+        let mut moves = MoveList::new();
+        pos.legal_moves(&mut moves);
+        if moves.is_empty() {
+            break;
+        }
+        let m = moves[(id + ply * 7) % moves.len()].clone();
+        pgn.push(Uci::from_move(&m, CastlingMode::Standard));
+        sans.push(San::from_move(&pos, &m));
+        pos.play_unchecked(&m);
+    }
+    let game = Game {
+        _id: GameId(format!("bench{:03}", id)),
+        emts: vec![100; pgn.len()],
+        pgn,
+        black: None,
+        white: None,
+        provenance: Provenance::Unknown,
+        date_stored: None,
+        not_analysable: false,
+    };
+    (game, sans)
+}
+
+/// Matrix analysis of every ply, in lc0's native q scores, with the game's
+/// own continuation as the pv at every depth so it replays legally.
+fn matrix_analysis(game: &Game) -> Vec<Option<PlyAnalysis>> {
+    (0..=game.pgn.len())
+        .map(|ply| {
+            let pv: Vec<Uci> = game.pgn.iter().skip(ply).take(PV_LENGTH).cloned().collect();
+            let q = ((ply % 41) as f64 - 20f64) / 21f64;
+            Some(PlyAnalysis::Matrix(MatrixAnalysis {
+                pv: vec![vec![Some(pv); DEPTHS]],
+                score: vec![vec![Some(Score::Q(q)); DEPTHS]],
+                depth: Depth::new(DEPTHS as u8),
+                nodes: Nodes::MAX,
+                time: 1000,
+                nps: None,
+                raw_score: None,
+            }))
+        })
+        .collect()
+}
+
+fn game_analysis(game: &Game, analysis: Vec<Option<PlyAnalysis>>) -> GameAnalysis {
+    GameAnalysis {
+        _id: ObjectId::new(),
+        job_id: JobId(ObjectId::new()),
+        game_id: game._id.clone(),
+        source_id: "bench".to_string().into(),
+        analysis,
+        requested_pvs: Some(MultiPv::ONE),
+        requested_depth: Some(Depth::new(DEPTHS as u8)),
+        requested_nodes: NodeBudget {
+            nnue: Nodes::MAX,
+            classical: Nodes::MAX,
+        },
+        quarantine_reason: None,
+        engine: Default::default(),
+        provenance: Provenance::Unknown,
+        pv_history_truncated: false,
+    }
+}
+
+fn bench_san_to_uci(c: &mut Criterion) {
+    let (_, sans) = long_game(0, PLIES);
+    c.bench_function("uci_from_san", |b| b.iter(|| uci_from_san(&sans).expect("legal game")));
+}
+
+fn bench_verify(c: &mut Criterion) {
+    let (game, _) = long_game(0, PLIES);
+    let analysis = game_analysis(&game, matrix_analysis(&game));
+    c.bench_function("verify", |b| b.iter(|| verify(&game, &analysis).expect("valid analysis")));
+}
+
+fn bench_normalize(c: &mut Criterion) {
+    let (game, _) = long_game(0, PLIES);
+    let analysis = matrix_analysis(&game);
+    c.bench_function("normalize_matrix", |b| {
+        b.iter(|| {
+            analysis
+                .iter()
+                .cloned()
+                .map(|ply| ply.map(PlyAnalysis::normalized))
+                .collect::<Vec<_>>()
+        })
+    });
+}
+
+fn bench_assembly(c: &mut Criterion) {
+    let rt = tokio::runtime::Runtime::new().expect("tokio runtime");
+    let games: Vec<(Game, Option<GameAnalysis>)> = (0..GAMES)
+        .map(|id| {
+            let (game, _) = long_game(id, PLIES);
+            let analysis = matrix_analysis(&game)
+                .into_iter()
+                .map(|ply| ply.map(PlyAnalysis::normalized))
+                .collect();
+            let analysis = game_analysis(&game, analysis);
+            (game, Some(analysis))
+        })
+        .collect();
+    let mut group = c.benchmark_group("assemble_analysed_games");
+    for concurrency in [1, 8].iter() {
+        group.bench_with_input(
+            BenchmarkId::from_parameter(concurrency),
+            concurrency,
+            |b, &concurrency| {
+                b.iter(|| rt.block_on(assemble_games(games.clone(), concurrency)).expect("legal games"))
+            },
+        );
+    }
+    group.finish();
+}
+
+/// Tighter than criterion's defaults, so noise isn't reported as a change.
+fn config() -> Criterion {
+    Criterion::default().noise_threshold(0.03).significance_level(0.01)
+}
+
+criterion_group! {
+    name = benches;
+    config = config();
+    targets = bench_san_to_uci, bench_verify, bench_normalize, bench_assembly
+}
+criterion_main!(benches);
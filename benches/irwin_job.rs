@@ -0,0 +1,71 @@
+// Copyright 2020 Lakin Wecker
+//
+// This file is part of lila-deepq.
+//
+// lila-deepq is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// lila-deepq is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with lila-deepq.  If not, see <https://www.gnu.org/licenses/>.
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use shakmaty::{uci::Uci, CastlingMode, Chess, MoveList, Position};
+
+use lila_deepq::deepq::model::{Game, GameAnalysis, GameId, Provenance};
+use lila_deepq::irwin::job::assemble_games;
+
+/// A long, legal, deterministic game: always the n-th legal move, rotating.
+fn long_game(id: usize, plies: usize) -> Game {
+    let mut pos = Chess::default();
+    let mut pgn = Vec::with_capacity(plies);
+    for ply in 0..plies {
+        let mut moves = MoveList::new();
+        pos.legal_moves(&mut moves);
+        if moves.is_empty() {
+            break;
+        }
+        let m = moves[(id + ply * 7) % moves.len()].clone();
+        pgn.push(Uci::from_move(&m, CastlingMode::Standard));
+        pos.play_unchecked(&m);
+    }
+    Game {
+        _id: GameId(format!("bench{:03}", id)),
+        emts: vec![100; pgn.len()],
+        pgn,
+        black: None,
+        white: None,
+        provenance: Provenance::Unknown,
+        date_stored: None,
+        not_analysable: false,
+    }
+}
+
+fn report_games() -> Vec<(Game, Option<GameAnalysis>)> {
+    (0..30).map(|id| (long_game(id, 200), None)).collect()
+}
+
+fn bench_assembly(c: &mut Criterion) {
+    let rt = tokio::runtime::Runtime::new().expect("tokio runtime");
+    let games = report_games();
+    let mut group = c.benchmark_group("assemble_games");
+    for concurrency in [1, 8].iter() {
+        group.bench_with_input(
+            BenchmarkId::from_parameter(concurrency),
+            concurrency,
+            |b, &concurrency| {
+                b.iter(|| rt.block_on(assemble_games(games.clone(), concurrency)).expect("legal games"))
+            },
+        );
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_assembly);
+criterion_main!(benches);
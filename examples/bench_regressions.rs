@@ -0,0 +1,86 @@
+// Copyright 2020 Lakin Wecker
+//
+// This file is part of lila-deepq.
+//
+// lila-deepq is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// lila-deepq is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with lila-deepq.  If not, see <https://www.gnu.org/licenses/>.
+
+// Fails when a benchmark regressed against its baseline, for CI. Criterion
+// only reports changes, so this reads the estimates it leaves behind after
+// `cargo bench -- --baseline <name>`.
+//
+//     cargo run --example bench_regressions [-- <criterion dir> <threshold>]
+//
+// The threshold is the relative slowdown of the mean allowed, 0.10 by default.
+
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process;
+
+fn change_estimates(dir: &Path, found: &mut Vec<PathBuf>) {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return,
+    };
+    for entry in entries.flatten() {
+        let path = entry.path();
+        if path.is_dir() {
+            change_estimates(&path, found);
+        } else if path.ends_with("change/estimates.json") {
+            found.push(path);
+        }
+    }
+}
+
+fn mean_change(path: &Path) -> Option<f64> {
+    let estimates: serde_json::Value = serde_json::from_slice(&fs::read(path).ok()?).ok()?;
+    estimates["mean"]["point_estimate"].as_f64()
+}
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    let dir = PathBuf::from(args.get(1).map(String::as_str).unwrap_or("target/criterion"));
+    let threshold: f64 = args.get(2).and_then(|t| t.parse().ok()).unwrap_or(0.10);
+
+    let mut found = Vec::new();
+    change_estimates(&dir, &mut found);
+    found.sort();
+    if found.is_empty() {
+        eprintln!("No comparisons under {}, run the benchmarks against a baseline", dir.display());
+        process::exit(2);
+    }
+
+    let mut regressions = 0;
+    for path in found {
+        // NOTE: <criterion dir>/<benchmark...>/change/estimates.json
+        let name = path
+            .parent()
+            .and_then(Path::parent)
+            .and_then(|bench| bench.strip_prefix(&dir).ok())
+            .map(|bench| bench.display().to_string())
+            .unwrap_or_default();
+        match mean_change(&path) {
+            Some(change) if change > threshold => {
+                regressions += 1;
+                println!("REGRESSED {:>+7.2}% {}", change * 100f64, name);
+            }
+            Some(change) => println!("ok        {:>+7.2}% {}", change * 100f64, name),
+            None => println!("unreadable          {}", name),
+        }
+    }
+    if regressions > 0 {
+        println!("{} benchmarks regressed by more than {}%", regressions, threshold * 100f64);
+        process::exit(1);
+    }
+}
@@ -0,0 +1,286 @@
+// Copyright 2021 Lakin Wecker
+//
+// This file is part of lila-deepq.
+//
+// lila-deepq is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// lila-deepq is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with lila-deepq.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::collections::HashMap;
+
+use chrono::prelude::*;
+use futures::stream::StreamExt;
+use mongodb::bson::{doc, from_document, Bson, Document};
+use mongodb::Collection;
+use serde::Serialize;
+
+use crate::db::{live, DbConn};
+use crate::deepq::reaper::REAP_ORPHAN_GAMES;
+use crate::deepq::{model as deepq_m, policy};
+use crate::error::Result;
+use crate::fishnet::model as fishnet_m;
+
+#[derive(Serialize, Debug)]
+pub struct ReportDigest {
+    created: i64,
+    completed: i64,
+    open: i64,
+    breached_sla: i64,
+}
+
+#[derive(Serialize, Debug)]
+pub struct JobDigest {
+    completed: i64,
+    expired: i64, // Acquired, but not finished in time
+}
+
+#[derive(Serialize, Debug, Default)]
+pub struct ReaperDigest {
+    orphan_games_removed: i64,
+}
+
+#[derive(Serialize, Debug)]
+pub struct KeyDigest {
+    name: String,
+    completed: i64,
+    expired: i64,
+}
+
+/// A summary of the activity since a point in time, for the mod team's tooling.
+#[derive(Serialize, Debug)]
+pub struct Digest {
+    since: DateTime<Utc>,
+    until: DateTime<Utc>,
+    reports: ReportDigest,
+    jobs: JobDigest,
+    keys: Vec<KeyDigest>,
+    reaper: ReaperDigest,
+}
+
+async fn count(coll: &Collection, filter: Document) -> Result<i64> {
+    Ok(coll.count_documents(filter, None).await?)
+}
+
+async fn report_digest(db: DbConn, since: DateTime<Utc>) -> Result<ReportDigest> {
+    let coll = deepq_m::Report::coll(db);
+    let created = count(&coll, live(doc! {"date_requested": {"$gte": since}})).await?;
+    let completed = count(&coll, live(doc! {"date_completed": {"$gte": since}})).await?;
+    let open = count(&coll, live(doc! {"sent_to_irwin": {"$ne": true}})).await?;
+    let mut breached_sla = 0;
+    for origin in deepq_m::ReportOrigin::all().iter() {
+        let late = Utc::now() - policy::report_sla(origin.clone());
+        breached_sla += count(
+            &coll,
+            live(doc! {
+                "origin": origin.clone(),
+                "sent_to_irwin": {"$ne": true},
+                "date_requested": {"$lt": late},
+            }),
+        )
+        .await?;
+    }
+    Ok(ReportDigest {
+        created,
+        completed,
+        open,
+        breached_sla,
+    })
+}
+
+fn expired_jobs_filter() -> Document {
+    live(doc! {
+        "is_complete": {"$ne": true},
+        "owner": {"$ne": Bson::Null},
+        "expires_at": {"$lt": Utc::now()},
+    })
+}
+
+async fn job_digest(db: DbConn, since: DateTime<Utc>) -> Result<JobDigest> {
+    let coll = fishnet_m::Job::coll(db);
+    let completed = count(&coll, live(doc! {"date_completed": {"$gte": since}})).await?;
+    let expired = count(&coll, expired_jobs_filter()).await?;
+    Ok(JobDigest { completed, expired })
+}
+
+/// A $sum from a $group stage, which mongo returns as whichever int fits.
+fn group_count(doc: &Document, key: &str) -> i64 {
+    match doc.get(key) {
+        Some(Bson::Int32(count)) => i64::from(*count),
+        Some(Bson::Int64(count)) => *count,
+        _ => 0,
+    }
+}
+
+async fn jobs_by_owner(db: DbConn, filter: Document) -> Result<HashMap<String, i64>> {
+    let mut counts = HashMap::new();
+    let (coll, filter) = (fishnet_m::Job::coll(db), live(filter));
+    let pipeline = vec![
+        doc! {"$match": filter},
+        doc! {"$group": {"_id": "$owner", "count": {"$sum": 1}}},
+    ];
+    let mut cursor = coll.aggregate(pipeline, None).await?;
+    while let Some(doc) = cursor.next().await {
+        let doc = doc?;
+        // NOTE: reconciled jobs may have completed without an owner.
+        if let Ok(owner) = doc.get_str("_id") {
+            counts.insert(owner.to_string(), group_count(&doc, "count"));
+        }
+    }
+    Ok(counts)
+}
+
+async fn key_digest(db: DbConn, since: DateTime<Utc>) -> Result<Vec<KeyDigest>> {
+    let completed = jobs_by_owner(db.clone(), doc! {"date_completed": {"$gte": since}}).await?;
+    let expired = jobs_by_owner(db.clone(), expired_jobs_filter()).await?;
+
+    // NOTE: never hand out the keys themselves, only their names.
+    let mut keys = Vec::new();
+    let mut api_users = fishnet_m::ApiUser::coll(db).find(doc! {}, None).await?;
+    while let Some(doc) = api_users.next().await {
+        let api_user: fishnet_m::ApiUser = from_document(doc?)?;
+        // NOTE: job owners are stored lowercased
+        let owner = api_user.key.0.to_lowercase();
+        let key = KeyDigest {
+            name: api_user.name,
+            completed: completed.get(&owner).copied().unwrap_or(0),
+            expired: expired.get(&owner).copied().unwrap_or(0),
+        };
+        if key.completed > 0 || key.expired > 0 {
+            keys.push(key);
+        }
+    }
+    Ok(keys)
+}
+
+/// Sums what the reaper recorded in the audit log, it runs in its own process.
+/// Passes that removed nothing aren't audited, see the scheduler's counters.
+async fn reaper_digest(db: DbConn, since: DateTime<Utc>) -> Result<ReaperDigest> {
+    let mut digest = ReaperDigest::default();
+    let coll = deepq_m::AuditEntry::coll(db);
+    let filter = doc! {"action": REAP_ORPHAN_GAMES, "date": {"$gte": since}};
+    let mut entries = coll.find(filter, None).await?;
+    while let Some(doc) = entries.next().await {
+        let entry: deepq_m::AuditEntry = from_document(doc?)?;
+        digest.orphan_games_removed += entry.details.get_i64("removed").unwrap_or(0);
+    }
+    Ok(digest)
+}
+
+pub async fn digest(db: DbConn, since: DateTime<Utc>) -> Result<Digest> {
+    let db = db.analytics();
+    Ok(Digest {
+        since,
+        until: Utc::now(),
+        reports: report_digest(db.clone(), since).await?,
+        jobs: job_digest(db.clone(), since).await?,
+        keys: key_digest(db.clone(), since).await?,
+        reaper: reaper_digest(db, since).await?,
+    })
+}
+
+/// Aborts for one reason from one worker version.
+#[derive(Serialize, Debug)]
+pub struct AbortBreakdown {
+    reason: fishnet_m::AbortReason,
+    client_version: Option<String>,
+    aborts: i64,
+    rate: f64, // Of the attempts by workers of this version
+}
+
+/// How often workers gave jobs back since a point in time. Unsupported and oom
+/// aborts usually point at the work we hand out, shutdowns and network
+/// trouble at the providers.
+#[derive(Serialize, Debug)]
+pub struct AbortStats {
+    since: DateTime<Utc>,
+    until: DateTime<Utc>,
+    attempts: i64, // Jobs completed or aborted
+    aborts: i64,
+    rate: f64,
+    breakdown: Vec<AbortBreakdown>,
+}
+
+fn rate(count: i64, attempts: i64) -> f64 {
+    count as f64 / attempts.max(1) as f64
+}
+
+/// Completed jobs by the fishnet version that completed them.
+async fn completed_by_version(db: DbConn, since: DateTime<Utc>) -> Result<HashMap<Option<String>, i64>> {
+    let mut counts = HashMap::new();
+    let (coll, filter) = (fishnet_m::Job::coll(db), live(doc! {"date_completed": {"$gte": since}}));
+    let pipeline = vec![
+        doc! {"$match": filter},
+        doc! {"$group": {"_id": "$client_version", "count": {"$sum": 1}}},
+    ];
+    let mut cursor = coll.aggregate(pipeline, None).await?;
+    while let Some(doc) = cursor.next().await {
+        let doc = doc?;
+        // NOTE: jobs completed before versions were recorded group under None.
+        let version = doc.get_str("_id").ok().map(str::to_string);
+        counts.insert(version, group_count(&doc, "count"));
+    }
+    Ok(counts)
+}
+
+pub async fn abort_stats(db: DbConn, since: DateTime<Utc>) -> Result<AbortStats> {
+    let db = db.analytics();
+    let completed = completed_by_version(db.clone(), since).await?;
+    let mut groups = Vec::new();
+    let (coll, filter) = (fishnet_m::Abort::coll(db), doc! {"date_aborted": {"$gte": since}});
+    let pipeline = vec![
+        doc! {"$match": filter},
+        doc! {"$group": {
+            "_id": {"reason": "$reason", "client_version": "$client_version"},
+            "aborts": {"$sum": 1},
+        }},
+        doc! {"$sort": {"aborts": -1}},
+    ];
+    let mut cursor = coll.aggregate(pipeline, None).await?;
+    while let Some(doc) = cursor.next().await {
+        let doc = doc?;
+        let group = doc.get_document("_id")?;
+        groups.push((
+            group
+                .get_str("reason")
+                .ok()
+                .and_then(|reason| reason.parse().ok())
+                .unwrap_or_default(),
+            group.get_str("client_version").ok().map(str::to_string),
+            group_count(&doc, "aborts"),
+        ));
+    }
+    let aborts: i64 = groups.iter().map(|(_, _, aborts)| aborts).sum();
+    let attempts = completed.values().sum::<i64>() + aborts;
+    let mut aborts_by_version: HashMap<Option<String>, i64> = HashMap::new();
+    for (_, client_version, aborts) in &groups {
+        *aborts_by_version.entry(client_version.clone()).or_default() += aborts;
+    }
+    let attempts_by = |client_version: &Option<String>| {
+        completed.get(client_version).copied().unwrap_or(0) + aborts_by_version[client_version]
+    };
+    Ok(AbortStats {
+        since,
+        until: Utc::now(),
+        attempts,
+        aborts,
+        rate: rate(aborts, attempts),
+        breakdown: groups
+            .into_iter()
+            .map(|(reason, client_version, aborts)| AbortBreakdown {
+                rate: rate(aborts, attempts_by(&client_version)),
+                reason,
+                client_version,
+                aborts,
+            })
+            .collect(),
+    })
+}
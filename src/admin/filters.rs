@@ -0,0 +1,42 @@
+// Copyright 2021 Lakin Wecker
+//
+// This file is part of lila-deepq.
+//
+// lila-deepq is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// lila-deepq is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with lila-deepq.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::result::Result as StdResult;
+
+use warp::{Filter, Rejection};
+
+use crate::db::DbConn;
+use crate::fishnet::{filters as fishnet_filters, model as fishnet_m};
+use crate::http::{forbidden, required_or_unauthenticated};
+
+async fn required_admin(api_user: fishnet_m::ApiUser) -> StdResult<fishnet_m::ApiUser, Rejection> {
+    if api_user.admin {
+        Ok(api_user)
+    } else {
+        Err(forbidden())
+    }
+}
+
+/// Admin endpoints use the same bearer keys as fishnet, but the key
+/// must have been created with admin rights.
+pub fn admin_required(
+    db: DbConn,
+) -> impl Filter<Extract = (fishnet_m::ApiUser,), Error = Rejection> + Clone {
+    fishnet_filters::authentication_from_header(db)
+        .and_then(required_or_unauthenticated)
+        .and_then(required_admin)
+}
@@ -0,0 +1,1108 @@
+// Copyright 2021 Lakin Wecker
+//
+// This file is part of lila-deepq.
+//
+// lila-deepq is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// lila-deepq is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with lila-deepq.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::collections::HashMap;
+use std::io::Error as IoError;
+use std::result::Result as StdResult;
+use std::str::FromStr;
+
+use chrono::{prelude::*, Duration};
+use futures::stream::{Stream, StreamExt};
+use log::{error, info};
+use mongodb::bson::{doc, oid::ObjectId, to_bson};
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast;
+use warp::{
+    filters::{method, BoxedFilter},
+    http, hyper, path, reject,
+    reply::{self, Json, Reply},
+    Buf, Filter, Rejection,
+};
+
+use super::{api, filters as f, search};
+use crate::crypto::Secret;
+use crate::db::{slow, DbConn};
+use crate::deepq::api::{
+    delete_report, exclude_games, find_irwin_submissions, find_report,
+    insert_audit_entry, undelete_report,
+};
+use crate::deepq::backlog::{Advisory, Backlog};
+use crate::deepq::policy::precedence_for_origin;
+use crate::deepq::{export, import, preset, results};
+use crate::deepq::model::{GameId, PresetSettings, ReportId, ReportOrigin, UserId};
+use crate::error::Error;
+use crate::fishnet::{
+    api::{self as fishnet_api, Receipt},
+    model::{AnalysisType, ApiUser, JobId, TrustLevel},
+    FishnetMsg,
+};
+use crate::flags::Flags;
+use crate::lichess::status::GameStatusCache;
+use crate::metrics::{find_published, Histogram, Metrics, PublishedCounters, LATENCY_BUCKETS_MS};
+use crate::pause::Pauses;
+use crate::scheduler;
+use crate::supervisor;
+use crate::irwin::api::schedule_follow_up;
+use crate::deepq::filters::report_id;
+use crate::http::{id, recover, with, Id};
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SetFlag {
+    enabled: bool,
+}
+
+async fn list_flags(_admin: ApiUser, flags: Flags) -> StdResult<Json, Rejection> {
+    Ok(reply::json(&flags.all().await?))
+}
+
+async fn set_flag(
+    admin: ApiUser,
+    flags: Flags,
+    name: String,
+    set_flag: SetFlag,
+) -> StdResult<Json, Rejection> {
+    info!("set_flag > {} > {} = {}", admin.name, name, set_flag.enabled);
+    Ok(reply::json(&flags.set(&name, set_flag.enabled).await?))
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SetPause {
+    analysis_type: Option<AnalysisType>, // Pauses the whole queue when unset
+    reason: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ResumeQuery {
+    analysis_type: Option<AnalysisType>,
+}
+
+async fn list_pauses(_admin: ApiUser, pauses: Pauses) -> StdResult<Json, Rejection> {
+    pauses.refresh().await?;
+    Ok(reply::json(&pauses.all().await))
+}
+
+async fn set_pause(
+    db: DbConn,
+    admin: ApiUser,
+    pauses: Pauses,
+    set_pause: SetPause,
+) -> StdResult<Json, Rejection> {
+    info!("set_pause > {} > {:?}", admin.name, set_pause.analysis_type);
+    let pause = pauses
+        .pause(set_pause.analysis_type, set_pause.reason, admin.name.clone())
+        .await?;
+    insert_audit_entry(
+        db,
+        &admin.name,
+        "pause",
+        doc! {
+            "analysis_type": to_bson(&pause.analysis_type).map_err(Error::from)?,
+            "reason": pause.reason.clone(),
+        },
+    )
+    .await?;
+    Ok(reply::json(&pause))
+}
+
+async fn resume(
+    db: DbConn,
+    admin: ApiUser,
+    pauses: Pauses,
+    query: ResumeQuery,
+) -> StdResult<Json, Rejection> {
+    info!("resume > {} > {:?}", admin.name, query.analysis_type);
+    let pause = pauses
+        .resume(query.analysis_type)
+        .await?
+        .ok_or_else(reject::not_found)?;
+    insert_audit_entry(
+        db,
+        &admin.name,
+        "resume",
+        doc! {"analysis_type": to_bson(&pause.analysis_type).map_err(Error::from)?},
+    )
+    .await?;
+    Ok(reply::json(&pause))
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SetTrust {
+    trust: TrustLevel,
+}
+
+async fn set_trust(
+    db: DbConn,
+    admin: ApiUser,
+    key: String,
+    set_trust: SetTrust,
+) -> StdResult<Json, Rejection> {
+    info!("set_trust > {} > {} = {}", admin.name, key, set_trust.trust);
+    let api_user = fishnet_api::set_trust(db.clone(), key.into(), set_trust.trust)
+        .await?
+        .ok_or_else(reject::not_found)?;
+    insert_audit_entry(
+        db,
+        &admin.name,
+        "set_trust",
+        doc! {"key_name": api_user.name.clone(), "trust": api_user.trust},
+    )
+    .await?;
+    Ok(reply::json(&SetTrust {
+        trust: api_user.trust,
+    }))
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SetOrg {
+    org: Option<String>, // None removes the key from its org
+}
+
+async fn set_org(
+    db: DbConn,
+    admin: ApiUser,
+    key: String,
+    set_org: SetOrg,
+) -> StdResult<Json, Rejection> {
+    info!("set_org > {} > {} = {:?}", admin.name, key, set_org.org);
+    let api_user = fishnet_api::set_org(db.clone(), key.into(), set_org.org)
+        .await?
+        .ok_or_else(reject::not_found)?;
+    insert_audit_entry(
+        db,
+        &admin.name,
+        "set_org",
+        doc! {"key_name": api_user.name.clone(), "org": api_user.org.clone().unwrap_or_default()},
+    )
+    .await?;
+    Ok(reply::json(&SetOrg { org: api_user.org }))
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SetCommitment {
+    committed_jobs_per_hour: Option<i64>, // None removes the commitment
+}
+
+async fn set_commitment(
+    db: DbConn,
+    admin: ApiUser,
+    key: String,
+    set_commitment: SetCommitment,
+) -> StdResult<Json, Rejection> {
+    info!(
+        "set_commitment > {} > {} = {:?}",
+        admin.name, key, set_commitment.committed_jobs_per_hour
+    );
+    let api_user =
+        fishnet_api::set_commitment(db.clone(), key.into(), set_commitment.committed_jobs_per_hour)
+            .await?
+            .ok_or_else(reject::not_found)?;
+    insert_audit_entry(
+        db,
+        &admin.name,
+        "set_commitment",
+        doc! {
+            "key_name": api_user.name.clone(),
+            "committed_jobs_per_hour": api_user.committed_jobs_per_hour.unwrap_or(0),
+        },
+    )
+    .await?;
+    Ok(reply::json(&SetCommitment {
+        committed_jobs_per_hour: api_user.committed_jobs_per_hour,
+    }))
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SetAllowedOrigins {
+    allowed_origins: Option<Vec<ReportOrigin>>, // None lets the key work on any origin again
+}
+
+/// For the audit log, where no restriction is an empty list.
+fn origin_names(origins: &Option<Vec<ReportOrigin>>) -> Vec<String> {
+    origins
+        .iter()
+        .flatten()
+        .map(|origin| origin.to_string().to_lowercase())
+        .collect()
+}
+
+async fn set_allowed_origins(
+    db: DbConn,
+    admin: ApiUser,
+    key: String,
+    set_allowed_origins: SetAllowedOrigins,
+) -> StdResult<Json, Rejection> {
+    info!(
+        "set_allowed_origins > {} > {} = {:?}",
+        admin.name, key, set_allowed_origins.allowed_origins
+    );
+    let api_user =
+        fishnet_api::set_allowed_origins(db.clone(), key.into(), set_allowed_origins.allowed_origins)
+            .await?
+            .ok_or_else(reject::not_found)?;
+    insert_audit_entry(
+        db,
+        &admin.name,
+        "set_allowed_origins",
+        doc! {
+            "key_name": api_user.name.clone(),
+            "allowed_origins": origin_names(&api_user.allowed_origins),
+        },
+    )
+    .await?;
+    Ok(reply::json(&SetAllowedOrigins {
+        allowed_origins: api_user.allowed_origins,
+    }))
+}
+
+/// The same options as the fishnet-new-user command.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct NewKey {
+    name: String,
+    user: Option<UserId>,
+    #[serde(default)]
+    perms: Vec<AnalysisType>,
+    #[serde(default)]
+    admin: bool,
+    #[serde(default)]
+    trust: TrustLevel,
+    org: Option<String>,
+    committed_jobs_per_hour: Option<i64>,
+    allowed_origins: Option<Vec<ReportOrigin>>,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct CreatedKey {
+    key: String, // Only in this response, it isn't shown again
+    #[serde(flatten)]
+    new_key: NewKey,
+}
+
+async fn create_key(db: DbConn, admin: ApiUser, new_key: NewKey) -> StdResult<impl Reply, Rejection> {
+    info!("create_key > {} > {}", admin.name, new_key.name);
+    let api_user = fishnet_api::create_api_user(
+        db.clone(),
+        fishnet_api::CreateApiUser {
+            user: new_key.user.clone(),
+            name: new_key.name.clone(),
+            perms: new_key.perms.clone(),
+            admin: new_key.admin,
+            trust: new_key.trust,
+            org: new_key.org.clone(),
+            committed_jobs_per_hour: new_key.committed_jobs_per_hour,
+            allowed_origins: new_key.allowed_origins.clone(),
+        },
+    )
+    .await?;
+    insert_audit_entry(
+        db,
+        &admin.name,
+        "create_key",
+        doc! {
+            "key_name": api_user.name.clone(),
+            "user": api_user.user.as_ref().map(ToString::to_string).unwrap_or_default(),
+            "perms": api_user.perms.iter().map(ToString::to_string).collect::<Vec<_>>(),
+            "admin": api_user.admin,
+            "trust": api_user.trust,
+            "org": api_user.org.clone().unwrap_or_default(),
+            "committed_jobs_per_hour": api_user.committed_jobs_per_hour.unwrap_or(0),
+            "allowed_origins": origin_names(&api_user.allowed_origins),
+        },
+    )
+    .await?;
+    Ok(reply::with_status(
+        reply::json(&CreatedKey {
+            key: api_user.key.0,
+            new_key,
+        }),
+        http::StatusCode::CREATED,
+    ))
+}
+
+async fn list_presets(db: DbConn, _admin: ApiUser) -> StdResult<Json, Rejection> {
+    Ok(reply::json(&preset::find_all(db).await?))
+}
+
+async fn list_scheduled_tasks(db: DbConn, _admin: ApiUser) -> StdResult<Json, Rejection> {
+    Ok(reply::json(&scheduler::find_all(db).await?))
+}
+
+async fn set_preset(
+    db: DbConn,
+    admin: ApiUser,
+    id: String,
+    settings: PresetSettings,
+) -> StdResult<Json, Rejection> {
+    info!("set_preset > {} > {} = {:?}", admin.name, id, settings);
+    let preset = preset::set_preset(db.clone(), &id, settings, &admin.name)
+        .await?
+        .ok_or_else(reject::not_found)?;
+    let settings = to_bson(&preset.settings).map_err(Error::from)?;
+    insert_audit_entry(db, &admin.name, "set_preset", doc! {"preset_id": id, "settings": settings}).await?;
+    Ok(reply::json(&preset))
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DigestQuery {
+    since: Option<DateTime<Utc>>,
+}
+
+async fn digest(db: DbConn, _admin: ApiUser, query: DigestQuery) -> StdResult<Json, Rejection> {
+    let since = query.since.unwrap_or_else(|| Utc::now() - Duration::days(1));
+    Ok(reply::json(&api::digest(db, since).await?))
+}
+
+async fn abort_stats(db: DbConn, _admin: ApiUser, query: DigestQuery) -> StdResult<Json, Rejection> {
+    let since = query.since.unwrap_or_else(|| Utc::now() - Duration::days(1));
+    Ok(reply::json(&api::abort_stats(db, since).await?))
+}
+
+async fn search(db: DbConn, _admin: ApiUser, query: search::SearchQuery) -> StdResult<Json, Rejection> {
+    Ok(reply::json(&search::search(db, query).await?))
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct FollowUp {
+    games: Vec<GameId>, // The games irwin was unsure about
+}
+
+/// Lets waiting acquires know there's new work, rather than have them poll for it.
+fn jobs_queued(tx: &broadcast::Sender<FishnetMsg>) {
+    if let Err(err) = tx.send(FishnetMsg::JobsQueued) {
+        error!("jobs_queued > unable to send msg: {:?}", err);
+    }
+}
+
+async fn follow_up(
+    db: DbConn,
+    tx: broadcast::Sender<FishnetMsg>,
+    admin: ApiUser,
+    report_id: ReportId,
+    follow_up: FollowUp,
+) -> StdResult<Json, Rejection> {
+    info!("follow_up > {} > {}", admin.name, report_id);
+    let job_ids = schedule_follow_up(db.clone(), report_id.clone(), follow_up.games)
+        .await?
+        .ok_or_else(reject::not_found)?;
+    if !job_ids.is_empty() {
+        jobs_queued(&tx);
+    }
+    insert_audit_entry(
+        db,
+        &admin.name,
+        "follow_up",
+        doc! {"report_id": report_id.0, "jobs": job_ids.len() as i64},
+    )
+    .await?;
+    Ok(reply::json(
+        &job_ids.iter().map(ObjectId::to_hex).collect::<Vec<String>>(),
+    ))
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ExcludeGames {
+    games: Vec<GameId>, // Irrelevant to the report, e.g. berserked bullet
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Excluded {
+    excluded_games: Vec<GameId>,
+    cancelled_jobs: i64,
+}
+
+async fn exclude_report_games(
+    db: DbConn,
+    tx: broadcast::Sender<FishnetMsg>,
+    admin: ApiUser,
+    report_id: ReportId,
+    exclude: ExcludeGames,
+) -> StdResult<Json, Rejection> {
+    info!("exclude_report_games > {} > {}", admin.name, report_id);
+    let (report, cancelled) = exclude_games(db.clone(), report_id.clone(), exclude.games.clone())
+        .await?
+        .ok_or_else(reject::not_found)?;
+    let cancelled_jobs = cancelled.len() as i64;
+    // NOTE: the rest of the report may have been waiting on just these.
+    for job_id in cancelled {
+        if let Err(err) = tx.send(FishnetMsg::JobCompleted(job_id)) {
+            error!("exclude_report_games > unable to send msg: {:?}", err);
+        }
+    }
+    insert_audit_entry(
+        db,
+        &admin.name,
+        "exclude_report_games",
+        doc! {"report_id": report_id.0, "games": exclude.games, "cancelled_jobs": cancelled_jobs},
+    )
+    .await?;
+    Ok(reply::json(&Excluded {
+        excluded_games: report.excluded_games,
+        cancelled_jobs,
+    }))
+}
+
+async fn evidence_bundle(
+    db: DbConn,
+    admin: ApiUser,
+    report_id: ReportId,
+) -> StdResult<http::Response<hyper::Body>, Rejection> {
+    info!("evidence_bundle > {} > {}", admin.name, report_id);
+    let report = find_report(db.clone(), report_id.clone())
+        .await?
+        .ok_or_else(reject::not_found)?;
+    // NOTE: these leave the building, so we want to know who took them.
+    insert_audit_entry(
+        db.clone(),
+        &admin.name,
+        "evidence_bundle",
+        doc! {"report_id": report_id.0.clone()},
+    )
+    .await?;
+    let chunks = export::evidence_bundle(db, report, admin.name.clone());
+    http::Response::builder()
+        .header("Content-Type", "application/zip")
+        .header(
+            "Content-Disposition",
+            format!("attachment; filename=\"report-{}.zip\"", report_id),
+        )
+        .body(hyper::Body::wrap_stream(chunks))
+        .map_err(|_| reject::not_found())
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct GameRefresh {
+    game_id: GameId,
+    found: bool,
+    reopened_jobs: i64, // Jobs that had been skipped for missing the game
+}
+
+async fn refresh_game(
+    db: DbConn,
+    game_status: GameStatusCache,
+    admin: ApiUser,
+    game_id: String,
+) -> StdResult<Json, Rejection> {
+    let game_id = GameId(game_id);
+    info!("refresh_game > {} > {}", admin.name, game_id);
+    game_status.forget_missing(&game_id).await;
+    let found = game_status.find_game(db.clone(), game_id.clone()).await?.is_some();
+    let reopened_jobs = if found {
+        fishnet_api::reopen_game_missing_jobs(db.clone(), game_id.clone()).await?
+    } else {
+        0
+    };
+    insert_audit_entry(
+        db,
+        &admin.name,
+        "refresh_game",
+        doc! {"game_id": game_id.clone(), "found": found, "reopened_jobs": reopened_jobs},
+    )
+    .await?;
+    Ok(reply::json(&GameRefresh {
+        game_id,
+        found,
+        reopened_jobs,
+    }))
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Deleted {
+    id: String,
+    deleted_at: Option<DateTime<Utc>>, // None once undeleted
+}
+
+async fn delete_job(db: DbConn, admin: ApiUser, job_id: JobId) -> StdResult<Json, Rejection> {
+    info!("delete_job > {} > {}", admin.name, job_id);
+    let job = fishnet_api::delete_job(db.clone(), job_id.clone())
+        .await?
+        .ok_or_else(reject::not_found)?;
+    insert_audit_entry(db, &admin.name, "delete_job", doc! {"job_id": job_id.0}).await?;
+    Ok(reply::json(&Deleted {
+        id: job._id.to_string(),
+        deleted_at: job.deleted_at.map(|date| date.0),
+    }))
+}
+
+async fn undelete_job(db: DbConn, admin: ApiUser, job_id: JobId) -> StdResult<Json, Rejection> {
+    info!("undelete_job > {} > {}", admin.name, job_id);
+    let job = fishnet_api::undelete_job(db.clone(), job_id.clone())
+        .await?
+        .ok_or_else(reject::not_found)?;
+    insert_audit_entry(db, &admin.name, "undelete_job", doc! {"job_id": job_id.0}).await?;
+    Ok(reply::json(&Deleted {
+        id: job._id.to_string(),
+        deleted_at: job.deleted_at.map(|date| date.0),
+    }))
+}
+
+async fn delete_report_handler(
+    db: DbConn,
+    admin: ApiUser,
+    report_id: ReportId,
+) -> StdResult<Json, Rejection> {
+    info!("delete_report > {} > {}", admin.name, report_id);
+    let report = delete_report(db.clone(), report_id.clone())
+        .await?
+        .ok_or_else(reject::not_found)?;
+    insert_audit_entry(db, &admin.name, "delete_report", doc! {"report_id": report_id.0}).await?;
+    Ok(reply::json(&Deleted {
+        id: report._id.to_string(),
+        deleted_at: report.deleted_at.map(|date| date.0),
+    }))
+}
+
+async fn undelete_report_handler(
+    db: DbConn,
+    admin: ApiUser,
+    report_id: ReportId,
+) -> StdResult<Json, Rejection> {
+    info!("undelete_report > {} > {}", admin.name, report_id);
+    let report = undelete_report(db.clone(), report_id.clone())
+        .await?
+        .ok_or_else(reject::not_found)?;
+    insert_audit_entry(db, &admin.name, "undelete_report", doc! {"report_id": report_id.0}).await?;
+    Ok(reply::json(&Deleted {
+        id: report._id.to_string(),
+        deleted_at: report.deleted_at.map(|date| date.0),
+    }))
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ImportQuery {
+    origin: ReportOrigin, // Sets the precedence of the imported games
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct Imported {
+    #[serde(flatten)]
+    summary: import::ImportSummary,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    advisory: Option<Advisory>, // Queued anyway, but the caller should throttle
+}
+
+async fn import_pgn(
+    db: DbConn,
+    tx: broadcast::Sender<FishnetMsg>,
+    admin: ApiUser,
+    query: ImportQuery,
+    backlog: Backlog,
+    body: impl Stream<Item = StdResult<impl Buf, warp::Error>> + Send + 'static,
+) -> StdResult<reply::Response, Rejection> {
+    info!("import_pgn > {} > {}", admin.name, query.origin);
+    let body = Box::pin(body.map(|chunk| {
+        chunk
+            .map(|mut chunk| chunk.copy_to_bytes(chunk.remaining()).to_vec())
+            .map_err(IoError::other)
+    }));
+    let summary = import::import_pgn(db.clone(), &admin.name, query.origin.clone(), body).await?;
+    if summary.imported > 0 {
+        jobs_queued(&tx);
+    }
+    let advisory = backlog
+        .advise(db, precedence_for_origin(query.origin))
+        .await?;
+    let retry_after = advisory.as_ref().map(|advisory| advisory.retry_after);
+    let imported = reply::json(&Imported { summary, advisory });
+    Ok(match retry_after {
+        Some(retry_after) => reply::with_header(
+            reply::with_status(imported, http::StatusCode::ACCEPTED),
+            http::header::RETRY_AFTER,
+            retry_after.to_string(),
+        )
+        .into_response(),
+        None => imported.into_response(),
+    })
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RegisterResultWebhook {
+    url: String, // Must be https, receives the analysis of completed CR jobs
+}
+
+async fn register_result_webhook(
+    db: DbConn,
+    admin: ApiUser,
+    register: RegisterResultWebhook,
+) -> StdResult<Json, Rejection> {
+    info!("register_result_webhook > {} > {}", admin.name, register.url);
+    let registered = results::register(db.clone(), &admin.name, register.url.clone()).await?;
+    insert_audit_entry(
+        db,
+        &admin.name,
+        "register_result_webhook",
+        doc! {"url": register.url},
+    )
+    .await?;
+    Ok(reply::json(&registered))
+}
+
+async fn list_result_webhooks(db: DbConn, _admin: ApiUser) -> StdResult<Json, Rejection> {
+    Ok(reply::json(
+        &results::find_all(db)
+            .await?
+            .into_iter()
+            .map(results::Registered::from)
+            .collect::<Vec<_>>(),
+    ))
+}
+
+async fn unregister_result_webhook(
+    db: DbConn,
+    admin: ApiUser,
+    id: Id,
+) -> StdResult<Json, Rejection> {
+    let id = ObjectId::from(id);
+    info!("unregister_result_webhook > {} > {}", admin.name, id);
+    let webhook = results::unregister(db.clone(), id)
+        .await?
+        .ok_or_else(reject::not_found)?;
+    insert_audit_entry(
+        db,
+        &admin.name,
+        "unregister_result_webhook",
+        doc! {"url": webhook.url.clone()},
+    )
+    .await?;
+    Ok(reply::json(&results::Registered::from(webhook)))
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct IrwinSubmissionsQuery {
+    report_id: Option<String>,
+    limit: Option<i64>,
+}
+
+// NOTE: enough to page through a bad day without loading the whole collection.
+const MAX_IRWIN_SUBMISSIONS: i64 = 500;
+
+async fn list_irwin_submissions(
+    db: DbConn,
+    _admin: ApiUser,
+    query: IrwinSubmissionsQuery,
+) -> StdResult<Json, Rejection> {
+    let report_id = query.report_id.as_deref().map(ReportId::from_str).transpose()?;
+    let limit = query.limit.unwrap_or(50).clamp(1, MAX_IRWIN_SUBMISSIONS);
+    Ok(reply::json(&find_irwin_submissions(db, report_id, limit).await?))
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct VerifyReceipt {
+    receipt: String, // As handed out in the receipt header
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ReceiptVerification {
+    valid: bool,
+    receipt: Option<Receipt>,
+}
+
+async fn verify_receipt(
+    _admin: ApiUser,
+    secret: Secret,
+    verify: VerifyReceipt,
+) -> StdResult<Json, Rejection> {
+    let receipt = verify.receipt.parse::<Receipt>().ok();
+    Ok(reply::json(&ReceiptVerification {
+        valid: receipt.as_ref().is_some_and(|r| r.is_valid(&secret)),
+        receipt,
+    }))
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct RawMetrics {
+    routes: HashMap<String, Histogram>,
+    latency_buckets_ms: Vec<u64>,
+    counters: HashMap<String, u64>, // e.g. deepq::payload's near limit analyses
+    published: Vec<PublishedCounters>, // By the listener and other processes without an admin api
+    supervisor_restarts: u64,
+    slow_queries: u64, // See db::slow
+}
+
+async fn raw_metrics(db: DbConn, _admin: ApiUser, metrics: Metrics) -> StdResult<Json, Rejection> {
+    Ok(reply::json(&RawMetrics {
+        published: find_published(db.clone()).await?,
+        routes: metrics.snapshot(),
+        latency_buckets_ms: LATENCY_BUCKETS_MS.to_vec(),
+        counters: metrics.counters(),
+        supervisor_restarts: supervisor::restarts(),
+        slow_queries: db.slow_queries.count(),
+    }))
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct SlowQueries {
+    threshold_ms: u64,
+    slow_queries: u64, // Since startup
+    slowest: Vec<slow::SlowQuery>,
+}
+
+/// The slowest queries this process made lately, to spot missing indexes.
+async fn slow_queries(db: DbConn, _admin: ApiUser) -> StdResult<Json, Rejection> {
+    Ok(reply::json(&SlowQueries {
+        threshold_ms: db.slow_queries.threshold().as_millis() as u64,
+        slow_queries: db.slow_queries.count(),
+        slowest: db.slow_queries.slowest(),
+    }))
+}
+
+async fn slo(_admin: ApiUser, metrics: Metrics) -> StdResult<Json, Rejection> {
+    Ok(reply::json(&metrics.slo_summary()))
+}
+
+pub fn mount(
+    db: DbConn,
+    flags: Flags,
+    secret: Secret,
+    metrics: Metrics,
+    backlog: Backlog,
+    tx: broadcast::Sender<FishnetMsg>,
+    game_status: GameStatusCache,
+) -> BoxedFilter<(impl Reply,)> {
+    let admin_required = f::admin_required(db.clone());
+    let pauses = flags.pauses();
+
+    let get_flags = path("flags")
+        .and(path::end())
+        .and(method::get())
+        .and(admin_required.clone())
+        .and(with(flags.clone()))
+        .and_then(list_flags);
+
+    let put_flag = path("flags")
+        .and(method::put())
+        .and(admin_required.clone())
+        .and(with(flags))
+        .and(path::param())
+        .and(path::end())
+        .and(warp::body::json())
+        .and_then(set_flag);
+
+    let get_pauses = path("pause")
+        .and(path::end())
+        .and(method::get())
+        .and(admin_required.clone())
+        .and(with(pauses.clone()))
+        .and_then(list_pauses);
+
+    let put_pause = path("pause")
+        .and(path::end())
+        .and(method::put())
+        .and(with(db.clone()))
+        .and(admin_required.clone())
+        .and(with(pauses.clone()))
+        .and(warp::body::json())
+        .and_then(set_pause);
+
+    let delete_pause = path("pause")
+        .and(path::end())
+        .and(method::delete())
+        .and(with(db.clone()))
+        .and(admin_required.clone())
+        .and(with(pauses))
+        .and(warp::query::<ResumeQuery>())
+        .and_then(resume);
+
+    let post_key = path("keys")
+        .and(path::end())
+        .and(method::post())
+        .and(with(db.clone()))
+        .and(admin_required.clone())
+        .and(warp::body::json())
+        .and_then(create_key);
+
+    let get_presets = path("presets")
+        .and(path::end())
+        .and(method::get())
+        .and(with(db.clone()))
+        .and(admin_required.clone())
+        .and_then(list_presets);
+
+    let put_preset = path("presets")
+        .and(method::put())
+        .and(with(db.clone()))
+        .and(admin_required.clone())
+        .and(path::param())
+        .and(path::end())
+        .and(warp::body::json())
+        .and_then(set_preset);
+
+    let get_scheduled_tasks = path("scheduled-tasks")
+        .and(path::end())
+        .and(method::get())
+        .and(with(db.clone()))
+        .and(admin_required.clone())
+        .and_then(list_scheduled_tasks);
+
+    let put_trust = path("keys")
+        .and(method::put())
+        .and(with(db.clone()))
+        .and(admin_required.clone())
+        .and(path::param())
+        .and(path("trust"))
+        .and(path::end())
+        .and(warp::body::json())
+        .and_then(set_trust);
+
+    let put_org = path("keys")
+        .and(method::put())
+        .and(with(db.clone()))
+        .and(admin_required.clone())
+        .and(path::param())
+        .and(path("org"))
+        .and(path::end())
+        .and(warp::body::json())
+        .and_then(set_org);
+
+    let put_commitment = path("keys")
+        .and(method::put())
+        .and(with(db.clone()))
+        .and(admin_required.clone())
+        .and(path::param())
+        .and(path("commitment"))
+        .and(path::end())
+        .and(warp::body::json())
+        .and_then(set_commitment);
+
+    let put_allowed_origins = path("keys")
+        .and(method::put())
+        .and(with(db.clone()))
+        .and(admin_required.clone())
+        .and(path::param())
+        .and(path("origins"))
+        .and(path::end())
+        .and(warp::body::json())
+        .and_then(set_allowed_origins);
+
+    let get_digest = path("digest")
+        .and(path::end())
+        .and(method::get())
+        .and(with(db.clone()))
+        .and(admin_required.clone())
+        .and(warp::query::<DigestQuery>())
+        .and_then(digest);
+
+    let get_aborts = path("aborts")
+        .and(path::end())
+        .and(method::get())
+        .and(with(db.clone()))
+        .and(admin_required.clone())
+        .and(warp::query::<DigestQuery>())
+        .and_then(abort_stats);
+
+    let get_search = path("search")
+        .and(path::end())
+        .and(method::get())
+        .and(with(db.clone()))
+        .and(admin_required.clone())
+        .and(warp::query::<search::SearchQuery>())
+        .and_then(search);
+
+    let delete_job_route = path("jobs")
+        .and(method::delete())
+        .and(with(db.clone()))
+        .and(admin_required.clone())
+        .and(id::<JobId>())
+        .and(path::end())
+        .and_then(delete_job);
+
+    let post_undelete_job = path("jobs")
+        .and(method::post())
+        .and(with(db.clone()))
+        .and(admin_required.clone())
+        .and(id::<JobId>())
+        .and(path("undelete"))
+        .and(path::end())
+        .and_then(undelete_job);
+
+    let delete_report_route = path("reports")
+        .and(method::delete())
+        .and(with(db.clone()))
+        .and(admin_required.clone())
+        .and(report_id(db.clone()))
+        .and(path::end())
+        .and_then(delete_report_handler);
+
+    let post_undelete_report = path("reports")
+        .and(method::post())
+        .and(with(db.clone()))
+        .and(admin_required.clone())
+        .and(report_id(db.clone()))
+        .and(path("undelete"))
+        .and(path::end())
+        .and_then(undelete_report_handler);
+
+    let post_import = path("imports")
+        .and(path::end())
+        .and(method::post())
+        .and(with(db.clone()))
+        .and(with(tx.clone()))
+        .and(admin_required.clone())
+        .and(warp::query::<ImportQuery>())
+        .and(with(backlog))
+        .and(warp::body::stream())
+        .and_then(import_pgn);
+
+    let post_result_webhook = path("result-webhooks")
+        .and(path::end())
+        .and(method::post())
+        .and(with(db.clone()))
+        .and(admin_required.clone())
+        .and(warp::body::json())
+        .and_then(register_result_webhook);
+
+    let get_result_webhooks = path("result-webhooks")
+        .and(path::end())
+        .and(method::get())
+        .and(with(db.clone()))
+        .and(admin_required.clone())
+        .and_then(list_result_webhooks);
+
+    let delete_result_webhook = path("result-webhooks")
+        .and(method::delete())
+        .and(with(db.clone()))
+        .and(admin_required.clone())
+        .and(id::<Id>())
+        .and(path::end())
+        .and_then(unregister_result_webhook);
+
+    let post_exclude_games = path("reports")
+        .and(method::post())
+        .and(with(db.clone()))
+        .and(with(tx.clone()))
+        .and(admin_required.clone())
+        .and(report_id(db.clone()))
+        .and(path("exclude"))
+        .and(path::end())
+        .and(warp::body::json())
+        .and_then(exclude_report_games);
+
+    let get_evidence_bundle = path("reports")
+        .and(method::get())
+        .and(with(db.clone()))
+        .and(admin_required.clone())
+        .and(report_id(db.clone()))
+        .and(path("bundle"))
+        .and(path::end())
+        .and_then(evidence_bundle);
+
+    let post_refresh_game = path("games")
+        .and(method::post())
+        .and(with(db.clone()))
+        .and(with(game_status))
+        .and(admin_required.clone())
+        .and(path::param())
+        .and(path("refresh"))
+        .and(path::end())
+        .and_then(refresh_game);
+
+    let post_follow_up = path("reports")
+        .and(method::post())
+        .and(with(db.clone()))
+        .and(with(tx))
+        .and(admin_required.clone())
+        .and(report_id(db.clone()))
+        .and(path("follow-up"))
+        .and(path::end())
+        .and(warp::body::json())
+        .and_then(follow_up);
+
+    let get_irwin_submissions = path("irwin-submissions")
+        .and(path::end())
+        .and(method::get())
+        .and(with(db.clone()))
+        .and(admin_required.clone())
+        .and(warp::query::<IrwinSubmissionsQuery>())
+        .and_then(list_irwin_submissions);
+
+    let get_metrics = path("metrics")
+        .and(path::end())
+        .and(method::get())
+        .and(with(db.clone()))
+        .and(admin_required.clone())
+        .and(with(metrics.clone()))
+        .and_then(raw_metrics);
+
+    let get_slo = path("slo")
+        .and(path::end())
+        .and(method::get())
+        .and(admin_required.clone())
+        .and(with(metrics))
+        .and_then(slo);
+
+    let get_slow_queries = path("slow-queries")
+        .and(path::end())
+        .and(method::get())
+        .and(with(db))
+        .and(admin_required.clone())
+        .and_then(slow_queries);
+
+    let post_verify_receipt = path("receipts")
+        .and(path("verify"))
+        .and(path::end())
+        .and(method::post())
+        .and(admin_required)
+        .and(with(secret))
+        .and(warp::body::json())
+        .and_then(verify_receipt);
+
+    // NOTE: boxed in groups, one chain of every route overflows the compiler.
+    let settings = get_flags
+        .or(put_flag)
+        .or(get_pauses)
+        .or(put_pause)
+        .or(delete_pause)
+        .or(get_presets)
+        .or(put_preset)
+        .or(get_scheduled_tasks)
+        .boxed();
+    let keys = post_key
+        .or(put_trust)
+        .or(put_org)
+        .or(put_commitment)
+        .or(put_allowed_origins)
+        .or(post_verify_receipt)
+        .boxed();
+    let reports = get_digest
+        .or(get_aborts)
+        .or(get_search)
+        .or(post_follow_up)
+        .or(post_exclude_games)
+        .or(post_refresh_game)
+        .or(get_evidence_bundle)
+        .or(post_import)
+        .boxed();
+    let deletes = delete_job_route
+        .or(post_undelete_job)
+        .or(delete_report_route)
+        .or(post_undelete_report)
+        .boxed();
+    let observability = get_metrics
+        .or(get_slo)
+        .or(get_slow_queries)
+        .or(post_result_webhook)
+        .or(get_result_webhooks)
+        .or(delete_result_webhook)
+        .or(get_irwin_submissions)
+        .boxed();
+
+    settings
+        .or(keys)
+        .or(reports)
+        .or(deletes)
+        .or(observability)
+        .recover(recover)
+        .boxed()
+}
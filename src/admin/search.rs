@@ -0,0 +1,214 @@
+// Copyright 2020 Lakin Wecker
+//
+// This file is part of lila-deepq.
+//
+// lila-deepq is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// lila-deepq is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with lila-deepq.  If not, see <https://www.gnu.org/licenses/>.
+//
+// One search box for the mod team: whatever they paste, a game id, a report
+// or job id, a report slug, a username or a key name, finds what it refers to.
+//
+use std::str::FromStr;
+
+use chrono::prelude::*;
+use futures::stream::TryStreamExt;
+use mongodb::bson::{doc, from_document, oid::ObjectId, Document};
+use mongodb::options::FindOptions;
+use mongodb::Collection;
+use serde::{Deserialize, Serialize};
+
+use crate::db::{live, DbConn};
+use crate::deepq::model::{Game, Report, ReportSlug};
+use crate::error::{Error, Result};
+use crate::fishnet::model::{ApiUser, Job};
+
+pub const DEFAULT_LIMIT: i64 = 50;
+pub const MAX_LIMIT: i64 = 200;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SearchQuery {
+    pub q: Option<String>,
+    pub since: Option<DateTime<Utc>>, // Narrows games, reports and jobs by when they were stored
+    pub until: Option<DateTime<Utc>>,
+    pub limit: Option<i64>,
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum HitKind {
+    Game,
+    Report,
+    Job,
+    Key,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct Hit {
+    kind: HitKind,
+    id: String,
+    summary: String,
+    date: Option<DateTime<Utc>>,
+    links: Vec<String>, // Admin endpoints with more about it
+}
+
+fn report_links(id: &str) -> Vec<String> {
+    vec![
+        format!("/admin/reports/{}/bundle", id),
+        format!("/admin/irwin-submissions?report_id={}", id),
+        format!("/reports/{}/eta", id),
+    ]
+}
+
+fn report_hit(report: Report) -> Hit {
+    let id = report._id.to_string();
+    Hit {
+        kind: HitKind::Report,
+        summary: format!(
+            "{} report{} of {} with {} games, {}",
+            report.origin,
+            report.slug.map(|slug| format!(" {}", slug)).unwrap_or_default(),
+            report.user_id,
+            report.games.len(),
+            if report.sent_to_irwin { "sent to irwin" } else { "in progress" }
+        ),
+        date: Some(report.date_requested.0),
+        links: report_links(&id),
+        id,
+    }
+}
+
+fn job_hit(job: Job) -> Hit {
+    Hit {
+        kind: HitKind::Job,
+        id: job._id.to_string(),
+        summary: format!(
+            "{} job for game {}, {}",
+            job.analysis_type,
+            job.game_id,
+            if job.is_complete { "complete" } else { "incomplete" }
+        ),
+        date: Some(job.date_last_updated.0),
+        links: job
+            .report_id
+            .map(|report_id| report_links(&report_id.to_string()))
+            .unwrap_or_default(),
+    }
+}
+
+fn game_hit(game: Game) -> Hit {
+    let player = |player: &Option<_>| {
+        player
+            .as_ref()
+            .map(ToString::to_string)
+            .unwrap_or_else(|| "?".to_string())
+    };
+    Hit {
+        kind: HitKind::Game,
+        id: game._id.to_string(),
+        summary: format!(
+            "{} vs {}, {} plies",
+            player(&game.white),
+            player(&game.black),
+            game.pgn.len()
+        ),
+        date: game.date_stored.map(|date| date.0),
+        links: Vec::new(),
+    }
+}
+
+// NOTE: never the key itself, only what it's called.
+fn key_hit(api_user: ApiUser) -> Hit {
+    Hit {
+        kind: HitKind::Key,
+        id: api_user._id.to_hex(),
+        summary: format!(
+            "{} key{}, {}",
+            api_user.trust,
+            api_user.org.map(|org| format!(" of {}", org)).unwrap_or_default(),
+            api_user.name
+        ),
+        date: None,
+        links: Vec::new(),
+    }
+}
+
+fn in_range(mut filter: Document, field: &str, query: &SearchQuery) -> Document {
+    let mut range = Document::new();
+    if let Some(since) = query.since {
+        range.insert("$gte", since);
+    }
+    if let Some(until) = query.until {
+        range.insert("$lt", until);
+    }
+    if !range.is_empty() {
+        filter.insert(field, range);
+    }
+    filter
+}
+
+async fn find<T, F>(coll: Collection, filter: Document, limit: i64, hit: F) -> Result<Vec<Hit>>
+where
+    T: serde::de::DeserializeOwned,
+    F: Fn(T) -> Hit,
+{
+    let options = FindOptions::builder().limit(limit).build();
+    coll.find(filter, options)
+        .await?
+        .map_err(Error::from)
+        .and_then(|doc| async move { Ok(from_document::<T>(doc)?) })
+        .map_ok(hit)
+        .try_collect()
+        .await
+}
+
+/// Everything `q` could refer to, within the date range, at most `limit` of each kind.
+pub async fn search(db: DbConn, query: SearchQuery) -> Result<Vec<Hit>> {
+    let db = db.analytics();
+    let limit = query.limit.unwrap_or(DEFAULT_LIMIT).clamp(1, MAX_LIMIT);
+    let q = query.q.clone().unwrap_or_default().trim().to_string();
+    let mut hits = Vec::new();
+    if q.is_empty() {
+        // NOTE: just a date range lists the reports requested in it.
+        let filter = in_range(doc! {}, "date_requested", &query);
+        hits.extend(find(Report::coll(db), live(filter), limit, report_hit).await?);
+        return Ok(hits);
+    }
+    if let Ok(id) = ObjectId::with_string(&q) {
+        let filter = in_range(doc! {"_id": id.clone()}, "date_requested", &query);
+        hits.extend(find(Report::coll(db.clone()), live(filter), limit, report_hit).await?);
+        let filter = in_range(doc! {"_id": id}, "date_last_updated", &query);
+        hits.extend(find(Job::coll(db.clone()), live(filter), limit, job_hit).await?);
+        return Ok(hits);
+    }
+    // NOTE: a slug may also be a username, so both are searched.
+    if let Ok(slug) = ReportSlug::from_str(&q) {
+        let filter = in_range(doc! {"slug": slug.0}, "date_requested", &query);
+        hits.extend(find(Report::coll(db.clone()), live(filter), limit, report_hit).await?);
+    }
+    let filter = in_range(doc! {"_id": q.clone()}, "date_stored", &query);
+    hits.extend(find(Game::coll(db.clone()), filter, limit, game_hit).await?);
+
+    // NOTE: lichess usernames are case insensitive, and stored lowercase.
+    let username = q.to_lowercase();
+    let filter = in_range(doc! {"user_id": username.clone()}, "date_requested", &query);
+    hits.extend(find(Report::coll(db.clone()), live(filter), limit, report_hit).await?);
+    let filter = in_range(
+        doc! {"$or": [{"white": username.clone()}, {"black": username}]},
+        "date_stored",
+        &query,
+    );
+    hits.extend(find(Game::coll(db.clone()), filter, limit, game_hit).await?);
+
+    hits.extend(find(ApiUser::coll(db), doc! {"name": q}, limit, key_hit).await?);
+    Ok(hits)
+}
@@ -0,0 +1,86 @@
+// Copyright 2020 Lakin Wecker
+//
+// This file is part of lila-deepq.
+//
+// lila-deepq is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// lila-deepq is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with lila-deepq.  If not, see <https://www.gnu.org/licenses/>.
+//
+// Configuration comes from the command line, the environment and .env, as
+// parsed by structopt. This checks what structopt can't: ranges, urls and
+// settings that only make sense together.
+//
+use std::fmt::{Debug, Display};
+
+use structopt::StructOpt;
+
+use crate::crypto::Secret;
+
+/// Everything wrong with a configuration, rather than just the first problem.
+#[derive(Debug, Default)]
+pub struct Problems(Vec<String>);
+
+impl Problems {
+    pub fn check(&mut self, ok: bool, problem: impl FnOnce() -> String) {
+        if !ok {
+            self.0.push(problem());
+        }
+    }
+
+    pub fn range<T: PartialOrd + Display>(&mut self, name: &str, value: T, min: T, max: T) {
+        self.check(min <= value && value <= max, || {
+            format!("{} must be between {} and {}, not {}", name, min, max, value)
+        });
+    }
+
+    pub fn positive<T: PartialOrd + Display + Default>(&mut self, name: &str, value: T) {
+        self.check(value > T::default(), || format!("{} must be positive, not {}", name, value));
+    }
+
+    pub fn url(&mut self, name: &str, url: &str) {
+        self.check(is_http_url(url), || format!("{} must be an http(s) url, not {:?}", name, url));
+    }
+
+    /// Like url, without repeating a url that may carry credentials.
+    pub fn secret_url(&mut self, name: &str, url: &Secret) {
+        self.check(is_http_url(url.expose()), || format!("{} must be an http(s) url", name));
+    }
+}
+
+fn is_http_url(url: &str) -> bool {
+    reqwest::Url::parse(url).is_ok_and(|url| matches!(url.scheme(), "http" | "https"))
+}
+
+pub trait Validate {
+    fn validate(&self, problems: &mut Problems);
+}
+
+/// `check` for one subcommand's settings.
+pub type Check = fn(&str) -> usize;
+
+/// Loads the settings for a subcommand as it would be run, then prints them
+/// with secrets redacted, along with any problems. Returns the problem count.
+pub fn check<T: StructOpt + Validate + Debug>(subcommand: &str) -> usize {
+    let problems = match T::from_iter_safe(vec![subcommand]) {
+        Ok(settings) => {
+            println!("{}: {:#?}", subcommand, settings);
+            let mut problems = Problems::default();
+            settings.validate(&mut problems);
+            problems.0
+        }
+        Err(err) => vec![err.message],
+    };
+    for problem in problems.iter() {
+        println!("{}: problem: {}", subcommand, problem);
+    }
+    problems.len()
+}
@@ -0,0 +1,78 @@
+// Copyright 2021 Lakin Wecker
+//
+// This file is part of lila-deepq.
+//
+// lila-deepq is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// lila-deepq is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with lila-deepq.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::convert::Infallible;
+use std::fmt;
+use std::str::FromStr;
+
+use hmac::{Hmac, Mac, NewMac};
+use sha2::{Digest, Sha256};
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// A server side secret, used for signing or to reach other services. Never printed.
+#[derive(Clone)]
+pub struct Secret(String);
+
+impl Secret {
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+}
+
+impl From<String> for Secret {
+    fn from(s: String) -> Secret {
+        Secret(s)
+    }
+}
+
+impl FromStr for Secret {
+    type Err = Infallible;
+
+    fn from_str(s: &str) -> Result<Secret, Infallible> {
+        Ok(Secret(s.to_string()))
+    }
+}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Secret(<redacted>)")
+    }
+}
+
+fn mac(secret: &Secret, msg: &[u8]) -> HmacSha256 {
+    let mut mac =
+        HmacSha256::new_varkey(secret.0.as_bytes()).expect("hmac accepts keys of any size");
+    mac.update(msg);
+    mac
+}
+
+pub fn sha256_hex(bytes: &[u8]) -> String {
+    hex::encode(Sha256::digest(bytes))
+}
+
+pub fn sign(secret: &Secret, msg: &[u8]) -> String {
+    hex::encode(mac(secret, msg).finalize().into_bytes())
+}
+
+/// Constant time verification of a signature produced by `sign`.
+pub fn verify(secret: &Secret, msg: &[u8], signature: &str) -> bool {
+    match hex::decode(signature) {
+        Ok(signature) => mac(secret, msg).verify(&signature).is_ok(),
+        Err(_) => false,
+    }
+}
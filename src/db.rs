@@ -15,24 +15,151 @@
 // You should have received a copy of the GNU Affero General Public License
 // along with lila-deepq.  If not, see <https://www.gnu.org/licenses/>.
 
-use mongodb::{Client, Database};
+pub mod slow;
 
+use std::sync::Arc;
+use std::time::Duration;
+
+use mongodb::{
+    bson::{doc, Bson, Document},
+    error::{Error as MongoError, ErrorKind, WriteFailure},
+    options::{ClientOptions, DatabaseOptions, ReadPreference, ReadPreferenceOptions, SelectionCriteria},
+    Client, Database,
+};
+
+use crate::db::slow::SlowQueries;
 use crate::error::Result;
 
+/// Where analytical queries read from. Anything but primary keeps admin
+/// listings, exports and dashboards from contending with acquire and submit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, strum_macros::EnumString, strum_macros::Display)]
+#[strum(serialize_all = "snake_case")]
+pub enum AnalyticsReadPreference {
+    #[default]
+    Primary,
+    SecondaryPreferred,
+    Secondary,
+    Nearest,
+}
+
+impl From<AnalyticsReadPreference> for ReadPreference {
+    fn from(preference: AnalyticsReadPreference) -> ReadPreference {
+        let options = ReadPreferenceOptions::default();
+        match preference {
+            AnalyticsReadPreference::Primary => ReadPreference::Primary,
+            AnalyticsReadPreference::SecondaryPreferred => ReadPreference::SecondaryPreferred { options },
+            AnalyticsReadPreference::Secondary => ReadPreference::Secondary { options },
+            AnalyticsReadPreference::Nearest => ReadPreference::Nearest { options },
+        }
+    }
+}
+
 #[derive(Clone)]
 pub struct ConnectionOpts {
     pub mongo_uri: String,
     pub mongo_database: String,
+    pub analytics_uri: Option<String>, // A separate deployment for analytics, e.g. a hidden member
+    pub analytics_read_preference: AnalyticsReadPreference,
+    pub slow_query_threshold: Duration, // Queries that take longer are logged, see slow
 }
 
 #[derive(Clone)]
 pub struct DbConn {
     pub client: Client,
     pub database: Database,
+    analytics_client: Client, // The same as client unless configured otherwise
+    analytics: Database,      // The same as database unless configured otherwise
+    pub slow_queries: SlowQueries, // Of both clients
+}
+
+impl DbConn {
+    /// The connection that heavy, read-only queries should go through. Reads
+    /// may lag the primary slightly, so never use it for what gets written back.
+    pub fn analytics(&self) -> DbConn {
+        DbConn {
+            client: self.analytics_client.clone(),
+            database: self.analytics.clone(),
+            analytics_client: self.analytics_client.clone(),
+            analytics: self.analytics.clone(),
+            slow_queries: self.slow_queries.clone(),
+        }
+    }
+}
+
+/// Creates the index if it doesn't exist yet, which is a no-op otherwise.
+pub async fn ensure_index(db: DbConn, coll: &str, name: &str, keys: Document) -> Result<()> {
+    db.database
+        .run_command(
+            doc! {
+                "createIndexes": coll,
+                "indexes": [{"key": keys, "name": name}],
+            },
+            None,
+        )
+        .await?;
+    Ok(())
+}
+
+/// Like ensure_index, but no two documents may share the keys. Documents
+/// without them are left out of the index.
+pub async fn ensure_unique_index(db: DbConn, coll: &str, name: &str, keys: Document) -> Result<()> {
+    db.database
+        .run_command(
+            doc! {
+                "createIndexes": coll,
+                "indexes": [{"key": keys, "name": name, "unique": true, "sparse": true}],
+            },
+            None,
+        )
+        .await?;
+    Ok(())
+}
+
+const DUPLICATE_KEY: i32 = 11000;
+
+/// Whether a write failed on a unique index.
+pub fn is_duplicate_key(err: &MongoError) -> bool {
+    matches!(
+        &*err.kind,
+        ErrorKind::WriteError(WriteFailure::WriteError(err)) if err.code == DUPLICATE_KEY
+    )
+}
+
+/// Excludes soft deleted documents. Every query on jobs and reports should go
+/// through this, except the ones that undelete or purge them.
+pub fn live(mut filter: Document) -> Document {
+    filter.insert("deleted_at", Bson::Null);
+    filter
+}
+
+/// A client that reports every command it sends to the slow query tracker.
+async fn timed_client(uri: &str, slow_queries: &SlowQueries) -> Result<Client> {
+    let mut options = ClientOptions::parse(uri).await?;
+    options.command_event_handler = Some(Arc::new(slow_queries.clone()));
+    Ok(Client::with_options(options)?)
 }
 
 pub async fn connection(opts: &ConnectionOpts) -> Result<DbConn> {
-    let client = Client::with_uri_str(&opts.mongo_uri).await?;
+    let slow_queries = SlowQueries::new(opts.slow_query_threshold);
+    let client = timed_client(&opts.mongo_uri, &slow_queries).await?;
     let database = client.database(&opts.mongo_database);
-    Ok(DbConn { client, database })
+    let analytics_client = match &opts.analytics_uri {
+        Some(uri) => timed_client(uri, &slow_queries).await?,
+        None => client.clone(),
+    };
+    let analytics = analytics_client.database_with_options(
+        &opts.mongo_database,
+        DatabaseOptions::builder()
+            .selection_criteria(Some(SelectionCriteria::ReadPreference(
+                opts.analytics_read_preference.into(),
+            )))
+            .build(),
+    );
+    Ok(DbConn {
+        client,
+        database,
+        analytics_client,
+        analytics,
+        slow_queries,
+    })
 }
@@ -0,0 +1,203 @@
+// Copyright 2021 Lakin Wecker
+//
+// This file is part of lila-deepq.
+//
+// lila-deepq is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// lila-deepq is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with lila-deepq.  If not, see <https://www.gnu.org/licenses/>.
+
+//
+// Times every command the driver sends, so that index gaps show up in the
+// logs and the admin api without access to mongo's profiler. It's installed
+// on the clients themselves, so no query can go around it.
+//
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use chrono::prelude::*;
+use log::warn;
+use mongodb::bson::{Bson, Document};
+use mongodb::event::command::{
+    CommandEventHandler, CommandFailedEvent, CommandStartedEvent, CommandSucceededEvent,
+};
+use serde::Serialize;
+
+pub const DEFAULT_THRESHOLD: Duration = Duration::from_millis(100);
+
+/// How many of the slowest queries are kept, and for how long.
+const SLOWEST_KEPT: usize = 20;
+const SLOWEST_WINDOW_HOURS: i64 = 1;
+
+#[derive(Serialize, Debug, Clone)]
+pub struct SlowQuery {
+    pub collection: String,
+    pub operation: String,
+    pub shape: String, // The filter with its values redacted, see shape
+    pub duration_ms: u64,
+    pub at: DateTime<Utc>,
+}
+
+/// What a command was, kept from when it started until it finishes.
+struct Started {
+    collection: String,
+    operation: String,
+    shape: String,
+}
+
+#[derive(Clone)]
+pub struct SlowQueries {
+    threshold: Duration,
+    started: Arc<Mutex<HashMap<i32, Started>>>, // By request id
+    slowest: Arc<Mutex<Vec<SlowQuery>>>,
+    count: Arc<AtomicU64>,
+}
+
+impl SlowQueries {
+    pub fn new(threshold: Duration) -> SlowQueries {
+        SlowQueries {
+            threshold,
+            started: Arc::new(Mutex::new(HashMap::new())),
+            slowest: Arc::new(Mutex::new(Vec::new())),
+            count: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    pub fn threshold(&self) -> Duration {
+        self.threshold
+    }
+
+    /// How many queries took longer than the threshold since startup.
+    pub fn count(&self) -> u64 {
+        self.count.load(Ordering::Relaxed)
+    }
+
+    /// The slowest queries of the last hour, slowest first.
+    pub fn slowest(&self) -> Vec<SlowQuery> {
+        let cutoff = Utc::now() - chrono::Duration::hours(SLOWEST_WINDOW_HOURS);
+        let mut slowest = self.slowest.lock().expect("slowest queries lock poisoned");
+        slowest.retain(|query| query.at >= cutoff);
+        slowest.clone()
+    }
+
+    fn finished(&self, request_id: i32, elapsed: Duration) {
+        let started = self
+            .started
+            .lock()
+            .expect("started queries lock poisoned")
+            .remove(&request_id);
+        if let Some(started) = started.filter(|_| elapsed >= self.threshold) {
+            self.record(started, elapsed);
+        }
+    }
+
+    fn record(&self, started: Started, elapsed: Duration) {
+        self.count.fetch_add(1, Ordering::Relaxed);
+        let query = SlowQuery {
+            collection: started.collection,
+            operation: started.operation,
+            shape: started.shape,
+            duration_ms: elapsed.as_millis() as u64,
+            at: Utc::now(),
+        };
+        warn!(
+            "Slow query > {}.{} {} took {}ms",
+            query.collection, query.operation, query.shape, query.duration_ms
+        );
+        let cutoff = query.at - chrono::Duration::hours(SLOWEST_WINDOW_HOURS);
+        let mut slowest = self.slowest.lock().expect("slowest queries lock poisoned");
+        slowest.retain(|query| query.at >= cutoff);
+        slowest.push(query);
+        slowest.sort_by_key(|query| Reverse(query.duration_ms));
+        slowest.truncate(SLOWEST_KEPT);
+    }
+}
+
+impl CommandEventHandler for SlowQueries {
+    fn handle_command_started_event(&self, event: CommandStartedEvent) {
+        // NOTE: handshakes, pings and the like have no collection.
+        let collection = match event
+            .command
+            .get_str(&event.command_name)
+            .or_else(|_| event.command.get_str("collection"))
+        {
+            Ok(collection) => collection.to_string(),
+            Err(_) => return,
+        };
+        let shape = command_filter(&event.command_name, &event.command)
+            .into_relaxed_extjson()
+            .to_string();
+        self.started.lock().expect("started queries lock poisoned").insert(
+            event.request_id,
+            Started {
+                collection,
+                operation: event.command_name,
+                shape,
+            },
+        );
+    }
+
+    fn handle_command_succeeded_event(&self, event: CommandSucceededEvent) {
+        self.finished(event.request_id, event.duration);
+    }
+
+    fn handle_command_failed_event(&self, event: CommandFailedEvent) {
+        self.finished(event.request_id, event.duration);
+    }
+}
+
+/// The redacted filter of a command, or its pipeline for aggregates.
+fn command_filter(command_name: &str, command: &Document) -> Bson {
+    let first_statement = |field: &str| {
+        command
+            .get_array(field)
+            .ok()
+            .and_then(|statements| statements.first())
+            .and_then(Bson::as_document)
+            .and_then(|statement| statement.get_document("q").ok())
+    };
+    let filter = match command_name {
+        "find" => command.get_document("filter").ok(),
+        "count" | "distinct" | "findAndModify" => command.get_document("query").ok(),
+        "update" => first_statement("updates"),
+        "delete" => first_statement("deletes"),
+        "aggregate" => {
+            return command
+                .get_array("pipeline")
+                .map(|pipeline| redact(&Bson::Array(pipeline.clone())))
+                .unwrap_or_else(|_| Bson::Document(Document::new()))
+        }
+        _ => None,
+    };
+    Bson::Document(filter.map(shape).unwrap_or_default())
+}
+
+fn redact(value: &Bson) -> Bson {
+    match value {
+        Bson::Document(doc) => Bson::Document(shape(doc)),
+        // NOTE: $and, $or and $nor hold filters of their own.
+        Bson::Array(values)
+            if !values.is_empty() && values.iter().all(|v| matches!(v, Bson::Document(_))) =>
+        {
+            Bson::Array(values.iter().map(redact).collect())
+        }
+        _ => Bson::String("?".to_string()),
+    }
+}
+
+/// The fields and operators of a filter, without the values, which may be
+/// usernames or keys and would make every query look different anyway.
+pub fn shape(filter: &Document) -> Document {
+    filter.iter().map(|(key, value)| (key.clone(), redact(value))).collect()
+}
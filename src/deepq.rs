@@ -15,5 +15,26 @@
 // You should have received a copy of the GNU Affero General Public License
 // along with lila-deepq.  If not, see <https://www.gnu.org/licenses/>.
 
+pub mod anomaly;
 pub mod api;
+pub mod backlog;
+pub mod badge;
+pub mod export;
+#[cfg(feature = "web")]
+pub mod filters;
+#[cfg(feature = "web")]
+pub mod handlers;
+#[cfg(any(feature = "web", feature = "cli-admin"))]
+pub mod import;
+pub mod integrity;
 pub mod model;
+pub mod payload;
+pub mod phase;
+pub mod policy;
+pub mod preset;
+pub mod reaper;
+pub mod results;
+pub mod score;
+pub mod stats;
+pub mod units;
+pub mod verifier;
@@ -0,0 +1,151 @@
+// Copyright 2020 Lakin Wecker
+//
+// This file is part of lila-deepq.
+//
+// lila-deepq is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// lila-deepq is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with lila-deepq.  If not, see <https://www.gnu.org/licenses/>.
+//
+// Correlates the reported player's move times with the evals: humans slow
+// down when the position gets critical, engine assistance tends to produce
+// instant top moves exactly there. CR analysts used to work this out by hand
+// from exported data.
+//
+use crate::db::DbConn;
+use crate::deepq::api::{find_analysis_for_job, find_game, set_game_anomaly};
+use crate::deepq::model::{Game, GameAnomaly, PlyAnalysis, Report, Score, UserId};
+use crate::error::Result;
+use crate::fishnet::model::Job;
+
+// NOTE: opening moves are instant and match the engine for everyone.
+const OPENING_PLIES: usize = 16;
+
+// A position is critical when the second best move is this much worse than
+// the best one, i.e. only the best move holds the eval.
+pub const CRITICAL_GAP_CP: i64 = 150;
+
+// Evals past this are decided either way, losing more of them isn't critical.
+const DECIDED_EVAL_CP: i64 = 1000;
+
+// Lichess sends move times in centiseconds.
+const INSTANT_EMT_CS: i32 = 50;
+
+// Fewer critical moves than this says nothing either way.
+const MIN_CRITICAL_MOVES: i32 = 5;
+
+// How much of the score comes from instant top moves, the rest is from not
+// slowing down on critical moves.
+const INSTANT_WEIGHT: f64 = 0.75;
+
+fn cp(score: &Score) -> Option<i64> {
+    match score.normalized() {
+        Score::Cp(cp) => Some(cp),
+        Score::Mate(mate) => Some(if mate > 0 { 10_000 } else { -10_000 }),
+        _ => None,
+    }
+}
+
+/// Needs at least two pvs, so only multipv analysis can tell.
+fn is_critical(ply_analysis: &PlyAnalysis) -> bool {
+    let cps: Vec<i64> = ply_analysis
+        .pv_scores()
+        .iter()
+        .filter_map(cp)
+        .map(|cp| cp.clamp(-DECIDED_EVAL_CP, DECIDED_EVAL_CP))
+        .collect();
+    match cps.as_slice() {
+        [best, second, ..] => best - second >= CRITICAL_GAP_CP,
+        _ => false,
+    }
+}
+
+fn mean(emts: &[i32]) -> Option<f64> {
+    if emts.is_empty() {
+        None
+    } else {
+        Some(emts.iter().map(|&emt| f64::from(emt)).sum::<f64>() / emts.len() as f64)
+    }
+}
+
+/// None when the player didn't play this game.
+pub fn score_game(
+    game: &Game,
+    analysis: &[Option<PlyAnalysis>],
+    player: &UserId,
+) -> Option<GameAnomaly> {
+    let first_ply = if game.white.as_ref() == Some(player) {
+        0
+    } else if game.black.as_ref() == Some(player) {
+        1
+    } else {
+        return None;
+    };
+    let mut all_emts = Vec::new();
+    let mut critical_emts = Vec::new();
+    let mut instant_top_moves = 0;
+    for ply in (first_ply..game.pgn.len()).step_by(2) {
+        let emt = match game.emts.get(ply) {
+            Some(&emt) => emt,
+            None => continue,
+        };
+        all_emts.push(emt);
+        let ply_analysis = match analysis.get(ply) {
+            Some(Some(ply_analysis)) => ply_analysis,
+            _ => continue,
+        };
+        if ply < OPENING_PLIES || !is_critical(ply_analysis) {
+            continue;
+        }
+        critical_emts.push(emt);
+        let top_move = ply_analysis.pvs().first().and_then(|pv| pv.first()).cloned();
+        if emt <= INSTANT_EMT_CS && top_move.as_ref() == Some(&game.pgn[ply]) {
+            instant_top_moves += 1;
+        }
+    }
+    let critical_moves = critical_emts.len() as i32;
+    let critical_time_ratio = match (mean(&critical_emts), mean(&all_emts)) {
+        (Some(critical), Some(all)) if all > 0.0 => Some(critical / all),
+        _ => None,
+    };
+    let score = if critical_moves < MIN_CRITICAL_MOVES {
+        0.0
+    } else {
+        let instant = f64::from(instant_top_moves) / f64::from(critical_moves);
+        let hurried = critical_time_ratio.map_or(0.0, |ratio| (1.0 - ratio).clamp(0.0, 1.0));
+        INSTANT_WEIGHT * instant + (1.0 - INSTANT_WEIGHT) * hurried
+    };
+    Some(GameAnomaly {
+        game_id: game._id.clone(),
+        critical_moves,
+        instant_top_moves,
+        critical_time_ratio,
+        score,
+    })
+}
+
+/// Scores the game of a completed job and keeps it on the report, replacing
+/// any earlier score for the game. Done once per job, as it completes.
+pub async fn score_job(db: DbConn, report: &Report, job: &Job) -> Result<Option<GameAnomaly>> {
+    if job.skip_reason.is_some() {
+        return Ok(None);
+    }
+    let game = find_game(db.clone(), job.game_id.clone()).await?;
+    let analysis = find_analysis_for_job(db.clone(), job._id.clone()).await?;
+    let anomaly = match (game, analysis) {
+        (Some(game), Some(analysis)) => score_game(&game, &analysis.analysis, &report.user_id),
+        _ => None,
+    };
+    if let Some(anomaly) = anomaly.as_ref() {
+        set_game_anomaly(db, report._id.clone(), anomaly).await?;
+    }
+    Ok(anomaly)
+}
@@ -15,32 +15,154 @@
 // You should have received a copy of the GNU Affero General Public License
 // along with lila-deepq.  If not, see <https://www.gnu.org/licenses/>.
 
+use std::fmt;
+use std::str::FromStr;
+
 use chrono::prelude::*;
-use futures::future::Future;
-use log::debug;
+use futures::{
+    future::Future,
+    stream::{Stream, StreamExt, TryStreamExt},
+};
+use log::{debug, info, warn};
 use mongodb::{
-    bson::{doc, from_document, oid::ObjectId, to_document, DateTime as BsonDateTime},
-    options::{UpdateModifications, UpdateOptions},
+    bson::{
+        doc, from_document, oid::ObjectId, to_document, Bson, DateTime as BsonDateTime, Document,
+    },
+    options::{
+        FindOneAndUpdateOptions, FindOneOptions, FindOptions, ReturnDocument, UpdateModifications,
+        UpdateOptions,
+    },
 };
+use rand::{seq::SliceRandom, thread_rng};
+use serde::Deserialize;
 use shakmaty::{fen::Fen, uci::Uci};
 
-use crate::db::DbConn;
+use crate::db::{ensure_index, ensure_unique_index, is_duplicate_key, live, DbConn};
 use crate::deepq::model as m;
-use crate::error::Result;
-use crate::fishnet::model::JobId;
+use crate::deepq::payload::PayloadGuard;
+use crate::deepq::policy::is_analysable;
+use crate::deepq::units::{Depth, MultiPv, NodeBudget};
+use crate::error::{DomainError, Error, ProtocolError, Result};
+use crate::fishnet::model::{ApiUser, Job, JobId};
+use crate::telemetry::{instrument, Span, SpanKind};
 
 #[derive(Debug, Clone)]
 pub struct CreateReport {
-    pub user_id: m::UserId,
-    pub origin: m::ReportOrigin,
-    pub report_type: m::ReportType,
-    pub games: Vec<m::GameId>,
+    id: m::ReportId,
+    user_id: m::UserId,
+    origin: m::ReportOrigin,
+    report_type: m::ReportType,
+    games: Vec<m::GameId>,
+    not_analysable_games: Vec<m::GameId>,
+    signals: m::ReportSignals,
+}
+
+impl CreateReport {
+    /// Known before the report is inserted, so its jobs can be built and
+    /// checked first.
+    pub fn id(&self) -> &m::ReportId {
+        &self.id
+    }
+
+    pub fn builder(
+        user_id: m::UserId,
+        origin: m::ReportOrigin,
+        report_type: m::ReportType,
+    ) -> CreateReportBuilder {
+        CreateReportBuilder {
+            user_id,
+            origin,
+            report_type,
+            games: Vec::new(),
+            not_analysable_games: Vec::new(),
+            signals: m::ReportSignals::default(),
+        }
+    }
+}
+
+/// Builds a CreateReport, refusing the combinations that make no sense.
+#[derive(Debug, Clone)]
+pub struct CreateReportBuilder {
+    user_id: m::UserId,
+    origin: m::ReportOrigin,
+    report_type: m::ReportType,
+    games: Vec<m::GameId>,
+    not_analysable_games: Vec<m::GameId>,
+    signals: m::ReportSignals,
+}
+
+impl CreateReportBuilder {
+    pub fn games(mut self, games: Vec<m::GameId>) -> Self {
+        self.games = games;
+        self
+    }
+
+    pub fn not_analysable_games(mut self, games: Vec<m::GameId>) -> Self {
+        self.not_analysable_games = games;
+        self
+    }
+
+    pub fn signals(mut self, signals: m::ReportSignals) -> Self {
+        self.signals = signals;
+        self
+    }
+
+    fn problem(&self) -> Option<String> {
+        if self.user_id.as_str().is_empty() {
+            return Some("user is required".to_string());
+        }
+        // NOTE: only irwin reports come from lila's automatic triggers.
+        if !matches!(self.report_type, m::ReportType::Irwin)
+            && !matches!(self.origin, m::ReportOrigin::Moderator)
+        {
+            return Some(format!(
+                "{:?} reports are only requested by moderators, not {:?}",
+                self.report_type, self.origin
+            ));
+        }
+        if let Some(game) = self
+            .games
+            .iter()
+            .find(|game| self.not_analysable_games.iter().any(|g| g.0 == game.0))
+        {
+            return Some(format!("Game({}) is both analysable and not", game));
+        }
+        None
+    }
+
+    pub fn build(self) -> Result<CreateReport> {
+        if let Some(problem) = self.problem() {
+            return Err(DomainError::Invalid(format!("report for {}: {}", self.user_id, problem)).into());
+        }
+        Ok(CreateReport {
+            id: m::ReportId(ObjectId::new()),
+            user_id: self.user_id,
+            origin: self.origin,
+            report_type: self.report_type,
+            games: self.games,
+            not_analysable_games: self.not_analysable_games,
+            signals: self.signals,
+        })
+    }
+}
+
+/// A fresh slug, two groups of m::SLUG_ALPHABET characters.
+fn new_slug() -> m::ReportSlug {
+    let mut rng = thread_rng();
+    let mut group = || -> String {
+        (0..m::SLUG_GROUP_LEN)
+            .filter_map(|_| m::SLUG_ALPHABET.choose(&mut rng))
+            .map(|c| char::from(*c))
+            .collect()
+    };
+    m::ReportSlug(format!("{}-{}", group(), group()))
 }
 
 impl From<CreateReport> for m::Report {
     fn from(report: CreateReport) -> m::Report {
         m::Report {
-            _id: m::ReportId(ObjectId::new()),
+            _id: report.id,
+            slug: Some(new_slug()),
             user_id: report.user_id,
             origin: report.origin,
             report_type: report.report_type,
@@ -48,22 +170,93 @@ impl From<CreateReport> for m::Report {
             date_requested: BsonDateTime(Utc::now()),
             date_completed: None,
             sent_to_irwin: false,
+            signals: Some(report.signals),
+            analysis_reaped: false,
+            date_reaped: None,
+            date_state_changed: Some(BsonDateTime(Utc::now())),
+            missing_games: Vec::new(),
+            excluded_games: Vec::new(),
+            not_analysable_games: report.not_analysable_games,
+            submission_pending: false,
+            anomalies: Vec::new(),
+            deleted_at: None,
         }
     }
 }
 
+// NOTE: about 10^12 slugs, so a second collision in a row means something
+//       else is wrong.
+const SLUG_ATTEMPTS: usize = 3;
+
 pub async fn insert_one_report(db: DbConn, report: CreateReport) -> Result<m::ReportId> {
     let reports_coll = m::Report::coll(db.clone());
-    let report: m::Report = report.into();
-    reports_coll.insert_one(to_document(&report)?, None).await?;
-    Ok(report._id)
+    let mut report: m::Report = report.into();
+    let mut attempt = 1;
+    loop {
+        match reports_coll.insert_one(to_document(&report)?, None).await {
+            Ok(_) => return Ok(report._id),
+            Err(err) if is_duplicate_key(&err) && attempt < SLUG_ATTEMPTS => {
+                warn!("insert_one_report > slug {:?} is taken, retrying", report.slug);
+                report.slug = Some(new_slug());
+                attempt += 1;
+            }
+            Err(err) => return Err(err.into()),
+        }
+    }
 }
 
 pub async fn atomically_update_sent_to_irwin(db: DbConn, id: m::ReportId) -> Result<Option<m::Report>> {
     Ok(m::Report::coll(db)
         .find_one_and_update(
-            doc! {"_id": {"$eq": id.0}, "sent_to_irwin": { "$eq": false }},
-            UpdateModifications::Document(doc! {"$set": { "sent_to_irwin": true }}),
+            live(doc! {"_id": {"$eq": id.0}, "sent_to_irwin": { "$eq": false }}),
+            UpdateModifications::Document(doc! {"$set": {
+                "sent_to_irwin": true,
+                "submission_pending": true,
+                "date_completed": Utc::now(),
+                "date_state_changed": Utc::now(),
+            }}),
+            None,
+        )
+        .await?
+        .map(from_document)
+        .transpose()?)
+}
+
+/// Like `atomically_update_sent_to_irwin`, but for a report submitted without
+/// some of its games.
+pub async fn atomically_update_sent_partially_to_irwin(
+    db: DbConn,
+    id: m::ReportId,
+    missing_games: Vec<m::GameId>,
+) -> Result<Option<m::Report>> {
+    Ok(m::Report::coll(db)
+        .find_one_and_update(
+            live(doc! {"_id": {"$eq": id.0}, "sent_to_irwin": { "$eq": false }}),
+            UpdateModifications::Document(doc! {"$set": {
+                "sent_to_irwin": true,
+                "submission_pending": true,
+                "missing_games": missing_games,
+                "date_completed": Utc::now(),
+                "date_state_changed": Utc::now(),
+            }}),
+            None,
+        )
+        .await?
+        .map(from_document)
+        .transpose()?)
+}
+
+/// Clears the missing games of a partially submitted report, returning it if
+/// we're the ones who get to send the amended submission.
+pub async fn atomically_clear_missing_games(db: DbConn, id: m::ReportId) -> Result<Option<m::Report>> {
+    Ok(m::Report::coll(db)
+        .find_one_and_update(
+            live(doc! {"_id": {"$eq": id.0}, "sent_to_irwin": { "$eq": true }, "missing_games.0": { "$exists": true }}),
+            UpdateModifications::Document(doc! {"$set": {
+                "missing_games": [],
+                "submission_pending": true,
+                "date_state_changed": Utc::now(),
+            }}),
             None,
         )
         .await?
@@ -71,22 +264,489 @@ pub async fn atomically_update_sent_to_irwin(db: DbConn, id: m::ReportId) -> Res
         .transpose()?)
 }
 
+/// Excludes games a moderator knows are irrelevant from a report that hasn't
+/// been sent to irwin yet, cancelling their jobs and dropping their partial
+/// analyses. Returns the report and the jobs that were cancelled.
+pub async fn exclude_games(
+    db: DbConn,
+    id: m::ReportId,
+    games: Vec<m::GameId>,
+) -> Result<Option<(m::Report, Vec<JobId>)>> {
+    let report: Option<m::Report> = m::Report::coll(db.clone())
+        .find_one_and_update(
+            live(doc! {"_id": id.0.clone(), "sent_to_irwin": false}),
+            UpdateModifications::Document(doc! {
+                "$addToSet": {"excluded_games": {"$each": games.clone()}},
+                "$set": {"date_state_changed": Utc::now()},
+            }),
+            FindOneAndUpdateOptions::builder()
+                .return_document(ReturnDocument::After)
+                .build(),
+        )
+        .await?
+        .map(from_document)
+        .transpose()?;
+    let report = match report {
+        Some(report) => report,
+        None => return Ok(None),
+    };
+    let jobs_coll = Job::coll(db.clone());
+    let filter = live(doc! {"report_id": id.0, "game_id": {"$in": games}, "is_complete": false});
+    let options = FindOptions::builder().projection(doc! {"_id": 1}).build();
+    let job_ids: Vec<ObjectId> = jobs_coll
+        .find(filter, options)
+        .await?
+        .map(|doc| Ok::<_, Error>(doc?.get_object_id("_id")?.clone()))
+        .try_collect()
+        .await?;
+    // NOTE: one at a time, so a job that completes in the meantime is left
+    //       alone and we know exactly which were cancelled.
+    let mut cancelled = Vec::new();
+    for job_id in job_ids {
+        let filter = doc! {"_id": job_id.clone(), "is_complete": false};
+        let cancel = jobs_coll
+            .update_one(
+                filter,
+                UpdateModifications::Document(doc! {"$set": {
+                    "owner": Bson::Null,
+                    "expires_at": Bson::Null,
+                    "is_complete": true,
+                    "date_completed": Utc::now(),
+                    "skip_reason": "excluded by a moderator",
+                }}),
+                None,
+            )
+            .await?;
+        if cancel.modified_count > 0 {
+            cancelled.push(job_id);
+        }
+    }
+    drop_analyses(db, cancelled.clone()).await?;
+    Ok(Some((report, cancelled.into_iter().map(JobId).collect())))
+}
+
+/// The partial analyses of cancelled jobs will never be sent anywhere.
+async fn drop_analyses(db: DbConn, job_ids: Vec<ObjectId>) -> Result<()> {
+    if job_ids.is_empty() {
+        return Ok(());
+    }
+    let coll = m::GameAnalysis::coll(db);
+    let filter = doc! {"job_id": {"$in": job_ids}};
+    coll.delete_many(filter, None).await?;
+    Ok(())
+}
+
+/// Soft deletes the report along with its jobs, it's only removed for good
+/// once it's purged. The partial analyses of its unfinished jobs are dropped
+/// straight away, undeleting restarts those jobs.
+pub async fn delete_report(db: DbConn, id: m::ReportId) -> Result<Option<m::Report>> {
+    let deleted_at = Utc::now();
+    let report: Option<m::Report> = m::Report::coll(db.clone())
+        .find_one_and_update(
+            live(doc! {"_id": id.0.clone()}),
+            UpdateModifications::Document(doc! {"$set": {
+                "deleted_at": deleted_at,
+                "date_state_changed": deleted_at,
+            }}),
+            None,
+        )
+        .await?
+        .map(from_document)
+        .transpose()?;
+    if report.is_some() {
+        // NOTE: the jobs share the report's deleted_at, so undeleting the
+        //       report doesn't also undelete jobs that were deleted on their own.
+        let coll = Job::coll(db.clone());
+        let unfinished = live(doc! {"report_id": id.0.clone(), "is_complete": false});
+        let options = FindOptions::builder().projection(doc! {"_id": 1}).build();
+        let unfinished: Vec<ObjectId> = coll
+            .find(unfinished, options)
+            .await?
+            .map(|doc| Ok::<_, Error>(doc?.get_object_id("_id")?.clone()))
+            .try_collect()
+            .await?;
+        let filter = live(doc! {"report_id": id.0});
+        coll.update_many(
+            filter,
+            UpdateModifications::Document(doc! {"$set": {
+                "owner": Bson::Null,
+                "expires_at": Bson::Null,
+                "deleted_at": deleted_at,
+            }}),
+            None,
+        )
+        .await?;
+        drop_analyses(db, unfinished).await?;
+    }
+    Ok(report)
+}
+
+pub async fn undelete_report(db: DbConn, id: m::ReportId) -> Result<Option<m::Report>> {
+    let report: Option<m::Report> = m::Report::coll(db.clone())
+        .find_one(doc! {"_id": id.0.clone(), "deleted_at": {"$ne": Bson::Null}}, None)
+        .await?
+        .map(from_document)
+        .transpose()?;
+    if let Some(deleted_at) = report.and_then(|report| report.deleted_at) {
+        let coll = Job::coll(db.clone());
+        let filter = doc! {"report_id": id.0.clone(), "deleted_at": deleted_at.0};
+        coll.update_many(
+            filter,
+            UpdateModifications::Document(doc! {"$set": {"deleted_at": Bson::Null}}),
+            None,
+        )
+        .await?;
+        m::Report::coll(db.clone())
+            .update_one(
+                doc! {"_id": id.0.clone()},
+                UpdateModifications::Document(doc! {"$set": {
+                    "deleted_at": Bson::Null,
+                    "date_state_changed": Utc::now(),
+                }}),
+                None,
+            )
+            .await?;
+        return find_report(db, id).await;
+    }
+    Ok(None)
+}
+
+pub async fn mark_report_unsent(db: DbConn, id: m::ReportId) -> Result<()> {
+    m::Report::coll(db)
+        .update_one(
+            doc! {"_id": id.0},
+            UpdateModifications::Document(doc! {"$set": {
+                "sent_to_irwin": false,
+                "date_completed": Bson::Null,
+                "date_state_changed": Utc::now(),
+            }}),
+            None,
+        )
+        .await?;
+    Ok(())
+}
+
+pub async fn find_unsent_reports(db: DbConn) -> Result<impl Stream<Item = Result<m::Report>>> {
+    let (coll, filter) = (m::Report::coll(db), live(doc! {"sent_to_irwin": {"$ne": true}}));
+    Ok(coll.find(filter, None)
+        .await?
+        .map(|doc| Ok(from_document::<m::Report>(doc?)?)))
+}
+
+/// Reports claimed for irwin whose submission never finished, e.g. because
+/// we were restarted with it still queued.
+pub async fn find_pending_submissions(db: DbConn) -> Result<impl Stream<Item = Result<m::Report>>> {
+    let (coll, filter) = (m::Report::coll(db), live(doc! {"submission_pending": true}));
+    Ok(coll.find(filter, None)
+        .await?
+        .map(|doc| Ok(from_document::<m::Report>(doc?)?)))
+}
+
+/// Replaces the report's anomaly score for the game, keeping them most
+/// anomalous first.
+pub async fn set_game_anomaly(db: DbConn, id: m::ReportId, anomaly: &m::GameAnomaly) -> Result<()> {
+    let coll = m::Report::coll(db);
+    let filter = doc! {"_id": id.0};
+    coll.update_one(
+        filter.clone(),
+        UpdateModifications::Document(doc! {"$pull": {
+            "anomalies": {"game_id": anomaly.game_id.0.clone()},
+        }}),
+        None,
+    )
+    .await?;
+    coll.update_one(
+        filter,
+        UpdateModifications::Document(doc! {"$push": {
+            "anomalies": {"$each": [to_document(anomaly)?], "$sort": {"score": -1}},
+        }}),
+        None,
+    )
+    .await?;
+    Ok(())
+}
+
+pub async fn mark_submitted(db: DbConn, id: m::ReportId) -> Result<()> {
+    m::Report::coll(db)
+        .update_one(
+            doc! {"_id": id.0},
+            UpdateModifications::Document(doc! {"$set": {"submission_pending": false}}),
+            None,
+        )
+        .await?;
+    Ok(())
+}
+
+pub const REPORT_CHANGES_PAGE_SIZE: i64 = 100;
+
+pub async fn ensure_indexes(db: DbConn) -> Result<()> {
+    ensure_index(
+        db.clone(),
+        "deepq_reports",
+        "date_state_changed_1__id_1",
+        doc! {"date_state_changed": 1, "_id": 1},
+    )
+    .await?;
+    ensure_index(
+        db.clone(),
+        "deepq_irwin_submissions",
+        "report_id_1_date_attempted_-1",
+        doc! {"report_id": 1, "date_attempted": -1},
+    )
+    .await?;
+    // NOTE: for admin search, by username.
+    ensure_index(
+        db.clone(),
+        "deepq_reports",
+        "user_id_1_date_requested_-1",
+        doc! {"user_id": 1, "date_requested": -1},
+    )
+    .await?;
+    // NOTE: replaces the plain slug_1 index, which can't coexist with a unique one.
+    let drop = doc! {"dropIndexes": "deepq_reports", "index": "slug_1"};
+    if let Err(err) = db.database.run_command(drop, None).await {
+        debug!("ensure_indexes > slug_1 > {}", err);
+    }
+    ensure_unique_index(db.clone(), "deepq_reports", "slug_1_unique", doc! {"slug": 1}).await?;
+    ensure_index(db.clone(), "deepq_games", "white_1", doc! {"white": 1}).await?;
+    // NOTE: the orphan games reaper looks games up in both.
+    ensure_index(db.clone(), "deepq_fishnetjobs", "game_id_1", doc! {"game_id": 1}).await?;
+    ensure_index(db.clone(), "deepq_reports", "games_1", doc! {"games": 1}).await?;
+    ensure_index(db.clone(), "deepq_games", "black_1", doc! {"black": 1}).await?;
+    ensure_index(
+        db.clone(),
+        "deepq_aborts",
+        "date_aborted_-1",
+        doc! {"date_aborted": -1},
+    )
+    .await?;
+    ensure_index(db, "deepq_apiuser", "name_1", doc! {"name": 1}).await
+}
+
+/// Migration: reports from before date_state_changed existed take their
+/// request date, so they show up in /reports/changes too. Cheap to rerun.
+pub async fn backfill_date_state_changed(db: DbConn) -> Result<()> {
+    let p = "backfill_date_state_changed >";
+    let result = m::Report::coll(db)
+        .update_many(
+            doc! {"date_state_changed": {"$exists": false}},
+            UpdateModifications::Pipeline(vec![
+                doc! {"$set": {"date_state_changed": "$date_requested"}},
+            ]),
+            None,
+        )
+        .await?;
+    if result.modified_count > 0 {
+        info!("{} {} reports", p, result.modified_count);
+    }
+    Ok(())
+}
+
+/// Where a page of report changes ended, the next page starts after it.
+#[derive(Debug, Clone)]
+pub struct ChangesCursor {
+    pub date_state_changed: DateTime<Utc>,
+    pub id: ObjectId,
+}
+
+impl fmt::Display for ChangesCursor {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.date_state_changed.timestamp_millis(), self.id)
+    }
+}
+
+impl FromStr for ChangesCursor {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<ChangesCursor> {
+        let (millis, id) = s.split_once(':').ok_or(ProtocolError::Deserialization)?;
+        let millis: i64 = millis.parse().map_err(|_| ProtocolError::Deserialization)?;
+        Ok(ChangesCursor {
+            date_state_changed: Utc.timestamp_millis(millis),
+            id: ObjectId::with_string(id)?,
+        })
+    }
+}
+
+/// A page of reports whose state changed since the timestamp (or cursor),
+/// oldest change first, and the cursor for the next page if it was full.
+pub async fn find_report_changes(
+    db: DbConn,
+    since: DateTime<Utc>,
+    cursor: Option<ChangesCursor>,
+) -> Result<(Vec<m::Report>, Option<ChangesCursor>)> {
+    let filter = match cursor {
+        Some(cursor) => doc! {"$or": [
+            {"date_state_changed": {"$gt": cursor.date_state_changed}},
+            {"date_state_changed": cursor.date_state_changed, "_id": {"$gt": cursor.id}},
+        ]},
+        None => doc! {"date_state_changed": {"$gte": since}},
+    };
+    // NOTE: deleted reports are included, deleting one is a change too.
+    let coll = m::Report::coll(db);
+    let options = FindOptions::builder()
+        .sort(doc! {"date_state_changed": 1, "_id": 1})
+        .limit(REPORT_CHANGES_PAGE_SIZE)
+        .build();
+    let reports: Vec<m::Report> = coll
+        .find(filter, options)
+        .await?
+        .map(|doc| Ok(from_document::<m::Report>(doc?)?))
+        .collect::<Vec<Result<m::Report>>>()
+        .await
+        .into_iter()
+        .collect::<Result<_>>()?;
+    let next = match reports.last() {
+        Some(last) if reports.len() as i64 == REPORT_CHANGES_PAGE_SIZE => {
+            last.date_state_changed.as_ref().map(|date| ChangesCursor {
+                date_state_changed: date.0,
+                id: last._id.0.clone(),
+            })
+        }
+        _ => None,
+    };
+    Ok((reports, next))
+}
+
+/// The id of the report referred to, whether or not it's deleted.
+pub async fn resolve_report(db: DbConn, report: m::ReportRef) -> Result<Option<m::ReportId>> {
+    let slug = match report {
+        m::ReportRef::Id(id) => return Ok(Some(id)),
+        m::ReportRef::Slug(slug) => slug,
+    };
+    let coll = m::Report::coll(db);
+    let filter = doc! {"slug": slug.0};
+    let options = FindOneOptions::builder().projection(doc! {"_id": 1}).build();
+    let found = coll.find_one(filter, options).await?;
+    Ok(found.map(|found| found.get_object_id("_id").cloned()).transpose()?.map(m::ReportId))
+}
+
 pub async fn find_report(db: DbConn, id: m::ReportId) -> Result<Option<m::Report>> {
     let reports_coll = m::Report::coll(db.clone());
     Ok(reports_coll
-        .find_one(doc! {"_id": id.0}, None)
+        .find_one(live(doc! {"_id": id.0}), None)
         .await?
         .map(from_document)
         .transpose()?)
 }
 
-pub fn precedence_for_origin(origin: m::ReportOrigin) -> i32 {
-    match origin {
-        m::ReportOrigin::Moderator => 1_000_000i32,
-        m::ReportOrigin::Leaderboard => 1000i32,
-        m::ReportOrigin::Tournament => 100i32,
-        m::ReportOrigin::Random => 10i32,
+pub async fn find_reports_for_user(
+    db: DbConn,
+    user_id: m::UserId,
+) -> Result<impl Stream<Item = Result<m::Report>>> {
+    let (coll, filter) = (m::Report::coll(db), live(doc! {"user_id": user_id}));
+    Ok(coll.find(filter, None)
+        .await?
+        .map(|doc| Ok(from_document::<m::Report>(doc?)?)))
+}
+
+/// The user's most recent report requested since the timestamp, if any.
+pub async fn latest_report_for_user(
+    db: DbConn,
+    user_id: m::UserId,
+    since: DateTime<Utc>,
+) -> Result<Option<m::Report>> {
+    let coll = m::Report::coll(db);
+    let filter = live(doc! {"user_id": user_id, "date_requested": {"$gte": since}});
+    let options = FindOneOptions::builder().sort(doc! {"date_requested": -1}).build();
+    Ok(coll.find_one(filter, options)
+        .await?
+        .map(from_document)
+        .transpose()?)
+}
+
+pub async fn count_recent_reports_for_user(
+    db: DbConn,
+    user_id: m::UserId,
+    since: DateTime<Utc>,
+) -> Result<i64> {
+    let coll = m::Report::coll(db);
+    let filter = live(doc! {"user_id": user_id, "date_requested": {"$gte": since}});
+    Ok(coll.count_documents(filter, None).await?)
+}
+
+/// Lowercases a string field in place, leaving missing and null values alone.
+fn normalized_field(field: &str) -> Document {
+    let value = format!("${}", field);
+    doc! {
+        field: {
+            "$cond": [
+                {"$eq": [{"$type": value.clone()}, "string"]},
+                {"$toLower": {"$trim": {"input": value.clone()}}},
+                value,
+            ]
+        }
+    }
+}
+
+/// Migration: normalizes the usernames stored before UserId normalized them.
+pub async fn normalize_user_ids(db: DbConn) -> Result<i64> {
+    let p = "normalize_user_ids >";
+    let mut total = 0;
+    for (coll, fields) in [
+        (m::Report::coll(db.clone()), vec!["user_id"]),
+        (m::Game::coll(db.clone()), vec!["white", "black"]),
+        (ApiUser::coll(db.clone()), vec!["user"]),
+    ] {
+        for field in fields {
+            let result = coll
+                .update_many(
+                    doc! {field: {"$type": "string"}},
+                    UpdateModifications::Pipeline(vec![doc! {"$set": normalized_field(field)}]),
+                    None,
+                )
+                .await?;
+            info!("{} {}.{} > {} normalized", p, coll.name(), field, result.modified_count);
+            total += result.modified_count;
+        }
     }
+    Ok(total)
+}
+
+pub async fn insert_audit_entry(
+    db: DbConn,
+    actor: &str,
+    action: &str,
+    details: Document,
+) -> Result<()> {
+    let entry = m::AuditEntry {
+        _id: ObjectId::new(),
+        date: BsonDateTime(Utc::now()),
+        actor: actor.to_string(),
+        action: action.to_string(),
+        details,
+    };
+    info!("audit > {} > {} > {}", entry.actor, entry.action, entry.details);
+    m::AuditEntry::coll(db)
+        .insert_one(to_document(&entry)?, None)
+        .await?;
+    Ok(())
+}
+
+pub async fn insert_irwin_submission(db: DbConn, submission: m::IrwinSubmission) -> Result<()> {
+    m::IrwinSubmission::coll(db)
+        .insert_one(to_document(&submission)?, None)
+        .await?;
+    Ok(())
+}
+
+/// The latest submission attempts, optionally only those of one report.
+pub async fn find_irwin_submissions(
+    db: DbConn,
+    report_id: Option<m::ReportId>,
+    limit: i64,
+) -> Result<Vec<m::IrwinSubmission>> {
+    let filter = report_id.map_or_else(Document::new, |id| doc! {"report_id": id.0});
+    let coll = m::IrwinSubmission::coll(db.analytics());
+    let options = FindOptions::builder()
+        .sort(doc! {"date_attempted": -1})
+        .limit(limit)
+        .build();
+    coll.find(filter, options)
+        .await?
+        .map(|doc| Ok::<_, Error>(from_document::<m::IrwinSubmission>(doc?)?))
+        .try_collect()
+        .await
 }
 
 pub fn starting_position(_game: m::Game) -> Fen {
@@ -105,16 +765,21 @@ pub struct CreateGame {
     pub pgn: Vec<Uci>,
     pub black: Option<m::UserId>,
     pub white: Option<m::UserId>,
+    pub provenance: m::Provenance,
 }
 
 impl From<CreateGame> for m::Game {
     fn from(g: CreateGame) -> m::Game {
+        let not_analysable = !is_analysable(g.pgn.len());
         m::Game {
             _id: g.game_id,
             emts: g.emts,
             pgn: g.pgn,
             black: g.black,
             white: g.white,
+            not_analysable,
+            provenance: g.provenance,
+            date_stored: Some(BsonDateTime(Utc::now())),
         }
     }
 }
@@ -148,22 +813,42 @@ where
 
 pub async fn find_game(db: DbConn, game_id: m::GameId) -> Result<Option<m::Game>> {
     let games_coll = m::Game::coll(db.clone());
-    Ok(games_coll
-        .find_one(doc! {"_id": game_id}, None)
+    let span = Span::start("db.find_game", SpanKind::Client);
+    Ok(instrument(span, games_coll.find_one(doc! {"_id": game_id}, None))
         .await?
         .map(from_document)
         .transpose()?)
 }
 
+#[derive(Deserialize)]
+struct GameProvenance {
+    #[serde(default)]
+    provenance: m::Provenance,
+}
+
+async fn game_provenance(db: DbConn, game_id: m::GameId) -> Result<m::Provenance> {
+    let options = FindOneOptions::builder()
+        .projection(doc! {"provenance": 1})
+        .build();
+    Ok(m::Game::coll(db)
+        .find_one(doc! {"_id": game_id}, options)
+        .await?
+        .map(from_document::<GameProvenance>)
+        .transpose()?
+        .map(|game| game.provenance)
+        .unwrap_or_default())
+}
+
 #[derive(Debug, Clone)]
 pub struct UpdateGameAnalysis {
     pub job_id: JobId,
     pub game_id: m::GameId,
     pub source_id: m::UserId,
     pub analysis: Vec<Option<m::PlyAnalysis>>,
-    pub requested_pvs: Option<i32>,
-    pub requested_depth: Option<i32>,
-    pub requested_nodes: m::Nodes,
+    pub requested_pvs: Option<MultiPv>,
+    pub requested_depth: Option<Depth>,
+    pub requested_nodes: NodeBudget,
+    pub engine: m::EngineType,
 }
 
 impl From<UpdateGameAnalysis> for m::GameAnalysis {
@@ -177,32 +862,65 @@ impl From<UpdateGameAnalysis> for m::GameAnalysis {
             requested_pvs: g.requested_pvs,
             requested_depth: g.requested_depth,
             requested_nodes: g.requested_nodes,
+            quarantine_reason: None,
+            engine: g.engine,
+            provenance: m::Provenance::Unknown, // Copied from the game when upserted
+            pv_history_truncated: false,
         }
     }
 }
 
+/// Analyses must belong to a job, deleted or not, for the game they analyse,
+/// otherwise nothing would ever clean them up.
+async fn ensure_job_for_game(db: DbConn, job_id: &JobId, game_id: &m::GameId) -> Result<()> {
+    let options = FindOneOptions::builder().projection(doc! {"_id": 1}).build();
+    Job::coll(db)
+        .find_one(doc! {"_id": job_id.0.clone(), "game_id": game_id.clone()}, options)
+        .await?
+        .ok_or_else(|| {
+            DomainError::DanglingReference(format!("Job({}) of Game({})", job_id, game_id)).into()
+        })
+        .map(|_| ())
+}
+
 pub async fn upsert_one_game_analysis(
     db: DbConn,
+    payload: &PayloadGuard,
     analysis: UpdateGameAnalysis,
 ) -> Result<ObjectId> {
     let analysis_coll = m::GameAnalysis::coll(db.clone());
-    let analysis: m::GameAnalysis = analysis.into();
-    let result = analysis_coll
-        .update_one(
+    ensure_job_for_game(db.clone(), &analysis.job_id, &analysis.game_id).await?;
+    let mut analysis: m::GameAnalysis = analysis.into();
+    analysis.provenance = game_provenance(db, analysis.game_id.clone()).await?;
+    let span = Span::start("db.upsert_one_game_analysis", SpanKind::Client);
+    let result = instrument(
+        span,
+        analysis_coll.update_one(
             doc! { "_id": analysis._id.clone() },
-            to_document(&analysis)?,
+            payload.fitted_document(&mut analysis)?,
             Some(UpdateOptions::builder().upsert(true).build()),
-        )
-        .await?;
+        ),
+    )
+    .await?;
     debug!("Result: {:?}", result);
     Ok(analysis._id)
 }
 
 pub async fn find_analysis_for_job(db: DbConn, job_id: JobId) -> Result<Option<m::GameAnalysis>> {
-    let analysis_coll = m::GameAnalysis::coll(db.clone());
-    Ok(analysis_coll
-        .find_one(doc! {"job_id": job_id.0}, None)
+    let (coll, filter) = (m::GameAnalysis::coll(db), doc! {"job_id": job_id.0});
+    Ok(coll.find_one(filter, None)
         .await?
         .map(from_document)
         .transpose()?)
 }
+
+pub async fn has_complete_analysis(db: DbConn, job_id: JobId) -> Result<bool> {
+    let (coll, filter) = (m::GameAnalysis::coll(db), doc! {"job_id": job_id.0});
+    let mut analyses = coll.find(filter, None).await?;
+    while let Some(doc) = analyses.next().await {
+        if from_document::<m::GameAnalysis>(doc?)?.is_analysis_complete() {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
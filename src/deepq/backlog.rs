@@ -0,0 +1,83 @@
+// Copyright 2020 Lakin Wecker
+//
+// This file is part of lila-deepq.
+//
+// lila-deepq is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// lila-deepq is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with lila-deepq.  If not, see <https://www.gnu.org/licenses/>.
+//
+// Overload control. Past the high watermark intake still queues the work, but
+// tells whoever sent it how far behind we are, so that they can throttle
+// instead of us silently falling further and further behind.
+//
+use chrono::Duration;
+use mongodb::bson::{doc, Bson};
+use serde::Serialize;
+
+use crate::db::{live, DbConn};
+use crate::deepq::stats::ServiceTimes;
+use crate::error::Result;
+use crate::fishnet::model::{AnalysisType, Job};
+
+// NOTE: when nothing completed recently there's no service time to go on.
+const DEFAULT_RETRY_AFTER_SECONDS: i64 = 15 * 60;
+
+/// What intake responds with once the backlog is past its high watermark.
+#[derive(Serialize, Debug, Clone)]
+pub struct Advisory {
+    pub queued: i64,        // Deep jobs waiting for a worker
+    pub queued_behind: i64, // Of those, how many are at least as urgent as the new work
+    pub retry_after: i64,   // Seconds until the backlog should be back under the watermark
+}
+
+#[derive(Clone)]
+pub struct Backlog {
+    pub high_watermark: i64,
+    pub service_times: ServiceTimes,
+}
+
+impl Backlog {
+    pub fn new(high_watermark: i64, service_times: ServiceTimes) -> Backlog {
+        Backlog {
+            high_watermark,
+            service_times,
+        }
+    }
+
+    /// None while the backlog is below the high watermark.
+    pub async fn advise(&self, db: DbConn, precedence: i32) -> Result<Option<Advisory>> {
+        let waiting = doc! {
+            "owner": Bson::Null,
+            "is_complete": false,
+            "analysis_type": AnalysisType::Deep,
+        };
+        let queued = Job::coll(db.clone())
+            .count_documents(live(waiting.clone()), None)
+            .await?;
+        if queued < self.high_watermark {
+            return Ok(None);
+        }
+        let mut ahead = waiting;
+        ahead.insert("precedence", doc! {"$gte": precedence});
+        let queued_behind = Job::coll(db).count_documents(live(ahead), None).await?;
+        let retry_after = self
+            .service_times
+            .eta(AnalysisType::Deep, queued - self.high_watermark)
+            .await
+            .map_or(DEFAULT_RETRY_AFTER_SECONDS, |eta| eta.num_seconds());
+        Ok(Some(Advisory {
+            queued,
+            queued_behind,
+            retry_after: retry_after.max(Duration::minutes(1).num_seconds()),
+        }))
+    }
+}
@@ -0,0 +1,82 @@
+// Copyright 2020 Lakin Wecker
+//
+// This file is part of lila-deepq.
+//
+// lila-deepq is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// lila-deepq is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with lila-deepq.  If not, see <https://www.gnu.org/licenses/>.
+//
+// A small SVG badge of a report's progress, so lila's mod views can show it
+// live with a plain image tag.
+//
+use crate::crypto::{sign, verify, Secret};
+use crate::deepq::model::ReportId;
+use crate::deepq::stats::{ProgressState, ReportProgress};
+
+// NOTE: roughly the width of a character at the badge's font size.
+const CHAR_WIDTH: usize = 7;
+const PADDING: usize = 10;
+
+fn colour(state: ProgressState) -> &'static str {
+    match state {
+        ProgressState::Empty | ProgressState::Queued => "#9f9f9f",
+        ProgressState::Analysing => "#dfb317",
+        ProgressState::Partial => "#fe7d37",
+        ProgressState::Complete => "#4c1",
+    }
+}
+
+fn token_message(report_id: &ReportId) -> String {
+    format!("badge:{}", report_id.0.to_hex())
+}
+
+/// Signs a report's badge url, so it can be embedded in an image tag without
+/// handing out an api key.
+pub fn token(secret: &Secret, report_id: &ReportId) -> String {
+    sign(secret, token_message(report_id).as_bytes())
+}
+
+pub fn is_valid_token(secret: &Secret, report_id: &ReportId, token: &str) -> bool {
+    verify(secret, token_message(report_id).as_bytes(), token)
+}
+
+/// Renders the badge, "deepq" on the left and the state and percentage on the right.
+pub fn render(progress: &ReportProgress) -> String {
+    let label = "deepq";
+    let message = match progress.state {
+        ProgressState::Empty => progress.state.to_string(),
+        state => format!("{} {}%", state, progress.percent),
+    };
+    let label_width = label.len() * CHAR_WIDTH + PADDING;
+    let message_width = message.len() * CHAR_WIDTH + PADDING;
+    let width = label_width + message_width;
+    format!(
+        concat!(
+            r##"<svg xmlns="http://www.w3.org/2000/svg" width="{width}" height="20" role="img" aria-label="{label}: {message}">"##,
+            r##"<title>{label}: {message}</title>"##,
+            r##"<rect width="{label_width}" height="20" fill="#555"/>"##,
+            r##"<rect x="{label_width}" width="{message_width}" height="20" fill="{colour}"/>"##,
+            r##"<g fill="#fff" text-anchor="middle" font-family="Verdana,DejaVu Sans,sans-serif" font-size="11">"##,
+            r##"<text x="{label_x}" y="14">{label}</text>"##,
+            r##"<text x="{message_x}" y="14">{message}</text>"##,
+            r##"</g></svg>"##,
+        ),
+        width = width,
+        label = label,
+        message = message,
+        label_width = label_width,
+        message_width = message_width,
+        colour = colour(progress.state),
+        label_x = label_width / 2,
+        message_x = label_width + message_width / 2,
+    )
+}
@@ -0,0 +1,411 @@
+// Copyright 2020 Lakin Wecker
+//
+// This file is part of lila-deepq.
+//
+// lila-deepq is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// lila-deepq is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with lila-deepq.  If not, see <https://www.gnu.org/licenses/>.
+//
+// Evidence bundles for escalations to lichess's appeals and legal process: a
+// zip of everything we know about a report, with a manifest of hashes so it
+// can be shown to be unaltered. Entries are stored uncompressed and sent as
+// each one is assembled, so a large report never sits in memory as a whole.
+//
+// irwin's verdict isn't part of it: irwin answers lila, not us, so all we
+// have is what irwin was sent. The manifest says as much.
+//
+use std::io::Error as IoError;
+
+use chrono::{prelude::*, Duration};
+use futures::channel::mpsc;
+use futures::sink::SinkExt;
+use futures::stream::{StreamExt, TryStreamExt};
+use log::{error, info};
+use mongodb::bson::{doc, from_document, Bson};
+use serde::Serialize;
+use shakmaty::{san::San, CastlingMode, Chess, Position};
+
+use crate::crypto::sha256_hex;
+use crate::db::DbConn;
+use crate::deepq::api::{find_analysis_for_job, find_game, starting_position};
+use crate::deepq::model::{AuditEntry, Game, GameAnalysis, Report, Score};
+use crate::deepq::results::compact;
+use crate::deepq::score::{in_perspective, Perspective};
+use crate::error::{DomainError, Error, Result};
+use crate::fishnet::model::Job;
+use crate::irwin::job::irwin_job_from_report;
+
+pub const MANIFEST: &str = "manifest.json";
+
+const NO_VERDICT: &str =
+    "irwin reports its verdict to lila, not to lila-deepq, so it isn't included. irwin.json is what irwin was sent.";
+
+/// Chunks of the zip as they're assembled, an error aborts the download.
+pub type Chunks = mpsc::Receiver<std::result::Result<Vec<u8>, IoError>>;
+
+struct ZipEntry {
+    name: String,
+    crc: u32,
+    size: u32,
+    offset: u32,
+}
+
+/// Writes a zip archive of stored entries, one entry at a time.
+struct ZipWriter {
+    entries: Vec<ZipEntry>,
+    offset: u32,
+    time: u16, // MS-DOS format, shared by every entry
+    date: u16,
+}
+
+impl ZipWriter {
+    fn new(now: DateTime<Utc>) -> ZipWriter {
+        ZipWriter {
+            entries: Vec::new(),
+            offset: 0,
+            time: ((now.hour() << 11) | (now.minute() << 5) | (now.second() / 2)) as u16,
+            date: (((now.year() - 1980).max(0) as u32) << 9 | (now.month() << 5) | now.day()) as u16,
+        }
+    }
+
+    /// The local header and data of the entry.
+    fn entry(&mut self, name: &str, data: &[u8]) -> Vec<u8> {
+        let entry = ZipEntry {
+            name: name.to_string(),
+            crc: crc32fast::hash(data),
+            size: data.len() as u32,
+            offset: self.offset,
+        };
+        let mut out = Vec::with_capacity(30 + name.len() + data.len());
+        out.extend_from_slice(&0x0403_4b50u32.to_le_bytes());
+        out.extend_from_slice(&20u16.to_le_bytes()); // version needed
+        out.extend_from_slice(&0x0800u16.to_le_bytes()); // utf-8 names
+        out.extend_from_slice(&0u16.to_le_bytes()); // stored
+        out.extend_from_slice(&self.time.to_le_bytes());
+        out.extend_from_slice(&self.date.to_le_bytes());
+        out.extend_from_slice(&entry.crc.to_le_bytes());
+        out.extend_from_slice(&entry.size.to_le_bytes()); // compressed
+        out.extend_from_slice(&entry.size.to_le_bytes()); // uncompressed
+        out.extend_from_slice(&(name.len() as u16).to_le_bytes());
+        out.extend_from_slice(&0u16.to_le_bytes()); // extra
+        out.extend_from_slice(name.as_bytes());
+        out.extend_from_slice(data);
+        self.offset += out.len() as u32;
+        self.entries.push(entry);
+        out
+    }
+
+    /// The central directory, which ends the archive.
+    fn finish(self) -> Vec<u8> {
+        let mut out = Vec::new();
+        for entry in self.entries.iter() {
+            out.extend_from_slice(&0x0201_4b50u32.to_le_bytes());
+            out.extend_from_slice(&20u16.to_le_bytes()); // version made by
+            out.extend_from_slice(&20u16.to_le_bytes()); // version needed
+            out.extend_from_slice(&0x0800u16.to_le_bytes());
+            out.extend_from_slice(&0u16.to_le_bytes());
+            out.extend_from_slice(&self.time.to_le_bytes());
+            out.extend_from_slice(&self.date.to_le_bytes());
+            out.extend_from_slice(&entry.crc.to_le_bytes());
+            out.extend_from_slice(&entry.size.to_le_bytes());
+            out.extend_from_slice(&entry.size.to_le_bytes());
+            out.extend_from_slice(&(entry.name.len() as u16).to_le_bytes());
+            out.extend_from_slice(&[0u8; 12]); // extra, comment, disk, attributes
+            out.extend_from_slice(&entry.offset.to_le_bytes());
+            out.extend_from_slice(entry.name.as_bytes());
+        }
+        let size = out.len() as u32;
+        out.extend_from_slice(&0x0605_4b50u32.to_le_bytes());
+        out.extend_from_slice(&[0u8; 4]); // disk numbers
+        out.extend_from_slice(&(self.entries.len() as u16).to_le_bytes());
+        out.extend_from_slice(&(self.entries.len() as u16).to_le_bytes());
+        out.extend_from_slice(&size.to_le_bytes());
+        out.extend_from_slice(&self.offset.to_le_bytes());
+        out.extend_from_slice(&0u16.to_le_bytes()); // comment
+        out
+    }
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct ManifestFile {
+    name: String,
+    bytes: usize,
+    sha256: String,
+}
+
+/// A file that should have been in the bundle, but couldn't be assembled.
+#[derive(Serialize, Debug, Clone)]
+pub struct ManifestError {
+    name: String,
+    error: String,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct Manifest {
+    report_id: String,
+    user_id: String,
+    generated_at: DateTime<Utc>,
+    generated_by: String,
+    files: Vec<ManifestFile>, // Everything in the bundle besides the manifest
+    errors: Vec<ManifestError>,
+    verdict: &'static str, // Why there's none
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct TimelineEvent {
+    date: DateTime<Utc>,
+    event: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    details: Option<serde_json::Value>,
+}
+
+impl TimelineEvent {
+    fn new(date: DateTime<Utc>, event: impl Into<String>) -> TimelineEvent {
+        TimelineEvent {
+            date,
+            event: event.into(),
+            details: None,
+        }
+    }
+}
+
+struct Bundle {
+    zip: ZipWriter,
+    files: Vec<ManifestFile>,
+    errors: Vec<ManifestError>,
+    tx: mpsc::Sender<std::result::Result<Vec<u8>, IoError>>,
+    closed: bool, // The download was abandoned, stop assembling
+}
+
+impl Bundle {
+    async fn add(&mut self, name: String, data: Vec<u8>) {
+        if self.closed {
+            return;
+        }
+        self.files.push(ManifestFile {
+            name: name.clone(),
+            bytes: data.len(),
+            sha256: sha256_hex(&data),
+        });
+        let chunk = self.zip.entry(&name, &data);
+        self.closed = self.tx.send(Ok(chunk)).await.is_err();
+    }
+
+    /// Adds the file, or notes in the manifest why it couldn't be.
+    async fn add_or_note(&mut self, name: String, data: Result<Vec<u8>>) {
+        match data {
+            Ok(data) => self.add(name, data).await,
+            Err(err) => {
+                error!("evidence_bundle > {} > {:?}", name, err);
+                self.errors.push(ManifestError {
+                    name,
+                    error: err.to_string(),
+                });
+            }
+        }
+    }
+}
+
+/// Formats a score already from white's point of view, as PGN evals are.
+fn eval(score: &Score) -> Option<String> {
+    match score.normalized() {
+        Score::Cp(cp) => Some(format!("{:.2}", cp as f64 / 100f64)),
+        Score::Mate(mate) => Some(format!("#{}", mate)),
+        _ => None,
+    }
+}
+
+/// The game with the engine's evaluation and the move times as comments.
+fn annotated_pgn(game: &Game, analysis: Option<&GameAnalysis>) -> Result<String> {
+    let player = |player: &Option<_>| {
+        player
+            .as_ref()
+            .map(ToString::to_string)
+            .unwrap_or_else(|| "?".to_string())
+    };
+    let mut pgn = format!(
+        "[Event \"lila-deepq evidence\"]\n[Site \"https://lichess.org/{}\"]\n[White \"{}\"]\n[Black \"{}\"]\n[Provenance \"{}\"]\n",
+        game._id,
+        player(&game.white),
+        player(&game.black),
+        game.provenance
+    );
+    if let Some(analysis) = analysis {
+        pgn.push_str(&format!("[Annotator \"lila-deepq {}\"]\n", analysis.engine));
+    }
+    pgn.push('\n');
+    let mut pos: Chess = starting_position(game.clone())
+        .position(CastlingMode::Standard)
+        .map_err(|_| DomainError::IllegalPosition)?;
+    let mut moves = Vec::with_capacity(game.pgn.len());
+    for (ply, uci) in game.pgn.iter().enumerate() {
+        let m = uci
+            .to_move(&pos)
+            .map_err(|_| DomainError::IllegalPosition)?;
+        let mut text = if ply % 2 == 0 {
+            format!("{}. {}", ply / 2 + 1, San::from_move(&pos, &m))
+        } else {
+            San::from_move(&pos, &m).to_string()
+        };
+        pos.play_unchecked(&m);
+        let mut comments = Vec::new();
+        let score = analysis
+            .and_then(|analysis| analysis.analysis.get(ply + 1))
+            .and_then(|ply| ply.as_ref())
+            .and_then(|ply| ply.score());
+        let score = score.map(|score| in_perspective(&score, ply + 1, Perspective::White));
+        if let Some(eval) = score.as_ref().and_then(eval) {
+            comments.push(format!("[%eval {}]", eval));
+        }
+        if let Some(emt) = game.emts.get(ply) {
+            // NOTE: emts are in centiseconds.
+            let emt = Duration::milliseconds(*emt as i64 * 10);
+            comments.push(format!(
+                "[%emt {}:{:02}:{:02}]",
+                emt.num_hours(),
+                emt.num_minutes() % 60,
+                emt.num_seconds() % 60
+            ));
+        }
+        if !comments.is_empty() {
+            text.push_str(&format!(" {{ {} }}", comments.join(" ")));
+        }
+        moves.push(text);
+    }
+    pgn.push_str(&moves.join(" "));
+    pgn.push_str(" *\n");
+    Ok(pgn)
+}
+
+async fn timeline(db: DbConn, report: &Report, jobs: &[Job]) -> Result<Vec<TimelineEvent>> {
+    let mut events = vec![TimelineEvent::new(report.date_requested.0, "report_requested")];
+    for job in jobs.iter() {
+        let details = serde_json::json!({"job_id": job._id.to_string(), "game_id": job.game_id});
+        events.push(TimelineEvent {
+            details: Some(details.clone()),
+            ..TimelineEvent::new(job._id.0.timestamp(), "job_queued")
+        });
+        if let Some(date_completed) = job.date_completed {
+            let event = if job.skip_reason.is_some() { "job_skipped" } else { "job_completed" };
+            events.push(TimelineEvent {
+                details: Some(details),
+                ..TimelineEvent::new(date_completed.0, event)
+            });
+        }
+    }
+    if let Some(date_completed) = report.date_completed {
+        events.push(TimelineEvent::new(date_completed.0, "report_sent_to_irwin"));
+    }
+    let job_ids: Vec<Bson> = jobs.iter().map(|job| Bson::ObjectId(job._id.0.clone())).collect();
+    let audit: Vec<AuditEntry> = AuditEntry::coll(db)
+        .find(
+            doc! {"$or": [
+                {"details.report_id": report._id.0.clone()},
+                {"details.job_id": {"$in": job_ids}},
+            ]},
+            None,
+        )
+        .await?
+        .map(|doc| Ok::<_, Error>(from_document::<AuditEntry>(doc?)?))
+        .try_collect()
+        .await?;
+    for entry in audit {
+        events.push(TimelineEvent {
+            details: Some(serde_json::json!({"actor": entry.actor, "details": entry.details})),
+            ..TimelineEvent::new(entry.date.0, format!("audit:{}", entry.action))
+        });
+    }
+    events.sort_by_key(|event| event.date);
+    Ok(events)
+}
+
+async fn assemble(db: DbConn, report: Report, generated_by: String, bundle: &mut Bundle) -> Result<()> {
+    let mut jobs: Vec<Job> = Job::find_by_report(db.clone(), report.clone())
+        .await?
+        .try_collect()
+        .await?;
+    // NOTE: follow up analysis is deeper, so it's what the pgn is annotated with.
+    jobs.sort_by_key(|job| job.follow_up);
+
+    let mut games: Vec<(Game, Option<GameAnalysis>)> = Vec::new();
+    for job in jobs.iter() {
+        let analysis = find_analysis_for_job(db.clone(), job._id.clone()).await?;
+        if let Some(analysis) = analysis.as_ref() {
+            let name = format!("analysis/{}-{}.json", job.game_id, job._id);
+            bundle.add(name, serde_json::to_vec_pretty(&compact(analysis, Perspective::White))?).await;
+        }
+        match games.iter_mut().find(|(game, _)| game._id.0 == job.game_id.0) {
+            Some((_, existing)) => *existing = analysis.or(existing.take()),
+            None => {
+                if let Some(game) = find_game(db.clone(), job.game_id.clone()).await? {
+                    games.push((game, analysis));
+                }
+            }
+        }
+        if bundle.closed {
+            return Ok(());
+        }
+    }
+    for (game, analysis) in games.iter() {
+        let pgn = annotated_pgn(game, analysis.as_ref()).map(String::into_bytes);
+        bundle.add_or_note(format!("games/{}.pgn", game._id), pgn).await;
+    }
+
+    let irwin_job = irwin_job_from_report(db.clone(), report.clone())
+        .await
+        .and_then(|irwin_job| Ok(serde_json::to_vec_pretty(&irwin_job)?));
+    bundle.add_or_note("irwin.json".to_string(), irwin_job).await;
+    let timeline = timeline(db, &report, &jobs).await?;
+    bundle.add("timeline.json".to_string(), serde_json::to_vec_pretty(&timeline)?).await;
+
+    let manifest = Manifest {
+        report_id: report._id.to_string(),
+        user_id: report.user_id.to_string(),
+        generated_at: Utc::now(),
+        generated_by,
+        files: bundle.files.clone(),
+        errors: bundle.errors.clone(),
+        verdict: NO_VERDICT,
+    };
+    bundle.add(MANIFEST.to_string(), serde_json::to_vec_pretty(&manifest)?).await;
+    Ok(())
+}
+
+/// Starts assembling the report's evidence bundle, returning the zip's
+/// chunks as they're ready.
+pub fn evidence_bundle(db: DbConn, report: Report, generated_by: String) -> Chunks {
+    let (tx, rx) = mpsc::channel(4);
+    tokio::spawn(async move {
+        let p = "evidence_bundle >";
+        let report_id = report._id.clone();
+        let mut bundle = Bundle {
+            zip: ZipWriter::new(Utc::now()),
+            files: Vec::new(),
+            errors: Vec::new(),
+            tx,
+            closed: false,
+        };
+        match assemble(db.analytics(), report, generated_by, &mut bundle).await {
+            Ok(()) if !bundle.closed => {
+                let end = bundle.zip.finish();
+                let _ = bundle.tx.send(Ok(end)).await;
+                info!("{} Report({}) > {} files", p, report_id, bundle.files.len());
+            }
+            Ok(()) => info!("{} Report({}) > abandoned", p, report_id),
+            Err(err) => {
+                error!("{} Report({}) > {:?}", p, report_id, err);
+                let _ = bundle.tx.send(Err(IoError::other(err.to_string()))).await;
+            }
+        }
+    });
+    rx
+}
@@ -0,0 +1,48 @@
+// Copyright 2021 Lakin Wecker
+//
+// This file is part of lila-deepq.
+//
+// lila-deepq is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// lila-deepq is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with lila-deepq.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::result::Result as StdResult;
+
+use warp::{reject, Filter, Rejection};
+
+use super::{api, model as m};
+use crate::db::DbConn;
+use crate::http::{id, with};
+
+async fn resolve(db: DbConn, report: m::ReportRef) -> StdResult<m::ReportId, Rejection> {
+    api::resolve_report(db, report)
+        .await?
+        .ok_or_else(reject::not_found)
+}
+
+/// A report's id from the path, given either as its ObjectId or its slug.
+/// Malformed ones are a 400, slugs that no report has a 404.
+pub fn report_id(db: DbConn) -> impl Filter<Extract = (m::ReportId,), Error = Rejection> + Clone {
+    with(db).and(id::<m::ReportRef>()).and_then(resolve)
+}
+
+/// Like report_id, but the slug is only resolved once the rest of the route
+/// matches, e.g. `path("eta").and(path::end()).and(method::get())`.
+pub fn report_route<F>(
+    db: DbConn,
+    route: F,
+) -> impl Filter<Extract = (m::ReportId,), Error = Rejection> + Clone
+where
+    F: Filter<Extract = (), Error = Rejection> + Clone + Send + Sync + 'static,
+{
+    with(db).and(id::<m::ReportRef>()).and(route).and_then(resolve)
+}
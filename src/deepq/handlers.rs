@@ -0,0 +1,180 @@
+// Copyright 2020 Lakin Wecker
+//
+// This file is part of lila-deepq.
+//
+// lila-deepq is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// lila-deepq is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with lila-deepq.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::result::Result as StdResult;
+
+use chrono::prelude::*;
+use serde::{Deserialize, Serialize};
+use serde_with::skip_serializing_none;
+use warp::{
+    filters::{method, BoxedFilter},
+    path, reject,
+    reply::{self, Json, Reply},
+    Filter, Rejection,
+};
+
+use super::{api, badge, filters::report_route, model as m, stats::{self, ServiceTimes}};
+use crate::admin::filters::admin_required;
+use crate::crypto::Secret;
+use crate::db::DbConn;
+use crate::fishnet::model::ApiUser;
+use crate::http::{forbidden, recover, with};
+
+// NOTE: short enough that mods see progress, long enough to spare us a busy mod page.
+const BADGE_CACHE_CONTROL: &str = "public, max-age=15";
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ChangesQuery {
+    since: DateTime<Utc>,
+    cursor: Option<String>, // The `next` of the previous page
+}
+
+#[skip_serializing_none]
+#[derive(Serialize, Debug, Clone)]
+pub struct ReportChange {
+    id: String,
+    user_id: m::UserId,
+    origin: m::ReportOrigin,
+    report_type: m::ReportType,
+    sent_to_irwin: bool,
+    analysis_reaped: bool,
+    date_requested: DateTime<Utc>,
+    date_completed: Option<DateTime<Utc>>,
+    date_state_changed: Option<DateTime<Utc>>,
+    deleted_at: Option<DateTime<Utc>>,
+}
+
+impl From<m::Report> for ReportChange {
+    fn from(report: m::Report) -> ReportChange {
+        ReportChange {
+            id: report._id.to_string(),
+            user_id: report.user_id,
+            origin: report.origin,
+            report_type: report.report_type,
+            sent_to_irwin: report.sent_to_irwin,
+            analysis_reaped: report.analysis_reaped,
+            date_requested: report.date_requested.0,
+            date_completed: report.date_completed.map(|date| date.0),
+            date_state_changed: report.date_state_changed.map(|date| date.0),
+            deleted_at: report.deleted_at.map(|date| date.0),
+        }
+    }
+}
+
+#[skip_serializing_none]
+#[derive(Serialize, Debug, Clone)]
+pub struct ReportChanges {
+    reports: Vec<ReportChange>,
+    next: Option<String>,
+}
+
+async fn report_changes(
+    db: DbConn,
+    _api_user: ApiUser,
+    query: ChangesQuery,
+) -> StdResult<Json, Rejection> {
+    let cursor = query
+        .cursor
+        .map(|cursor| cursor.parse::<api::ChangesCursor>())
+        .transpose()?;
+    let (reports, next) = api::find_report_changes(db, query.since, cursor).await?;
+    Ok(reply::json(&ReportChanges {
+        reports: reports.into_iter().map(Into::into).collect(),
+        next: next.map(|cursor| cursor.to_string()),
+    }))
+}
+
+async fn report_eta(
+    db: DbConn,
+    service_times: ServiceTimes,
+    _api_user: ApiUser,
+    report_id: m::ReportId,
+) -> StdResult<Json, Rejection> {
+    let report = api::find_report(db.clone(), report_id)
+        .await?
+        .ok_or_else(reject::not_found)?;
+    Ok(reply::json(&stats::report_eta(db, &service_times, report).await?))
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct BadgeQuery {
+    token: String, // See badge_token
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct BadgeToken {
+    token: String,
+}
+
+async fn badge_token(
+    secret: Secret,
+    _api_user: ApiUser,
+    report_id: m::ReportId,
+) -> StdResult<Json, Rejection> {
+    Ok(reply::json(&BadgeToken {
+        token: badge::token(&secret, &report_id),
+    }))
+}
+
+/// Authorized by a signed token instead of a key, as it's embedded as an image.
+async fn report_badge(
+    db: DbConn,
+    secret: Secret,
+    report_id: m::ReportId,
+    query: BadgeQuery,
+) -> StdResult<impl Reply, Rejection> {
+    if !badge::is_valid_token(&secret, &report_id, &query.token) {
+        return Err(forbidden());
+    }
+    let report = api::find_report(db.clone(), report_id)
+        .await?
+        .ok_or_else(reject::not_found)?;
+    let progress = stats::report_progress(db, &report).await?;
+    let reply = reply::with_header(badge::render(&progress), "Content-Type", "image/svg+xml");
+    Ok(reply::with_header(reply, "Cache-Control", BADGE_CACHE_CONTROL))
+}
+
+pub fn mount(db: DbConn, service_times: ServiceTimes, secret: Secret) -> BoxedFilter<(impl Reply,)> {
+    let changes = path("changes")
+        .and(path::end())
+        .and(method::get())
+        .and(with(db.clone()))
+        .and(admin_required(db.clone()))
+        .and(warp::query::<ChangesQuery>())
+        .and_then(report_changes);
+
+    let eta = report_route(db.clone(), path("eta").and(path::end()).and(method::get()))
+        .and(with(db.clone()))
+        .and(with(service_times))
+        .and(admin_required(db.clone()))
+        .and_then(
+            |report_id, db, service_times, api_user| report_eta(db, service_times, api_user, report_id),
+        );
+
+    let token = report_route(db.clone(), path("badge").and(path::end()).and(method::get()))
+        .and(with(secret.clone()))
+        .and(admin_required(db.clone()))
+        .and_then(|report_id, secret, api_user| badge_token(secret, api_user, report_id));
+
+    let badge = report_route(db.clone(), path("badge.svg").and(path::end()).and(method::get()))
+        .and(with(db))
+        .and(with(secret))
+        .and(warp::query::<BadgeQuery>())
+        .and_then(|report_id, db, secret, query| report_badge(db, secret, report_id, query));
+
+    changes.or(eta).or(token).or(badge).recover(recover).boxed()
+}
@@ -0,0 +1,343 @@
+// Copyright 2020 Lakin Wecker
+//
+// This file is part of lila-deepq.
+//
+// lila-deepq is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// lila-deepq is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with lila-deepq.  If not, see <https://www.gnu.org/licenses/>.
+//
+// Bulk PGN intake. Uploads can be tens of megabytes, so the body is parsed
+// as it arrives, on a blocking thread, and every game is imported as soon as
+// it's complete. The import record is kept up to date as games go by.
+//
+use std::collections::HashMap;
+use std::io::{self, Read, Result as IoResult};
+use std::ops::ControlFlow;
+
+use chrono::prelude::*;
+use futures::stream::{Stream, StreamExt};
+use log::{debug, info, warn};
+use mongodb::bson::{doc, oid::ObjectId, to_document, DateTime as BsonDateTime};
+use pgn_reader::{RawTag, Reader, SanPlus, Visitor};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use shakmaty::san::San;
+use tokio::sync::mpsc;
+use tokio::task::spawn_blocking;
+
+use crate::db::DbConn;
+use crate::deepq::api::{insert_audit_entry, insert_one_game, CreateGame};
+use crate::deepq::model::{GameId, Import, ImportError, Provenance, ReportOrigin, UserId};
+use crate::deepq::policy::{is_analysable, precedence_for_origin};
+use crate::error::{DomainError, Result};
+use crate::fishnet::api::{insert_one_job, CreateJob};
+use crate::fishnet::model::AnalysisType;
+use crate::irwin::api::uci_from_san;
+
+/// Only this many failures are kept on the import document.
+pub const MAX_IMPORT_ERRORS: usize = 100;
+
+// NOTE: how far the parser may get ahead of the body, and the importer of the parser.
+const CHUNKS_IN_FLIGHT: usize = 4;
+const GAMES_IN_FLIGHT: usize = 16;
+
+const LICHESS_SITES: [&str; 2] = ["https://lichess.org/", "http://lichess.org/"];
+
+#[derive(Debug, Clone, Default)]
+pub struct PgnGame {
+    pub headers: HashMap<String, String>,
+    pub sans: Vec<String>,
+}
+
+impl PgnGame {
+    /// Lichess games keep their id, the path of the Site header. Games from
+    /// elsewhere get one derived from their moves, see movetext_id.
+    pub fn game_id(&self) -> Option<GameId> {
+        let site = self.headers.get("Site").map(String::as_str).unwrap_or_default();
+        match LICHESS_SITES.iter().find_map(|prefix| site.strip_prefix(prefix)) {
+            Some(path) => path
+                .split('/')
+                .next()
+                .filter(|id| !id.is_empty())
+                .map(|id| GameId(id.chars().take(8).collect())),
+            None if self.sans.is_empty() => None,
+            None => Some(movetext_id(&self.sans)),
+        }
+    }
+
+    fn player(&self, color: &str) -> Option<UserId> {
+        self.headers
+            .get(color)
+            .filter(|name| !name.is_empty() && name.as_str() != "?")
+            .map(|name| name.clone().into())
+    }
+}
+
+/// An id for a game from outside lichess, the start of the hash of its moves.
+///
+/// NOTE: twice as long as lichess ids so the two never collide, and the same
+///       game uploaded twice is found again rather than duplicated.
+pub fn movetext_id(sans: &[String]) -> GameId {
+    let digest = Sha256::digest(sans.join(" ").as_bytes());
+    GameId(hex::encode(&digest[..8]))
+}
+
+/// Collects the tags and mainline moves of each game. Comments, variations
+/// and NAGs are skipped.
+struct Collector;
+
+impl Visitor for Collector {
+    type Tags = HashMap<String, String>;
+    type Movetext = PgnGame;
+    type Output = PgnGame;
+
+    fn begin_tags(&mut self) -> ControlFlow<PgnGame, Self::Tags> {
+        ControlFlow::Continue(HashMap::new())
+    }
+
+    fn tag(&mut self, tags: &mut Self::Tags, name: &[u8], value: RawTag<'_>) -> ControlFlow<PgnGame> {
+        tags.insert(
+            String::from_utf8_lossy(name).into_owned(),
+            value.decode_utf8_lossy().into_owned(),
+        );
+        ControlFlow::Continue(())
+    }
+
+    fn begin_movetext(&mut self, headers: Self::Tags) -> ControlFlow<PgnGame, PgnGame> {
+        ControlFlow::Continue(PgnGame {
+            headers,
+            sans: Vec::new(),
+        })
+    }
+
+    fn san(&mut self, game: &mut PgnGame, san_plus: SanPlus) -> ControlFlow<PgnGame> {
+        game.sans.push(san_plus.san.to_string());
+        ControlFlow::Continue(())
+    }
+
+    fn end_game(&mut self, game: PgnGame) -> PgnGame {
+        game
+    }
+}
+
+/// Every game of a PGN that's already in memory.
+pub fn read_games(pgn: &[u8]) -> IoResult<Vec<PgnGame>> {
+    let mut reader = Reader::new(pgn);
+    let mut games = Vec::new();
+    while let Some(game) = reader.read_game(&mut Collector)? {
+        games.push(game);
+    }
+    Ok(games)
+}
+
+/// The body as it arrives, for the blocking parser.
+struct ChunkReader {
+    chunks: mpsc::Receiver<IoResult<Vec<u8>>>,
+    chunk: io::Cursor<Vec<u8>>,
+}
+
+impl Read for ChunkReader {
+    fn read(&mut self, buf: &mut [u8]) -> IoResult<usize> {
+        loop {
+            let read = self.chunk.read(buf)?;
+            if read > 0 || buf.is_empty() {
+                return Ok(read);
+            }
+            match self.chunks.blocking_recv() {
+                Some(chunk) => self.chunk = io::Cursor::new(chunk?),
+                None => return Ok(0),
+            }
+        }
+    }
+}
+
+async fn forward_body<S>(mut body: S, chunks: mpsc::Sender<IoResult<Vec<u8>>>)
+where
+    S: Stream<Item = IoResult<Vec<u8>>> + Unpin,
+{
+    while let Some(chunk) = body.next().await {
+        let failed = chunk.is_err();
+        // NOTE: the parser is gone once it fails, there's no one left to read.
+        if chunks.send(chunk).await.is_err() || failed {
+            return;
+        }
+    }
+}
+
+fn parse_games(chunks: mpsc::Receiver<IoResult<Vec<u8>>>, games: mpsc::Sender<IoResult<PgnGame>>) {
+    let mut reader = Reader::new(ChunkReader {
+        chunks,
+        chunk: io::Cursor::new(Vec::new()),
+    });
+    loop {
+        let game = match reader.read_game(&mut Collector) {
+            Ok(Some(game)) => Ok(game),
+            Ok(None) => return,
+            Err(err) => Err(err),
+        };
+        let failed = game.is_err();
+        if games.blocking_send(game).is_err() || failed {
+            return;
+        }
+    }
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct ImportSummary {
+    pub id: String,
+    pub imported: i64,
+    pub failed: i64,
+    pub errors: Vec<ImportError>, // The first MAX_IMPORT_ERRORS of them
+}
+
+async fn import_game(db: DbConn, origin: ReportOrigin, game: &PgnGame) -> Result<GameId> {
+    let game_id = game
+        .game_id()
+        .ok_or_else(|| DomainError::InvalidPgn("no moves".to_string()))?;
+    if game.headers.contains_key("FEN") {
+        return Err(DomainError::InvalidPgn("games from a position are unsupported".to_string()).into());
+    }
+    let sans = game
+        .sans
+        .iter()
+        .map(|san| {
+            san.parse::<San>()
+                .map_err(|_| DomainError::InvalidPgn(format!("invalid move {}", san)))
+        })
+        .collect::<std::result::Result<Vec<San>, DomainError>>()?;
+    insert_one_game(
+        db.clone(),
+        CreateGame {
+            game_id: game_id.clone(),
+            emts: Vec::new(),
+            pgn: uci_from_san(&sans)?,
+            white: game.player("White"),
+            black: game.player("Black"),
+            provenance: Provenance::PgnUpload,
+        },
+    )
+    .await?;
+    if !is_analysable(game.sans.len()) {
+        debug!("import_game > Game({}) > too short to analyse", game_id);
+        return Ok(game_id);
+    }
+    let job = CreateJob::builder(game_id.clone(), AnalysisType::Deep)
+        .origin(origin.clone())
+        .precedence(precedence_for_origin(origin))
+        .build()?;
+    insert_one_job(db, job).await?;
+    Ok(game_id)
+}
+
+/// Counts one more game on the import record, and keeps its error, if any.
+async fn record_game(db: DbConn, import_id: &ObjectId, error: Option<&ImportError>) -> Result<()> {
+    let update = match error {
+        None => doc! {"$inc": {"imported": 1}},
+        Some(error) => doc! {
+            "$inc": {"failed": 1},
+            "$push": {"errors": {
+                "$each": [to_document(error)?],
+                "$slice": MAX_IMPORT_ERRORS as i64,
+            }},
+        },
+    };
+    Import::coll(db)
+        .update_one(doc! {"_id": import_id.clone()}, update, None)
+        .await?;
+    Ok(())
+}
+
+/// Imports every game in the PGN as it's read, queueing Deep analysis for
+/// each. A game that fails to import doesn't stop the rest.
+pub async fn import_pgn<S>(
+    db: DbConn,
+    actor: &str,
+    origin: ReportOrigin,
+    body: S,
+) -> Result<ImportSummary>
+where
+    S: Stream<Item = IoResult<Vec<u8>>> + Unpin + Send + 'static,
+{
+    let p = "import_pgn >";
+    let mut import = Import {
+        _id: ObjectId::new(),
+        actor: actor.to_string(),
+        origin: origin.clone(),
+        date_started: BsonDateTime(Utc::now()),
+        date_finished: None,
+        imported: 0,
+        failed: 0,
+        errors: Vec::new(),
+    };
+    Import::coll(db.clone()).insert_one(to_document(&import)?, None).await?;
+
+    let (chunks_tx, chunks_rx) = mpsc::channel(CHUNKS_IN_FLIGHT);
+    let (games_tx, mut games_rx) = mpsc::channel(GAMES_IN_FLIGHT);
+    tokio::spawn(forward_body(body, chunks_tx));
+    let parser = spawn_blocking(move || parse_games(chunks_rx, games_tx));
+    let mut read = Ok(());
+    while let Some(game) = games_rx.recv().await {
+        let game = match game {
+            Ok(game) => game,
+            Err(err) => {
+                read = Err(err);
+                break;
+            }
+        };
+        let index = import.imported + import.failed;
+        let error = match import_game(db.clone(), origin.clone(), &game).await {
+            Ok(game_id) => {
+                debug!("{} #{} > Game({}) > imported", p, index, game_id);
+                import.imported += 1;
+                None
+            }
+            Err(err) => {
+                warn!("{} #{} > failed: {}", p, index, err);
+                import.failed += 1;
+                Some(ImportError {
+                    index,
+                    game_id: game.game_id(),
+                    error: err.to_string(),
+                })
+            }
+        };
+        record_game(db.clone(), &import._id, error.as_ref()).await?;
+        if let Some(error) = error.filter(|_| import.errors.len() < MAX_IMPORT_ERRORS) {
+            import.errors.push(error);
+        }
+    }
+    parser.await?;
+
+    // NOTE: whatever was imported before the upload broke off stays imported.
+    Import::coll(db.clone())
+        .update_one(
+            doc! {"_id": import._id.clone()},
+            doc! {"$set": {"date_finished": Utc::now()}},
+            None,
+        )
+        .await?;
+    read?;
+    insert_audit_entry(
+        db,
+        actor,
+        "import_pgn",
+        doc! {"import_id": import._id.clone(), "imported": import.imported, "failed": import.failed},
+    )
+    .await?;
+    info!("{} {} imported, {} failed", p, import.imported, import.failed);
+    Ok(ImportSummary {
+        id: import._id.to_hex(),
+        imported: import.imported,
+        failed: import.failed,
+        errors: import.errors,
+    })
+}
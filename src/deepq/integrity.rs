@@ -0,0 +1,169 @@
+// Copyright 2021 Lakin Wecker
+//
+// This file is part of lila-deepq.
+//
+// lila-deepq is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// lila-deepq is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with lila-deepq.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::collections::HashMap;
+
+use futures::stream::TryStreamExt;
+use log::{info, warn};
+use mongodb::{
+    bson::{doc, from_document, oid::ObjectId, Bson},
+    options::FindOptions,
+};
+use serde::Deserialize;
+
+use crate::db::DbConn;
+use crate::deepq::api::insert_audit_entry;
+use crate::deepq::model as m;
+use crate::error::{Error, Result};
+use crate::fishnet::model::{Job, JobId};
+
+const ACTOR: &str = "integrity";
+
+/// The audit action of each pass, with how many analyses were checked and dangling.
+pub const AUDIT_ANALYSIS_LINKS: &str = "audit_analysis_links";
+
+// NOTE: keeps each reference check to a reasonably sized $in.
+const AUDIT_BATCH_SIZE: usize = 500;
+
+// NOTE: enough to start investigating from, without bloating the audit entry.
+const MAX_DANGLING_SAMPLES: usize = 20;
+
+#[derive(Debug, Default, Clone)]
+pub struct IntegrityAudit {
+    pub checked: i64,
+    pub missing_job: i64,  // The job was purged, or never existed
+    pub missing_game: i64, // The game was reaped, or never stored
+    pub wrong_game: i64,   // The job is for some other game
+    pub samples: Vec<ObjectId>, // The first few dangling analyses
+}
+
+impl IntegrityAudit {
+    pub fn dangling(&self) -> i64 {
+        self.missing_job + self.missing_game + self.wrong_game
+    }
+}
+
+#[derive(Deserialize)]
+struct AnalysisLinks {
+    _id: ObjectId,
+    job_id: JobId,
+    game_id: m::GameId,
+}
+
+#[derive(Deserialize)]
+struct JobGame {
+    _id: JobId,
+    game_id: m::GameId,
+}
+
+async fn audit_batch(db: DbConn, batch: &[AnalysisLinks], audit: &mut IntegrityAudit) -> Result<()> {
+    let job_ids: Vec<Bson> = batch.iter().map(|a| Bson::ObjectId(a.job_id.0.clone())).collect();
+    let jobs: HashMap<String, String> = Job::coll(db.clone())
+        .find(
+            doc! {"_id": {"$in": job_ids}},
+            FindOptions::builder().projection(doc! {"game_id": 1}).build(),
+        )
+        .await?
+        .map_err(Error::from)
+        .and_then(|doc| async move {
+            let job: JobGame = from_document(doc)?;
+            Ok((job._id.to_string(), job.game_id.0))
+        })
+        .try_collect()
+        .await?;
+    let game_ids: Vec<String> = batch.iter().map(|a| a.game_id.0.clone()).collect();
+    let games = m::Game::coll(db)
+        .distinct("_id", doc! {"_id": {"$in": game_ids}}, None)
+        .await?;
+    for analysis in batch {
+        audit.checked += 1;
+        let dangling = match jobs.get(&analysis.job_id.to_string()) {
+            None => {
+                audit.missing_job += 1;
+                true
+            }
+            Some(game_id) if *game_id != analysis.game_id.0 => {
+                audit.wrong_game += 1;
+                true
+            }
+            Some(_) if !games.iter().any(|g| g.as_str() == Some(analysis.game_id.0.as_str())) => {
+                audit.missing_game += 1;
+                true
+            }
+            Some(_) => false,
+        };
+        if dangling && audit.samples.len() < MAX_DANGLING_SAMPLES {
+            audit.samples.push(analysis._id.clone());
+        }
+    }
+    Ok(())
+}
+
+/// Checks that every stored analysis still refers to its job and game, and
+/// that the job is for that game. Nothing is removed, the reaper and purge
+/// should already prevent these, so any dangling analysis is a bug to chase.
+pub async fn audit_analysis_links(db: DbConn) -> Result<IntegrityAudit> {
+    let p = "audit_analysis_links >";
+    let mut audit = IntegrityAudit::default();
+    let mut cursor = m::GameAnalysis::coll(db.clone())
+        .find(
+            doc! {},
+            FindOptions::builder()
+                .projection(doc! {"job_id": 1, "game_id": 1})
+                .build(),
+        )
+        .await?;
+    let mut batch: Vec<AnalysisLinks> = Vec::with_capacity(AUDIT_BATCH_SIZE);
+    while let Some(doc) = cursor.try_next().await? {
+        batch.push(from_document(doc)?);
+        if batch.len() == AUDIT_BATCH_SIZE {
+            audit_batch(db.clone(), &batch, &mut audit).await?;
+            batch.clear();
+        }
+    }
+    if !batch.is_empty() {
+        audit_batch(db.clone(), &batch, &mut audit).await?;
+    }
+    // NOTE: recorded even when everything is fine, so it's clear the audit ran.
+    insert_audit_entry(
+        db,
+        ACTOR,
+        AUDIT_ANALYSIS_LINKS,
+        doc! {
+            "checked": audit.checked,
+            "missing_job": audit.missing_job,
+            "missing_game": audit.missing_game,
+            "wrong_game": audit.wrong_game,
+            "samples": audit.samples.clone(),
+        },
+    )
+    .await?;
+    if audit.dangling() > 0 {
+        warn!(
+            "{} {} of {} analyses are dangling, e.g. {:?}",
+            p,
+            audit.dangling(),
+            audit.checked,
+            audit.samples.first()
+        );
+    }
+    info!(
+        "{} checked: {}, missing job: {}, missing game: {}, wrong game: {}",
+        p, audit.checked, audit.missing_job, audit.missing_game, audit.wrong_game
+    );
+    Ok(audit)
+}
@@ -17,18 +17,34 @@
 use std::str::FromStr;
 
 use derive_more::{Display, From};
-use mongodb::bson::{doc, oid::ObjectId, Bson, DateTime};
+use mongodb::bson::{doc, oid::ObjectId, Bson, DateTime, Document};
 use mongodb::Collection;
 use serde::{Deserialize, Serialize};
 use serde_with::{serde_as, DisplayFromStr, SpaceSeparator, StringWithSeparator};
 use shakmaty::uci::Uci;
 
 use crate::db::DbConn;
-use crate::error::{Error, Result};
+use crate::deepq::units::{Depth, MultiPv, NodeBudget, Nodes};
+use crate::error::{Error, ProtocolError, Result};
 use crate::fishnet::model::JobId;
 
-#[derive(Serialize, Deserialize, Debug, Clone, From, Display)]
-pub struct UserId(pub String);
+// NOTE: lila and the providers don't agree on the case of usernames, so
+//       they are normalized whenever one is constructed or deserialized.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash, Display)]
+#[serde(from = "String")]
+pub struct UserId(String);
+
+impl From<String> for UserId {
+    fn from(s: String) -> UserId {
+        UserId(s.trim().to_lowercase())
+    }
+}
+
+impl UserId {
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
 
 // TODO: this should be easy enough to make into a macro
 impl From<UserId> for Bson {
@@ -46,8 +62,9 @@ impl From<GameId> for Bson {
     }
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone, From, Display)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, From, Display, strum_macros::EnumString)]
 #[serde(rename_all = "lowercase")]
+#[strum(serialize_all = "lowercase")]
 pub enum ReportOrigin {
     Moderator,
     Random,
@@ -55,13 +72,24 @@ pub enum ReportOrigin {
     Tournament,
 }
 
+impl ReportOrigin {
+    pub fn all() -> [ReportOrigin; 4] {
+        [
+            ReportOrigin::Moderator,
+            ReportOrigin::Leaderboard,
+            ReportOrigin::Tournament,
+            ReportOrigin::Random,
+        ]
+    }
+}
+
 impl From<ReportOrigin> for Bson {
     fn from(ro: ReportOrigin) -> Bson {
         Bson::String(ro.to_string().to_lowercase())
     }
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone, strum_macros::ToString)]
+#[derive(Serialize, Deserialize, Debug, Clone, strum_macros::Display)]
 #[serde(rename_all = "lowercase")]
 pub enum ReportType {
     Irwin,
@@ -92,9 +120,62 @@ impl FromStr for ReportId {
     }
 }
 
+/// The letters and digits of report slugs, without the ones that are easily confused.
+pub const SLUG_ALPHABET: &[u8] = b"23456789abcdefghjkmnpqrstuvwxyz";
+pub const SLUG_GROUP_LEN: usize = 4;
+
+/// A short name for a report that's easier to read out or type than its
+/// ObjectId, e.g. "k7qm-x2dn", generated at creation.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Display)]
+pub struct ReportSlug(pub String);
+
+impl FromStr for ReportSlug {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let slug = s.to_lowercase();
+        let valid = slug.split('-').count() == 2
+            && slug.split('-').all(|group| {
+                group.len() == SLUG_GROUP_LEN && group.bytes().all(|c| SLUG_ALPHABET.contains(&c))
+            });
+        if !valid {
+            return Err(ProtocolError::InvalidId(format!("report slug {:?}", s)).into());
+        }
+        Ok(ReportSlug(slug))
+    }
+}
+
+/// How a report is referred to from outside, by ObjectId or by slug.
+#[derive(Debug, Clone, Display)]
+pub enum ReportRef {
+    Id(ReportId),
+    Slug(ReportSlug),
+}
+
+impl FromStr for ReportRef {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match ReportId::from_str(s) {
+            Ok(id) => Ok(ReportRef::Id(id)),
+            Err(_) => Ok(ReportRef::Slug(s.parse()?)),
+        }
+    }
+}
+
+/// The inputs, besides origin, that went into a report's precedence.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct ReportSignals {
+    pub score: Option<i32>, // lila's suspicion score, 0-100
+    pub titled: bool,
+    pub engine: bool,
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Report {
     pub _id: ReportId,
+    #[serde(default)]
+    pub slug: Option<ReportSlug>, // Reports from before slugs have none
     pub user_id: UserId,
     pub date_requested: DateTime,
     pub date_completed: Option<DateTime>,
@@ -102,6 +183,22 @@ pub struct Report {
     pub report_type: ReportType,
     pub games: Vec<GameId>,
     pub sent_to_irwin: bool,
+    pub signals: Option<ReportSignals>,
+    #[serde(default)]
+    pub analysis_reaped: bool, // The raw analyses have been removed per the retention policy
+    pub date_reaped: Option<DateTime>, // When they were, housekeeping isn't a state change
+    pub date_state_changed: Option<DateTime>, // Bumped on every state change, for delta syncs
+    #[serde(default)]
+    pub missing_games: Vec<GameId>, // Left out of a partial submission to irwin
+    #[serde(default)]
+    pub excluded_games: Vec<GameId>, // Irrelevant according to a moderator, never sent to irwin
+    #[serde(default)]
+    pub not_analysable_games: Vec<GameId>, // Too short to analyse, so not counted in games
+    #[serde(default)]
+    pub submission_pending: bool, // Claimed for irwin but not yet submitted, resumed on startup
+    #[serde(default)]
+    pub anomalies: Vec<GameAnomaly>, // Time usage against eval quality, per game, once complete
+    pub deleted_at: Option<DateTime>, // Soft deleted, purged after the retention window
 }
 
 impl Report {
@@ -110,18 +207,62 @@ impl Report {
     }
 }
 
+/// How suspicious the reported player's time usage is, given the evals.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct GameAnomaly {
+    pub game_id: GameId,
+    pub critical_moves: i32,    // Made past the opening where only the best move holds the eval
+    pub instant_top_moves: i32, // Of those, played instantly and matching the top engine line
+    pub critical_time_ratio: Option<f64>, // Time on critical moves relative to all their moves
+    pub score: f64,             // 0 to 1, higher is more anomalous
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Blurs {
     pub nb: i32,
     pub bits: String, // TODO: why string?!
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default, strum_macros::Display)]
+#[serde(rename_all = "lowercase")]
+#[strum(serialize_all = "lowercase")]
+pub enum EngineType {
+    #[default]
+    Stockfish,
+    Lc0,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub enum Score {
     #[serde(rename = "cp")]
     Cp(i64),
     #[serde(rename = "mate")]
     Mate(i64),
+    #[serde(rename = "q")]
+    Q(f64), // Leela's expected score, -1 to 1
+    #[serde(rename = "wdl")]
+    Wdl([i64; 3]), // Leela's win/draw/loss, per mille
+}
+
+impl Score {
+    /// Converts engine native scores to cp, which is what irwin understands.
+    ///
+    /// NOTE: this is the conversion lc0 itself uses for its cp output.
+    pub fn normalized(&self) -> Score {
+        let from_q = |q: f64| {
+            let q = q.clamp(-0.99999, 0.99999);
+            Score::Cp((111.714_640_912 * (1.562_068_842_1 * q).tan()).round() as i64)
+        };
+        match self {
+            Score::Cp(_) | Score::Mate(_) => self.clone(),
+            Score::Q(q) => from_q(*q),
+            Score::Wdl([w, _d, l]) => from_q((w - l) as f64 / 1000f64),
+        }
+    }
+
+    pub fn is_native(&self) -> bool {
+        matches!(self, Score::Q(_) | Score::Wdl(_))
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -131,8 +272,10 @@ pub struct SkippedAnalysis {
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct EmptyAnalysis {
-    depth: i32,
+    depth: Depth,
     score: Score,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    raw_score: Option<Score>, // The engine native score, when it wasn't cp
 }
 
 #[serde_as]
@@ -140,11 +283,13 @@ pub struct EmptyAnalysis {
 pub struct BestMove {
     #[serde_as(as = "StringWithSeparator::<SpaceSeparator, Uci>")]
     pv: Vec<Uci>,
-    depth: i32,
+    depth: Depth,
     score: Score,
     time: i64,
-    nodes: i64,
+    nodes: Nodes,
     nps: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    raw_score: Option<Score>, // The engine native score, when it wasn't cp
 }
 
 #[serde_as]
@@ -153,11 +298,13 @@ pub struct MatrixAnalysis {
     #[serde_as(as = "Vec<Vec<Option<Vec<DisplayFromStr>>>>")]
     pub pv: Vec<Vec<Option<Vec<Uci>>>>,
     pub score: Vec<Vec<Option<Score>>>,
-    pub depth: i32,
-    pub nodes: i64,
+    pub depth: Depth,
+    pub nodes: Nodes,
     pub time: i64,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub nps: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub raw_score: Option<Vec<Vec<Option<Score>>>>, // The engine native scores, when they weren't cp
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -169,6 +316,89 @@ pub enum PlyAnalysis {
     Empty(EmptyAnalysis),
 }
 
+fn normalize(score: &mut Score, raw_score: &mut Option<Score>) {
+    if score.is_native() {
+        *raw_score = Some(score.clone());
+        *score = score.normalized();
+    }
+}
+
+impl PlyAnalysis {
+    /// Scores in cp, keeping any engine native scores alongside them.
+    pub fn normalized(mut self) -> PlyAnalysis {
+        match &mut self {
+            PlyAnalysis::Matrix(matrix) => {
+                let native = matrix.score.iter().flatten().flatten().any(Score::is_native);
+                if native {
+                    matrix.raw_score = Some(matrix.score.clone());
+                    for score in matrix.score.iter_mut().flatten().flatten() {
+                        *score = score.normalized();
+                    }
+                }
+            }
+            PlyAnalysis::Best(best) => normalize(&mut best.score, &mut best.raw_score),
+            PlyAnalysis::Empty(empty) => normalize(&mut empty.score, &mut empty.raw_score),
+            PlyAnalysis::Skipped(_) => {}
+        }
+        self
+    }
+
+    /// The engine's evaluation of the position, from its principal variation.
+    pub fn score(&self) -> Option<Score> {
+        match self {
+            PlyAnalysis::Matrix(matrix) => matrix
+                .score
+                .first()
+                .and_then(|by_depth| by_depth.iter().rev().flatten().next())
+                .cloned(),
+            PlyAnalysis::Best(best) => Some(best.score.clone()),
+            PlyAnalysis::Empty(empty) => Some(empty.score.clone()),
+            PlyAnalysis::Skipped(_) => None,
+        }
+    }
+
+    /// The deepest score of each pv, best first. One at most without multipv.
+    pub fn pv_scores(&self) -> Vec<Score> {
+        match self {
+            PlyAnalysis::Matrix(matrix) => matrix
+                .score
+                .iter()
+                .filter_map(|by_depth| by_depth.iter().rev().flatten().next())
+                .cloned()
+                .collect(),
+            _ => self.score().into_iter().collect(),
+        }
+    }
+
+    pub fn pvs(&self) -> Vec<&Vec<Uci>> {
+        match self {
+            PlyAnalysis::Matrix(matrix) => matrix.pv.iter().flatten().flatten().collect(),
+            PlyAnalysis::Best(best) => vec![&best.pv],
+            PlyAnalysis::Skipped(_) | PlyAnalysis::Empty(_) => Vec::new(),
+        }
+    }
+}
+
+/// Where a game's moves came from, so consumers can weigh how far to trust
+/// them and so bad data can be traced back to its source.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default, strum_macros::Display)]
+#[serde(rename_all = "snake_case")]
+#[strum(serialize_all = "snake_case")]
+pub enum Provenance {
+    #[default]
+    Unknown, // Stored before provenance was recorded
+    LichessStream, // Sent along with a request on lila's irwin stream
+    LichessExport, // Fetched from the lichess game export API
+    PgnUpload,     // Imported from a PGN by an admin
+    LilaDb,        // Read from lila's database
+}
+
+impl From<Provenance> for Bson {
+    fn from(provenance: Provenance) -> Bson {
+        Bson::String(provenance.to_string())
+    }
+}
+
 // TODO: this should come directly from the lila db, why store this more than once?
 #[serde_as]
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -179,6 +409,11 @@ pub struct Game {
     pub pgn: Vec<Uci>,
     pub black: Option<UserId>,
     pub white: Option<UserId>,
+    #[serde(default)]
+    pub provenance: Provenance,
+    pub date_stored: Option<DateTime>, // Bumped every time it's queued again, older games predate it
+    #[serde(default)]
+    pub not_analysable: bool, // Too short to analyse, see policy::is_analysable
 }
 
 impl Game {
@@ -187,12 +422,6 @@ impl Game {
     }
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
-pub struct Nodes {
-    pub nnue: i64,
-    pub classical: i64,
-}
-
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct GameAnalysis {
     pub _id: ObjectId,
@@ -200,9 +429,16 @@ pub struct GameAnalysis {
     pub game_id: GameId,
     pub source_id: UserId,
     pub analysis: Vec<Option<PlyAnalysis>>,
-    pub requested_pvs: Option<i32>,
-    pub requested_depth: Option<i32>,
-    pub requested_nodes: Nodes,
+    pub requested_pvs: Option<MultiPv>,
+    pub requested_depth: Option<Depth>,
+    pub requested_nodes: NodeBudget,
+    pub quarantine_reason: Option<String>, // Set by the verifier if the pvs don't replay
+    #[serde(default)]
+    pub engine: EngineType,
+    #[serde(default)]
+    pub provenance: Provenance, // Of the game that was analysed
+    #[serde(default)]
+    pub pv_history_truncated: bool, // Older depths were dropped to fit, see deepq::payload
 }
 
 impl GameAnalysis {
@@ -213,3 +449,144 @@ impl GameAnalysis {
         self.analysis.iter().filter(|o| o.is_none()).count() == 0_usize
     }
 }
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ImportError {
+    pub index: i64, // Of the game within the upload
+    pub game_id: Option<GameId>,
+    pub error: String,
+}
+
+/// The outcome of a bulk PGN upload.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Import {
+    pub _id: ObjectId,
+    pub actor: String,
+    pub origin: ReportOrigin,
+    pub date_started: DateTime,
+    pub date_finished: Option<DateTime>,
+    pub imported: i64,
+    pub failed: i64,
+    pub errors: Vec<ImportError>, // The first MAX_IMPORT_ERRORS of them
+}
+
+impl Import {
+    pub fn coll(db: DbConn) -> Collection {
+        db.database.collection("deepq_imports")
+    }
+}
+
+/// A record of something destructive or noteworthy that happened, so we can
+/// answer "who/what removed this?" after the fact.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AuditEntry {
+    pub _id: ObjectId,
+    pub date: DateTime,
+    pub actor: String,
+    pub action: String,
+    pub details: Document,
+}
+
+impl AuditEntry {
+    pub fn coll(db: DbConn) -> Collection {
+        db.database.collection("deepq_audit")
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, strum_macros::Display)]
+#[serde(rename_all = "lowercase")]
+#[strum(serialize_all = "lowercase")]
+pub enum SubmissionKind {
+    Complete,
+    Partial,   // Timed out waiting on stragglers
+    Amendment, // The stragglers of an earlier partial submission
+}
+
+/// One attempt at submitting a report to irwin, whether or not it succeeded.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct IrwinSubmission {
+    pub _id: ObjectId,
+    pub report_id: ReportId,
+    pub kind: SubmissionKind,
+    pub date_attempted: DateTime,
+    pub latency_ms: i64,
+    pub payload_sha256: Option<String>, // Of the canonical json, None if it couldn't be assembled
+    pub payload_bytes: Option<i64>,
+    pub response_status: Option<i32>, // Irwin's http status, once jobs are delivered over http
+    pub error: Option<String>,        // The start of the error, if the attempt failed
+}
+
+impl IrwinSubmission {
+    pub fn coll(db: DbConn) -> Collection {
+        db.database.collection("deepq_irwin_submissions")
+    }
+}
+
+/// An external endpoint that receives the analysis of completed CR jobs.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ResultWebhook {
+    pub _id: ObjectId,
+    pub url: String,
+    pub secret: String, // Signs the deliveries, handed out once when registered
+    pub created_by: String,
+    pub date_created: DateTime,
+}
+
+impl ResultWebhook {
+    pub fn coll(db: DbConn) -> Collection {
+        db.database.collection("deepq_result_webhooks")
+    }
+}
+
+/// Which positions of a game the workers are told to skip.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, strum_macros::Display)]
+#[serde(rename_all = "snake_case")]
+#[strum(serialize_all = "snake_case")]
+pub enum SkipStrategy {
+    Opening,    // The opening plies, where analysis says little
+    Downscoped, // None, but searches quiet positions lighter, see preset::ply_settings
+    Phase,      // The opening and endgame positions, see deepq::phase
+    Nothing,
+}
+
+/// Where the phases of a game are assumed to change, see deepq::phase.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct PhaseSettings {
+    pub book_plies: u8, // The most an opening can last
+    pub endgame_pieces: Option<u8>, // Besides pawns and kings, None to never skip endgames
+}
+
+impl Default for PhaseSettings {
+    fn default() -> PhaseSettings {
+        PhaseSettings {
+            book_plies: 16,
+            endgame_pieces: Some(2),
+        }
+    }
+}
+
+/// How thoroughly a job is analysed.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct PresetSettings {
+    pub nodes: NodeBudget,
+    pub depth: Option<Depth>,
+    pub multipv: Option<MultiPv>,
+    pub skip: SkipStrategy,
+    #[serde(default)] // Older presets predate phase detection
+    pub phase: PhaseSettings,
+}
+
+/// A named bundle of analysis settings that jobs refer to by id.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Preset {
+    pub _id: String,
+    pub settings: PresetSettings,
+    pub updated_by: Option<String>, // None for the built in defaults
+    pub date_updated: Option<DateTime>,
+}
+
+impl Preset {
+    pub fn coll(db: DbConn) -> Collection {
+        db.database.collection("deepq_presets")
+    }
+}
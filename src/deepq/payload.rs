@@ -0,0 +1,172 @@
+// Copyright 2020 Lakin Wecker
+//
+// This file is part of lila-deepq.
+//
+// lila-deepq is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// lila-deepq is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with lila-deepq.  If not, see <https://www.gnu.org/licenses/>.
+//
+// Mongo refuses documents over 16MB, and multipv matrices of long games can
+// get close. Rather than have the write fail, we measure the analysis first
+// and past a soft limit drop the oldest depths of each pv's history, the
+// final depth is what everything downstream reads anyway.
+//
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use log::{error, warn};
+use mongodb::bson::{to_document, Document};
+use serde::Serialize;
+
+use crate::deepq::model::{GameAnalysis, MatrixAnalysis, PlyAnalysis};
+use crate::error::{DomainError, Error, Result};
+use crate::metrics::Metrics;
+use crate::telemetry::{self, SpanContext};
+
+/// Mongo's own limit.
+pub const MAX_DOCUMENT_BYTES: usize = 16 * 1024 * 1024;
+
+pub const NEAR_LIMIT_METRIC: &str = "payload.near_limit";
+pub const TRUNCATED_METRIC: &str = "payload.truncated";
+
+// NOTE: a long game tends to come with others, one alert covers the lot.
+const ALERT_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+#[derive(Debug, Clone, Copy)]
+pub struct PayloadLimits {
+    pub near_limit_bytes: usize, // Past this pv history is truncated, and it counts as near the limit
+    pub pv_history_depths: usize, // How many of the last depths of each pv are kept when truncating
+}
+
+impl Default for PayloadLimits {
+    fn default() -> PayloadLimits {
+        PayloadLimits {
+            near_limit_bytes: 12 * 1024 * 1024,
+            pv_history_depths: 4,
+        }
+    }
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct NearLimitAlert {
+    pub job_id: String,
+    pub bytes: usize,
+    pub near_limit_bytes: usize,
+    pub truncated_bytes: usize,
+}
+
+/// Fits analyses into mongo, counting and alerting on the ones near the limit.
+#[derive(Clone)]
+pub struct PayloadGuard {
+    limits: PayloadLimits,
+    metrics: Metrics,
+    alert_webhook_url: Option<String>,
+    client: reqwest::Client,
+    last_alert: Arc<Mutex<Option<Instant>>>,
+}
+
+impl PayloadGuard {
+    pub fn new(limits: PayloadLimits, metrics: Metrics, alert_webhook_url: Option<String>) -> PayloadGuard {
+        PayloadGuard {
+            limits,
+            metrics,
+            alert_webhook_url,
+            client: reqwest::Client::new(),
+            last_alert: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    /// The analysis as a document that fits in mongo, truncating its pv history if need be.
+    pub fn fitted_document(&self, analysis: &mut GameAnalysis) -> Result<Document> {
+        let p = "fitted_document >";
+        let doc = to_document(analysis)?;
+        let bytes = document_bytes(&doc)?;
+        if bytes <= self.limits.near_limit_bytes {
+            return Ok(doc);
+        }
+        self.metrics.count(NEAR_LIMIT_METRIC, 1);
+        let mut dropped = 0;
+        for ply in analysis.analysis.iter_mut().flatten() {
+            if let PlyAnalysis::Matrix(matrix) = ply {
+                dropped += matrix.truncate_pv_history(self.limits.pv_history_depths);
+            }
+        }
+        analysis.pv_history_truncated = dropped > 0;
+        let doc = to_document(analysis)?;
+        let truncated_bytes = document_bytes(&doc)?;
+        warn!(
+            "{} Job({}) > {} bytes is near the limit. Dropped {} pvs, now {} bytes",
+            p, analysis.job_id, bytes, dropped, truncated_bytes
+        );
+        if dropped > 0 {
+            self.metrics.count(TRUNCATED_METRIC, 1);
+        }
+        self.alert(NearLimitAlert {
+            job_id: analysis.job_id.to_string(),
+            bytes,
+            near_limit_bytes: self.limits.near_limit_bytes,
+            truncated_bytes,
+        });
+        if truncated_bytes > MAX_DOCUMENT_BYTES {
+            return Err(DomainError::DocumentTooLarge(truncated_bytes).into());
+        }
+        Ok(doc)
+    }
+
+    /// Posts the alert to the webhook, at most once an hour, without holding up the write.
+    fn alert(&self, alert: NearLimitAlert) {
+        let url = match &self.alert_webhook_url {
+            Some(url) => url,
+            None => return,
+        };
+        {
+            let mut last_alert = self.last_alert.lock().expect("payload alert lock poisoned");
+            if last_alert.is_some_and(|last| last.elapsed() < ALERT_INTERVAL) {
+                return;
+            }
+            *last_alert = Some(Instant::now());
+        }
+        let request = self.client.post(url).json(&alert);
+        let request = telemetry::propagate(request, SpanContext::current());
+        tokio::spawn(async move {
+            let sent = request.send().await.and_then(|response| response.error_for_status());
+            if let Err(err) = sent {
+                error!("payload > alert > Job({}) > {}", alert.job_id, err);
+            }
+        });
+    }
+}
+
+pub fn document_bytes(doc: &Document) -> Result<usize> {
+    let mut bytes = Vec::new();
+    doc.to_writer(&mut bytes).map_err(Error::from)?;
+    Ok(bytes.len())
+}
+
+impl MatrixAnalysis {
+    /// Keeps only the last `depths` depths of each pv, returning how many were dropped.
+    ///
+    /// NOTE: dropped depths become None rather than being removed, as the
+    ///       index of a depth is the depth.
+    pub fn truncate_pv_history(&mut self, depths: usize) -> usize {
+        let mut dropped = 0;
+        for by_depth in self.pv.iter_mut() {
+            let keep_from = by_depth.len().saturating_sub(depths.max(1));
+            for pv in by_depth.iter_mut().take(keep_from) {
+                if pv.take().is_some() {
+                    dropped += 1;
+                }
+            }
+        }
+        dropped
+    }
+}
@@ -0,0 +1,86 @@
+// Copyright 2021 Lakin Wecker
+//
+// This file is part of lila-deepq.
+//
+// lila-deepq is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// lila-deepq is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with lila-deepq.  If not, see <https://www.gnu.org/licenses/>.
+
+//
+// Tells the phases of a game apart, so screening analyses can skip the
+// positions where engine help is hard to tell from preparation or technique.
+//
+use std::convert::TryFrom;
+
+use shakmaty::{fen::Fen, uci::Uci, CastlingMode, Chess, Position, Setup};
+
+use crate::deepq::model::PhaseSettings;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Phase {
+    Opening,
+    Middlegame,
+    Endgame,
+}
+
+/// Knights, bishops, rooks and queens, of both sides.
+fn pieces(pos: &Chess) -> usize {
+    let board = pos.board();
+    (board.occupied() & !board.pawns() & !board.kings()).count()
+}
+
+/// The phase of every position of the game, before each move and after the
+/// last one, as far as the moves replay.
+///
+/// The opening lasts for the book depth, unless a capture leaves book earlier.
+/// Endgames are the positions with at most endgame_pieces pieces besides
+/// pawns and kings.
+pub fn phases(position: &Fen, moves: &[Uci], settings: &PhaseSettings) -> Vec<Phase> {
+    let mut pos: Chess = match position.position(CastlingMode::Standard) {
+        Ok(pos) => pos,
+        Err(_) => return Vec::new(),
+    };
+    let mut in_book = true;
+    let mut phases = Vec::with_capacity(moves.len() + 1);
+    for ply in 0..=moves.len() {
+        in_book = in_book && ply < usize::from(settings.book_plies);
+        phases.push(if in_book {
+            Phase::Opening
+        } else if settings
+            .endgame_pieces
+            .is_some_and(|max| pieces(&pos) <= usize::from(max))
+        {
+            Phase::Endgame
+        } else {
+            Phase::Middlegame
+        });
+        let m = match moves.get(ply).map(|uci| uci.to_move(&pos)) {
+            Some(Ok(m)) => m,
+            _ => break,
+        };
+        in_book = in_book && !m.is_capture();
+        pos.play_unchecked(&m);
+    }
+    phases
+}
+
+/// The positions to skip, the opening and endgame ones, by index into the game.
+///
+/// NOTE: fishnet can't address positions past 255, those get analysed.
+pub fn skip_positions(phases: &[Phase]) -> Vec<u8> {
+    phases
+        .iter()
+        .enumerate()
+        .filter(|(_, phase)| **phase != Phase::Middlegame)
+        .filter_map(|(position, _)| u8::try_from(position).ok())
+        .collect()
+}
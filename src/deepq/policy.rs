@@ -0,0 +1,166 @@
+// Copyright 2021 Lakin Wecker
+//
+// This file is part of lila-deepq.
+//
+// lila-deepq is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// lila-deepq is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with lila-deepq.  If not, see <https://www.gnu.org/licenses/>.
+
+use chrono::Duration;
+
+use crate::deepq::model as m;
+use crate::deepq::preset::OPENING_PLIES;
+
+pub fn precedence_for_origin(origin: m::ReportOrigin) -> i32 {
+    match origin {
+        m::ReportOrigin::Moderator => 1_000_000i32,
+        m::ReportOrigin::Leaderboard => 1000i32,
+        m::ReportOrigin::Tournament => 100i32,
+        m::ReportOrigin::Random => 10i32,
+    }
+}
+
+/// Combines the origin with the signals lila sent along with the request.
+///
+/// NOTE: each adjustment is a fraction of the origin's base precedence so that
+///       a highly suspicious Random report never outranks a Tournament one.
+pub fn precedence(origin: m::ReportOrigin, signals: &m::ReportSignals) -> i32 {
+    let base = precedence_for_origin(origin);
+    let mut precedence = base;
+    if let Some(score) = signals.score {
+        // Up to double the base for a maximally suspicious user.
+        // NOTE: multiplied first, Random's base is too small to divide by 100.
+        precedence += base * score.clamp(0, 100) / 100;
+    }
+    if signals.titled {
+        precedence += base / 4;
+    }
+    if signals.engine {
+        // Already marked, it's less urgent to know more about them.
+        precedence -= base / 2;
+    }
+    precedence
+}
+
+/// Games shorter than the opening the workers skip, aborts mostly, leave
+/// nothing to analyse, so they're never queued.
+pub fn is_analysable(plies: usize) -> bool {
+    plies >= usize::from(OPENING_PLIES)
+}
+
+// NOTE: lila's evals swing by less than this on most moves, those are the
+//       ones we don't need more pvs for.
+pub const DOWNSCOPE_SWING_CP: i64 = 50;
+
+const MATE_CP: i64 = 10_000;
+
+fn centipawns(score: &m::Score) -> i64 {
+    match score.normalized() {
+        m::Score::Cp(cp) => cp.clamp(-MATE_CP, MATE_CP),
+        m::Score::Mate(mate) if mate < 0 => -MATE_CP,
+        _ => MATE_CP,
+    }
+}
+
+/// The positions of a Deep job that lichess's own server analysis shows
+/// nothing happened at, which are searched lighter. None unless that
+/// analysis covers every ply.
+///
+/// NOTE: `analysis[i]` is lila's eval after ply i + 1, and the move played in
+///       position i is the one whose swing matters.
+pub fn downscoped_quiet_positions(analysis: &[m::Score], plies: usize) -> Option<Vec<i32>> {
+    if plies == 0 || analysis.len() < plies {
+        return None;
+    }
+    let mut before = 0;
+    let mut quiet = Vec::new();
+    for (position, score) in analysis.iter().take(plies).enumerate() {
+        let after = centipawns(score);
+        if (after - before).abs() <= DOWNSCOPE_SWING_CP {
+            quiet.push(position as i32);
+        }
+        before = after;
+    }
+    Some(quiet)
+}
+
+/// How far back earlier requests for the same user count against a new one.
+pub fn repeat_request_window() -> Duration {
+    Duration::days(1)
+}
+
+pub const DEFAULT_SCREENING_COOLDOWN_DAYS: i64 = 7;
+
+/// How long after a report for a user the automatic origins may not queue
+/// another, None for the origins that bypass it or when it's disabled.
+pub fn screening_cooldown(origin: m::ReportOrigin, cooldown: Duration) -> Option<Duration> {
+    match origin {
+        m::ReportOrigin::Random | m::ReportOrigin::Leaderboard => Some(cooldown),
+        m::ReportOrigin::Moderator | m::ReportOrigin::Tournament => None,
+    }
+    .filter(|cooldown| *cooldown > Duration::zero())
+}
+
+// NOTE: never below 1/8th, so that a flapping Moderator report still
+//       outranks every Leaderboard report.
+const MAX_DECAY_STEPS: i64 = 3;
+
+/// The upper bound on precedence for an origin, whatever the signals say.
+pub fn max_precedence(origin: m::ReportOrigin) -> i32 {
+    let base = precedence_for_origin(origin);
+    base * 2 + base / 4
+}
+
+/// Halves the precedence for every other request lila made for the same user
+/// within the repeat request window, so re-requesting doesn't monopolize the queue.
+pub fn decayed_precedence(origin: m::ReportOrigin, precedence: i32, recent_requests: i64) -> i32 {
+    let steps = recent_requests.clamp(0, MAX_DECAY_STEPS);
+    precedence.min(max_precedence(origin)) >> steps
+}
+
+/// How long the raw analyses for a report are kept before the reaper removes them.
+pub fn analysis_retention(origin: m::ReportOrigin, report_type: m::ReportType) -> Duration {
+    match (report_type, origin) {
+        // Evidence, keep it around for appeals.
+        (m::ReportType::CR, _) | (m::ReportType::PGNSPY, _) => Duration::days(5 * 365),
+        (m::ReportType::Irwin, m::ReportOrigin::Moderator) => Duration::days(5 * 365),
+        (m::ReportType::Irwin, m::ReportOrigin::Leaderboard) => Duration::days(365),
+        (m::ReportType::Irwin, m::ReportOrigin::Tournament) => Duration::days(90),
+        (m::ReportType::Irwin, m::ReportOrigin::Random) => Duration::weeks(4),
+    }
+}
+
+/// How long the raw analyses of jobs without a report, user and system
+/// analysis, are kept before the reaper removes them.
+pub fn unreported_analysis_retention() -> Duration {
+    Duration::days(90)
+}
+
+/// How long soft deleted jobs and reports can be undeleted before they're purged.
+pub fn soft_delete_retention() -> Duration {
+    Duration::days(30)
+}
+
+/// How long a game no job or report refers to is kept, in case it's about to be queued.
+pub fn orphan_game_grace() -> Duration {
+    Duration::days(7)
+}
+
+/// How long a report may take before we consider it late.
+pub fn report_sla(origin: m::ReportOrigin) -> Duration {
+    match origin {
+        m::ReportOrigin::Moderator => Duration::hours(6),
+        m::ReportOrigin::Leaderboard => Duration::days(1),
+        m::ReportOrigin::Tournament => Duration::days(2),
+        m::ReportOrigin::Random => Duration::weeks(1),
+    }
+}
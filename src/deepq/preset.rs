@@ -0,0 +1,216 @@
+// Copyright 2020 Lakin Wecker
+//
+// This file is part of lila-deepq.
+//
+// lila-deepq is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// lila-deepq is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with lila-deepq.  If not, see <https://www.gnu.org/licenses/>.
+//
+// Named analysis presets. Jobs refer to one by id when created, and the
+// settings it resolves to when a job is first handed out are snapshotted on
+// the job, so its analysis can be reproduced after the preset is edited.
+//
+use std::convert::TryFrom;
+
+use chrono::prelude::*;
+use mongodb::bson::{doc, from_document, to_bson, to_document, Bson, DateTime};
+use mongodb::options::{FindOneAndReplaceOptions, ReturnDocument};
+
+use crate::db::DbConn;
+use crate::deepq::api::starting_position;
+use crate::deepq::model::{Game, PhaseSettings, Preset, PresetSettings, SkipStrategy};
+use crate::deepq::phase;
+use crate::deepq::units::{MultiPv, NodeBudget, Nodes};
+use crate::error::Result;
+use crate::fishnet::api::get_job;
+use crate::fishnet::model::{AnalysisType, Job};
+
+pub const SCREENING: &str = "screening";
+pub const STANDARD_DEEP: &str = "standard-deep";
+pub const FORENSIC: &str = "forensic";
+
+pub const PRESET_IDS: [&str; 3] = [SCREENING, STANDARD_DEEP, FORENSIC];
+
+// TODO: what is the default right now for lila's fishnet queue?
+pub const OPENING_PLIES: u8 = 10;
+
+fn deep_nodes() -> NodeBudget {
+    NodeBudget {
+        nnue: Nodes::new(2_500_000),
+        classical: Nodes::new(4_500_000),
+    }
+}
+
+/// The settings of a preset until an admin edits it.
+pub fn builtin(id: &str) -> Option<PresetSettings> {
+    match id {
+        SCREENING => Some(PresetSettings {
+            nodes: deep_nodes().scaled(9, 10),
+            depth: None,
+            multipv: None,
+            skip: SkipStrategy::Phase,
+            phase: PhaseSettings::default(),
+        }),
+        STANDARD_DEEP => Some(PresetSettings {
+            nodes: deep_nodes(),
+            depth: None,
+            multipv: MultiPv::new(5),
+            skip: SkipStrategy::Downscoped,
+            phase: PhaseSettings::default(),
+        }),
+        FORENSIC => Some(PresetSettings {
+            nodes: deep_nodes().scaled(2, 1),
+            depth: None,
+            multipv: MultiPv::new(8),
+            skip: SkipStrategy::Downscoped,
+            phase: PhaseSettings::default(),
+        }),
+        _ => None,
+    }
+}
+
+/// The preset new jobs of this kind refer to.
+pub fn default_for(analysis_type: &AnalysisType, follow_up: bool) -> &'static str {
+    match analysis_type {
+        AnalysisType::UserAnalysis | AnalysisType::SystemAnalysis => SCREENING,
+        AnalysisType::Deep if follow_up => FORENSIC,
+        AnalysisType::Deep => STANDARD_DEEP,
+    }
+}
+
+/// The settings a job was handed out with, or for jobs handed out before
+/// presets existed, the defaults they were analysed with.
+pub fn settings_for_job(job: &Job) -> PresetSettings {
+    job.preset.clone().unwrap_or_else(|| {
+        builtin(default_for(&job.analysis_type, job.follow_up)).expect("every default is built in")
+    })
+}
+
+/// The positions to skip, by index into the game.
+pub fn skip_positions(settings: &PresetSettings, game: &Game) -> Vec<u8> {
+    match settings.skip {
+        SkipStrategy::Opening => (0..OPENING_PLIES).collect(),
+        SkipStrategy::Phase => phase::skip_positions(&phase::phases(
+            &starting_position(game.clone()),
+            &game.pgn,
+            &settings.phase,
+        )),
+        SkipStrategy::Downscoped | SkipStrategy::Nothing => Vec::new(),
+    }
+}
+
+/// The settings to search one position of the job with, lighter for the
+/// quiet positions of a downscoped job: a single pv with half the nodes.
+///
+/// NOTE: fishnet clients take one set of settings per job, so only the
+///       embedded worker searches quiet positions lighter.
+pub fn ply_settings(settings: &PresetSettings, job: &Job, ply: usize) -> PresetSettings {
+    let quiet = settings.skip == SkipStrategy::Downscoped
+        && i32::try_from(ply).is_ok_and(|ply| job.quiet_positions.contains(&ply));
+    if !quiet {
+        return settings.clone();
+    }
+    PresetSettings {
+        nodes: settings.nodes.scaled(1, 2),
+        multipv: None,
+        ..settings.clone()
+    }
+}
+
+/// The preset as currently configured, stored or built in.
+pub async fn find_preset(db: DbConn, id: &str) -> Result<Option<Preset>> {
+    let stored = Preset::coll(db)
+        .find_one(doc! {"_id": id}, None)
+        .await?
+        .map(from_document::<Preset>)
+        .transpose()?;
+    Ok(stored.or_else(|| {
+        builtin(id).map(|settings| Preset {
+            _id: id.to_string(),
+            settings,
+            updated_by: None,
+            date_updated: None,
+        })
+    }))
+}
+
+pub async fn find_all(db: DbConn) -> Result<Vec<Preset>> {
+    let mut presets = Vec::with_capacity(PRESET_IDS.len());
+    for id in PRESET_IDS.iter() {
+        if let Some(preset) = find_preset(db.clone(), id).await? {
+            presets.push(preset);
+        }
+    }
+    Ok(presets)
+}
+
+/// Replaces the settings of a preset, for jobs handed out from now on.
+pub async fn set_preset(
+    db: DbConn,
+    id: &str,
+    settings: PresetSettings,
+    updated_by: &str,
+) -> Result<Option<Preset>> {
+    if builtin(id).is_none() {
+        return Ok(None);
+    }
+    let preset = Preset {
+        _id: id.to_string(),
+        settings,
+        updated_by: Some(updated_by.to_string()),
+        date_updated: Some(DateTime(Utc::now())),
+    };
+    Preset::coll(db)
+        .find_one_and_replace(
+            doc! {"_id": id},
+            to_document(&preset)?,
+            FindOneAndReplaceOptions::builder()
+                .upsert(true)
+                .return_document(ReturnDocument::After)
+                .build(),
+        )
+        .await?;
+    Ok(Some(preset))
+}
+
+/// Resolves the job's preset and snapshots it on the job, the first time
+/// it's handed out. After that the job keeps its snapshot, so a job that's
+/// handed out again is analysed the same way.
+pub async fn snapshot_for_job(db: DbConn, job: &Job) -> Result<PresetSettings> {
+    if let Some(settings) = job.preset.clone() {
+        return Ok(settings);
+    }
+    let id = job
+        .preset_id
+        .clone()
+        .unwrap_or_else(|| default_for(&job.analysis_type, job.follow_up).to_string());
+    let settings = match find_preset(db.clone(), &id).await? {
+        Some(preset) => preset.settings,
+        // NOTE: a job referring to a preset we no longer know gets its kind's default.
+        None => builtin(default_for(&job.analysis_type, job.follow_up)).expect("every default is built in"),
+    };
+    let snapshotted = Job::coll(db.clone())
+        .update_one(
+            doc! {"_id": job._id.0.clone(), "preset": Bson::Null},
+            doc! {"$set": {"preset": to_bson(&settings)?}},
+            None,
+        )
+        .await?
+        .modified_count
+        > 0;
+    if snapshotted {
+        return Ok(settings);
+    }
+    // NOTE: snapshotted in the meantime, by whoever had it before.
+    let job = get_job(db, job._id.clone()).await?;
+    Ok(job.and_then(|job| job.preset).unwrap_or(settings))
+}
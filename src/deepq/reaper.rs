@@ -0,0 +1,250 @@
+// Copyright 2021 Lakin Wecker
+//
+// This file is part of lila-deepq.
+//
+// lila-deepq is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// lila-deepq is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with lila-deepq.  If not, see <https://www.gnu.org/licenses/>.
+
+use chrono::prelude::*;
+use futures::stream::{StreamExt, TryStreamExt};
+use log::{debug, info};
+use mongodb::{
+    bson::{doc, from_document, Bson},
+    options::{AggregateOptions, UpdateModifications},
+};
+
+use crate::db::{live, DbConn};
+use crate::deepq::api::insert_audit_entry;
+use crate::deepq::model as m;
+use crate::deepq::policy::{
+    analysis_retention, orphan_game_grace, soft_delete_retention, unreported_analysis_retention,
+};
+use crate::error::{Error, Result};
+use crate::fishnet::model::Job;
+use crate::metrics::Metrics;
+
+const ACTOR: &str = "reaper";
+
+/// The audit action of each orphan game pass, with how many were examined and removed.
+pub const REAP_ORPHAN_GAMES: &str = "reap_orphan_games";
+
+// NOTE: keeps each delete to a reasonably sized $in.
+const REAP_BATCH_SIZE: usize = 500;
+
+const REPORT_TYPES: [m::ReportType; 3] = [
+    m::ReportType::Irwin,
+    m::ReportType::CR,
+    m::ReportType::PGNSPY,
+];
+
+async fn reap_report_analyses(db: DbConn, report: m::Report) -> Result<i64> {
+    let job_ids: Vec<Bson> = Job::find_by_report(db.clone(), report.clone())
+        .await?
+        .map_ok(|job| Bson::ObjectId(job._id.0))
+        .try_collect()
+        .await?;
+    let deleted = m::GameAnalysis::coll(db.clone())
+        .delete_many(doc! {"job_id": {"$in": job_ids}}, None)
+        .await?
+        .deleted_count;
+    m::Report::coll(db.clone())
+        .update_one(
+            doc! {"_id": report._id.0.clone()},
+            UpdateModifications::Document(doc! {"$set": {
+                "analysis_reaped": true,
+                "date_reaped": Utc::now(),
+            }}),
+            None,
+        )
+        .await?;
+    insert_audit_entry(
+        db,
+        ACTOR,
+        "delete_analysis",
+        doc! {
+            "report_id": report._id.0,
+            "origin": report.origin,
+            "report_type": report.report_type,
+            "deleted": deleted,
+        },
+    )
+    .await?;
+    Ok(deleted)
+}
+
+/// Removes the raw analyses of jobs without a report once they've outlived
+/// their retention. Returns the number of analyses removed.
+async fn reap_unreported_analyses(db: DbConn) -> Result<i64> {
+    let p = "reap_unreported_analyses >";
+    let cutoff = Utc::now() - unreported_analysis_retention();
+    debug!("{} older than {}", p, cutoff);
+    let jobs = Job::coll(db.clone());
+    let mut job_ids = jobs
+        .find(
+            live(doc! {
+                "report_id": Bson::Null,
+                "is_complete": true,
+                // NOTE: jobs completed before date_completed existed are all past it.
+                "date_completed": {"$not": {"$gte": cutoff}},
+                "analysis_reaped": {"$ne": true},
+            }),
+            None,
+        )
+        .await?
+        .map(|doc| Ok::<_, Error>(Bson::ObjectId(from_document::<Job>(doc?)?._id.0)))
+        .chunks(REAP_BATCH_SIZE);
+    let (mut reaped_jobs, mut deleted) = (0i64, 0i64);
+    while let Some(batch) = job_ids.next().await {
+        let batch = batch.into_iter().collect::<Result<Vec<Bson>>>()?;
+        deleted += m::GameAnalysis::coll(db.clone())
+            .delete_many(doc! {"job_id": {"$in": batch.clone()}}, None)
+            .await?
+            .deleted_count;
+        reaped_jobs += jobs
+            .update_many(
+                doc! {"_id": {"$in": batch}},
+                UpdateModifications::Document(doc! {"$set": {"analysis_reaped": true}}),
+                None,
+            )
+            .await?
+            .modified_count;
+    }
+    if reaped_jobs > 0 {
+        insert_audit_entry(
+            db,
+            ACTOR,
+            "delete_analysis",
+            doc! {"jobs": reaped_jobs, "deleted": deleted},
+        )
+        .await?;
+    }
+    Ok(deleted)
+}
+
+/// Removes the raw analyses of reports that have outlived the retention
+/// policy for their origin and type, and of jobs without a report that
+/// outlived theirs. Returns the number of analyses removed.
+pub async fn reap_analyses(db: DbConn) -> Result<i64> {
+    let p = "reap_analyses >";
+    let mut total = 0i64;
+    for report_type in REPORT_TYPES.iter() {
+        for origin in m::ReportOrigin::all().iter() {
+            let cutoff = Utc::now() - analysis_retention(origin.clone(), report_type.clone());
+            debug!("{} {:?}/{:?} older than {}", p, report_type, origin, cutoff);
+            let mut reports = m::Report::coll(db.clone())
+                .find(
+                    live(doc! {
+                        "origin": origin.clone(),
+                        "report_type": report_type.clone(),
+                        "date_requested": {"$lt": cutoff},
+                        "analysis_reaped": {"$ne": true},
+                    }),
+                    None,
+                )
+                .await?
+                .map(|doc| Ok::<_, Error>(from_document::<m::Report>(doc?)?));
+            while let Some(report) = reports.next().await {
+                total += reap_report_analyses(db.clone(), report?).await?;
+            }
+        }
+    }
+    total += reap_unreported_analyses(db).await?;
+    info!("{} removed {} analyses", p, total);
+    Ok(total)
+}
+
+/// Removes games that were stored longer ago than the grace period, but that
+/// no job or report refers to, e.g. because their report was cancelled before
+/// its jobs were created. Returns the number of games removed.
+pub async fn reap_orphan_games(db: DbConn, metrics: &Metrics) -> Result<i64> {
+    let p = "reap_orphan_games >";
+    let cutoff = Utc::now() - orphan_game_grace();
+    // NOTE: games stored before date_stored existed are all past their grace.
+    let candidates = doc! {"date_stored": {"$not": {"$gte": cutoff}}};
+    let games = m::Game::coll(db.clone());
+    let examined = games.count_documents(candidates.clone(), None).await?;
+    // NOTE: an anti-join, mongo only hands back the orphans, a batch at a time.
+    let pipeline = vec![
+        doc! {"$match": candidates},
+        doc! {"$project": {"_id": 1}},
+        doc! {"$lookup": {"from": "deepq_fishnetjobs", "localField": "_id", "foreignField": "game_id", "as": "jobs"}},
+        doc! {"$match": {"jobs": {"$size": 0}}},
+        doc! {"$lookup": {"from": "deepq_reports", "localField": "_id", "foreignField": "games", "as": "reports"}},
+        doc! {"$match": {"reports": {"$size": 0}}},
+        doc! {"$project": {"_id": 1}},
+    ];
+    let mut orphans = games
+        .aggregate(pipeline, AggregateOptions::builder().batch_size(REAP_BATCH_SIZE as u32).build())
+        .await?
+        .map(|doc| Ok::<_, Error>(doc?.get_str("_id")?.to_string()))
+        .chunks(REAP_BATCH_SIZE);
+    let mut removed = 0i64;
+    while let Some(batch) = orphans.next().await {
+        let batch = batch.into_iter().collect::<Result<Vec<String>>>()?;
+        removed += games
+            .delete_many(doc! {"_id": {"$in": batch}}, None)
+            .await?
+            .deleted_count;
+    }
+    metrics.count("reap_orphan_games.examined", examined as u64);
+    metrics.count("reap_orphan_games.removed", removed as u64);
+    if removed > 0 {
+        insert_audit_entry(
+            db,
+            ACTOR,
+            REAP_ORPHAN_GAMES,
+            doc! {"examined": examined, "removed": removed},
+        )
+        .await?;
+    }
+    info!("{} examined {} games, removed {} orphans", p, examined, removed);
+    Ok(removed)
+}
+
+/// Permanently removes jobs and reports, along with their analyses, that were
+/// soft deleted longer ago than the retention window. Returns the number of
+/// jobs and reports removed.
+pub async fn purge_deleted(db: DbConn) -> Result<i64> {
+    let p = "purge_deleted >";
+    let cutoff = Utc::now() - soft_delete_retention();
+    let deleted = doc! {"deleted_at": {"$lt": cutoff}};
+    let job_ids: Vec<Bson> = Job::coll(db.clone())
+        .find(deleted.clone(), None)
+        .await?
+        .map(|doc| Ok::<_, Error>(Bson::ObjectId(from_document::<Job>(doc?)?._id.0)))
+        .try_collect()
+        .await?;
+    let analyses = m::GameAnalysis::coll(db.clone())
+        .delete_many(doc! {"job_id": {"$in": job_ids.clone()}}, None)
+        .await?
+        .deleted_count;
+    let jobs = Job::coll(db.clone())
+        .delete_many(doc! {"_id": {"$in": job_ids}}, None)
+        .await?
+        .deleted_count;
+    let reports = m::Report::coll(db.clone())
+        .delete_many(deleted, None)
+        .await?
+        .deleted_count;
+    if jobs > 0 || reports > 0 {
+        insert_audit_entry(
+            db,
+            ACTOR,
+            "purge_deleted",
+            doc! {"jobs": jobs, "reports": reports, "analyses": analyses},
+        )
+        .await?;
+    }
+    info!("{} purged {} jobs and {} reports", p, jobs, reports);
+    Ok(jobs + reports)
+}
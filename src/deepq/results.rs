@@ -0,0 +1,257 @@
+// Copyright 2020 Lakin Wecker
+//
+// This file is part of lila-deepq.
+//
+// lila-deepq is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// lila-deepq is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with lila-deepq.  If not, see <https://www.gnu.org/licenses/>.
+//
+// CR analysts run their own scripts against our analysis. Rather than giving
+// them the database, we push each completed Deep job of a CR report to the
+// endpoints they register, signed so they can tell it came from us.
+//
+use std::iter;
+
+use chrono::prelude::*;
+use futures::stream::{StreamExt, TryStreamExt};
+use log::{debug, error, info, warn};
+use mongodb::bson::{doc, from_document, oid::ObjectId, to_document};
+use rand::distributions::Alphanumeric;
+use rand::{thread_rng, Rng};
+use serde::Serialize;
+use tokio::sync::broadcast::{self, error::RecvError};
+use tokio::time::{sleep, Duration};
+
+use crate::crypto::{sign, Secret};
+use crate::db::DbConn;
+use crate::deepq::api::{find_analysis_for_job, find_report};
+use crate::deepq::model::{self as m, EngineType, GameId, ReportType, Score};
+use crate::deepq::score::{in_perspective, Perspective};
+use crate::error::{Error, ProtocolError, Result};
+use crate::fishnet::api::get_job;
+use crate::fishnet::model::{AnalysisType, JobId};
+use crate::fishnet::webhooks::{post, validate_url};
+use crate::fishnet::FishnetMsg;
+use crate::telemetry::SpanContext;
+
+pub const SIGNATURE_HEADER: &str = "X-Deepq-Signature";
+
+/// Deliveries back off exponentially from a second, giving up after this many.
+pub const MAX_ATTEMPTS: u32 = 5;
+
+const DELIVERY_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Serialize, Debug, Clone)]
+pub struct CompactPly {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    score: Option<Score>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pv: Option<String>,
+}
+
+/// Scores in cp from `perspective` and only the principal variation, one per ply.
+pub fn compact(analysis: &m::GameAnalysis, perspective: Perspective) -> Vec<Option<CompactPly>> {
+    analysis
+        .analysis
+        .iter()
+        .enumerate()
+        .map(|(i, ply)| {
+            ply.as_ref().map(|ply| CompactPly {
+                score: ply.score().map(|score| in_perspective(&score, i, perspective)),
+                pv: ply
+                    .pvs()
+                    .first()
+                    .map(|pv| pv.iter().map(ToString::to_string).collect::<Vec<_>>().join(" ")),
+            })
+        })
+        .collect()
+}
+
+/// The analysis of one job, in its compact form.
+#[derive(Serialize, Debug, Clone)]
+pub struct JobResult {
+    job_id: String,
+    game_id: GameId,
+    report_id: String,
+    user_id: String,
+    engine: EngineType,
+    analysis: Vec<Option<CompactPly>>,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct Registered {
+    id: String,
+    url: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    secret: Option<String>, // Only when registering, it isn't shown again
+}
+
+impl From<m::ResultWebhook> for Registered {
+    fn from(webhook: m::ResultWebhook) -> Registered {
+        Registered {
+            id: webhook._id.to_hex(),
+            url: webhook.url,
+            secret: None,
+        }
+    }
+}
+
+pub async fn register(db: DbConn, created_by: &str, url: String) -> Result<Registered> {
+    validate_url(&url).await?;
+    let secret: String = iter::repeat(())
+        .map(|()| thread_rng().sample(Alphanumeric))
+        .map(char::from)
+        .take(32)
+        .collect();
+    let webhook = m::ResultWebhook {
+        _id: ObjectId::new(),
+        url,
+        secret: secret.clone(),
+        created_by: created_by.to_string(),
+        date_created: Utc::now().into(),
+    };
+    m::ResultWebhook::coll(db)
+        .insert_one(to_document(&webhook)?, None)
+        .await?;
+    Ok(Registered {
+        secret: Some(secret),
+        ..webhook.into()
+    })
+}
+
+pub async fn find_all(db: DbConn) -> Result<Vec<m::ResultWebhook>> {
+    m::ResultWebhook::coll(db)
+        .find(doc! {}, None)
+        .await?
+        .map(|doc| Ok::<_, Error>(from_document::<m::ResultWebhook>(doc?)?))
+        .try_collect()
+        .await
+}
+
+pub async fn unregister(db: DbConn, id: ObjectId) -> Result<Option<m::ResultWebhook>> {
+    Ok(m::ResultWebhook::coll(db)
+        .find_one_and_delete(doc! {"_id": id}, None)
+        .await?
+        .map(from_document::<m::ResultWebhook>)
+        .transpose()?)
+}
+
+/// The result of a job, if it's a completed Deep job of a CR report.
+async fn cr_result(db: DbConn, job_id: JobId, perspective: Perspective) -> Result<Option<JobResult>> {
+    let job = match get_job(db.clone(), job_id.clone()).await? {
+        Some(job) if matches!(job.analysis_type, AnalysisType::Deep) => job,
+        _ => return Ok(None),
+    };
+    let report = match job.report_id.clone() {
+        Some(report_id) => find_report(db.clone(), report_id).await?,
+        None => None,
+    };
+    let report = match report {
+        Some(report) if matches!(report.report_type, ReportType::CR) => report,
+        _ => return Ok(None),
+    };
+    let analysis = match find_analysis_for_job(db, job_id).await? {
+        Some(analysis) => analysis,
+        None => return Ok(None),
+    };
+    Ok(Some(JobResult {
+        job_id: job._id.to_string(),
+        game_id: job.game_id,
+        report_id: report._id.to_string(),
+        user_id: report.user_id.to_string(),
+        engine: analysis.engine,
+        analysis: compact(&analysis, perspective),
+    }))
+}
+
+#[derive(Clone)]
+pub struct ResultWebhooks {
+    perspective: Perspective,
+}
+
+impl ResultWebhooks {
+    pub fn new(perspective: Perspective) -> ResultWebhooks {
+        ResultWebhooks { perspective }
+    }
+
+    /// Delivers to one endpoint, retrying in the background until it succeeds.
+    fn deliver(&self, webhook: m::ResultWebhook, body: Vec<u8>) {
+        let signature = sign(&Secret::from(webhook.secret.clone()), &body);
+        let trace = SpanContext::current();
+        tokio::spawn(async move {
+            for attempt in 0..MAX_ATTEMPTS {
+                if attempt > 0 {
+                    sleep(Duration::from_secs(1 << (attempt - 1))).await;
+                }
+                let headers = [(SIGNATURE_HEADER, format!("sha256={}", signature))];
+                let result = post(&webhook.url, &headers, body.clone(), DELIVERY_TIMEOUT, trace).await;
+                match result {
+                    Ok(()) => {
+                        debug!("ResultWebhooks::deliver > {} > delivered", webhook.url);
+                        return;
+                    }
+                    Err(err) if matches!(err.root(), Error::Protocol(ProtocolError::InvalidUrl(_))) => {
+                        error!("ResultWebhooks::deliver > {} > {}", webhook.url, err);
+                        return;
+                    }
+                    Err(err) => warn!(
+                        "ResultWebhooks::deliver > {} > attempt {} > {}",
+                        webhook.url,
+                        attempt + 1,
+                        err
+                    ),
+                }
+            }
+            error!("ResultWebhooks::deliver > {} > gave up", webhook.url);
+        });
+    }
+
+    pub async fn handle_job_completed(&self, db: DbConn, job_id: JobId) -> Result<()> {
+        let result = match cr_result(db.clone(), job_id, self.perspective).await? {
+            Some(result) => result,
+            None => return Ok(()),
+        };
+        let webhooks = find_all(db).await?;
+        if webhooks.is_empty() {
+            return Ok(());
+        }
+        info!(
+            "handle_job_completed > {} > {} webhooks",
+            result.job_id,
+            webhooks.len()
+        );
+        let body = serde_json::to_vec(&result)?;
+        for webhook in webhooks {
+            self.deliver(webhook, body.clone());
+        }
+        Ok(())
+    }
+
+    pub async fn fishnet_listener(self, db: DbConn, tx: broadcast::Sender<FishnetMsg>) {
+        let p = "result_webhooks >";
+        let mut rx = tx.subscribe();
+        loop {
+            match rx.recv().await {
+                Ok(FishnetMsg::JobCompleted(id)) => {
+                    if let Err(err) = self.handle_job_completed(db.clone(), id.clone()).await {
+                        error!("{} {} > {:?}", p, id, err);
+                    }
+                }
+                Ok(_) => {}
+                Err(RecvError::Lagged(n)) => {
+                    warn!("{} unable to keep up. Skip {} messages", p, n);
+                }
+                Err(RecvError::Closed) => return,
+            }
+        }
+    }
+}
@@ -0,0 +1,73 @@
+// Copyright 2020 Lakin Wecker
+//
+// This file is part of lila-deepq.
+//
+// lila-deepq is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// lila-deepq is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with lila-deepq.  If not, see <https://www.gnu.org/licenses/>.
+//
+// Engines score from the side to move. Some consumers want that, others want
+// every score from white's point of view, so the flip happens here, once, with
+// each consumer choosing its convention instead of hard-coding it.
+//
+use serde::{Deserialize, Serialize};
+
+use crate::deepq::model::Score;
+
+/// Whose point of view the scores handed to a consumer are from.
+#[derive(
+    Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default,
+    strum_macros::EnumString, strum_macros::Display,
+)]
+#[serde(rename_all = "snake_case")]
+#[strum(serialize_all = "snake_case")]
+pub enum Perspective {
+    #[default]
+    Mover, // The side to move, as the engine reports it
+    White,
+}
+
+impl Score {
+    /// The same score, from the other side's point of view.
+    pub fn flipped(&self) -> Score {
+        match self {
+            Score::Cp(cp) => Score::Cp(-cp),
+            Score::Mate(mate) => Score::Mate(-mate),
+            Score::Q(q) => Score::Q(-q),
+            Score::Wdl([w, d, l]) => Score::Wdl([*l, *d, *w]),
+        }
+    }
+}
+
+/// Whether white is to move in the position before `ply`, counting from 0.
+///
+/// NOTE: every game starts from the standard position, see starting_position.
+pub fn white_to_move(ply: usize) -> bool {
+    ply.is_multiple_of(2)
+}
+
+/// Converts an engine score of the position before `ply` to `perspective`.
+pub fn in_perspective(score: &Score, ply: usize, perspective: Perspective) -> Score {
+    match perspective {
+        Perspective::White if !white_to_move(ply) => score.flipped(),
+        _ => score.clone(),
+    }
+}
+
+/// Converts one score per ply, as the engine reported them, to `perspective`.
+pub fn plies_in_perspective(scores: Vec<Option<Score>>, perspective: Perspective) -> Vec<Option<Score>> {
+    scores
+        .into_iter()
+        .enumerate()
+        .map(|(ply, score)| score.map(|score| in_perspective(&score, ply, perspective)))
+        .collect()
+}
@@ -0,0 +1,201 @@
+// Copyright 2020 Lakin Wecker
+//
+// This file is part of lila-deepq.
+//
+// lila-deepq is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// lila-deepq is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with lila-deepq.  If not, see <https://www.gnu.org/licenses/>.
+//
+// Expected service times, from how quickly the queues have been draining.
+//
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use chrono::{prelude::*, Duration};
+use log::{error, info};
+use serde::Serialize;
+use tokio::sync::RwLock;
+
+use crate::db::DbConn;
+use crate::deepq::model as m;
+use crate::deepq::policy::report_sla;
+use crate::error::Result;
+use crate::irwin::api::report_complete_percentage;
+use crate::fishnet::model::{AnalysisType, Job};
+
+/// How far back completions count towards the expected service time, by default.
+pub const DEFAULT_HISTORY_DAYS: i64 = 7;
+
+pub const REFRESH_INTERVAL_SECONDS: u64 = 3600;
+
+#[derive(Serialize, Debug, Clone, Copy)]
+pub struct ServiceTime {
+    pub completed: i64, // Within the history window
+    pub seconds_per_job: f64, // Mean time from a worker acquiring a job to completing it
+    pub seconds_between_completions: f64, // How quickly the queue drains, across all workers
+}
+
+/// Per analysis type service times, shared by everything that surfaces an ETA.
+#[derive(Clone)]
+pub struct ServiceTimes {
+    history: Duration,
+    by_type: Arc<RwLock<HashMap<String, ServiceTime>>>,
+}
+
+impl ServiceTimes {
+    pub fn new(history: Duration) -> ServiceTimes {
+        ServiceTimes {
+            history,
+            by_type: Arc::new(RwLock::new(HashMap::new())),
+        }
+    }
+
+    /// Computes the service times up front, so the first ETAs aren't empty.
+    pub async fn warm_start(db: DbConn, history: Duration) -> Result<ServiceTimes> {
+        let service_times = ServiceTimes::new(history);
+        service_times.refresh(db).await?;
+        Ok(service_times)
+    }
+
+    pub async fn refresh(&self, db: DbConn) -> Result<()> {
+        let p = "ServiceTimes::refresh >";
+        let since = Utc::now() - self.history;
+        let db = db.analytics();
+        let mut by_type = HashMap::new();
+        for analysis_type in [
+            AnalysisType::UserAnalysis,
+            AnalysisType::SystemAnalysis,
+            AnalysisType::Deep,
+        ] {
+            let (completed, seconds_per_job) =
+                Job::completions_since(db.clone(), analysis_type.clone(), since).await?;
+            if let (true, Some(seconds_per_job)) = (completed > 0, seconds_per_job) {
+                let service_time = ServiceTime {
+                    completed,
+                    seconds_per_job,
+                    seconds_between_completions: self.history.num_seconds() as f64 / completed as f64,
+                };
+                info!(
+                    "{} {} > {:.1}s per job, one every {:.1}s",
+                    p, analysis_type, service_time.seconds_per_job, service_time.seconds_between_completions
+                );
+                by_type.insert(analysis_type.to_string(), service_time);
+            }
+        }
+        *self.by_type.write().await = by_type;
+        Ok(())
+    }
+
+    pub async fn refresh_periodically(self, db: DbConn) {
+        loop {
+            tokio::time::sleep(tokio::time::Duration::from_secs(REFRESH_INTERVAL_SECONDS)).await;
+            if let Err(err) = self.refresh(db.clone()).await {
+                error!("Unable to refresh service times: {:?}", err);
+            }
+        }
+    }
+
+    pub async fn get(&self, analysis_type: AnalysisType) -> Option<ServiceTime> {
+        self.by_type.read().await.get(&analysis_type.to_string()).copied()
+    }
+
+    /// How long until a job with `ahead` jobs in front of it is done: the
+    /// queue draining down to it, then its own service time. None when
+    /// nothing of this type completed recently enough to tell.
+    pub async fn eta(&self, analysis_type: AnalysisType, ahead: i64) -> Option<Duration> {
+        self.get(analysis_type).await.map(|service_time| {
+            let seconds = ahead.max(0) as f64 * service_time.seconds_between_completions
+                + service_time.seconds_per_job;
+            Duration::seconds(seconds.round() as i64)
+        })
+    }
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct ReportEta {
+    pub remaining_jobs: i64,
+    pub position: Option<i64>, // Of its furthest back job, None when none are queued
+    pub eta_seconds: Option<i64>,
+    pub sla_deadline: DateTime<Utc>,
+    pub projected_breach: Option<bool>,
+}
+
+/// Projects when a report will be complete, and whether that's within its SLA.
+pub async fn report_eta(db: DbConn, service_times: &ServiceTimes, report: m::Report) -> Result<ReportEta> {
+    let mut remaining_jobs = 0;
+    let mut furthest: Option<(AnalysisType, i64)> = None;
+    for job in Job::find_incomplete_for_report(db.clone(), report._id.clone()).await? {
+        remaining_jobs += 1;
+        if job.owner.is_none() {
+            let position = Job::queue_position(db.clone(), &job).await?;
+            if furthest.as_ref().is_none_or(|(_, furthest)| position > *furthest) {
+                furthest = Some((job.analysis_type.clone(), position));
+            }
+        }
+    }
+    let eta = match furthest.clone() {
+        Some((analysis_type, position)) => service_times.eta(analysis_type, position).await,
+        // NOTE: everything left is already with a worker.
+        None if remaining_jobs > 0 => service_times.eta(AnalysisType::Deep, 0).await,
+        None => Some(Duration::zero()),
+    };
+    let sla_deadline = report.date_requested.0 + report_sla(report.origin);
+    Ok(ReportEta {
+        remaining_jobs,
+        position: furthest.map(|(_, position)| position),
+        eta_seconds: eta.map(|eta| eta.num_seconds()),
+        sla_deadline,
+        projected_breach: eta.map(|eta| Utc::now() + eta > sla_deadline),
+    })
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq, strum_macros::Display)]
+#[serde(rename_all = "snake_case")]
+#[strum(serialize_all = "snake_case")]
+pub enum ProgressState {
+    Empty,     // Every game was excluded or too short, nothing to analyse
+    Queued,    // Nothing picked up by a worker yet
+    Analysing,
+    Partial,   // Sent to irwin without its stragglers
+    Complete,
+}
+
+#[derive(Serialize, Debug, Clone, Copy)]
+pub struct ReportProgress {
+    pub percent: i64,
+    pub state: ProgressState,
+}
+
+/// How far along a report's analysis is, by the same count that decides
+/// when it's sent to irwin.
+pub async fn report_progress(db: DbConn, report: &m::Report) -> Result<ReportProgress> {
+    let complete = report_complete_percentage(db.clone(), report.clone()).await?;
+    let incomplete = Job::find_incomplete_for_report(db, report._id.clone()).await?;
+    let state = if complete.is_none() {
+        ProgressState::Empty
+    } else if report.date_completed.is_some() && report.missing_games.is_empty() {
+        ProgressState::Complete
+    } else if report.sent_to_irwin {
+        ProgressState::Partial
+    } else if complete > Some(0f64) || incomplete.iter().any(|job| job.owner.is_some()) {
+        ProgressState::Analysing
+    } else {
+        ProgressState::Queued
+    };
+    Ok(ReportProgress {
+        percent: match state {
+            ProgressState::Complete => 100,
+            _ => (complete.unwrap_or(0f64) * 100f64).floor() as i64,
+        },
+        state,
+    })
+}
@@ -0,0 +1,152 @@
+// Copyright 2020 Lakin Wecker
+//
+// This file is part of lila-deepq.
+//
+// lila-deepq is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// lila-deepq is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with lila-deepq.  If not, see <https://www.gnu.org/licenses/>.
+//
+// Engine search limits. They are stored as signed integers because that's
+// what bson has, but they are validated on the way in.
+//
+use std::convert::TryFrom;
+use std::num::NonZeroU8;
+use std::ops::Add;
+
+use derive_more::Display;
+use serde::{Deserialize, Serialize};
+
+use crate::error::Error;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default, Display)]
+#[serde(try_from = "i64", into = "i64")]
+pub struct Nodes(u64);
+
+impl Nodes {
+    pub const MAX: Nodes = Nodes(i64::MAX as u64);
+
+    pub fn new(nodes: u64) -> Nodes {
+        Nodes(nodes.min(Nodes::MAX.0))
+    }
+
+    pub fn get(self) -> u64 {
+        self.0
+    }
+
+    /// Scales the budget by a fraction, e.g. for tier multipliers or time controls.
+    pub fn scaled(self, numerator: u64, denominator: u64) -> Nodes {
+        let scaled = u128::from(self.0) * u128::from(numerator) / u128::from(denominator.max(1));
+        Nodes::new(u64::try_from(scaled).unwrap_or(u64::MAX))
+    }
+}
+
+impl Add for Nodes {
+    type Output = Nodes;
+
+    fn add(self, other: Nodes) -> Nodes {
+        Nodes::new(self.0.saturating_add(other.0))
+    }
+}
+
+impl TryFrom<i64> for Nodes {
+    type Error = Error;
+
+    fn try_from(nodes: i64) -> Result<Nodes, Error> {
+        Ok(Nodes(u64::try_from(nodes)?))
+    }
+}
+
+impl From<Nodes> for i64 {
+    fn from(nodes: Nodes) -> i64 {
+        // NOTE: construction caps at i64::MAX so this can't fail.
+        i64::try_from(nodes.0).unwrap_or(i64::MAX)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default, Display)]
+#[serde(try_from = "i32", into = "i32")]
+pub struct Depth(u8);
+
+impl Depth {
+    pub fn new(depth: u8) -> Depth {
+        Depth(depth)
+    }
+
+    pub fn get(self) -> u8 {
+        self.0
+    }
+
+    pub fn saturating_add(self, plies: u8) -> Depth {
+        Depth(self.0.saturating_add(plies))
+    }
+}
+
+impl TryFrom<i32> for Depth {
+    type Error = Error;
+
+    fn try_from(depth: i32) -> Result<Depth, Error> {
+        Ok(Depth(u8::try_from(depth)?))
+    }
+}
+
+impl From<Depth> for i32 {
+    fn from(depth: Depth) -> i32 {
+        i32::from(depth.0)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Display)]
+#[serde(try_from = "i32", into = "i32")]
+pub struct MultiPv(NonZeroU8);
+
+impl MultiPv {
+    pub const ONE: MultiPv = MultiPv(NonZeroU8::MIN);
+
+    /// None for 0, which isn't a valid number of lines.
+    pub fn new(lines: u8) -> Option<MultiPv> {
+        NonZeroU8::new(lines).map(MultiPv)
+    }
+
+    pub fn get(self) -> u8 {
+        self.0.get()
+    }
+}
+
+impl TryFrom<i32> for MultiPv {
+    type Error = Error;
+
+    fn try_from(lines: i32) -> Result<MultiPv, Error> {
+        Ok(MultiPv(NonZeroU8::try_from(u8::try_from(lines)?)?))
+    }
+}
+
+impl From<MultiPv> for i32 {
+    fn from(lines: MultiPv) -> i32 {
+        i32::from(lines.get())
+    }
+}
+
+/// The node budget for a search, depending on the flavor of stockfish.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NodeBudget {
+    pub nnue: Nodes,
+    pub classical: Nodes,
+}
+
+impl NodeBudget {
+    pub fn scaled(self, numerator: u64, denominator: u64) -> NodeBudget {
+        NodeBudget {
+            nnue: self.nnue.scaled(numerator, denominator),
+            classical: self.classical.scaled(numerator, denominator),
+        }
+    }
+}
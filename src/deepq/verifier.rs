@@ -0,0 +1,125 @@
+// Copyright 2021 Lakin Wecker
+//
+// This file is part of lila-deepq.
+//
+// lila-deepq is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// lila-deepq is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with lila-deepq.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::result::Result as StdResult;
+
+use futures::stream::StreamExt;
+use log::{debug, info, warn};
+use mongodb::{
+    bson::{doc, from_document, Bson},
+    options::UpdateModifications,
+};
+use shakmaty::{uci::Uci, CastlingMode, Chess, Position};
+
+use crate::db::DbConn;
+use crate::deepq::api::{find_game, starting_position};
+use crate::deepq::model as m;
+use crate::error::Result;
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Verification {
+    pub checked: u64,
+    pub corrupt: u64,
+}
+
+fn replay(pos: &Chess, pv: &[Uci]) -> StdResult<(), Uci> {
+    let mut pos = pos.clone();
+    for uci in pv {
+        let m = uci.to_move(&pos).map_err(|_| uci.clone())?;
+        pos.play_unchecked(&m);
+    }
+    Ok(())
+}
+
+/// Replays every pv of the analysis from the position it was for,
+/// returning the reason it's corrupt, if it is.
+pub fn verify(game: &m::Game, analysis: &m::GameAnalysis) -> StdResult<(), String> {
+    let mut pos: Chess = starting_position(game.clone())
+        .position(CastlingMode::Standard)
+        .map_err(|_| "invalid starting position".to_string())?;
+    if analysis.analysis.len() > game.pgn.len() + 1 {
+        return Err(format!(
+            "{} plies of analysis for {} moves",
+            analysis.analysis.len(),
+            game.pgn.len()
+        ));
+    }
+    for (ply, ply_analysis) in analysis.analysis.iter().enumerate() {
+        if let Some(ply_analysis) = ply_analysis {
+            for pv in ply_analysis.pvs() {
+                replay(&pos, pv).map_err(|uci| format!("ply {}: illegal pv move {}", ply, uci))?;
+            }
+        }
+        if let Some(uci) = game.pgn.get(ply) {
+            let m = uci
+                .to_move(&pos)
+                .map_err(|_| format!("ply {}: illegal game move {}", ply, uci))?;
+            pos.play_unchecked(&m);
+        }
+    }
+    Ok(())
+}
+
+async fn quarantine(db: DbConn, analysis: &m::GameAnalysis, reason: String) -> Result<()> {
+    m::GameAnalysis::coll(db)
+        .update_one(
+            doc! {"_id": analysis._id.clone()},
+            UpdateModifications::Document(doc! {"$set": {"quarantine_reason": reason}}),
+            None,
+        )
+        .await?;
+    Ok(())
+}
+
+/// Verifies a random sample of the stored analyses, quarantining the corrupt ones.
+pub async fn verify_sample(db: DbConn, size: i64) -> Result<Verification> {
+    let p = "verify_sample >";
+    let mut verification = Verification::default();
+    let mut cursor = m::GameAnalysis::coll(db.clone())
+        .aggregate(
+            vec![
+                doc! {"$match": {"quarantine_reason": Bson::Null}},
+                doc! {"$sample": {"size": size}},
+            ],
+            None,
+        )
+        .await?;
+    while let Some(doc) = cursor.next().await {
+        let analysis: m::GameAnalysis = from_document(doc?)?;
+        let game = match find_game(db.clone(), analysis.game_id.clone()).await? {
+            Some(game) => game,
+            None => {
+                debug!("{} no game for {}", p, analysis.game_id);
+                continue;
+            }
+        };
+        verification.checked += 1;
+        if let Err(reason) = verify(&game, &analysis) {
+            warn!(
+                "{} quarantining analysis {} of {}: {}",
+                p, analysis._id, analysis.game_id, reason
+            );
+            verification.corrupt += 1;
+            quarantine(db.clone(), &analysis, reason).await?;
+        }
+    }
+    info!(
+        "{} checked: {}, corrupt: {}",
+        p, verification.checked, verification.corrupt
+    );
+    Ok(verification)
+}
@@ -16,15 +16,17 @@
 // along with lila-deepq.  If not, see <https://www.gnu.org/licenses/>.
 
 use std::env::VarError;
+use std::fmt::Display;
 use std::num::TryFromIntError;
 
 use mongodb::bson::{
     de::Error as _BsonDeError, document::ValueAccessError as _BsonValueAccessError,
-    ser::Error as _BsonSeError,
+    oid::Error as _BsonOidError, ser::Error as _BsonSeError,
 };
 use mongodb::error::Error as _MongoDBError;
-//use serde::de::{Error as _SerdeDeError};
+use shakmaty::san::SanError;
 
+#[cfg(feature = "web")]
 use warp::reject;
 use tokio::task::JoinError;
 
@@ -40,75 +42,192 @@ pub enum HttpError {
 
     #[error("Forbidden")]
     Forbidden, // Insufficient permissions
+
+    #[error("Job Expired")]
+    JobExpired, // Submitted after the job expired or was deleted, the analysis was quarantined
+
+    #[error("Unsupported Work Type")]
+    UnsupportedWorkType, // We only hand out analysis work
+
+    #[error("Replayed")]
+    Replayed, // Body authentication with a stale or reused nonce
+
+    #[error("Unsupported API Version")]
+    UnsupportedApiVersion, // Asked for in the Api-Version header, and not one we serve
 }
 
+#[cfg(feature = "web")]
 impl reject::Reject for HttpError {}
 
-// TODO: this desperately needs to be cleaned up.
+/// Failures talking to the database, or converting to and from its documents.
 #[derive(Error, Debug)]
-pub enum Error {
-    #[error("Invalid command line arguments")]
-    InvalidCommandLineArguments,
+pub enum DbError {
+    #[error("Mongo Database Error: {0}")]
+    Mongo(#[from] _MongoDBError),
+
+    #[error("BSON Serialization Error: {0}")]
+    BsonSerialization(#[from] _BsonSeError),
+
+    #[error("BSON Deserialization Error: {0}")]
+    BsonDeserialization(#[from] _BsonDeError),
+
+    #[error("BSON Value Access Error: {0}")]
+    BsonValueAccess(#[from] _BsonValueAccessError),
 
-    // #[error("Serde Deserialization Error")]
-    // SerdeDeserializationError(#[from] _SerdeDeError),
     #[error("I am somehow unable to create a record in the database.")]
-    CreateError,
+    Create,
+}
 
-    #[error("I am somehow unable to find a record in the database.")]
-    NotFoundError,
+/// Failures in what we exchange with lichess, irwin and fishnet clients.
+#[derive(Error, Debug)]
+pub enum ProtocolError {
+    #[error("HTTP Request Error: {0}")]
+    Request(#[from] reqwest::Error),
 
-    #[error("BSON Error")]
-    BsonSerializationError(#[from] _BsonSeError),
+    #[error("JSON Error: {0}")]
+    Json(#[from] serde_json::Error),
 
-    #[error("BSON Error")]
-    BsonDeserializationError(#[from] _BsonDeError),
+    #[error("IO Error: {0}")]
+    Io(#[from] std::io::Error),
 
-    #[error("BSON Error")]
-    BsonValueAccessError(#[from] _BsonValueAccessError),
+    #[error("Invalid ObjectId: {0}")]
+    InvalidObjectId(#[from] _BsonOidError),
 
-    #[error("Mongo Database Error")]
-    MongoDBError(#[from] _MongoDBError),
+    #[error("Unable to deserialize something")]
+    Deserialization,
 
-    #[error("Converstion Error")]
-    TryFromIntError(#[from] TryFromIntError),
-    #[error("Mongo Database Error")]
-    HttpError(#[from] HttpError),
+    #[error("Invalid URL: {0}")]
+    InvalidUrl(String),
 
-    #[error("IrwinStreamError")]
-    IrwinStreamError(#[from] reqwest::Error),
+    #[error("Invalid id: {0}")]
+    InvalidId(String),
 
-    #[error("serde_json Error")]
-    SerdeJsonError(#[from] serde_json::Error),
+    #[error("HTTP Connection Error: {0}")]
+    Connection(#[from] hyper::Error),
 
-    #[error("std::io::Error")]
-    IoError(#[from] std::io::Error),
+    #[error("TLS Error: {0}")]
+    Tls(#[from] native_tls::Error),
 
-    #[error("env::VarError")]
-    VarError(#[from] VarError),
+    #[error("Webhook Delivery Failed: {0}")]
+    Delivery(String), // Timed out, or the webhook didn't answer with a 2xx
+}
+
+/// Failures of the chess, or of our own rules about jobs and reports.
+#[derive(Error, Debug)]
+pub enum DomainError {
+    #[error("Illegal SAN: {0}")]
+    IllegalSan(#[from] SanError),
 
-    #[error("mongodb::bson::oid::Error")]
-    BsonOidError(#[from] mongodb::bson::oid::Error),
+    #[error("Illegal Position")]
+    IllegalPosition,
 
-    #[error("shakmaty::san::SanError")]
-    SanError(#[from] shakmaty::san::SanError),
+    #[error("Value out of range: {0}")]
+    OutOfRange(#[from] TryFromIntError),
 
-    #[error("shakmaty::Chess")]
-    PositionError,
+    #[error("Invalid PGN: {0}")]
+    InvalidPgn(String),
 
-    #[error("Unable to deserialize something")]
-    DeserializationError,
+    #[error("I am somehow unable to find a record in the database.")]
+    NotFound,
 
-    #[error("unknown data store error")]
-    Unknown,
+    #[error("Document of {0} bytes is too large to store")]
+    DocumentTooLarge(usize),
+
+    #[error("Refers to something that doesn't exist: {0}")]
+    DanglingReference(String),
+
+    #[error("Invalid {0}")]
+    Invalid(String),
 
     #[error("I haven't implemented this yet")]
     Unimplemented,
+}
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error(transparent)]
+    Db(#[from] DbError),
+
+    #[error(transparent)]
+    Protocol(#[from] ProtocolError),
+
+    #[error(transparent)]
+    Domain(#[from] DomainError),
+
+    #[error(transparent)]
+    Http(#[from] HttpError),
+
+    #[error("Invalid command line arguments")]
+    InvalidCommandLineArguments,
+
+    #[error("env::VarError")]
+    VarError(#[from] VarError),
 
     #[error("Unable to join tokio task")]
     JoinError(#[from] JoinError),
+
+    #[error("{context}: {source}")]
+    Context {
+        context: String,
+        source: Box<Error>,
+    },
+}
+
+impl Error {
+    /// The underlying error, without any context that was attached to it.
+    pub fn root(&self) -> &Error {
+        match self {
+            Error::Context { source, .. } => source.root(),
+            err => err,
+        }
+    }
 }
 
+#[cfg(feature = "web")]
 impl reject::Reject for Error {}
 
+// NOTE: lets `?` lift library errors straight into the right layer.
+macro_rules! from_via {
+    ($from:ty => $layer:ident) => {
+        impl From<$from> for Error {
+            fn from(err: $from) -> Error {
+                Error::$layer(err.into())
+            }
+        }
+    };
+}
+
+from_via!(_MongoDBError => Db);
+from_via!(_BsonSeError => Db);
+from_via!(_BsonDeError => Db);
+from_via!(_BsonValueAccessError => Db);
+from_via!(reqwest::Error => Protocol);
+from_via!(serde_json::Error => Protocol);
+from_via!(std::io::Error => Protocol);
+from_via!(_BsonOidError => Protocol);
+from_via!(hyper::Error => Protocol);
+from_via!(native_tls::Error => Protocol);
+from_via!(SanError => Domain);
+from_via!(TryFromIntError => Domain);
+
 pub type Result<T> = std::result::Result<T, Error>;
+
+/// Attach context, like the job or report involved, to an error.
+pub trait Context<T> {
+    fn context<C: Display>(self, context: C) -> Result<T>;
+
+    fn with_context<C: Display, F: FnOnce() -> C>(self, f: F) -> Result<T>;
+}
+
+impl<T, E: Into<Error>> Context<T> for std::result::Result<T, E> {
+    fn context<C: Display>(self, context: C) -> Result<T> {
+        self.with_context(|| context)
+    }
+
+    fn with_context<C: Display, F: FnOnce() -> C>(self, f: F) -> Result<T> {
+        self.map_err(|err| Error::Context {
+            context: f().to_string(),
+            source: Box::new(err.into()),
+        })
+    }
+}
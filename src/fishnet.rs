@@ -16,14 +16,20 @@
 // along with lila-deepq.  If not, see <https://www.gnu.org/licenses/>.
 
 pub mod api;
+#[cfg(feature = "web")]
 pub mod filters;
+#[cfg(feature = "web")]
 pub mod handlers;
 pub mod model;
+pub mod sla;
+pub mod webhooks;
 
 use crate::fishnet::model::JobId;
-use crate::db::DbConn;
+#[cfg(feature = "web")]
+use crate::{crypto::Secret, db::DbConn, fishnet::handlers::Services};
 
 use tokio::sync::broadcast;
+#[cfg(feature = "web")]
 use warp::{
     filters::BoxedFilter,
     reply::Reply,
@@ -34,6 +40,7 @@ pub enum FishnetMsg {
     JobAcquired(JobId),
     JobAborted(JobId),
     JobCompleted(JobId),
+    JobsQueued, // New jobs were inserted, by this process
 }
 
 
@@ -48,8 +55,9 @@ impl Actor {
         Actor {tx}
     }
 
-    pub fn handlers(&self, db: DbConn) -> BoxedFilter<(impl Reply,)> { 
-        handlers::mount(db.clone(), self.tx.clone())
+    #[cfg(feature = "web")]
+    pub fn handlers(&self, db: DbConn, secret: Secret, services: Services) -> BoxedFilter<(impl Reply,)> {
+        handlers::mount(db, self.tx.clone(), secret, services)
     }
 }
 
@@ -16,29 +16,56 @@
 // along with lila-deepq.  If not, see <https://www.gnu.org/licenses/>.
 //
 //
-use chrono::prelude::*;
+use chrono::{prelude::*, Duration};
 use futures::future::Future;
+use futures::stream::{StreamExt, TryStreamExt};
 use std::convert::TryInto;
+use std::fmt;
 use std::iter;
+use std::str::FromStr;
 
 use mongodb::bson::{
-    doc, from_document, oid::ObjectId, to_document, Bson, DateTime as BsonDateTime,
+    doc, from_document, oid::ObjectId, to_bson, to_document, Bson, DateTime as BsonDateTime,
+};
+use mongodb::options::{
+    FindOneAndUpdateOptions, FindOptions, ReturnDocument, UpdateModifications,
 };
-use mongodb::options::{FindOneAndUpdateOptions, UpdateModifications};
 use rand::distributions::Alphanumeric;
 use rand::{thread_rng, Rng};
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 
-use crate::db::DbConn;
-use crate::deepq::model::{GameId, UserId, ReportId};
-use crate::error::{Error, Result};
+use crate::crypto::{sha256_hex, sign, verify, Secret};
+use crate::db::{live, DbConn};
+use crate::deepq::api::insert_audit_entry;
+use crate::deepq::model::{
+    EngineType, Game, GameId, PlyAnalysis, Report, ReportId, ReportOrigin, UserId,
+};
+use crate::deepq::policy::max_precedence;
+use crate::deepq::preset;
+use crate::deepq::stats::ServiceTimes;
+use crate::error::{DbError, DomainError, Error, ProtocolError, Result};
 use crate::fishnet::model as m;
+use crate::telemetry::{instrument, Span, SpanKind};
+
+// TODO: make this configurable, deep jobs may need longer.
+pub const JOB_TTL_SECONDS: i64 = 60 * 30;
+
+// TODO: this should also take into account canary results, once we have them.
+pub const TRUSTED_AFTER_COMPLETED_JOBS: i64 = 100;
+
+// NOTE: a cancelled report only wastes the work that is already in flight.
+pub const MAX_ACQUIRED_JOBS_PER_REPORT: i32 = 5;
 
 #[derive(Debug, Clone)]
 pub struct CreateApiUser {
     pub user: Option<UserId>,
     pub name: String,
     pub perms: Vec<m::AnalysisType>,
+    pub admin: bool,
+    pub trust: m::TrustLevel,
+    pub org: Option<String>,
+    pub committed_jobs_per_hour: Option<i64>,
+    pub allowed_origins: Option<Vec<ReportOrigin>>,
 }
 
 impl From<CreateApiUser> for m::ApiUser {
@@ -55,6 +82,12 @@ impl From<CreateApiUser> for m::ApiUser {
             user: job.user,
             name: job.name,
             perms: job.perms,
+            admin: job.admin,
+            trust: job.trust,
+            org: job.org,
+            committed_jobs_per_hour: job.committed_jobs_per_hour,
+            webhook_url: None,
+            allowed_origins: job.allowed_origins,
         }
     }
 }
@@ -66,14 +99,126 @@ pub async fn create_api_user(db: DbConn, create: CreateApiUser) -> Result<m::Api
         .await?
         .inserted_id
         .as_object_id()
-        .ok_or(Error::CreateError)?;
+        .ok_or(DbError::Create)?;
     Ok(api_user)
 }
 
+pub async fn set_trust(
+    db: DbConn,
+    key: m::Key,
+    trust: m::TrustLevel,
+) -> Result<Option<m::ApiUser>> {
+    Ok(m::ApiUser::coll(db)
+        .find_one_and_update(
+            doc! {"key": key.0},
+            UpdateModifications::Document(doc! {"$set": {"trust": trust}}),
+            FindOneAndUpdateOptions::builder()
+                .return_document(ReturnDocument::After)
+                .build(),
+        )
+        .await?
+        .map(from_document)
+        .transpose()?)
+}
+
+pub async fn set_org(db: DbConn, key: m::Key, org: Option<String>) -> Result<Option<m::ApiUser>> {
+    Ok(m::ApiUser::coll(db)
+        .find_one_and_update(
+            doc! {"key": key.0},
+            UpdateModifications::Document(doc! {"$set": {"org": org.map(Bson::String).unwrap_or(Bson::Null)}}),
+            FindOneAndUpdateOptions::builder()
+                .return_document(ReturnDocument::After)
+                .build(),
+        )
+        .await?
+        .map(from_document)
+        .transpose()?)
+}
+
+pub async fn set_allowed_origins(
+    db: DbConn,
+    key: m::Key,
+    allowed_origins: Option<Vec<ReportOrigin>>,
+) -> Result<Option<m::ApiUser>> {
+    let allowed: Bson = allowed_origins
+        .map(|origins| Bson::Array(origins.into_iter().map(Bson::from).collect()))
+        .unwrap_or(Bson::Null);
+    Ok(m::ApiUser::coll(db)
+        .find_one_and_update(
+            doc! {"key": key.0},
+            UpdateModifications::Document(doc! {"$set": {"allowed_origins": allowed}}),
+            FindOneAndUpdateOptions::builder()
+                .return_document(ReturnDocument::After)
+                .build(),
+        )
+        .await?
+        .map(from_document)
+        .transpose()?)
+}
+
+pub async fn set_commitment(
+    db: DbConn,
+    key: m::Key,
+    committed_jobs_per_hour: Option<i64>,
+) -> Result<Option<m::ApiUser>> {
+    let committed: Bson = committed_jobs_per_hour.map(Bson::Int64).unwrap_or(Bson::Null);
+    Ok(m::ApiUser::coll(db)
+        .find_one_and_update(
+            doc! {"key": key.0},
+            UpdateModifications::Document(doc! {"$set": {"committed_jobs_per_hour": committed}}),
+            FindOneAndUpdateOptions::builder()
+                .return_document(ReturnDocument::After)
+                .build(),
+        )
+        .await?
+        .map(from_document)
+        .transpose()?)
+}
+
+pub async fn set_webhook(
+    db: DbConn,
+    key: m::Key,
+    webhook_url: Option<String>,
+) -> Result<Option<m::ApiUser>> {
+    Ok(m::ApiUser::coll(db)
+        .find_one_and_update(
+            doc! {"key": key.0},
+            UpdateModifications::Document(doc! {"$set": {"webhook_url": webhook_url.map(Bson::String).unwrap_or(Bson::Null)}}),
+            FindOneAndUpdateOptions::builder()
+                .return_document(ReturnDocument::After)
+                .build(),
+        )
+        .await?
+        .map(from_document)
+        .transpose()?)
+}
+
+/// Promotes new keys to trusted once they have a track record.
+pub async fn maybe_promote(db: DbConn, api_user: m::ApiUser) -> Result<()> {
+    if api_user.trust != m::TrustLevel::New {
+        return Ok(());
+    }
+    let completed = m::Job::completed_jobs_for_key(db.clone(), api_user.key.clone()).await?;
+    if completed >= TRUSTED_AFTER_COMPLETED_JOBS {
+        set_trust(db.clone(), api_user.key.clone(), m::TrustLevel::Trusted).await?;
+        insert_audit_entry(
+            db,
+            "maybe_promote",
+            "set_trust",
+            doc! {
+                "key_name": api_user.name,
+                "trust": m::TrustLevel::Trusted,
+                "completed": completed,
+            },
+        )
+        .await?;
+    }
+    Ok(())
+}
+
 pub async fn get_api_user(db: DbConn, key: m::Key) -> Result<Option<m::ApiUser>> {
-    let col = m::ApiUser::coll(db);
-    Ok(col
-        .find_one(doc! {"key": key.0.clone()}, None)
+    let (coll, filter) = (m::ApiUser::coll(db), doc! {"key": key.0});
+    Ok(coll.find_one(filter, None)
         .await?
         .map(from_document)
         .transpose()?)
@@ -81,10 +226,127 @@ pub async fn get_api_user(db: DbConn, key: m::Key) -> Result<Option<m::ApiUser>>
 
 #[derive(Debug, Clone)]
 pub struct CreateJob {
-    pub game_id: GameId,
-    pub report_id: Option<ReportId>,
-    pub analysis_type: m::AnalysisType,
-    pub precedence: i32,
+    game_id: GameId,
+    report_id: Option<ReportId>,
+    origin: Option<ReportOrigin>,
+    analysis_type: m::AnalysisType,
+    precedence: i32,
+    follow_up: bool,
+    quiet_positions: Vec<i32>,
+    preset_id: String,
+}
+
+impl CreateJob {
+    pub fn builder(game_id: GameId, analysis_type: m::AnalysisType) -> CreateJobBuilder {
+        CreateJobBuilder {
+            game_id,
+            analysis_type,
+            report_id: None,
+            origin: None,
+            precedence: None,
+            follow_up: false,
+            quiet_positions: Vec::new(),
+            preset_id: None,
+        }
+    }
+}
+
+/// Builds a CreateJob, refusing the combinations that make no sense.
+#[derive(Debug, Clone)]
+pub struct CreateJobBuilder {
+    game_id: GameId,
+    analysis_type: m::AnalysisType,
+    report_id: Option<ReportId>,
+    origin: Option<ReportOrigin>,
+    precedence: Option<i32>,
+    follow_up: bool,
+    quiet_positions: Vec<i32>,
+    preset_id: Option<String>,
+}
+
+impl CreateJobBuilder {
+    /// A job for one of the report's games, which shares the report's origin.
+    pub fn report(mut self, report_id: ReportId, origin: ReportOrigin) -> Self {
+        self.report_id = Some(report_id);
+        self.origin = Some(origin);
+        self
+    }
+
+    /// Jobs without a report still have an origin, e.g. PGN uploads.
+    pub fn origin(mut self, origin: ReportOrigin) -> Self {
+        self.origin = Some(origin);
+        self
+    }
+
+    pub fn precedence(mut self, precedence: i32) -> Self {
+        self.precedence = Some(precedence);
+        self
+    }
+
+    pub fn follow_up(mut self, follow_up: bool) -> Self {
+        self.follow_up = follow_up;
+        self
+    }
+
+    pub fn quiet_positions(mut self, quiet_positions: Vec<i32>) -> Self {
+        self.quiet_positions = quiet_positions;
+        self
+    }
+
+    /// Otherwise the job gets its kind's default, see preset::default_for.
+    pub fn preset(mut self, preset_id: &str) -> Self {
+        self.preset_id = Some(preset_id.to_string());
+        self
+    }
+
+    fn problem(&self) -> Option<String> {
+        let precedence = match self.precedence {
+            Some(precedence) => precedence,
+            None => return Some("precedence is required".to_string()),
+        };
+        if precedence < 0 {
+            return Some(format!("precedence {} is negative", precedence));
+        }
+        if let Some(origin) = &self.origin {
+            let max = max_precedence(origin.clone());
+            if precedence > max {
+                return Some(format!("precedence {} is above {} for {:?}", precedence, max, origin));
+            }
+        }
+        if self.report_id.is_some() && !matches!(self.analysis_type, m::AnalysisType::Deep) {
+            return Some(format!("{} analysis is never part of a report", self.analysis_type));
+        }
+        if self.follow_up && self.report_id.is_none() {
+            return Some("follow ups are always for a report".to_string());
+        }
+        if self.quiet_positions.iter().any(|position| *position < 0) {
+            return Some("quiet positions can't be negative".to_string());
+        }
+        if let Some(preset_id) = &self.preset_id {
+            if preset::builtin(preset_id).is_none() {
+                return Some(format!("there's no {:?} preset", preset_id));
+            }
+        }
+        None
+    }
+
+    pub fn build(self) -> Result<CreateJob> {
+        if let Some(problem) = self.problem() {
+            return Err(DomainError::Invalid(format!("job for Game({}): {}", self.game_id, problem)).into());
+        }
+        let default = preset::default_for(&self.analysis_type, self.follow_up);
+        let preset_id = self.preset_id.unwrap_or_else(|| default.to_string());
+        Ok(CreateJob {
+            preset_id,
+            game_id: self.game_id,
+            report_id: self.report_id,
+            origin: self.origin,
+            analysis_type: self.analysis_type,
+            precedence: self.precedence.unwrap_or_default(),
+            follow_up: self.follow_up,
+            quiet_positions: self.quiet_positions,
+        })
+    }
 }
 
 impl From<CreateJob> for m::Job {
@@ -93,16 +355,38 @@ impl From<CreateJob> for m::Job {
             _id: m::JobId(ObjectId::new()),
             game_id: job.game_id,
             report_id: job.report_id,
+            origin: job.origin,
             analysis_type: job.analysis_type,
             precedence: job.precedence,
             owner: None,
+            handed_to: Vec::new(),
             date_last_updated: BsonDateTime(Utc::now()),
-            is_complete: false
+            is_complete: false,
+            date_completed: None,
+            follow_up: job.follow_up,
+            expires_at: None,
+            date_acquired: None,
+            skip_reason: None,
+            deleted_at: None,
+            quiet_positions: job.quiet_positions,
+            analysis_reaped: false,
+            deadline_warned: false,
+            game_missing_at: None,
+            preset_id: Some(job.preset_id),
+            preset: None,
+            client_version: None,
         }
     }
 }
 
+/// Refuses jobs for games that are too short to analyse, see
+/// policy::is_analysable, whichever way they were queued.
 pub async fn insert_one_job(db: DbConn, job: CreateJob) -> Result<ObjectId> {
+    let games = Game::coll(db.clone());
+    let filter = doc! {"_id": job.game_id.0.clone(), "not_analysable": true};
+    if games.count_documents(filter, None).await? > 0 {
+        return Err(DomainError::Invalid(format!("Game({}) is too short to analyse", job.game_id)).into());
+    }
     let job_col = m::Job::coll(db);
     let job: m::Job = job.into();
     Ok(job_col
@@ -110,7 +394,7 @@ pub async fn insert_one_job(db: DbConn, job: CreateJob) -> Result<ObjectId> {
         .await?
         .inserted_id
         .as_object_id()
-        .ok_or(Error::CreateError)?
+        .ok_or(DbError::Create)?
         .clone())
 }
 
@@ -125,38 +409,178 @@ where
         .map(move |job| insert_one_job(db.clone(), job.clone()))
 }
 
+/// The report origins a key may receive work from, None meaning all of them.
+fn origins_for_trust(trust: m::TrustLevel) -> Option<Vec<Bson>> {
+    match trust {
+        // NOTE: not jobs without an origin either, legacy jobs have none.
+        m::TrustLevel::New => Some(vec![ReportOrigin::Random.into()]),
+        m::TrustLevel::Trusted | m::TrustLevel::Core => None,
+    }
+}
+
+/// The origins the key's trust level allows, narrowed to the ones it was scoped to.
+fn origins_for_key(api_user: &m::ApiUser) -> Option<Vec<Bson>> {
+    let trusted = origins_for_trust(api_user.trust);
+    match &api_user.allowed_origins {
+        // NOTE: scoped keys never get user/system analysis, those jobs have no origin.
+        Some(allowed) => Some(
+            allowed
+                .iter()
+                .cloned()
+                .map(Bson::from)
+                .filter(|origin| trusted.as_ref().is_none_or(|trusted| trusted.contains(origin)))
+                .collect(),
+        ),
+        None => trusted,
+    }
+}
+
+/// Reports that already have MAX_ACQUIRED_JOBS_PER_REPORT jobs out with workers.
+async fn saturated_reports(db: DbConn, now: DateTime<Utc>) -> Result<Vec<Bson>> {
+    let coll = m::Job::coll(db);
+    let filter = live(doc! {
+        "owner": {"$ne": Bson::Null},
+        "expires_at": {"$gte": now},
+        "is_complete": false,
+        "report_id": {"$ne": Bson::Null},
+    });
+    let pipeline = vec![
+        doc! {"$match": filter},
+        doc! {"$group": {"_id": "$report_id", "in_flight": {"$sum": 1}}},
+        doc! {"$match": {"in_flight": {"$gte": MAX_ACQUIRED_JOBS_PER_REPORT}}},
+    ];
+    let mut cursor = coll.aggregate(pipeline, None).await?;
+    let mut saturated = Vec::new();
+    while let Some(doc) = cursor.next().await {
+        if let Some(report_id) = doc?.get("_id") {
+            saturated.push(report_id.clone());
+        }
+    }
+    Ok(saturated)
+}
+
+/// Hands a job to the key, however it was picked.
+fn acquisition(key: &m::Key, now: DateTime<Utc>) -> UpdateModifications {
+    UpdateModifications::Document(doc! {
+        "$set": {
+            "owner": key.clone(),
+            "expires_at": now + Duration::seconds(JOB_TTL_SECONDS),
+            "date_acquired": now,
+            "deadline_warned": false,
+        },
+        "$addToSet": {"handed_to": key.clone()},
+    })
+}
+
+/// Claims the most urgent job the key may work on.
+///
+/// NOTE: acquires claim straight from the database, there is no in-process
+///       queue in front of it. During worker outages the backlog grows in
+///       mongo only, so our memory use doesn't depend on its size.
 pub async fn assign_job(db: DbConn, api_user: m::ApiUser) -> Result<Option<m::Job>> {
-    let job_col = m::Job::coll(db);
-    Ok(job_col
-        .find_one_and_update(
-            doc! {
-                "owner": Bson::Null,
-                "analysis_type": doc!{ "$in": Bson::Array(api_user.perms.iter().map(Into::into).collect()) },
-            },
-            UpdateModifications::Document(doc! {"$set": {"owner": api_user.key.clone()}}),
-            FindOneAndUpdateOptions::builder()
-                .sort(doc! {"precedence": -1, "date_last_updated": 1})
-                .build(),
-        )
+    let job_col = m::Job::coll(db.clone());
+    let now = Utc::now();
+    let mut filter = doc! {
+        // NOTE: jobs whose owner let them expire are up for grabs again.
+        "$or": [
+            { "owner": Bson::Null },
+            { "expires_at": { "$lt": now } },
+        ],
+        "is_complete": false,
+        "analysis_type": doc!{ "$in": Bson::Array(api_user.perms.iter().map(Into::into).collect()) },
+    };
+    if let Some(origins) = origins_for_key(&api_user) {
+        filter.insert("origin", doc! { "$in": origins });
+    }
+    // NOTE: this is a soft cap, two concurrent acquires can both see room.
+    let saturated = saturated_reports(db, now).await?;
+    if !saturated.is_empty() {
+        filter.insert("report_id", doc! { "$nin": saturated });
+    }
+    let filter = live(filter);
+    let span = Span::start("db.assign_job", SpanKind::Client);
+    let assigned = job_col.find_one_and_update(
+        filter,
+        acquisition(&api_user.key, now),
+        FindOneAndUpdateOptions::builder()
+            .sort(doc! {"precedence": -1, "date_last_updated": 1})
+            .return_document(ReturnDocument::After)
+            .build(),
+    );
+    Ok(instrument(span, assigned)
         .await?
         .map(from_document)
         .transpose()?)
 }
 
-pub async fn unassign_job(db: DbConn, api_user: m::ApiUser, id: m::JobId) -> Result<()> {
-    m::Job::coll(db)
+/// Whether the key owned the job it gave back.
+pub async fn unassign_job(db: DbConn, api_user: m::ApiUser, id: m::JobId) -> Result<bool> {
+    let result = m::Job::coll(db)
         .update_one(
             doc! { "_id": id.0, "owner": api_user.key.clone() },
-            UpdateModifications::Document(doc! {"owner": Bson::Null}),
+            UpdateModifications::Document(doc! {"$set": {"owner": Bson::Null, "expires_at": Bson::Null}}),
             None,
         )
         .await?;
-    Ok(())
+    Ok(result.matched_count > 0)
+}
+
+#[derive(Debug, Clone)]
+pub struct CreateAbort {
+    pub job_id: m::JobId,
+    pub key_name: String,
+    pub reason: m::AbortReason,
+    pub client_version: Option<String>,
+}
+
+impl From<CreateAbort> for m::Abort {
+    fn from(abort: CreateAbort) -> m::Abort {
+        m::Abort {
+            _id: ObjectId::new(),
+            job_id: abort.job_id,
+            key_name: abort.key_name,
+            reason: abort.reason,
+            client_version: abort.client_version,
+            date_aborted: Utc::now().into(),
+        }
+    }
+}
+
+pub async fn insert_abort(db: DbConn, abort: CreateAbort) -> Result<m::Abort> {
+    let abort: m::Abort = abort.into();
+    m::Abort::coll(db).insert_one(to_document(&abort)?, None).await?;
+    Ok(abort)
+}
+
+/// Takes back every acquired job whose owner has stopped working on it, e.g.
+/// workers that died with us during a deploy. Returns how many were requeued.
+pub async fn requeue_stale_jobs(db: DbConn) -> Result<u64> {
+    let coll = m::Job::coll(db);
+    let filter = live(doc! {
+        "is_complete": false,
+        "owner": {"$ne": Bson::Null},
+        "$or": [
+            {"expires_at": {"$lt": Utc::now()}},
+            {"expires_at": Bson::Null}, // Acquired before jobs expired
+        ],
+    });
+    let result = coll
+        .update_many(
+            filter,
+            UpdateModifications::Document(doc! {"$set": {
+                "owner": Bson::Null,
+                "expires_at": Bson::Null,
+                "deadline_warned": false,
+            }}),
+            None,
+        )
+        .await?;
+    Ok(result.modified_count as u64)
 }
 
 pub async fn game_id_for_job_id(db: DbConn, id: m::JobId) -> Result<Option<GameId>> {
     Ok(m::Job::coll(db)
-        .find_one(doc! {"_id": id.0}, None)
+        .find_one(live(doc! {"_id": id.0}), None)
         .await?
         .map(from_document)
         .transpose()?
@@ -167,23 +591,274 @@ pub async fn set_complete(db: DbConn, id: m::JobId) -> Result<()> {
     m::Job::coll(db)
         .update_one(
             doc! {"_id": {"$eq": id.0}},
-            UpdateModifications::Document(doc! {"$set": { "is_complete": true }}),
+            UpdateModifications::Document(doc! {"$set": {
+                "is_complete": true,
+                "date_completed": Utc::now(),
+            }}),
             None,
         )
         .await?;
     Ok(())
 }
 
-pub async fn delete_job(db: DbConn, id: m::JobId) -> Result<()> {
+/// Records which fishnet version completed the job, see AbortStats.
+pub async fn set_client_version(db: DbConn, id: m::JobId, version: String) -> Result<()> {
     m::Job::coll(db)
-        .delete_one(doc! { "_id": id.0 }, None)
+        .update_one(
+            doc! {"_id": {"$eq": id.0}},
+            UpdateModifications::Document(doc! {"$set": {"client_version": version}}),
+            None,
+        )
+        .await?;
+    Ok(())
+}
+
+/// Completes the job without analysis, for games that no longer need it.
+pub async fn skip_job(db: DbConn, id: m::JobId, reason: String) -> Result<()> {
+    m::Job::coll(db)
+        .update_one(
+            doc! {"_id": id.0},
+            UpdateModifications::Document(doc! {"$set": {
+                "owner": Bson::Null,
+                "expires_at": Bson::Null,
+                "is_complete": true,
+                "date_completed": Utc::now(),
+                "skip_reason": reason,
+            }}),
+            None,
+        )
         .await?;
     Ok(())
 }
 
+/// Soft deletes the job, it's only removed for good once it's purged.
+pub async fn delete_job(db: DbConn, id: m::JobId) -> Result<Option<m::Job>> {
+    Ok(m::Job::coll(db)
+        .find_one_and_update(
+            live(doc! { "_id": id.0 }),
+            UpdateModifications::Document(doc! {"$set": {
+                "owner": Bson::Null,
+                "expires_at": Bson::Null,
+                "deleted_at": Utc::now(),
+            }}),
+            FindOneAndUpdateOptions::builder()
+                .return_document(ReturnDocument::After)
+                .build(),
+        )
+        .await?
+        .map(from_document)
+        .transpose()?)
+}
+
+pub async fn undelete_job(db: DbConn, id: m::JobId) -> Result<Option<m::Job>> {
+    Ok(m::Job::coll(db)
+        .find_one_and_update(
+            doc! { "_id": id.0, "deleted_at": { "$ne": Bson::Null } },
+            UpdateModifications::Document(doc! {"$set": { "deleted_at": Bson::Null }}),
+            FindOneAndUpdateOptions::builder()
+                .return_document(ReturnDocument::After)
+                .build(),
+        )
+        .await?
+        .map(from_document)
+        .transpose()?)
+}
+
 pub async fn get_user_job(db: DbConn, id: m::JobId, user: m::ApiUser) -> Result<Option<m::Job>> {
     Ok(m::Job::coll(db)
-        .find_one(doc! {"_id": id.0, "owner": user.key}, None)
+        .find_one(live(doc! {"_id": id.0, "owner": user.key}), None)
+        .await?
+        .map(from_document)
+        .transpose()?)
+}
+
+/// The most urgent incomplete jobs, assigned or not, for operators.
+pub async fn list_jobs(db: DbConn, limit: i64) -> Result<Vec<m::Job>> {
+    let (coll, filter) = (m::Job::coll(db), live(doc! {"is_complete": false}));
+    let options = FindOptions::builder()
+        .sort(doc! {"precedence": -1, "date_last_updated": 1})
+        .limit(limit)
+        .build();
+    coll.find(filter, options)
+        .await?
+        .map(|doc| Ok::<_, Error>(from_document::<m::Job>(doc?)?))
+        .try_collect()
+        .await
+}
+
+/// Takes an incomplete job away from its owner, so anyone may acquire it.
+pub async fn requeue_job(db: DbConn, id: m::JobId) -> Result<Option<m::Job>> {
+    Ok(m::Job::coll(db)
+        .find_one_and_update(
+            live(doc! {"_id": id.0, "is_complete": false}),
+            UpdateModifications::Document(doc! {"$set": {
+                "owner": Bson::Null,
+                "expires_at": Bson::Null,
+                "deadline_warned": false,
+            }}),
+            FindOneAndUpdateOptions::builder()
+                .return_document(ReturnDocument::After)
+                .build(),
+        )
+        .await?
+        .map(from_document)
+        .transpose()?)
+}
+
+/// Marks the job as known missing its game, completing it without analysis
+/// so nobody looks for the game again until it's refreshed.
+pub async fn mark_game_missing(db: DbConn, id: m::JobId) -> Result<()> {
+    let now = Utc::now();
+    m::Job::coll(db)
+        .update_one(
+            doc! {"_id": id.0},
+            UpdateModifications::Document(doc! {"$set": {
+                "owner": Bson::Null,
+                "expires_at": Bson::Null,
+                "is_complete": true,
+                "date_completed": now,
+                "skip_reason": "missing game",
+                "game_missing_at": now,
+            }}),
+            None,
+        )
+        .await?;
+    Ok(())
+}
+
+/// Requeues the jobs that were marked missing the game, now that it's back.
+///
+/// NOTE: jobs of reports already sent to irwin stay closed, reopening them
+///       would only have the report submitted again.
+pub async fn reopen_game_missing_jobs(db: DbConn, game_id: GameId) -> Result<i64> {
+    let coll = m::Job::coll(db.clone());
+    let mut filter = live(doc! {"game_id": game_id, "game_missing_at": {"$ne": Bson::Null}});
+    let report_ids = coll.distinct("report_id", filter.clone(), None).await?;
+    let sent = Report::coll(db)
+        .distinct("_id", doc! {"_id": {"$in": report_ids}, "sent_to_irwin": true}, None)
+        .await?;
+    filter.insert("report_id", doc! {"$nin": sent});
+    let result = coll
+        .update_many(
+            filter,
+            UpdateModifications::Document(doc! {"$set": {
+                "is_complete": false,
+                "date_completed": Bson::Null,
+                "skip_reason": Bson::Null,
+                "game_missing_at": Bson::Null,
+            }}),
+            None,
+        )
+        .await?;
+    Ok(result.modified_count)
+}
+
+/// The job, if the user ever acquired it and it has been deleted since, for
+/// late submissions.
+pub async fn get_user_deleted_job(
+    db: DbConn,
+    id: m::JobId,
+    user: m::ApiUser,
+) -> Result<Option<m::Job>> {
+    Ok(m::Job::coll(db)
+        .find_one(
+            doc! {
+                "_id": id.0,
+                "$or": [{"owner": user.key.clone()}, {"handed_to": user.key}],
+                "deleted_at": { "$ne": Bson::Null },
+            },
+            None,
+        )
+        .await?
+        .map(from_document)
+        .transpose()?)
+}
+
+#[derive(Debug, Clone)]
+pub struct CreateLateSubmission {
+    pub job: m::Job,
+    pub source_id: UserId,
+    pub analysis: Vec<Option<PlyAnalysis>>,
+    pub engine: EngineType,
+    pub reason: String,
+}
+
+impl From<CreateLateSubmission> for m::LateSubmission {
+    fn from(late: CreateLateSubmission) -> m::LateSubmission {
+        m::LateSubmission {
+            _id: ObjectId::new(),
+            job_id: late.job._id,
+            game_id: late.job.game_id,
+            source_id: late.source_id,
+            analysis: late.analysis,
+            engine: late.engine,
+            reason: late.reason,
+            date_submitted: Utc::now().into(),
+            attached_to: None,
+        }
+    }
+}
+
+pub async fn insert_late_submission(
+    db: DbConn,
+    late: CreateLateSubmission,
+) -> Result<m::LateSubmission> {
+    let late: m::LateSubmission = late.into();
+    m::LateSubmission::coll(db)
+        .insert_one(to_document(&late)?, None)
+        .await?;
+    Ok(late)
+}
+
+pub async fn attach_late_submission(db: DbConn, id: ObjectId, job_id: m::JobId) -> Result<()> {
+    m::LateSubmission::coll(db)
+        .update_one(
+            doc! {"_id": id},
+            UpdateModifications::Document(doc! {"$set": {"attached_to": job_id.0}}),
+            None,
+        )
+        .await?;
+    Ok(())
+}
+
+/// Claims a job that wants the same analysis of the same game as one that
+/// expired or was deleted, if one is still waiting and the key may work on
+/// it, so a late submission can complete it.
+///
+/// NOTE: the same analysis means the same preset and the same follow-up-ness,
+///       and if the job was handed out before, the same snapshot of it.
+pub async fn claim_job_wanting_game(
+    db: DbConn,
+    api_user: m::ApiUser,
+    job: &m::Job,
+) -> Result<Option<m::Job>> {
+    let now = Utc::now();
+    let settings = to_bson(&preset::settings_for_job(job))?;
+    let mut filter = doc! {
+        "$and": [
+            { "$or": [{ "owner": Bson::Null }, { "expires_at": { "$lt": now } }] },
+            { "$or": [{ "preset": Bson::Null }, { "preset": settings }] },
+        ],
+        "game_id": job.game_id.clone(),
+        "analysis_type": job.analysis_type.clone(),
+        "follow_up": job.follow_up,
+        "preset_id": to_bson(&job.preset_id)?,
+        "is_complete": false,
+    };
+    if let Some(origins) = origins_for_key(&api_user) {
+        filter.insert("origin", doc! { "$in": origins });
+    }
+    let filter = live(filter);
+    let coll = m::Job::coll(db);
+    Ok(coll
+        .find_one_and_update(
+            filter,
+            acquisition(&api_user.key, now),
+            FindOneAndUpdateOptions::builder()
+                .sort(doc! {"precedence": -1})
+                .return_document(ReturnDocument::After)
+                .build(),
+        )
         .await?
         .map(from_document)
         .transpose()?)
@@ -191,7 +866,7 @@ pub async fn get_user_job(db: DbConn, id: m::JobId, user: m::ApiUser) -> Result<
 
 pub async fn get_job(db: DbConn, id: m::JobId) -> Result<Option<m::Job>> {
     Ok(m::Job::coll(db)
-        .find_one(doc! {"_id": id.0}, None)
+        .find_one(live(doc! {"_id": id.0}), None)
         .await?
         .map(from_document)
         .transpose()?)
@@ -202,9 +877,14 @@ pub struct QStatus {
     acquired: u64,
     queued: u64,
     oldest: u64,
+    eta: Option<i64>, // Seconds until the queue drains, if we've a recent service time
 }
 
-pub async fn q_status(db: DbConn, analysis_type: m::AnalysisType) -> Result<QStatus> {
+pub async fn q_status(
+    db: DbConn,
+    analysis_type: m::AnalysisType,
+    service_times: &ServiceTimes,
+) -> Result<QStatus> {
     let acquired = m::Job::acquired_jobs(db.clone(), analysis_type.clone())
         .await?
         .try_into()?;
@@ -216,10 +896,16 @@ pub async fn q_status(db: DbConn, analysis_type: m::AnalysisType) -> Result<QSta
         .map(|job| job.seconds_since_created())
         .unwrap_or(0_i64)
         .try_into()?;
+    let eta = service_times
+        .eta(analysis_type, queued as i64 - 1)
+        .await
+        .filter(|_| queued > 0)
+        .map(|eta| eta.num_seconds());
     Ok(QStatus {
         acquired,
         queued,
         oldest,
+        eta,
     })
 }
 
@@ -235,3 +921,129 @@ pub fn key_status(api_user: Option<m::ApiUser>) -> Option<KeyStatus> {
     // TODO: Add in appropriate tracking for invalidated keys.
     api_user.map(|_| KeyStatus::Active)
 }
+
+#[derive(Serialize, Debug, Clone)]
+pub struct KeyThroughput {
+    pub completed_last_hour: i64,
+    pub committed_jobs_per_hour: Option<i64>,
+    pub below_commitment: bool,
+}
+
+/// The rolling hourly completion rate of a key against its commitment.
+pub async fn key_throughput(db: DbConn, api_user: &m::ApiUser) -> Result<KeyThroughput> {
+    let since = Utc::now() - Duration::hours(1);
+    let completed_last_hour =
+        m::Job::completed_jobs_for_key_since(db, api_user.key.clone(), since).await?;
+    Ok(KeyThroughput {
+        completed_last_hour,
+        committed_jobs_per_hour: api_user.committed_jobs_per_hour,
+        below_commitment: api_user
+            .committed_jobs_per_hour
+            .is_some_and(|committed| completed_last_hour < committed),
+    })
+}
+
+#[derive(Serialize)]
+pub struct OrgKeyStatus {
+    name: String,
+    owned: i64,
+    throughput: KeyThroughput,
+}
+
+#[derive(Serialize)]
+pub struct OrgStatus {
+    org: String,
+    owned: i64,
+    completed_last_hour: i64,
+    keys: Vec<OrgKeyStatus>,
+}
+
+/// Aggregated work for all keys in an org, None if the org has no keys.
+pub async fn org_status(db: DbConn, org: String) -> Result<Option<OrgStatus>> {
+    let db = db.analytics();
+    let api_users = m::ApiUser::find_by_org(db.clone(), &org).await?;
+    if api_users.is_empty() {
+        return Ok(None);
+    }
+    let mut keys = Vec::new();
+    for api_user in api_users {
+        let owned = m::Job::owned_jobs_for_key(db.clone(), api_user.key.clone()).await?;
+        let throughput = key_throughput(db.clone(), &api_user).await?;
+        keys.push(OrgKeyStatus {
+            name: api_user.name,
+            owned,
+            throughput,
+        });
+    }
+    Ok(Some(OrgStatus {
+        org,
+        owned: keys.iter().map(|k| k.owned).sum(),
+        completed_last_hour: keys.iter().map(|k| k.throughput.completed_last_hour).sum(),
+        keys,
+    }))
+}
+
+/// Proof that we accepted a submission, which providers can hold on to in
+/// case of disputes.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Receipt {
+    pub job_id: String,
+    pub content_hash: String,
+    pub timestamp: i64,
+    pub signature: String,
+}
+
+impl Receipt {
+    fn message(job_id: &str, content_hash: &str, timestamp: i64) -> String {
+        format!("{}:{}:{}", job_id, content_hash, timestamp)
+    }
+
+    pub fn new(secret: &Secret, job_id: &m::JobId, content: &[u8]) -> Receipt {
+        let job_id = job_id.to_string();
+        let content_hash = sha256_hex(content);
+        let timestamp = Utc::now().timestamp();
+        let signature = sign(
+            secret,
+            Receipt::message(&job_id, &content_hash, timestamp).as_bytes(),
+        );
+        Receipt {
+            job_id,
+            content_hash,
+            timestamp,
+            signature,
+        }
+    }
+
+    pub fn is_valid(&self, secret: &Secret) -> bool {
+        let message = Receipt::message(&self.job_id, &self.content_hash, self.timestamp);
+        verify(secret, message.as_bytes(), &self.signature)
+    }
+}
+
+impl fmt::Display for Receipt {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}:{}",
+            Receipt::message(&self.job_id, &self.content_hash, self.timestamp),
+            self.signature
+        )
+    }
+}
+
+impl FromStr for Receipt {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let parts: Vec<&str> = s.split(':').collect();
+        match parts.as_slice() {
+            [job_id, content_hash, timestamp, signature] => Ok(Receipt {
+                job_id: job_id.to_string(),
+                content_hash: content_hash.to_string(),
+                timestamp: timestamp.parse().map_err(|_| ProtocolError::Deserialization)?,
+                signature: signature.to_string(),
+            }),
+            _ => Err(ProtocolError::Deserialization.into()),
+        }
+    }
+}
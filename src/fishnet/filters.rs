@@ -15,12 +15,16 @@
 // You should have received a copy of the GNU Affero General Public License
 // along with lila-deepq.  If not, see <https://www.gnu.org/licenses/>.
 
+use std::collections::HashMap;
 use std::convert::Infallible;
 use std::result::Result as StdResult;
 use std::str::FromStr;
+use std::sync::Arc;
 
+use chrono::{prelude::*, Duration};
 use serde::de::DeserializeOwned;
-use warp::{Filter, Rejection};
+use tokio::sync::Mutex;
+use warp::{reject, Filter, Rejection};
 
 use super::{api, model as m};
 use crate::db::DbConn;
@@ -98,7 +102,7 @@ pub async fn authorize<T>(db: DbConn, t: T) -> StdResult<Authorized<T>, Rejectio
 where
     T: Into<m::Key> + Clone,
 {
-    Ok(Authorized::<T>::new(db.clone(), t).await?)
+    Authorized::<T>::new(db, t).await
 }
 
 pub async fn api_user_from_key<T>(
@@ -137,14 +141,74 @@ pub fn authentication_from_header(
         .unify()
 }
 
+/// How far a body-authenticated request's timestamp may drift from ours.
+pub const BODY_AUTH_WINDOW_SECONDS: i64 = 60;
+
+/// Bodies that carry their own api key must also carry a nonce and a
+/// timestamp, so that a logged or proxied body can't be replayed.
+pub trait Envelope {
+    fn nonce(&self) -> Option<String>;
+    fn timestamp(&self) -> Option<DateTime<Utc>>;
+}
+
+/// Nonces seen within the acceptance window.
+#[derive(Clone, Default)]
+pub struct ReplayCache {
+    seen: Arc<Mutex<HashMap<String, DateTime<Utc>>>>,
+}
+
+impl ReplayCache {
+    pub fn new() -> ReplayCache {
+        ReplayCache::default()
+    }
+
+    /// Records the nonce, returning false if the request is stale or a replay.
+    pub async fn accept(&self, nonce: String, timestamp: DateTime<Utc>) -> bool {
+        let window = Duration::seconds(BODY_AUTH_WINDOW_SECONDS);
+        let now = Utc::now();
+        if timestamp < now - window || timestamp > now + window {
+            return false;
+        }
+        let mut seen = self.seen.lock().await;
+        // NOTE: anything older than the window would be rejected as stale anyway.
+        seen.retain(|_, ts| *ts >= now - window);
+        if seen.contains_key(&nonce) {
+            return false;
+        }
+        seen.insert(nonce, timestamp);
+        true
+    }
+}
+
+async fn reject_replays<T>(cache: ReplayCache, t: T) -> StdResult<T, Rejection>
+where
+    T: Envelope,
+{
+    let accepted = match (t.nonce(), t.timestamp()) {
+        (Some(nonce), Some(timestamp)) => cache.accept(nonce, timestamp).await,
+        _ => false,
+    };
+    if accepted {
+        Ok(t)
+    } else {
+        Err(reject::custom(HttpError::Replayed))
+    }
+}
+
 pub fn authorized_json_body<T>(
     db: DbConn,
+    cache: ReplayCache,
 ) -> impl Filter<Extract = (Authorized<T>,), Error = Rejection> + Clone
 where
-    T: Into<m::Key> + Clone + Send + Sync + DeserializeOwned,
+    T: Into<m::Key> + Envelope + Clone + Send + Sync + DeserializeOwned,
 {
     warp::any()
         .and(with(db.clone()))
-        .and(warp::body::json::<T>())
+        .and(
+            warp::any()
+                .map(move || cache.clone())
+                .and(warp::body::json::<T>())
+                .and_then(reject_replays::<T>),
+        )
         .and_then(authorize::<T>)
 }
@@ -15,32 +15,48 @@
 // You should have received a copy of the GNU Affero General Public License
 // along with lila-deepq.  If not, see <https://www.gnu.org/licenses/>.
 
-use std::num::NonZeroU8;
 use std::result::Result as StdResult;
-use std::convert::{TryFrom, TryInto, Into};
+use std::convert::Into;
 
-use log::{debug, info, error};
+use chrono::prelude::*;
+use futures::future;
+use log::{debug, info, error, warn};
+use mongodb::bson::doc;
 use serde::{Deserialize, Serialize};
 use serde_with::{
     serde_as, skip_serializing_none, DisplayFromStr, SpaceSeparator, StringWithSeparator,
 };
 use shakmaty::{fen::Fen, uci::Uci};
-use tokio::sync::broadcast;
+use tokio::sync::broadcast::{self, error::RecvError};
+use tokio::time::{timeout_at, Duration, Instant};
 use warp::{
     filters::{method, BoxedFilter},
     http, path, reject,
-    reply::{self, Reply},
+    reply::{self, Json, Reply},
     Filter, Rejection,
 };
 
 use super::{api, filters as f, model as m, FishnetMsg};
+use crate::crypto::Secret;
 use crate::db::DbConn;
 use crate::deepq::api::{
-    find_game, starting_position, upsert_one_game_analysis, UpdateGameAnalysis
+    insert_audit_entry, starting_position, upsert_one_game_analysis, UpdateGameAnalysis
 };
-use crate::deepq::model::{PlyAnalysis, UserId, Nodes as ModelNodes};
-use crate::http::{json_object_or_no_content, recover, required_or_unauthenticated, with};
-use crate::error::{Error, Result};
+use crate::deepq::model::{EngineType, Game, PlyAnalysis};
+use crate::deepq::payload::PayloadGuard;
+use crate::deepq::preset;
+use crate::deepq::stats::ServiceTimes;
+use crate::deepq::units::{Depth, MultiPv, NodeBudget};
+use crate::http::{
+    accepted_json_object_or_no_content, forbidden, id, job_expired, json_object_or_no_content,
+    recover, required_or_unauthenticated, unsupported_work_type, with,
+};
+use crate::error::{Context, Error, ProtocolError, Result};
+use crate::flags::Flags;
+use crate::fishnet::webhooks::{validate_url, Event, Webhooks};
+use crate::lichess::status::{GameStatusCache, SkipReason};
+use crate::metrics::Metrics;
+use crate::pause::Pauses;
 
 // TODO: make this complete for all of the variant types we should support.
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -62,6 +78,9 @@ pub struct RequestInfo {
     version: String,
     #[serde(rename = "apikey")]
     api_key: m::Key,
+    // NOTE: only required for body authentication, see f::Envelope.
+    nonce: Option<String>,
+    timestamp: Option<DateTime<Utc>>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -75,28 +94,20 @@ impl From<FishnetRequest> for m::Key {
     }
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
-pub struct AcquireRequest {
-    fishnet: RequestInfo,
-}
-
-#[derive(Serialize, Deserialize, Debug)]
-pub struct Nodes {
-    nnue: u64,
-    classical: u64,
-}
-
-impl TryFrom<Nodes> for ModelNodes {
-    type Error = Error;
+impl f::Envelope for FishnetRequest {
+    fn nonce(&self) -> Option<String> {
+        self.fishnet.nonce.clone()
+    }
 
-    fn try_from(nodes: Nodes) -> Result<ModelNodes> {
-        Ok(ModelNodes{
-            nnue: nodes.nnue.try_into()?,
-            classical: nodes.classical.try_into()?
-        })
+    fn timestamp(&self) -> Option<DateTime<Utc>> {
+        self.fishnet.timestamp
     }
 }
 
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AcquireRequest {
+    fishnet: RequestInfo,
+}
 
 #[skip_serializing_none]
 #[derive(Serialize, Deserialize, Debug)]
@@ -104,9 +115,9 @@ pub struct WorkInfo {
     #[serde(rename = "type")]
     _type: WorkType,
     id: String,
-    nodes: Nodes,
-    depth: Option<u8>,
-    multipv: Option<NonZeroU8>,
+    nodes: NodeBudget,
+    depth: Option<Depth>,
+    multipv: Option<MultiPv>,
 }
 
 #[serde_as]
@@ -122,6 +133,9 @@ pub struct Job {
 
     #[serde(rename = "skipPositions")]
     skip_positions: Vec<u8>,
+
+    // NOTE: submissions after this point are rejected, the job will be requeued.
+    expires_at: Option<DateTime<Utc>>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -139,7 +153,9 @@ pub struct StockfishType {
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct AnalysisReport {
     fishnet: RequestInfo,
-    stockfish: StockfishType,
+    stockfish: Option<StockfishType>,
+    #[serde(default)]
+    engine: EngineType, // Workers running something other than stockfish say so
     analysis: Vec<Option<PlyAnalysis>>,
 }
 
@@ -154,162 +170,469 @@ impl AnalysisReport {
     }
 }
 
-// TODO: get this from config or env? or lila? (probably lila, tbh)
-fn nodes_for_job(job: &m::Job) -> Nodes {
-    match job.analysis_type {
-        // TODO: what is the default right now for lila's fishnet queue?
-        m::AnalysisType::UserAnalysis => Nodes {
-            nnue: 2_250_000_u64,
-            classical: 4_050_000_u64,
-        },
-        m::AnalysisType::SystemAnalysis => Nodes {
-            nnue: 2_250_000_u64,
-            classical: 4_050_000_u64,
-        },
-        m::AnalysisType::Deep => Nodes {
-            nnue: 2_500_000_u64,
-            classical: 4_500_000_u64,
-        },
+fn send(
+    tx: broadcast::Sender<FishnetMsg>,
+    msg: FishnetMsg
+) {
+    if let Err(err) = tx.send(msg.clone()) {
+        error!("Unable to send msg: {:?} due to: {:?}", msg, err);
+    } else {
+        debug!("Msg sent: {:?}", msg);
     }
 }
 
-// TODO: get this from config or env? or lila? (probably lila, tbh)
-fn multipv_for_job(job: &m::Job) -> Option<NonZeroU8> {
-    match job.analysis_type {
-        m::AnalysisType::Deep => NonZeroU8::new(5u8),
-        _ => None,
+// TODO: make this configurable?
+const MAX_ACQUIRE_WAIT_SECONDS: u64 = 60;
+const ACQUIRE_POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AcquireQuery {
+    wait: Option<u64>, // seconds to hold the request open if there is no work
+}
+
+/// Resolves once a job may have become available.
+async fn job_available(rx: &mut broadcast::Receiver<FishnetMsg>) {
+    loop {
+        match rx.recv().await {
+            Ok(FishnetMsg::JobAborted(_)) | Ok(FishnetMsg::JobsQueued) => return,
+            Ok(_) | Err(RecvError::Lagged(_)) => {}
+            Err(RecvError::Closed) => future::pending::<()>().await,
+        }
     }
 }
 
-fn depth_for_job(_job: &m::Job) -> Option<u8> {
-    // TODO: Currently none of them request a specific depth, I thought they did?
-    None
+/// When enabled, late submissions complete any job still wanting their game.
+pub const ATTACH_LATE_SUBMISSIONS: &str = "attach_late_submissions";
+
+/// What handing out jobs needs, besides the database.
+#[derive(Clone)]
+struct Dispatch {
+    tx: broadcast::Sender<FishnetMsg>,
+    game_status: GameStatusCache,
+    webhooks: Webhooks,
+    flags: Flags,
+    pauses: Pauses,
+    payload: PayloadGuard,
+    metrics: Metrics,
 }
 
-// TODO: get this from config or env? or lila? (probably lila, tbh)
-fn skip_positions_for_job(job: &m::Job) -> Vec<u8> {
-    match job.analysis_type {
-        // TODO: what is the default right now for lila's fishnet queue?
-        m::AnalysisType::UserAnalysis => vec![0, 1, 2, 3, 4, 5, 6, 7, 8, 9],
-        m::AnalysisType::SystemAnalysis => vec![0, 1, 2, 3, 4, 5, 6, 7, 8, 9],
-        m::AnalysisType::Deep => Vec::new(),
+async fn acquire_job(
+    db: DbConn,
+    dispatch: Dispatch,
+    api_user: f::Authorized<m::ApiUser>,
+    query: AcquireQuery,
+) -> StdResult<Option<Job>, Rejection> {
+    let wait = Duration::from_secs(query.wait.unwrap_or(0).min(MAX_ACQUIRE_WAIT_SECONDS));
+    let deadline = Instant::now() + wait;
+    let mut rx = dispatch.tx.subscribe();
+    loop {
+        if let Some(job) =
+            try_acquire_job(db.clone(), dispatch.clone(), api_user.clone()).await?
+        {
+            return Ok(Some(job));
+        }
+        let now = Instant::now();
+        if now >= deadline {
+            return Ok(None);
+        }
+        // NOTE: jobs are mostly inserted by the irwin listener, which is
+        //       usually another process, so we can't rely on messages alone.
+        let next_poll = deadline.min(now + ACQUIRE_POLL_INTERVAL);
+        let _ = timeout_at(next_poll, job_available(&mut rx)).await;
     }
 }
 
-fn send(
-    tx: broadcast::Sender<FishnetMsg>,
-    msg: FishnetMsg
-) {
-    if let Err(err) = tx.send(msg.clone()) {
-        error!("Unable to send msg: {:?} due to: {:?}", msg, err);
-    } else {
-        debug!("Msg sent: {:?}", msg);
+/// The reason the game no longer needs analysis, if the game status check is enabled.
+async fn skip_reason(game_status: &GameStatusCache, game: &Game) -> Option<SkipReason> {
+    match game_status.skip_reason(game).await {
+        Ok(reason) => reason,
+        Err(err) => {
+            // NOTE: lichess being unreachable shouldn't stop analysis.
+            warn!("skip_reason > Game({}) > {:?}", game._id, err);
+            None
+        }
     }
 }
 
-async fn acquire_job(
+async fn try_acquire_job(
     db: DbConn,
-    tx: broadcast::Sender<FishnetMsg>,
+    dispatch: Dispatch,
     api_user: f::Authorized<m::ApiUser>,
 ) -> StdResult<Option<Job>, Rejection> {
-    let api_user = api_user.val();
+    let mut api_user = api_user.val();
     info!("acquire_job > {}", api_user.name);
+    // NOTE: only narrows what this request may claim, the key is unchanged.
+    let pauses = dispatch.pauses.all().await;
+    api_user.perms.retain(|perm| !pauses.iter().any(|pause| pause.covers(perm)));
+    if api_user.perms.is_empty() {
+        debug!("acquire_job > {} > paused", api_user.name);
+        return Ok(None);
+    }
     // TODO: Multiple active jobs are allowed. Instead we should unassign old ones that
     //       are not finished.
     // NOTE: not using .map because of unstable async lambdas
     debug!("start");
-    Ok(match api::assign_job(db.clone(), api_user.clone()).await? {
-        Some(job) => {
-            debug!("Some(job) = {:?}", job);
-            let game = match find_game(db.clone(), job.game_id.clone()).await {
-                Ok(game) => Ok(game),
-                Err(err) => {
-                    api::unassign_job(db.clone(), api_user, job._id.clone()).await?;
-                    Err(err)
-                }
-            }?;
-            match game {
-                None => {
-                    debug!("No game for game_id: {:?}", job.game_id);
-                    api::delete_job(db.clone(), job._id).await?;
-                    // TODO: I don't yet understand recursion in an async function in Rust.
-                    None // acquire_job(db.clone(), api_user.clone())?
-                }
-                Some(game) => {
-                    send(
-                        tx,
-                        FishnetMsg::JobAcquired(job._id.clone())
-                    );
-                    let job = Job {
-                        game_id: job.game_id.to_string(),
-                        position: starting_position(game.clone()),
-                        variant: Variant::Standard,
-                        skip_positions: skip_positions_for_job(&job),
-                        moves: game.pgn,
-                        work: WorkInfo {
-                            id: job._id.to_string(),
-                            _type: WorkType::Analysis,
-                            nodes: nodes_for_job(&job).try_into()?,
-                            multipv: multipv_for_job(&job),
-                            depth: depth_for_job(&job),
-                        },
-                    };
-                    Some(job)
-                }
-            }
+    let job = match api::assign_job(db.clone(), api_user.clone()).await? {
+        Some(job) => job,
+        None => return Ok(None),
+    };
+    debug!("Some(job) = {:?}", job);
+    let job_id = job._id.clone();
+    match hand_out(db.clone(), dispatch, job).await {
+        Ok(job) => Ok(job),
+        Err(err) => {
+            // NOTE: frees it for another try rather than until it expires.
+            api::unassign_job(db, api_user, job_id).await?;
+            Err(err.into())
         }
+    }
+}
+
+/// The assigned job as fishnet expects it, or None if it turned out there's
+/// nothing to analyse.
+async fn hand_out(db: DbConn, dispatch: Dispatch, job: m::Job) -> Result<Option<Job>> {
+    let game = dispatch.game_status.find_game(db.clone(), job.game_id.clone()).await?;
+    let skip = match &game {
+        Some(game) => skip_reason(&dispatch.game_status, game).await,
         None => None,
+    };
+    Ok(match (game, skip) {
+        (None, _) => {
+            debug!("No game for game_id: {:?}", job.game_id);
+            api::mark_game_missing(db.clone(), job._id.clone()).await?;
+            insert_audit_entry(
+                db.clone(),
+                "acquire_job",
+                "mark_game_missing",
+                doc! {"job_id": job._id.0.clone(), "game_id": job.game_id},
+            )
+            .await?;
+            send(dispatch.tx, FishnetMsg::JobCompleted(job._id));
+            // TODO: I don't yet understand recursion in an async function in Rust.
+            None // acquire_job(db.clone(), api_user.clone())?
+        }
+        (Some(_), Some(reason)) => {
+            info!("acquire_job > Game({}) > skipped: {}", job.game_id, reason);
+            api::skip_job(db.clone(), job._id.clone(), reason.to_string()).await?;
+            send(dispatch.tx, FishnetMsg::JobCompleted(job._id));
+            None
+        }
+        (Some(game), None) => {
+            let settings = preset::snapshot_for_job(db.clone(), &job).await?;
+            send(
+                dispatch.tx,
+                FishnetMsg::JobAcquired(job._id.clone())
+            );
+            let job = Job {
+                game_id: job.game_id.to_string(),
+                position: starting_position(game.clone()),
+                variant: Variant::Standard,
+                skip_positions: preset::skip_positions(&settings, &game),
+                moves: game.pgn,
+                expires_at: job.expires_at.map(|expires_at| expires_at.0),
+                work: WorkInfo {
+                    id: job._id.to_string(),
+                    _type: WorkType::Analysis,
+                    nodes: settings.nodes,
+                    multipv: settings.multipv,
+                    depth: settings.depth,
+                },
+            };
+            Some(job)
+        }
     })
 }
 
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AbortQuery {
+    #[serde(default)]
+    reason: m::AbortReason,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AbortRequest {
+    fishnet: RequestInfo,
+}
+
 async fn abort_job(
     db: DbConn,
     tx: broadcast::Sender<FishnetMsg>,
     api_user: f::Authorized<m::ApiUser>,
     job_id: m::JobId,
+    query: AbortQuery,
+    body: AbortRequest,
 ) -> StdResult<Option<()>, Rejection> {
     let api_user = api_user.val();
-    info!("abort_job > {}", api_user.name);
-    api::unassign_job(db.clone(), api_user, job_id.clone()).await?;
+    info!("abort_job > {} > {}", api_user.name, query.reason);
+    let key_name = api_user.name.clone();
+    // NOTE: only count aborts of jobs the key actually held.
+    if api::unassign_job(db.clone(), api_user, job_id.clone()).await? {
+        api::insert_abort(
+            db,
+            api::CreateAbort {
+                job_id: job_id.clone(),
+                key_name,
+                reason: query.reason,
+                client_version: Some(body.fishnet.version),
+            },
+        )
+        .await?;
+    }
     send(tx, FishnetMsg::JobAborted(job_id));
     Ok(None) // None because we're going to return no-content
 }
 
 /// TODO: Not sure I'm checking to ensure that the job is "done"
 /// TODO: Need to mark job as done if it is done and update report.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AnalysisQuery {
+    stop: Option<bool>, // The client is shutting down and doesn't want more work
+}
+
+const RECEIPT_HEADER: &str = "x-deepq-receipt";
+
+pub const UNSUPPORTED_WORK_METRIC: &str = "fishnet.unsupported_work";
+
+/// Counted, as how often move work is turned away tells us when it becomes
+/// worth supporting.
+fn reject_unsupported_work(metrics: &Metrics, api_user: &m::ApiUser, job_id: &str) -> Rejection {
+    metrics.count(UNSUPPORTED_WORK_METRIC, 1);
+    info!("reject_unsupported_work > {:?} > {}", api_user.name, job_id);
+    unsupported_work_type()
+}
+
+/// Turns away move work before it reaches the analysis deserializer, whose
+/// complaints about missing fields only confuse clients.
+fn analysis_report(
+    metrics: &Metrics,
+    api_user: &m::ApiUser,
+    job_id: &m::JobId,
+    body: serde_json::Value,
+) -> StdResult<AnalysisReport, Rejection> {
+    if body.get("move").is_some() && body.get("analysis").is_none() {
+        return Err(reject_unsupported_work(metrics, api_user, &job_id.to_string()));
+    }
+    serde_json::from_value(body)
+        .map_err(|err| reject::custom(Error::from(ProtocolError::from(err))))
+}
+
 async fn save_job_analysis(
     db: DbConn,
-    tx: broadcast::Sender<FishnetMsg>,
-    api_user: f::Authorized<m::ApiUser>,
+    dispatch: Dispatch,
+    secret: Secret,
+    authorized: f::Authorized<m::ApiUser>,
     job_id: m::JobId,
-    report: AnalysisReport,
-) -> StdResult<Option<Job>, Rejection> {
-    let api_user = api_user.val();
+    query: AnalysisQuery,
+    body: serde_json::Value,
+) -> StdResult<(Option<Job>, api::Receipt), Rejection> {
+    let api_user = authorized.val();
     info!("save_job_analysis > {:?} > {:?}", api_user.name, job_id);
-
-    let job = api::get_user_job(db.clone(), job_id.clone().into(), api_user.clone())
-        .await?
-        .ok_or(reject::not_found())?;
+    let report = analysis_report(&dispatch.metrics, &api_user, &job_id, body)?;
+
+    let job = match api::get_user_job(db.clone(), job_id.clone(), api_user.clone()).await? {
+        Some(job) => job,
+        None => {
+            let deleted =
+                api::get_user_deleted_job(db.clone(), job_id.clone(), api_user.clone()).await?;
+            let late = match deleted {
+                Some(job) => Some((job, "deleted")),
+                // NOTE: expired and since acquired by another key. Keys that never had it get a 404.
+                None => api::get_job(db.clone(), job_id)
+                    .await?
+                    .filter(|job| job.was_handed_to(&api_user.key))
+                    .map(|job| (job, "reacquired")),
+            };
+            let (job, reason) = late.ok_or_else(reject::not_found)?;
+            save_late_analysis(db, &dispatch, &api_user, job, report, reason).await?;
+            return Err(job_expired());
+        }
+    };
     debug!("save_job_analysis > get_user_job > success");
+    if job.is_expired() {
+        save_late_analysis(db, &dispatch, &api_user, job, report, "expired").await?;
+        return Err(job_expired());
+    }
 
+    let settings = preset::settings_for_job(&job);
     let analysis = UpdateGameAnalysis {
-        job_id: job_id.into(),
-        game_id: job.clone().game_id.into(),
-        analysis: report.analysis.clone(),
-        source_id: UserId(api_user._id.to_string()),
-        requested_pvs: multipv_for_job(&job).map(|v| i32::from(v.get())),
-        requested_depth: depth_for_job(&job).map(Into::into),
-        requested_nodes: nodes_for_job(&job).try_into()?,
+        job_id,
+        game_id: job.game_id.clone(),
+        analysis: report
+            .analysis
+            .iter()
+            .cloned()
+            .map(|ply| ply.map(PlyAnalysis::normalized))
+            .collect(),
+        engine: report.engine,
+        source_id: api_user._id.to_string().into(),
+        requested_pvs: settings.multipv,
+        requested_depth: settings.depth,
+        requested_nodes: settings.nodes,
     };
     debug!("save_job_analysis > created UpdateGameAnalysis");
-    upsert_one_game_analysis(db.clone(), analysis).await?;
+    let upserted = upsert_one_game_analysis(db.clone(), &dispatch.payload, analysis).await;
+    if let Err(err) = &upserted {
+        dispatch.webhooks.notify(
+            &api_user,
+            Event::SubmissionRejected {
+                job_id: job._id.to_string(),
+                reason: err.to_string(),
+            },
+        );
+    }
+    upserted.with_context(|| format!("Job({})", job._id))?;
     debug!("save_job_analysis > upsert_one_game_analysis > success");
-    if report.is_complete() {
-        debug!("save_job_analysis > JobCompleted");
-        api::set_complete(db, job._id.clone()).await?;
-        send(tx, FishnetMsg::JobCompleted(job._id.clone()));
+    let receipt = api::Receipt::new(
+        &secret,
+        &job._id,
+        &serde_json::to_vec(&report.analysis).map_err(Error::from)?,
+    );
+    if !report.is_complete() {
+        // NOTE: progress report, the client will keep working on it.
+        return Ok((None, receipt));
+    }
+    debug!("save_job_analysis > JobCompleted");
+    api::set_client_version(db.clone(), job._id.clone(), report.fishnet.version.clone()).await?;
+    api::set_complete(db.clone(), job._id.clone()).await?;
+    send(dispatch.tx.clone(), FishnetMsg::JobCompleted(job._id.clone()));
+    // NOTE: the analysis is saved, failing now would only have it sent again.
+    if let Err(err) = api::maybe_promote(db.clone(), api_user).await {
+        error!("save_job_analysis > maybe_promote > {:?}", err);
+    }
+
+    // NOTE: like lila, hand out the next job right away, saving a round trip.
+    if query.stop.unwrap_or(false) {
+        Ok((None, receipt))
+    } else {
+        Ok((try_acquire_job(db, dispatch, authorized).await?, receipt))
+    }
+}
+
+/// Completes a job that wants the same analysis with a late submission.
+async fn attach_late_analysis(
+    db: DbConn,
+    dispatch: &Dispatch,
+    api_user: &m::ApiUser,
+    job: &m::Job,
+    late: m::LateSubmission,
+) -> StdResult<(), Error> {
+    let wanting = match api::claim_job_wanting_game(db.clone(), api_user.clone(), job).await? {
+        Some(wanting) => wanting,
+        None => return Ok(()),
+    };
+    info!("save_late_analysis > {:?} > attached to {:?}", job._id, wanting._id);
+    // NOTE: what the analysis was searched with, not what the wanting job asked for.
+    let settings = preset::settings_for_job(job);
+    let attached = upsert_one_game_analysis(
+        db.clone(),
+        &dispatch.payload,
+        UpdateGameAnalysis {
+            job_id: wanting._id.clone(),
+            game_id: wanting.game_id.clone(),
+            analysis: late.analysis,
+            engine: late.engine,
+            source_id: late.source_id,
+            requested_pvs: settings.multipv,
+            requested_depth: settings.depth,
+            requested_nodes: settings.nodes,
+        },
+    )
+    .await;
+    if let Err(err) = attached {
+        api::unassign_job(db, api_user.clone(), wanting._id.clone()).await?;
+        return Err(err);
+    }
+    api::set_complete(db.clone(), wanting._id.clone()).await?;
+    api::attach_late_submission(db, late._id, wanting._id.clone()).await?;
+    send(dispatch.tx.clone(), FishnetMsg::JobCompleted(wanting._id));
+    Ok(())
+}
+
+/// Quarantines the analysis of a job that expired or was deleted, attaching
+/// it to a job that still wants the game's analysis, if allowed.
+///
+/// NOTE: once quarantined the submission is safe, failing to attach it is
+///       only logged so the worker isn't told to send it again.
+async fn save_late_analysis(
+    db: DbConn,
+    dispatch: &Dispatch,
+    api_user: &m::ApiUser,
+    job: m::Job,
+    report: AnalysisReport,
+    reason: &str,
+) -> StdResult<(), Error> {
+    info!("save_late_analysis > {:?} > {}", job._id, reason);
+    dispatch.webhooks.notify(
+        api_user,
+        Event::SubmissionRejected {
+            job_id: job._id.to_string(),
+            reason: reason.to_string(),
+        },
+    );
+    let complete = report.is_complete();
+    let late = api::insert_late_submission(
+        db.clone(),
+        api::CreateLateSubmission {
+            job: job.clone(),
+            source_id: api_user._id.to_string().into(),
+            analysis: report
+                .analysis
+                .into_iter()
+                .map(|ply| ply.map(PlyAnalysis::normalized))
+                .collect(),
+            engine: report.engine,
+            reason: reason.to_string(),
+        },
+    )
+    .await?;
+    if !complete || !dispatch.flags.is_enabled(ATTACH_LATE_SUBMISSIONS).await {
+        return Ok(());
+    }
+    if let Err(err) = attach_late_analysis(db, dispatch, api_user, &job, late).await {
+        error!("save_late_analysis > {:?} > unable to attach: {:?}", job._id, err);
+    }
+    Ok(())
+}
+
+async fn with_receipt(
+    (job, receipt): (Option<Job>, api::Receipt),
+) -> StdResult<impl Reply, Rejection> {
+    Ok(reply::with_header(
+        accepted_json_object_or_no_content(job).await?,
+        RECEIPT_HEADER,
+        receipt.to_string(),
+    ))
+}
+
+async fn reject_move(
+    metrics: Metrics,
+    api_user: f::Authorized<m::ApiUser>,
+    job_id: String,
+) -> StdResult<Option<()>, Rejection> {
+    Err(reject_unsupported_work(&metrics, &api_user.val(), &job_id))
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SetWebhook {
+    url: Option<String>, // None removes the webhook
+}
+
+async fn set_webhook(
+    db: DbConn,
+    authorized: f::Authorized<m::ApiUser>,
+    set_webhook: SetWebhook,
+) -> StdResult<Json, Rejection> {
+    let api_user = authorized.api_user();
+    info!("set_webhook > {} > {:?}", api_user.name, set_webhook.url);
+    if let Some(url) = set_webhook.url.as_deref() {
+        validate_url(url).await?;
     }
-    Ok(None)
+    let api_user = api::set_webhook(db, api_user.key, set_webhook.url)
+        .await?
+        .ok_or_else(reject::not_found)?;
+    Ok(reply::json(&SetWebhook {
+        url: api_user.webhook_url,
+    }))
 }
 
 async fn check_key_validity(db: DbConn, key: String) -> StdResult<String, Rejection> {
@@ -326,24 +649,70 @@ struct FishnetAnalysisStatus {
     deep: api::QStatus,
 }
 
+#[derive(Serialize)]
+struct PauseStatus {
+    analysis_type: Option<m::AnalysisType>, // None when the whole queue is paused
+    reason: String,
+    since: DateTime<Utc>,
+}
+
 #[skip_serializing_none]
 #[derive(Serialize)]
 struct FishnetStatus {
     analysis: FishnetAnalysisStatus,
+    paused: Vec<PauseStatus>,
     key: Option<api::KeyStatus>,
+    throughput: Option<api::KeyThroughput>,
 }
 
 async fn fishnet_status(
     db: DbConn,
+    service_times: ServiceTimes,
+    pauses: Pauses,
     api_user: Option<m::ApiUser>,
 ) -> StdResult<FishnetStatus, Rejection> {
     info!("status");
-    let user = api::q_status(db.clone(), m::AnalysisType::UserAnalysis).await?;
-    let system = api::q_status(db.clone(), m::AnalysisType::SystemAnalysis).await?;
-    let deep = api::q_status(db.clone(), m::AnalysisType::Deep).await?;
+    let paused = pauses
+        .all()
+        .await
+        .into_iter()
+        .map(|pause| PauseStatus {
+            analysis_type: pause.analysis_type,
+            reason: pause.reason,
+            since: pause.date_paused.0,
+        })
+        .collect();
+    let user = api::q_status(db.clone(), m::AnalysisType::UserAnalysis, &service_times).await?;
+    let system = api::q_status(db.clone(), m::AnalysisType::SystemAnalysis, &service_times).await?;
+    let deep = api::q_status(db.clone(), m::AnalysisType::Deep, &service_times).await?;
     let key = api::key_status(api_user.clone());
+    let throughput = match api_user {
+        Some(api_user) => Some(api::key_throughput(db, &api_user).await?),
+        None => None,
+    };
     let analysis = FishnetAnalysisStatus { user, system, deep };
-    Ok(FishnetStatus { analysis, key })
+    Ok(FishnetStatus {
+        analysis,
+        paused,
+        key,
+        throughput,
+    })
+}
+
+async fn org_status(
+    db: DbConn,
+    authorized: f::Authorized<m::ApiUser>,
+    org: String,
+) -> StdResult<Json, Rejection> {
+    let api_user = authorized.api_user();
+    info!("org_status > {} > {}", api_user.name, org);
+    // NOTE: keys may only see the breakdown of their own org.
+    if !api_user.admin && api_user.org.as_deref() != Some(org.as_str()) {
+        return Err(forbidden());
+    }
+    Ok(reply::json(
+        &api::org_status(db, org).await?.ok_or_else(reject::not_found)?,
+    ))
 }
 
 fn _log_body() -> impl Filter<Extract = (), Error = Rejection> + Copy {
@@ -354,7 +723,40 @@ fn _log_body() -> impl Filter<Extract = (), Error = Rejection> + Copy {
         .untuple_one()
 }
 
-pub fn mount(db: DbConn, tx: broadcast::Sender<FishnetMsg>) -> BoxedFilter<(impl Reply,)> {
+/// What the handlers share with the rest of the webserver.
+pub struct Services {
+    pub game_status: GameStatusCache,
+    pub service_times: ServiceTimes,
+    pub webhooks: Webhooks,
+    pub flags: Flags,
+    pub payload: PayloadGuard,
+    pub metrics: Metrics,
+}
+
+pub fn mount(
+    db: DbConn,
+    tx: broadcast::Sender<FishnetMsg>,
+    secret: Secret,
+    services: Services,
+) -> BoxedFilter<(impl Reply,)> {
+    let Services {
+        game_status,
+        service_times,
+        webhooks,
+        flags,
+        payload,
+        metrics,
+    } = services;
+    let pauses = flags.pauses();
+    let dispatch = Dispatch {
+        tx: tx.clone(),
+        game_status,
+        webhooks,
+        flags,
+        pauses: pauses.clone(),
+        payload,
+        metrics: metrics.clone(),
+    };
     let authenticated = f::api_user_from_header(db.clone());
     let authentication_required = authenticated.clone().and_then(required_or_unauthenticated);
 
@@ -365,48 +767,89 @@ pub fn mount(db: DbConn, tx: broadcast::Sender<FishnetMsg>) -> BoxedFilter<(impl
 
     // NOTE: this supports the old fishnet 1.x style of authorization
     //       which I am not going to worry about supporting out of the box.
+    //       If it is ever enabled, bodies must carry a nonce and timestamp.
     //let authorized_api_user = warp::any()
     //.and(header_authorization_required)
-    //.or(f::authorized_json_body(db.clone())
+    //.or(f::authorized_json_body(db.clone(), f::ReplayCache::new())
     //.map(|fr: f::Authorized<FishnetRequest>| fr.clone().map(|_| fr.api_user())))
     //.unify();
 
     let acquire = path("acquire")
+        .and(path::end())
         .and(method::post())
         .and(with(db.clone()))
-        .and(with(tx.clone()))
+        .and(with(dispatch.clone()))
         .and(header_authorization_required.clone())
+        .and(warp::query::<AcquireQuery>())
         .and_then(acquire_job)
-        .and_then(json_object_or_no_content::<Job>);
+        .and_then(accepted_json_object_or_no_content::<Job>);
 
     let abort = path("abort")
         .and(method::post())
         .and(with(db.clone()))
         .and(with(tx.clone()))
         .and(header_authorization_required.clone())
-        .and(path::param())
+        .and(id::<m::JobId>())
+        .and(path::end())
+        .and(warp::query::<AbortQuery>())
+        .and(warp::body::json())
         .and_then(abort_job)
         .and_then(json_object_or_no_content::<()>);
 
     let analysis = path("analysis")
         .and(method::post())
         .and(with(db.clone()))
-        .and(with(tx.clone()))
+        .and(with(dispatch))
+        .and(with(secret))
         .and(header_authorization_required.clone())
-        .and(path::param())
+        .and(id::<m::JobId>())
+        .and(path::end())
+        .and(warp::query::<AnalysisQuery>())
         .and(warp::body::json())
         .and_then(save_job_analysis)
-        .and_then(json_object_or_no_content::<Job>);
+        .and_then(with_receipt);
+
+    // NOTE: we never hand out move work, but tell clients that try clearly.
+    let move_ = path("move")
+        .and(method::post())
+        .and(with(metrics))
+        .and(header_authorization_required.clone())
+        .and(path::param())
+        .and(path::end())
+        .and_then(reject_move)
+        .and_then(json_object_or_no_content::<()>);
 
     let valid_key = path("key")
         .and(method::get())
         .and(with(db.clone()))
         .and(path::param())
+        .and(path::end())
         .and_then(check_key_validity);
 
+    let put_webhook = path("key")
+        .and(path("webhook"))
+        .and(path::end())
+        .and(method::put())
+        .and(with(db.clone()))
+        .and(header_authorization_required.clone())
+        .and(warp::body::json())
+        .and_then(set_webhook);
+
+    let org_status = path("status")
+        .and(path("org"))
+        .and(method::get())
+        .and(with(db.clone()))
+        .and(header_authorization_required.clone())
+        .and(path::param())
+        .and(path::end())
+        .and_then(org_status);
+
     let status = path("status")
+        .and(path::end())
         .and(method::get())
         .and(with(db.clone()))
+        .and(with(service_times))
+        .and(with(pauses))
         .and(f::authentication_from_header(db))
         .and_then(fishnet_status)
         .map(|status| {
@@ -419,7 +862,10 @@ pub fn mount(db: DbConn, tx: broadcast::Sender<FishnetMsg>) -> BoxedFilter<(impl
     acquire
         .or(abort)
         .or(analysis)
+        .or(move_)
+        .or(put_webhook)
         .or(valid_key)
+        .or(org_status)
         .or(status)
         .recover(recover)
         .boxed()
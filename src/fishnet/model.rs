@@ -21,14 +21,16 @@ use derive_more::{Display, From};
 use futures::stream::{Stream, StreamExt};
 use log::warn;
 use mongodb::{
-    bson::{doc, from_document, oid::ObjectId, Bson, DateTime},
-    options::FindOneOptions,
+    bson::{doc, from_document, oid::ObjectId, Bson, DateTime, Document},
+    options::{FindOneOptions, UpdateModifications},
     Collection,
 };
 use serde::{Deserialize, Serialize};
 
-use crate::db::DbConn;
-use crate::deepq::model::{GameId, Report, UserId, ReportId};
+use crate::db::{live, DbConn};
+use crate::deepq::model::{
+    EngineType, GameId, PlyAnalysis, PresetSettings, Report, ReportId, ReportOrigin, UserId,
+};
 use crate::error::{Error, Result};
 
 #[derive(Serialize, Deserialize, Debug, Clone, From, Display)]
@@ -44,7 +46,7 @@ impl From<Key> for Bson {
 //       I'd like it if Irwin and CR were unified, and user/system
 //       analysis should also be unified. but it  might be easier
 //       to deal with very specific analysis requests.
-#[derive(Serialize, Deserialize, Debug, Clone, strum_macros::ToString)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, strum_macros::Display)]
 #[serde(rename_all = "lowercase")]
 pub enum AnalysisType {
     UserAnalysis,   // User requested analysis, single-pv
@@ -58,6 +60,34 @@ impl From<AnalysisType> for Bson {
     }
 }
 
+/// How much we trust a key with high-stakes work.
+#[derive(
+    Serialize,
+    Deserialize,
+    Debug,
+    Clone,
+    Copy,
+    PartialEq,
+    PartialOrd,
+    Default,
+    strum_macros::Display,
+    strum_macros::EnumString,
+)]
+#[serde(rename_all = "lowercase")]
+#[strum(serialize_all = "lowercase")]
+pub enum TrustLevel {
+    #[default]
+    New,     // Only gets screening work until promoted
+    Trusted, // Promoted automatically after enough completed work, or by an admin
+    Core,    // Run by us or people we know, only set by an admin
+}
+
+impl From<TrustLevel> for Bson {
+    fn from(tl: TrustLevel) -> Bson {
+        Bson::String(tl.to_string())
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct ApiUser {
     pub _id: ObjectId,
@@ -65,12 +95,55 @@ pub struct ApiUser {
     pub user: Option<UserId>,
     pub name: String,
     pub perms: Vec<AnalysisType>,
+    #[serde(default)]
+    pub admin: bool, // Allowed to use the admin api
+    #[serde(default)]
+    pub trust: TrustLevel,
+    pub org: Option<String>, // Providers running many keys are grouped under an org
+    pub committed_jobs_per_hour: Option<i64>, // Minimum throughput the provider committed to
+    pub webhook_url: Option<String>, // Set by the provider, see fishnet::webhooks
+    pub allowed_origins: Option<Vec<ReportOrigin>>, // Narrows what the trust level allows
+}
+
+impl ApiUser {
+    pub async fn find_committed(db: DbConn) -> Result<Vec<ApiUser>> {
+        let (coll, filter) = (ApiUser::coll(db), doc! { "committed_jobs_per_hour": { "$gt": 0 } });
+        coll.find(filter, None)
+            .await?
+            .map(|doc| Ok(from_document::<ApiUser>(doc?)?))
+            .collect::<Vec<Result<ApiUser>>>()
+            .await
+            .into_iter()
+            .collect()
+    }
 }
 
 impl ApiUser {
     pub fn coll(db: DbConn) -> Collection {
         db.database.collection("deepq_apiuser")
     }
+
+    pub async fn find_with_webhooks(db: DbConn) -> Result<Vec<ApiUser>> {
+        let (coll, filter) = (ApiUser::coll(db), doc! { "webhook_url": { "$type": "string" } });
+        coll.find(filter, None)
+            .await?
+            .map(|doc| Ok(from_document::<ApiUser>(doc?)?))
+            .collect::<Vec<Result<ApiUser>>>()
+            .await
+            .into_iter()
+            .collect()
+    }
+
+    pub async fn find_by_org(db: DbConn, org: &str) -> Result<Vec<ApiUser>> {
+        let (coll, filter) = (ApiUser::coll(db), doc! { "org": { "$eq": org } });
+        coll.find(filter, None)
+            .await?
+            .map(|doc| Ok(from_document::<ApiUser>(doc?)?))
+            .collect::<Vec<Result<ApiUser>>>()
+            .await
+            .into_iter()
+            .collect()
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone, From, Display)]
@@ -97,9 +170,30 @@ pub struct Job {
     pub analysis_type: AnalysisType,
     pub precedence: i32,
     pub owner: Option<String>, // TODO: this should be the key from the database
+    #[serde(default)]
+    pub handed_to: Vec<String>, // Every key that acquired it, lowercased like owner.
     pub date_last_updated: DateTime,
     pub report_id: Option<ReportId>,
+    pub origin: Option<ReportOrigin>, // Denormalized from the report, if any.
+    #[serde(default)] // Older jobs predate this field.
     pub is_complete: bool, // Denormalized cache of completion state.
+    pub date_completed: Option<DateTime>,
+    #[serde(default)]
+    pub follow_up: bool, // Deeper re-analysis of a game irwin was unsure about.
+    pub expires_at: Option<DateTime>, // Set when acquired, after which it may be requeued.
+    pub date_acquired: Option<DateTime>, // When its last owner acquired it, for service times.
+    pub skip_reason: Option<String>, // Completed without analysis, the game no longer needed it.
+    pub deleted_at: Option<DateTime>, // Soft deleted, purged after the retention window.
+    #[serde(default, alias = "skip_positions")]
+    pub quiet_positions: Vec<i32>, // Downscoped, lichess's own analysis shows nothing happened there.
+    #[serde(default)]
+    pub analysis_reaped: bool, // Without a report, the reaper removes the raw analyses itself.
+    #[serde(default)]
+    pub deadline_warned: bool, // The owner's webhook was told it's about to expire.
+    pub game_missing_at: Option<DateTime>, // Skipped, its game couldn't be found. Reopened on refresh.
+    pub preset_id: Option<String>, // See deepq::preset, older jobs predate presets.
+    pub preset: Option<PresetSettings>, // Snapshot of the preset when last handed out.
+    pub client_version: Option<String>, // The fishnet version of the worker that completed it.
 }
 
 impl Job {
@@ -111,12 +205,29 @@ impl Job {
         Utc::now().timestamp() - self.date_last_updated.timestamp()
     }
 
+    /// Whether the key acquired the job at some point, it may still own it.
+    pub fn was_handed_to(&self, key: &Key) -> bool {
+        let key = key.0.to_lowercase();
+        self.owner.as_ref() == Some(&key) || self.handed_to.contains(&key)
+    }
+
+    pub fn is_expired(&self) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at.0 < Utc::now())
+    }
+
+    /// Counts the live jobs matching the filter.
+    async fn count(db: DbConn, filter: Document) -> Result<i64> {
+        let (coll, filter) = (Job::coll(db), live(filter));
+        Ok(coll.count_documents(filter, None).await?)
+    }
+
     pub async fn acquired_jobs(db: DbConn, analysis_type: AnalysisType) -> Result<i64> {
         let filter = doc! {
             "owner": { "$ne": Bson::Null },
             "analysis_type": { "$eq": analysis_type },
+            "is_complete": false,
         };
-        Ok(Job::coll(db.clone()).count_documents(filter, None).await?)
+        Job::count(db, filter).await
     }
 
     pub async fn find_by_report(
@@ -127,8 +238,8 @@ impl Job {
         let filter = doc! {
             "report_id": { "$eq": report._id.0.clone() }
         };
-        Ok(Job::coll(db.clone())
-            .find(filter, None)
+        let (coll, filter) = (Job::coll(db), live(filter));
+        Ok(coll.find(filter, None)
             .await?
             .filter_map(move |doc_result| async move {
                 match doc_result.is_ok() {
@@ -149,26 +260,222 @@ impl Job {
         )
     }
 
+    pub async fn completed_jobs_for_key(db: DbConn, key: Key) -> Result<i64> {
+        let filter = doc! {
+            "owner": { "$eq": key },
+            "is_complete": { "$eq": true },
+        };
+        Job::count(db, filter).await
+    }
+
+    pub async fn owned_jobs_for_key(db: DbConn, key: Key) -> Result<i64> {
+        let filter = doc! {
+            "owner": { "$eq": key },
+            "is_complete": { "$eq": false },
+            "expires_at": { "$gte": Utc::now() },
+        };
+        Job::count(db, filter).await
+    }
+
+    pub async fn completed_jobs_for_key_since(
+        db: DbConn,
+        key: Key,
+        since: chrono::DateTime<Utc>,
+    ) -> Result<i64> {
+        let filter = doc! {
+            "owner": { "$eq": key },
+            "is_complete": { "$eq": true },
+            "date_completed": { "$gte": since },
+        };
+        Job::count(db, filter).await
+    }
+
+    /// How many jobs completed since, and the mean seconds from acquiring one
+    /// to completing it. None for the mean when none recorded their acquire.
+    pub async fn completions_since(
+        db: DbConn,
+        analysis_type: AnalysisType,
+        since: chrono::DateTime<Utc>,
+    ) -> Result<(i64, Option<f64>)> {
+        // NOTE: skipped jobs complete instantly, they'd skew service times.
+        let filter = live(doc! {
+            "analysis_type": { "$eq": analysis_type },
+            "is_complete": { "$eq": true },
+            "date_completed": { "$gte": since },
+            "skip_reason": Bson::Null,
+        });
+        let coll = Job::coll(db);
+        // NOTE: $avg ignores the nulls of jobs acquired before date_acquired existed.
+        let pipeline = vec![
+            doc! {"$match": filter},
+            doc! {"$group": {
+                "_id": Bson::Null,
+                "completed": {"$sum": 1},
+                "service_ms": {"$avg": {"$subtract": ["$date_completed", "$date_acquired"]}},
+            }},
+        ];
+        let mut cursor = coll.aggregate(pipeline, None).await?;
+        Ok(match cursor.next().await.transpose()? {
+            Some(doc) => (
+                match doc.get("completed") {
+                    Some(Bson::Int32(completed)) => i64::from(*completed),
+                    Some(Bson::Int64(completed)) => *completed,
+                    _ => 0,
+                },
+                doc.get_f64("service_ms").ok().map(|ms| ms / 1000f64),
+            ),
+            None => (0, None),
+        })
+    }
+
+    /// The number of queued jobs that will be handed out before this one.
+    pub async fn queue_position(db: DbConn, job: &Job) -> Result<i64> {
+        let filter = doc! {
+            "owner": { "$eq": Bson::Null },
+            "is_complete": { "$eq": false },
+            "analysis_type": { "$eq": job.analysis_type.clone() },
+            "$or": [
+                { "precedence": { "$gt": job.precedence } },
+                { "precedence": job.precedence, "date_last_updated": { "$lt": job.date_last_updated.0 } },
+            ],
+        };
+        Job::count(db, filter).await
+    }
+
+    pub async fn find_incomplete_for_report(db: DbConn, report_id: ReportId) -> Result<Vec<Job>> {
+        let (coll, filter) = (
+            Job::coll(db),
+            live(doc! { "report_id": report_id.0, "is_complete": { "$ne": true } }),
+        );
+        coll.find(filter, None)
+            .await?
+            .map(|doc| Ok(from_document::<Job>(doc?)?))
+            .collect::<Vec<Result<Job>>>()
+            .await
+            .into_iter()
+            .collect()
+    }
+
+    /// Marks a job that expires before `until` as warned, returning it, so
+    /// that each owner is only warned once per acquisition.
+    pub async fn claim_deadline_warning(
+        db: DbConn,
+        until: chrono::DateTime<Utc>,
+    ) -> Result<Option<Job>> {
+        let filter = doc! {
+            "owner": { "$ne": Bson::Null },
+            "is_complete": { "$eq": false },
+            "expires_at": { "$gte": Utc::now(), "$lt": until },
+            "deadline_warned": { "$ne": true },
+        };
+        let (coll, filter) = (Job::coll(db), live(filter));
+        let update = doc! {"$set": {"deadline_warned": true}};
+        Ok(coll
+            .find_one_and_update(filter, UpdateModifications::Document(update), None)
+            .await?
+            .map(from_document::<Job>)
+            .transpose()?)
+    }
+
+    pub async fn find_incomplete(db: DbConn) -> Result<impl Stream<Item = Result<Job>>> {
+        let (coll, filter) = (Job::coll(db), live(doc! { "is_complete": { "$ne": true } }));
+        Ok(coll.find(filter, None)
+            .await?
+            .map(|doc| Ok(from_document::<Job>(doc?)?)))
+    }
+
     pub async fn queued_jobs(db: DbConn, analysis_type: AnalysisType) -> Result<i64> {
         let filter = doc! {
             "owner": { "$eq": Bson::Null },
             "analysis_type": { "$eq": analysis_type },
+            "is_complete": false,
         };
-        Ok(Job::coll(db.clone()).count_documents(filter, None).await?)
+        Job::count(db, filter).await
     }
 
     pub async fn oldest_job(db: DbConn, analysis_type: AnalysisType) -> Result<Option<Job>> {
         let filter = doc! {
             "owner": { "$eq": Bson::Null },
             "analysis_type": { "$eq": analysis_type },
+            "is_complete": false,
         };
         let options = FindOneOptions::builder()
-            .sort(doc! { "date_last_updated": -1 })
+            .sort(doc! { "date_last_updated": 1 })
             .build();
-        Ok(Job::coll(db.clone())
-            .find_one(filter, options)
+        let (coll, filter) = (Job::coll(db), live(filter));
+        Ok(coll.find_one(filter, options)
             .await?
             .map(from_document::<Job>)
             .transpose()?)
     }
 }
+
+/// Analysis submitted for a job that had expired or been deleted by the
+/// time it arrived, kept rather than thrown away.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct LateSubmission {
+    pub _id: ObjectId,
+    pub job_id: JobId,
+    pub game_id: GameId,
+    pub source_id: UserId,
+    pub analysis: Vec<Option<PlyAnalysis>>,
+    pub engine: EngineType,
+    pub reason: String, // Either expired or deleted
+    pub date_submitted: DateTime,
+    pub attached_to: Option<JobId>, // The job that still wanted the game's analysis, if any
+}
+
+impl LateSubmission {
+    pub fn coll(db: DbConn) -> Collection {
+        db.database.collection("deepq_late_submissions")
+    }
+}
+
+/// Why a worker gave a job back, as the worker tells us.
+#[derive(
+    Serialize,
+    Deserialize,
+    Debug,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    Hash,
+    Default,
+    strum_macros::Display,
+    strum_macros::EnumString,
+)]
+#[serde(rename_all = "lowercase")]
+#[strum(serialize_all = "lowercase")]
+pub enum AbortReason {
+    Shutdown,    // The worker is stopping, usually a restart on the provider's side
+    Oom,         // The engine ran out of memory
+    Unsupported, // The worker can't analyse the position or variant we sent
+    Network,     // The worker couldn't reach us or lichess
+    #[default]
+    #[serde(other)]
+    Unknown, // Workers too old to give a reason, or giving one we don't know
+}
+
+impl From<AbortReason> for Bson {
+    fn from(reason: AbortReason) -> Bson {
+        Bson::String(reason.to_string())
+    }
+}
+
+/// A job a worker gave back before finishing it.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Abort {
+    pub _id: ObjectId,
+    pub job_id: JobId,
+    pub key_name: String, // NOTE: never the key itself
+    pub reason: AbortReason,
+    pub client_version: Option<String>,
+    pub date_aborted: DateTime,
+}
+
+impl Abort {
+    pub fn coll(db: DbConn) -> Collection {
+        db.database.collection("deepq_aborts")
+    }
+}
@@ -0,0 +1,119 @@
+// Copyright 2020 Lakin Wecker
+//
+// This file is part of lila-deepq.
+//
+// lila-deepq is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// lila-deepq is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with lila-deepq.  If not, see <https://www.gnu.org/licenses/>.
+//
+// Some providers commit to a minimum throughput, this keeps an eye on them.
+//
+use std::collections::HashMap;
+
+use log::{info, warn};
+use serde::Serialize;
+
+use crate::db::DbConn;
+use crate::deepq::stats::ServiceTimes;
+use crate::error::Result;
+use crate::fishnet::{api, model as m};
+use crate::telemetry::{self, SpanContext};
+
+// NOTE: a single slow hour happens, only alert once it's been sustained.
+pub const SUSTAINED_CHECKS: u32 = 3;
+
+#[derive(Serialize, Debug, Clone)]
+pub struct Alert {
+    pub key_name: String,
+    pub org: Option<String>,
+    pub completed_last_hour: i64,
+    pub committed_jobs_per_hour: i64,
+    // The fleet's service time for the slowest work the key may take, so a
+    // slow key can be told apart from slow work. None without recent completions.
+    pub expected_seconds_per_job: Option<f64>,
+}
+
+pub struct Monitor {
+    webhook_url: Option<String>,
+    client: reqwest::Client,
+    service_times: ServiceTimes,
+    misses: HashMap<String, u32>, // Consecutive checks below commitment, by key name
+}
+
+impl Monitor {
+    pub fn new(webhook_url: Option<String>, service_times: ServiceTimes) -> Monitor {
+        Monitor {
+            webhook_url,
+            client: reqwest::Client::new(),
+            service_times,
+            misses: HashMap::new(),
+        }
+    }
+
+    async fn expected_seconds_per_job(&self, api_user: &m::ApiUser) -> Option<f64> {
+        let mut slowest: Option<f64> = None;
+        for analysis_type in api_user.perms.iter() {
+            if let Some(service_time) = self.service_times.get(analysis_type.clone()).await {
+                slowest = Some(slowest.map_or(service_time.seconds_per_job, |slowest| {
+                    slowest.max(service_time.seconds_per_job)
+                }));
+            }
+        }
+        slowest
+    }
+
+    async fn alert(&self, alert: Alert) -> Result<()> {
+        warn!(
+            "sla > {} completed {} jobs in the last hour, committed to {}, expected {:?}s per job",
+            alert.key_name,
+            alert.completed_last_hour,
+            alert.committed_jobs_per_hour,
+            alert.expected_seconds_per_job
+        );
+        if let Some(url) = &self.webhook_url {
+            let request = self.client.post(url).json(&alert);
+            telemetry::propagate(request, SpanContext::current())
+                .send()
+                .await?
+                .error_for_status()?;
+        }
+        Ok(())
+    }
+
+    /// Checks every committed key, alerting once per sustained drop.
+    pub async fn check(&mut self, db: DbConn) -> Result<()> {
+        let p = "sla > check >";
+        let api_users = m::ApiUser::find_committed(db.clone()).await?;
+        info!("{} {} committed keys", p, api_users.len());
+        for api_user in api_users {
+            let throughput = api::key_throughput(db.clone(), &api_user).await?;
+            if !throughput.below_commitment {
+                self.misses.remove(&api_user.name);
+                continue;
+            }
+            let misses = self.misses.entry(api_user.name.clone()).or_insert(0);
+            *misses += 1;
+            if *misses == SUSTAINED_CHECKS {
+                let expected_seconds_per_job = self.expected_seconds_per_job(&api_user).await;
+                self.alert(Alert {
+                    key_name: api_user.name,
+                    org: api_user.org,
+                    completed_last_hour: throughput.completed_last_hour,
+                    committed_jobs_per_hour: throughput.committed_jobs_per_hour.unwrap_or(0),
+                    expected_seconds_per_job,
+                })
+                .await?;
+            }
+        }
+        Ok(())
+    }
+}
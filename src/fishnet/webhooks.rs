@@ -0,0 +1,265 @@
+// Copyright 2020 Lakin Wecker
+//
+// This file is part of lila-deepq.
+//
+// lila-deepq is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// lila-deepq is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with lila-deepq.  If not, see <https://www.gnu.org/licenses/>.
+//
+// Webhooks providers register on their own keys, so their tooling can react
+// to jobs that are about to be requeued and to rejected submissions.
+//
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::result::Result as StdResult;
+use std::task::{Context, Poll};
+
+use chrono::{prelude::*, Duration};
+use futures::future::{self, Ready};
+use hyper::client::connect::dns::Name;
+use hyper::client::HttpConnector;
+use hyper::{Body, Request};
+use hyper_tls::HttpsConnector;
+use log::{debug, error, info, warn};
+use serde::Serialize;
+use reqwest::Url;
+use tokio::net::lookup_host;
+use tokio::time::{sleep, timeout, Duration as TokioDuration};
+use tower_service::Service;
+
+use crate::db::DbConn;
+use crate::error::{ProtocolError, Result};
+use crate::fishnet::model as m;
+use crate::telemetry::{SpanContext, TRACEPARENT_HEADER};
+
+/// How long before a job expires its owner is warned.
+pub const DEADLINE_WARNING_SECONDS: i64 = 5 * 60;
+
+pub const DEADLINE_CHECK_INTERVAL_SECONDS: u64 = 60;
+
+const DELIVERY_TIMEOUT: TokioDuration = TokioDuration::from_secs(5);
+
+#[derive(Serialize, Debug, Clone)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    DeadlineWarning {
+        job_id: String,
+        game_id: String,
+        expires_at: DateTime<Utc>,
+    },
+    SubmissionRejected {
+        job_id: String,
+        reason: String,
+    },
+}
+
+/// Webhooks are called by us, so only allow https urls to public addresses.
+/// Checked when a webhook is set, see also post.
+pub async fn validate_url(url: &str) -> Result<()> {
+    public_addrs(url).await.map(|_| ())
+}
+
+/// The public addresses the url's host resolves to, if they all are.
+async fn public_addrs(url: &str) -> Result<Vec<SocketAddr>> {
+    let invalid = || ProtocolError::InvalidUrl(url.to_string());
+    let parsed = Url::parse(url).map_err(|_| invalid())?;
+    if parsed.scheme() != "https" {
+        return Err(invalid().into());
+    }
+    // NOTE: ipv6 hosts keep their brackets in the url.
+    let host = parsed
+        .host_str()
+        .ok_or_else(invalid)?
+        .trim_start_matches('[')
+        .trim_end_matches(']');
+    let port = parsed.port_or_known_default().unwrap_or(443);
+    let addrs: Vec<SocketAddr> = lookup_host((host, port))
+        .await
+        .map_err(|_| invalid())?
+        .collect();
+    if addrs.is_empty() || !addrs.iter().all(|addr| is_public(addr.ip())) {
+        return Err(invalid().into());
+    }
+    Ok(addrs)
+}
+
+/// Resolves the webhook's host to the addresses that were checked, so it
+/// can't resolve to an internal one by the time we connect.
+#[derive(Clone)]
+struct Pinned(Vec<SocketAddr>);
+
+impl Service<Name> for Pinned {
+    type Response = std::vec::IntoIter<SocketAddr>;
+    type Error = std::io::Error;
+    type Future = Ready<StdResult<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<StdResult<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, _name: Name) -> Self::Future {
+        future::ready(Ok(self.0.clone().into_iter()))
+    }
+}
+
+/// Posts a json body to a webhook, validating the url again first and
+/// connecting only to the addresses that passed. Redirects aren't followed.
+pub async fn post(
+    url: &str,
+    headers: &[(&str, String)],
+    body: Vec<u8>,
+    wait: TokioDuration,
+    trace: Option<SpanContext>,
+) -> Result<()> {
+    let mut http = HttpConnector::new_with_resolver(Pinned(public_addrs(url).await?));
+    http.enforce_http(false);
+    let mut https = HttpsConnector::from((http, native_tls::TlsConnector::new()?.into()));
+    https.https_only(true);
+    let client = hyper::Client::builder().build::<_, Body>(https);
+    let mut request = Request::post(url)
+        .header("User-Agent", "lila-deepq")
+        .header("Content-Type", "application/json");
+    for (name, value) in headers {
+        request = request.header(*name, value.as_str());
+    }
+    if let Some(trace) = trace {
+        request = request.header(TRACEPARENT_HEADER, trace.traceparent());
+    }
+    let request = request
+        .body(Body::from(body))
+        .map_err(|_| ProtocolError::InvalidUrl(url.to_string()))?;
+    let response = timeout(wait, client.request(request))
+        .await
+        .map_err(|_| ProtocolError::Delivery("timed out".to_string()))??;
+    if !response.status().is_success() {
+        return Err(ProtocolError::Delivery(response.status().to_string()).into());
+    }
+    Ok(())
+}
+
+/// False for loopback, private, link-local (which includes the cloud metadata
+/// address 169.254.169.254), multicast and other addresses that aren't routed
+/// publicly, including ipv6 addresses that embed one of them.
+pub fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, ..] = ip.octets();
+            !(ip.is_loopback()
+                || ip.is_private()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || ip.is_broadcast()
+                || ip.is_multicast()
+                || ip.is_documentation()
+                || a == 0
+                || (a == 100 && (64..128).contains(&b)) // shared address space
+                || a >= 240)
+        }
+        IpAddr::V6(ip) => {
+            if let Some(mapped) = ip.to_ipv4_mapped() {
+                return is_public(IpAddr::V4(mapped));
+            }
+            let segments = ip.segments();
+            let embedded = |high: u16, low: u16| {
+                IpAddr::V4(Ipv4Addr::new((high >> 8) as u8, high as u8, (low >> 8) as u8, low as u8))
+            };
+            match segments {
+                // NAT64, the well known prefix, and ipv4 compatible addresses
+                [0x64, 0xff9b, 0, 0, 0, 0, high, low] | [0, 0, 0, 0, 0, 0, high, low]
+                    if !ip.is_loopback() && !ip.is_unspecified() =>
+                {
+                    return is_public(embedded(high, low))
+                }
+                // 6to4
+                [0x2002, high, low, ..] => return is_public(embedded(high, low)),
+                _ => {}
+            }
+            let first = segments[0];
+            !(ip.is_loopback()
+                || ip.is_unspecified()
+                || ip.is_multicast()
+                || (first == 0x64 && segments[1] == 0xff9b && segments[2] == 1) // local-use NAT64
+                || (first == 0x2001 && segments[1] == 0xdb8) // documentation
+                || (first & 0xfe00) == 0xfc00 // unique local, fd00:ec2::254 among them
+                || (first & 0xffc0) == 0xfe80) // link-local
+        }
+    }
+}
+
+// NOTE: deliveries each get a client connecting to their validated
+//       addresses only, there's nothing to share between them.
+#[derive(Clone, Default)]
+pub struct Webhooks;
+
+impl Webhooks {
+
+    /// Calls the key's webhook, if it has one, without waiting on it.
+    pub fn notify(&self, api_user: &m::ApiUser, event: Event) {
+        let url = match api_user.webhook_url.clone() {
+            Some(url) => url,
+            None => return,
+        };
+        let name = api_user.name.clone();
+        let trace = SpanContext::current();
+        tokio::spawn(async move {
+            debug!("Webhooks::notify > {} > {:?}", name, event);
+            let result = match serde_json::to_vec(&event) {
+                Ok(body) => post(&url, &[], body, DELIVERY_TIMEOUT, trace).await,
+                Err(err) => Err(err.into()),
+            };
+            if let Err(err) = result {
+                warn!("Webhooks::notify > {} > {}", name, err);
+            }
+        });
+    }
+
+    /// Warns the owners of jobs that are about to expire, once per job.
+    pub async fn warn_deadlines(&self, db: DbConn) -> Result<i64> {
+        let p = "warn_deadlines >";
+        // NOTE: job owners are stored lowercased
+        let by_owner: HashMap<String, m::ApiUser> = m::ApiUser::find_with_webhooks(db.clone())
+            .await?
+            .into_iter()
+            .map(|api_user| (api_user.key.0.to_lowercase(), api_user))
+            .collect();
+        if by_owner.is_empty() {
+            return Ok(0);
+        }
+        let until = Utc::now() + Duration::seconds(DEADLINE_WARNING_SECONDS);
+        let mut warned = 0;
+        while let Some(job) = m::Job::claim_deadline_warning(db.clone(), until).await? {
+            let api_user = job.owner.as_ref().and_then(|owner| by_owner.get(owner));
+            if let (Some(api_user), Some(expires_at)) = (api_user, job.expires_at) {
+                self.notify(
+                    api_user,
+                    Event::DeadlineWarning {
+                        job_id: job._id.to_string(),
+                        game_id: job.game_id.to_string(),
+                        expires_at: expires_at.0,
+                    },
+                );
+                warned += 1;
+            }
+        }
+        info!("{} warned {} jobs", p, warned);
+        Ok(warned)
+    }
+
+    pub async fn warn_deadlines_periodically(self, db: DbConn) {
+        loop {
+            if let Err(err) = self.warn_deadlines(db.clone()).await {
+                error!("Unable to warn about job deadlines: {:?}", err);
+            }
+            sleep(TokioDuration::from_secs(DEADLINE_CHECK_INTERVAL_SECONDS)).await;
+        }
+    }
+}
@@ -0,0 +1,149 @@
+// Copyright 2021 Lakin Wecker
+//
+// This file is part of lila-deepq.
+//
+// lila-deepq is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// lila-deepq is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with lila-deepq.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use chrono::prelude::*;
+use futures::stream::TryStreamExt;
+use log::{debug, error};
+use mongodb::{
+    bson::{doc, from_document, to_document, DateTime},
+    options::{FindOneAndReplaceOptions, ReturnDocument},
+    Collection,
+};
+use serde::{Deserialize, Serialize};
+use tokio::sync::RwLock;
+use tokio::time::{Duration, Instant};
+
+use crate::db::DbConn;
+use crate::error::{Error, Result};
+use crate::pause::Pauses;
+
+// TODO: make this configurable?
+const REFRESH_INTERVAL: Duration = Duration::from_secs(30);
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Flag {
+    pub _id: String, // The name of the flag
+    pub enabled: bool,
+    pub date_last_updated: DateTime,
+}
+
+impl Flag {
+    pub fn coll(db: DbConn) -> Collection {
+        db.database.collection("deepq_flags")
+    }
+}
+
+#[derive(Default)]
+struct Cache {
+    flags: HashMap<String, bool>,
+    refreshed_at: Option<Instant>,
+}
+
+impl Cache {
+    fn is_stale(&self) -> bool {
+        self.refreshed_at
+            .is_none_or(|refreshed_at| refreshed_at.elapsed() > REFRESH_INTERVAL)
+    }
+}
+
+/// Runtime feature flags, stored in mongo and cached in process so that
+/// risky functionality can be turned on (or off) without a redeploy.
+///
+/// Unknown flags are disabled.
+#[derive(Clone)]
+pub struct Flags {
+    db: DbConn,
+    cache: Arc<RwLock<Cache>>,
+    pauses: Pauses,
+}
+
+impl Flags {
+    pub fn new(db: DbConn) -> Flags {
+        Flags {
+            db: db.clone(),
+            cache: Arc::new(RwLock::new(Cache::default())),
+            pauses: Pauses::new(db),
+        }
+    }
+
+    /// The queue pauses, the other switch we flip during incidents.
+    pub fn pauses(&self) -> Pauses {
+        self.pauses.clone()
+    }
+
+    pub async fn is_enabled(&self, name: &str) -> bool {
+        if self.cache.read().await.is_stale() {
+            if let Err(err) = self.refresh().await {
+                // NOTE: keep using whatever we had before, it's better than nothing.
+                error!("Flags::is_enabled > unable to refresh flags: {:?}", err);
+            }
+        }
+        self.cache
+            .read()
+            .await
+            .flags
+            .get(name)
+            .copied()
+            .unwrap_or(false)
+    }
+
+    pub async fn refresh(&self) -> Result<()> {
+        let flags = self.all().await?;
+        let mut cache = self.cache.write().await;
+        cache.flags = flags.into_iter().map(|f| (f._id, f.enabled)).collect();
+        cache.refreshed_at = Some(Instant::now());
+        debug!("Flags::refresh > {:?}", cache.flags);
+        Ok(())
+    }
+
+    pub async fn all(&self) -> Result<Vec<Flag>> {
+        Flag::coll(self.db.clone())
+            .find(doc! {}, None)
+            .await?
+            .map_err(Error::from)
+            .and_then(|doc| async move { Ok(from_document::<Flag>(doc)?) })
+            .try_collect()
+            .await
+    }
+
+    pub async fn set(&self, name: &str, enabled: bool) -> Result<Flag> {
+        let flag = Flag {
+            _id: name.to_string(),
+            enabled,
+            date_last_updated: DateTime(Utc::now()),
+        };
+        Flag::coll(self.db.clone())
+            .find_one_and_replace(
+                doc! {"_id": name},
+                to_document(&flag)?,
+                FindOneAndReplaceOptions::builder()
+                    .upsert(true)
+                    .return_document(ReturnDocument::After)
+                    .build(),
+            )
+            .await?;
+        self.cache
+            .write()
+            .await
+            .flags
+            .insert(flag._id.clone(), flag.enabled);
+        Ok(flag)
+    }
+}
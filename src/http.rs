@@ -16,20 +16,32 @@
 // along with lila-deepq.  If not, see <https://www.gnu.org/licenses/>.
 
 use std::convert::Infallible;
+use std::fmt;
+use std::io;
 use std::marker::Send;
+use std::net::SocketAddr;
+use std::path::PathBuf;
 use std::result::Result as StdResult;
 use std::str::FromStr;
 
-use futures::future::{self, Future};
+use futures::future::{self, BoxFuture, Future};
+use log::{error, info};
 use mongodb::bson::oid::ObjectId;
 use serde::Serialize;
+use tokio::net::UnixListener;
+use tokio_stream::wrappers::UnixListenerStream;
+use warp::hyper::server::{accept, Server};
+use warp::hyper::service::{make_service_fn, service_fn, Service};
+use warp::hyper::{Body, Request, Response};
 use warp::{
+    filters::BoxedFilter,
     http, reject,
     reply::{self, Json, Reply, WithStatus},
     Filter, Rejection,
 };
 
-use crate::error::{Error, HttpError};
+use crate::error::{DomainError, Error, HttpError, ProtocolError};
+use crate::telemetry;
 
 /// Unauthorized rejection
 pub fn forbidden() -> Rejection {
@@ -40,6 +52,14 @@ pub fn unauthenticated() -> Rejection {
     reject::custom(HttpError::Unauthenticated)
 }
 
+pub fn job_expired() -> Rejection {
+    reject::custom(HttpError::JobExpired)
+}
+
+pub fn unsupported_work_type() -> Rejection {
+    reject::custom(HttpError::UnsupportedWorkType)
+}
+
 /// extract an ApiUser from the json body request
 pub fn required_parameter<'a, F, E, V>(
     filter: F,
@@ -53,7 +73,7 @@ where
     filter.and_then(move |v: Option<V>| async move { v.ok_or_else(err) })
 }
 
-pub fn required_or_unauthenticated<'a, T>(
+pub fn required_or_unauthenticated<T>(
     o: Option<T>,
 ) -> impl Future<Output = StdResult<T, Rejection>> {
     if let Some(t) = o {
@@ -62,7 +82,7 @@ pub fn required_or_unauthenticated<'a, T>(
     future::err(unauthenticated())
 }
 
-pub fn required_or_forbidden<'a, T>(o: Option<T>) -> impl Future<Output = StdResult<T, Rejection>> {
+pub fn required_or_forbidden<T>(o: Option<T>) -> impl Future<Output = StdResult<T, Rejection>> {
     if let Some(t) = o {
         return future::ok(t);
     }
@@ -86,6 +106,18 @@ impl From<Id> for ObjectId {
     }
 }
 
+/// A path segment parsed as an id, e.g. m::JobId or Id.
+///
+/// NOTE: warp's path::param turns a malformed id into a 404, this makes it a
+///       400 like every other malformed input, rather than a missing record.
+pub fn id<T>() -> impl Filter<Extract = (T,), Error = Rejection> + Clone
+where
+    T: FromStr<Err = Error> + Send,
+{
+    warp::path::param::<String>()
+        .and_then(|segment: String| async move { T::from_str(&segment).map_err(reject::custom) })
+}
+
 pub fn with<T>(t: T) -> impl Filter<Extract = (T,), Error = std::convert::Infallible> + Clone
 where
     T: Clone + Sync + Send,
@@ -93,6 +125,253 @@ where
     warp::any().map(move || t.clone())
 }
 
+/// Where a group of routes is served, either `host:port` or `unix:/path/to.sock`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Listen {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+impl FromStr for Listen {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.strip_prefix("unix:") {
+            Some(path) if !path.is_empty() => Ok(Listen::Unix(path.into())),
+            Some(_) => Err(Error::InvalidCommandLineArguments),
+            None => Ok(Listen::Tcp(
+                s.parse().map_err(|_| Error::InvalidCommandLineArguments)?,
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Listen {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Listen::Tcp(addr) => write!(f, "{}", addr),
+            Listen::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+/// Routes whose replies have been erased, so that any of them may share a listener.
+pub type Routes = BoxedFilter<(Box<dyn Reply>,)>;
+
+pub fn routes<F, R>(filter: F) -> Routes
+where
+    F: Filter<Extract = (R,), Error = Rejection> + Clone + Send + Sync + 'static,
+    R: Reply + 'static,
+{
+    filter.map(|reply| Box::new(reply) as Box<dyn Reply>).boxed()
+}
+
+pub const API_VERSION_HEADER: &str = "Api-Version";
+
+/// The versions of the HTTP API, each mounted under its own path prefix.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ApiVersion {
+    V1,
+}
+
+impl fmt::Display for ApiVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ApiVersion::V1 => write!(f, "v1"),
+        }
+    }
+}
+
+impl FromStr for ApiVersion {
+    type Err = HttpError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().trim_start_matches('v') {
+            "1" => Ok(ApiVersion::V1),
+            _ => Err(HttpError::UnsupportedApiVersion),
+        }
+    }
+}
+
+#[derive(Clone)]
+struct Mount {
+    version: ApiVersion,
+    routes: Routes,
+    sunset: Option<String>, // An http-date, set once the version is deprecated
+}
+
+/// Mounts each version of a group of routes at /<version>/<group>, and the
+/// unprefixed /<group> that clients from before versioning use.
+///
+/// Unprefixed requests get the version in their Api-Version header, or the
+/// legacy version when there's none. Every reply says which version served
+/// it, and deprecated versions add Deprecation and Sunset headers.
+#[derive(Clone)]
+pub struct Versioned {
+    group: &'static str,
+    legacy: ApiVersion,
+    mounts: Vec<Mount>,
+}
+
+impl Versioned {
+    pub fn new(group: &'static str, legacy: ApiVersion) -> Versioned {
+        Versioned {
+            group,
+            legacy,
+            mounts: Vec::new(),
+        }
+    }
+
+    pub fn version(mut self, version: ApiVersion, routes: Routes) -> Versioned {
+        self.mounts.push(Mount {
+            version,
+            routes,
+            sunset: None,
+        });
+        self
+    }
+
+    /// Marks the version deprecated, to be removed after the sunset, e.g. "Sat, 01 Jan 2022 00:00:00 GMT".
+    pub fn deprecate(mut self, version: ApiVersion, sunset: Option<&str>) -> Versioned {
+        for mount in self.mounts.iter_mut().filter(|mount| mount.version == version) {
+            mount.sunset = Some(sunset.unwrap_or_default().to_string());
+        }
+        self
+    }
+
+    fn negotiated(
+        version: ApiVersion,
+        legacy: ApiVersion,
+    ) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+        warp::header::optional::<String>(API_VERSION_HEADER)
+            .and_then(move |asked: Option<String>| async move {
+                let asked = match asked {
+                    Some(asked) => asked.parse::<ApiVersion>().map_err(reject::custom)?,
+                    None => legacy,
+                };
+                if asked == version {
+                    Ok(())
+                } else {
+                    Err(reject::not_found())
+                }
+            })
+            .untuple_one()
+    }
+
+    fn with_headers(mount: &Mount, successor: Option<String>) -> impl Fn(Box<dyn Reply>) -> Box<dyn Reply> + Clone {
+        let (version, sunset) = (mount.version, mount.sunset.clone());
+        move |reply: Box<dyn Reply>| {
+            let mut reply = reply.into_response();
+            let headers = reply.headers_mut();
+            let mut insert = |name: &'static str, value: String| {
+                if let Ok(value) = http::HeaderValue::from_str(&value) {
+                    headers.insert(name, value);
+                }
+            };
+            insert(API_VERSION_HEADER, version.to_string());
+            if let Some(sunset) = sunset.clone() {
+                insert("Deprecation", "true".to_string());
+                if !sunset.is_empty() {
+                    insert("Sunset", sunset);
+                }
+            }
+            if let Some(successor) = successor.clone() {
+                insert("Link", format!("<{}>; rel=\"successor-version\"", successor));
+            }
+            Box::new(reply) as Box<dyn Reply>
+        }
+    }
+
+    pub fn routes(self) -> Routes {
+        let group = self.group;
+        let latest = self.mounts.last().map(|mount| mount.version);
+        let successor = |version: ApiVersion| {
+            latest
+                .filter(|latest| *latest != version)
+                .map(|latest| format!("/{}/{}", latest, group))
+        };
+        let mut all: Option<Routes> = None;
+        for mount in &self.mounts {
+            let prefixed = warp::path(mount.version.to_string())
+                .and(warp::path(group))
+                .and(mount.routes.clone())
+                .map(Versioned::with_headers(mount, successor(mount.version)));
+            // NOTE: the unprefixed paths point clients at the prefixed ones.
+            let unprefixed = warp::path(group)
+                .and(Versioned::negotiated(mount.version, self.legacy))
+                .and(mount.routes.clone())
+                .map(Versioned::with_headers(
+                    mount,
+                    Some(format!("/{}/{}", mount.version, group)),
+                ));
+            let mounted = routes(prefixed.or(unprefixed).unify());
+            all = Some(match all {
+                Some(all) => routes(all.or(mounted).unify()),
+                None => mounted,
+            });
+        }
+        let all = all.expect("at least one version is mounted");
+        routes(all.recover(unsupported_version).unify())
+    }
+}
+
+// NOTE: only this rejection, the others must fall through to the other groups
+//       sharing the listener.
+async fn unsupported_version(err: Rejection) -> StdResult<Box<dyn Reply>, Rejection> {
+    match err.find::<HttpError>() {
+        Some(HttpError::UnsupportedApiVersion) => Ok(Box::new(recover(err).await?)),
+        _ => Err(err),
+    }
+}
+
+type Handled = BoxFuture<'static, StdResult<Response<Body>, Infallible>>;
+
+/// The routes as a hyper service, each request handled in its own server
+/// span, see telemetry::serve.
+fn traced(routes: Routes) -> impl Fn(Request<Body>) -> Handled + Clone {
+    let service = warp::service(routes);
+    move |request| {
+        let mut service = service.clone();
+        Box::pin(async move {
+            let handle = |request| async move {
+                service.call(request).await.unwrap_or_else(|never| match never {})
+            };
+            Ok(telemetry::serve(request, handle).await)
+        })
+    }
+}
+
+/// Serves the routes until the process stops.
+pub async fn serve(listen: Listen, routes: Routes) -> io::Result<()> {
+    info!("serve > listening on {}", listen);
+    let handle = traced(routes);
+    let served = match listen {
+        Listen::Tcp(addr) => {
+            let make_service = make_service_fn(move |_| {
+                let handle = handle.clone();
+                async move { Ok::<_, Infallible>(service_fn(handle)) }
+            });
+            Server::try_bind(&addr)
+                .map_err(io::Error::other)?
+                .serve(make_service)
+                .await
+        }
+        Listen::Unix(path) => {
+            // NOTE: a socket left behind by a previous run would make bind fail.
+            if path.exists() {
+                std::fs::remove_file(&path)?;
+            }
+            let incoming = UnixListenerStream::new(UnixListener::bind(&path)?);
+            let make_service = make_service_fn(move |_| {
+                let handle = handle.clone();
+                async move { Ok::<_, Infallible>(service_fn(handle)) }
+            });
+            Server::builder(accept::from_stream(incoming)).serve(make_service).await
+        }
+    };
+    served.map_err(io::Error::other)
+}
+
 pub async fn json_object_or_no_content<T: Serialize>(
     value: Option<T>,
 ) -> StdResult<WithStatus<Json>, Rejection> {
@@ -105,6 +384,24 @@ pub async fn json_object_or_no_content<T: Serialize>(
     )
 }
 
+/// Like json_object_or_no_content, but fishnet clients expect 202 for new work.
+pub async fn accepted_json_object_or_no_content<T: Serialize>(
+    value: Option<T>,
+) -> StdResult<WithStatus<Json>, Rejection> {
+    value.map_or(
+        Ok(reply::with_status(
+            reply::json(&String::new()),
+            http::StatusCode::NO_CONTENT,
+        )),
+        |val| {
+            Ok(reply::with_status(
+                reply::json(&val),
+                http::StatusCode::ACCEPTED,
+            ))
+        },
+    )
+}
+
 /// An API error serializable to JSON.
 #[derive(Serialize)]
 pub struct ErrorMessage {
@@ -114,6 +411,48 @@ pub struct ErrorMessage {
 
 // This function receives a `Rejection` and tries to return a custom
 // value, otherwise simply passes the rejection along.
+fn http_error_status(err: &HttpError) -> (http::StatusCode, &'static str) {
+    match err {
+        HttpError::MalformedHeader | HttpError::Unauthenticated => {
+            (http::StatusCode::UNAUTHORIZED, "UNAUTHORIZED")
+        }
+        HttpError::Forbidden => (http::StatusCode::FORBIDDEN, "FORBIDDEN"),
+        HttpError::JobExpired => (http::StatusCode::GONE, "JOB_EXPIRED"),
+        HttpError::UnsupportedWorkType => (http::StatusCode::BAD_REQUEST, "UNSUPPORTED_WORK_TYPE"),
+        HttpError::Replayed => (http::StatusCode::UNAUTHORIZED, "REPLAYED_REQUEST"),
+        HttpError::UnsupportedApiVersion => {
+            (http::StatusCode::BAD_REQUEST, "UNSUPPORTED_API_VERSION")
+        }
+    }
+}
+
+fn error_status(err: &Error) -> (http::StatusCode, &'static str) {
+    match err {
+        Error::Http(err) => http_error_status(err),
+        Error::Db(_) => (http::StatusCode::INTERNAL_SERVER_ERROR, "DATABASE_ERROR"),
+        Error::Protocol(
+            ProtocolError::Json(_)
+            | ProtocolError::InvalidObjectId(_)
+            | ProtocolError::InvalidId(_)
+            | ProtocolError::Deserialization
+            | ProtocolError::InvalidUrl(_),
+        ) => (http::StatusCode::BAD_REQUEST, "BAD_REQUEST"),
+        Error::Domain(DomainError::NotFound) => (http::StatusCode::NOT_FOUND, "NOT_FOUND"),
+        Error::Domain(DomainError::DocumentTooLarge(_)) => {
+            (http::StatusCode::PAYLOAD_TOO_LARGE, "PAYLOAD_TOO_LARGE")
+        }
+        Error::Domain(
+            DomainError::IllegalSan(_)
+            | DomainError::IllegalPosition
+            | DomainError::OutOfRange(_)
+            | DomainError::InvalidPgn(_)
+            | DomainError::DanglingReference(_)
+            | DomainError::Invalid(_),
+        ) => (http::StatusCode::UNPROCESSABLE_ENTITY, "UNPROCESSABLE_ENTITY"),
+        _ => (http::StatusCode::INTERNAL_SERVER_ERROR, "INTERNAL_ERROR"),
+    }
+}
+
 pub async fn recover(err: Rejection) -> Result<impl Reply, Infallible> {
     let code;
     let message;
@@ -121,12 +460,13 @@ pub async fn recover(err: Rejection) -> Result<impl Reply, Infallible> {
     if err.is_not_found() {
         code = http::StatusCode::NOT_FOUND;
         message = "NOT_FOUND";
-    } else if let Some(HttpError::Unauthenticated) = err.find() {
-        code = http::StatusCode::UNAUTHORIZED;
-        message = "UNAUTHORIZED";
-    } else if let Some(HttpError::Forbidden) = err.find() {
-        code = http::StatusCode::FORBIDDEN;
-        message = "FORBIDDEN";
+    } else if let Some(http_err) = err.find::<HttpError>() {
+        (code, message) = http_error_status(http_err);
+    } else if let Some(app_err) = err.find::<Error>() {
+        (code, message) = error_status(app_err.root());
+        if code == http::StatusCode::INTERNAL_SERVER_ERROR {
+            error!("recover > {}", app_err);
+        }
     } else if err.find::<reject::MethodNotAllowed>().is_some() {
         code = http::StatusCode::METHOD_NOT_ALLOWED;
         message = "METHOD_NOT_ALLOWED";
@@ -17,4 +17,9 @@
 //
 //
 pub mod api;
+pub mod job;
+#[cfg(feature = "listener")]
+pub mod replay;
+#[cfg(feature = "listener")]
 pub mod stream;
+pub mod submitter;
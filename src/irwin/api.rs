@@ -17,27 +17,45 @@
 //
 //
 
-use std::convert::{TryFrom, TryInto};
+use std::convert::TryFrom;
 use std::iter::Iterator;
 use std::result::Result as StdResult;
 
-use futures::{future::try_join_all, stream::StreamExt};
+use chrono::prelude::*;
+use futures::{
+    future::try_join_all,
+    stream::{StreamExt, TryStreamExt},
+};
 use log::{debug, error, info, warn};
+use mongodb::bson::{doc, oid::ObjectId};
 use serde::{Deserialize, Serialize};
 use serde_with::{serde_as, SpaceSeparator, StringWithSeparator};
 use shakmaty::{san::San, uci::Uci, CastlingMode, Chess, Position};
 use tokio::sync::broadcast::{self, error::RecvError};
 
 use crate::db::DbConn;
+use crate::deepq::anomaly::score_job;
 use crate::deepq::api::{
-    atomically_update_sent_to_irwin, find_report, insert_many_games, insert_one_report,
-    precedence_for_origin, CreateGame, CreateReport,
+    atomically_clear_missing_games, atomically_update_sent_partially_to_irwin,
+    atomically_update_sent_to_irwin, count_recent_reports_for_user, find_pending_submissions, find_report,
+    find_unsent_reports, has_complete_analysis, insert_audit_entry,
+    insert_many_games, insert_one_report, latest_report_for_user, mark_report_unsent, CreateGame,
+    CreateReport,
+};
+use crate::deepq::model::{
+    GameId, Provenance, Report, ReportId, ReportOrigin, ReportSignals, ReportType, Score,
+    SubmissionKind, UserId,
 };
-use crate::deepq::model::{GameId, Report, ReportOrigin, ReportType, Score, UserId};
-use crate::error::{Error, Result};
-use crate::fishnet::api::{get_job, insert_many_jobs, CreateJob};
+use crate::deepq::policy::{
+    decayed_precedence, downscoped_quiet_positions, is_analysable, precedence, repeat_request_window,
+    screening_cooldown,
+};
+use crate::error::{Context, DomainError, Error, Result};
+use crate::fishnet::api::{get_job, insert_many_jobs, requeue_stale_jobs, set_complete, CreateJob};
 use crate::fishnet::model::{AnalysisType, Job, JobId};
 use crate::fishnet::FishnetMsg;
+use crate::irwin::submitter::Submitter;
+use crate::telemetry::{instrument, Span, SpanKind};
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct User {
@@ -60,14 +78,14 @@ pub struct Game {
     pub analysis: Option<Vec<Score>>,
 }
 
-fn uci_from_san(pgn: &Vec<San>) -> Result<Vec<Uci>> {
+pub fn uci_from_san(pgn: &[San]) -> Result<Vec<Uci>> {
     let mut pos = Chess::default();
     let mut ret_val = Vec::new();
     for san in pgn.iter() {
         let m = san.to_move(&pos)?;
         // TODO: the castling mode needs to come from the game!!
         ret_val.push(Uci::from_move(&m, CastlingMode::Standard));
-        pos = pos.play(&m).map_err(|_pos| Error::PositionError)?;
+        pos = pos.play(&m).map_err(|_pos| DomainError::IllegalPosition)?;
     }
     Ok(ret_val)
 }
@@ -79,10 +97,11 @@ impl TryFrom<&Game> for CreateGame {
         let g = g.clone();
         Ok(CreateGame {
             game_id: g.id,
-            emts: g.emts.unwrap_or_else(Vec::new),
+            emts: g.emts.unwrap_or_default(),
             pgn: uci_from_san(&g.pgn)?,
             black: Some(g.black),
             white: Some(g.white),
+            provenance: Provenance::Unknown, // Set by whoever knows where the game came from
         })
     }
 }
@@ -94,61 +113,181 @@ pub struct Request {
     pub origin: ReportOrigin,
     pub user: User,
     pub games: Vec<Game>,
+    pub score: Option<i32>, // Optional suspicion score from lila
 }
 
-impl From<Request> for CreateReport {
-    fn from(request: Request) -> CreateReport {
-        CreateReport {
-            user_id: request.user.id,
-            origin: request.origin,
-            report_type: ReportType::Irwin,
-            games: request.games.iter().map(|g| g.id.clone()).collect(),
+impl From<&Request> for ReportSignals {
+    fn from(request: &Request) -> ReportSignals {
+        ReportSignals {
+            score: request.score,
+            titled: request.user.titled,
+            engine: request.user.engine,
         }
     }
 }
 
-impl From<Request> for Vec<CreateJob> {
-    fn from(request: Request) -> Vec<CreateJob> {
-        request
+impl TryFrom<Request> for CreateReport {
+    type Error = Error;
+
+    fn try_from(request: Request) -> Result<CreateReport> {
+        let signals: ReportSignals = (&request).into();
+        let (games, not_analysable_games) = request
             .games
             .iter()
-            .map(|g| CreateJob {
-                game_id: g.id.clone(),
-                report_id: None,
-                analysis_type: AnalysisType::Deep,
-                precedence: precedence_for_origin(request.clone().origin),
-            })
-            .collect()
+            .partition::<Vec<&Game>, _>(|g| is_analysable(g.pgn.len()));
+        CreateReport::builder(request.user.id, request.origin, ReportType::Irwin)
+            .games(games.into_iter().map(|g| g.id.clone()).collect())
+            .not_analysable_games(not_analysable_games.into_iter().map(|g| g.id.clone()).collect())
+            .signals(signals)
+            .build()
     }
 }
 
-pub async fn add_to_queue(db: DbConn, request: Request) -> Result<()> {
+/// The jobs for the analysable games of the request's report.
+fn create_jobs(request: &Request, report_id: ReportId, recent_requests: i64) -> Result<Vec<CreateJob>> {
+    let signals: ReportSignals = request.into();
+    let precedence = decayed_precedence(
+        request.origin.clone(),
+        precedence(request.origin.clone(), &signals),
+        recent_requests,
+    );
+    request
+        .games
+        .iter()
+        .filter(|g| is_analysable(g.pgn.len()))
+        .map(|g| {
+            CreateJob::builder(g.id.clone(), AnalysisType::Deep)
+                .report(report_id.clone(), request.origin.clone())
+                .precedence(precedence)
+                .quiet_positions(
+                    g.analysis
+                        .as_ref()
+                        .and_then(|analysis| downscoped_quiet_positions(analysis, g.pgn.len()))
+                        .unwrap_or_default(),
+                )
+                .build()
+        })
+        .collect()
+}
+
+/// The audit action of each request refused because its user was screened recently.
+pub const SKIP_COOLING_DOWN: &str = "skip_cooling_down";
+
+/// Refuses the request, recording why, if it's from an origin with a
+/// cooldown and the user already has a report within it.
+async fn is_cooling_down(db: DbConn, request: &Request, cooldown: chrono::Duration) -> Result<bool> {
+    let cooldown = match screening_cooldown(request.origin.clone(), cooldown) {
+        Some(cooldown) => cooldown,
+        None => return Ok(false),
+    };
+    let since = Utc::now() - cooldown;
+    let last = match latest_report_for_user(db.clone(), request.user.id.clone(), since).await? {
+        Some(last) => last,
+        None => return Ok(false),
+    };
+    insert_audit_entry(
+        db,
+        "add_to_queue",
+        SKIP_COOLING_DOWN,
+        doc! {
+            "user_id": request.user.id.clone(),
+            "origin": request.origin.clone(),
+            "last_report_id": last._id.0,
+            "last_origin": last.origin,
+            "last_requested": last.date_requested.0,
+            "cooldown_days": cooldown.num_days(),
+        },
+    )
+    .await?;
+    Ok(true)
+}
+
+/// Queues a report and its jobs for the request, or nothing if its user is
+/// still cooling down from their last screening.
+pub async fn add_to_queue(
+    db: DbConn,
+    request: Request,
+    provenance: Provenance,
+    screening_cooldown: chrono::Duration,
+) -> Result<Option<ReportId>> {
+    if is_cooling_down(db.clone(), &request, screening_cooldown).await? {
+        return Ok(None);
+    }
     let games_with_uci = request
         .games
         .iter()
-        .map(TryInto::try_into)
+        .map(|game| {
+            CreateGame::try_from(game)
+                .map(|create| CreateGame { provenance, ..create })
+                .with_context(|| format!("Game({})", game.id))
+        })
         .collect::<Result<Vec<CreateGame>>>()?;
+    let recent_requests = count_recent_reports_for_user(
+        db.clone(),
+        request.user.id.clone(),
+        Utc::now() - repeat_request_window(),
+    )
+    .await?;
+    // NOTE: everything is built, and so checked, before anything is inserted.
+    let report = CreateReport::try_from(request.clone())?;
+    let fishnet_jobs = create_jobs(&request, report.id().clone(), recent_requests)?;
+
     try_join_all(insert_many_games(
         db.clone(),
         games_with_uci.iter().cloned(),
     ))
     .await?;
+    let report_id = insert_one_report(db.clone(), report).await?;
+    try_join_all(insert_many_jobs(db.clone(), fishnet_jobs.iter().by_ref())).await?;
+    Ok(Some(report_id))
+}
 
-    let report_id = insert_one_report(db.clone(), request.clone().into()).await?;
-
-    let fishnet_jobs: Vec<CreateJob> = request.into();
-    let fishnet_jobs: Vec<CreateJob> = fishnet_jobs
-        .iter()
-        .map(|j: &CreateJob| CreateJob {
-            game_id: j.game_id.clone(),
-            report_id: Some(report_id.clone()),
-            analysis_type: j.analysis_type.clone(),
-            precedence: j.precedence,
+/// Schedules deeper analysis for the games of a report irwin was unsure about.
+///
+/// The report is marked as unsent so that it's submitted again once the
+/// follow up jobs are complete.
+pub async fn schedule_follow_up(
+    db: DbConn,
+    report_id: ReportId,
+    game_ids: Vec<GameId>,
+) -> Result<Option<Vec<ObjectId>>> {
+    let report = match find_report(db.clone(), report_id.clone()).await? {
+        Some(report) => report,
+        None => return Ok(None),
+    };
+    let signals = report.signals.clone().unwrap_or_default();
+    let jobs: Vec<CreateJob> = game_ids
+        .into_iter()
+        // NOTE: only games we already have, for this report.
+        .filter(|game_id| report.games.iter().any(|g| g.0 == game_id.0))
+        // NOTE: irwin was unsure about these, analyse every position.
+        .map(|game_id| {
+            CreateJob::builder(game_id, AnalysisType::Deep)
+                .report(report_id.clone(), report.origin.clone())
+                .precedence(precedence(report.origin.clone(), &signals))
+                .follow_up(true)
+                .build()
         })
-        .collect();
+        .collect::<Result<_>>()?;
+    let job_ids = try_join_all(insert_many_jobs(db.clone(), jobs.iter().by_ref())).await?;
+    if !job_ids.is_empty() {
+        mark_report_unsent(db, report_id).await?;
+    }
+    Ok(Some(job_ids))
+}
 
-    try_join_all(insert_many_jobs(db.clone(), fishnet_jobs.iter().by_ref())).await?;
-    Ok(())
+/// When to give up waiting on a report's stragglers and submit what we have.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PartialSubmission {
+    pub timeout: Option<chrono::Duration>, // Never submit partially when unset
+    pub amend: bool, // Resubmit once the stragglers finish
+}
+
+impl PartialSubmission {
+    fn is_due(&self, report: &Report) -> bool {
+        self.timeout
+            .is_some_and(|timeout| report.date_requested.0 + timeout < Utc::now())
+    }
 }
 
 async fn handle_job_acquired(_db: DbConn, job_id: JobId) {
@@ -161,9 +300,14 @@ async fn handle_job_aborted(_db: DbConn, job_id: JobId) {
     debug!("{} Fishnet::JobAborted({})", p, job_id);
 }
 
-async fn handle_job_completed(db: DbConn, job_id: JobId) {
+async fn handle_job_completed(
+    db: DbConn,
+    job_id: JobId,
+    partial: PartialSubmission,
+    submitter: &Submitter,
+) {
     let p = "handle_job_completed >";
-    match get_job(db.clone(), job_id.clone().into()).await {
+    match get_job(db.clone(), job_id.clone()).await {
         Err(err) => {
             error!(
                 "{} Unable find job for {:?}. Error: {:?}",
@@ -176,7 +320,7 @@ async fn handle_job_completed(db: DbConn, job_id: JobId) {
             error!("{} Unable find job for {:?}.", p, job_id.clone());
         }
         Ok(Some(job)) => {
-            if let Some(report_id) = job.report_id {
+            if let Some(report_id) = job.report_id.clone() {
                 match find_report(db.clone(), report_id.clone()).await {
                     Err(err) => {
                         error!(
@@ -191,7 +335,12 @@ async fn handle_job_completed(db: DbConn, job_id: JobId) {
                     }
                     Ok(Some(report)) => {
                         debug!("{} Fishnet::JobCompleted({}) > handled", p, job_id);
-                        match update_report_completeness(db.clone(), report).await {
+                        if let Err(err) = score_job(db.clone(), &report, &job).await {
+                            warn!("{} Unable to score time usage for {:?}. Error: {:?}", p, job_id, err);
+                        }
+                        match update_report_completeness(db.clone(), report, partial, submitter)
+                            .await
+                        {
                             Ok(_) => {}
                             Err(err) => {
                                 error!(
@@ -209,7 +358,9 @@ async fn handle_job_completed(db: DbConn, job_id: JobId) {
     }
 }
 
-async fn report_complete_percentage(db: DbConn, report: Report) -> Result<f64> {
+/// None when the report has no jobs left to count, e.g. every game was
+/// excluded, as there's nothing to submit.
+pub async fn report_complete_percentage(db: DbConn, report: Report) -> Result<Option<f64>> {
     let p = "report_complete_percentage >";
     let mut jobs = Job::find_by_report(db.clone(), report.clone()).await?;
     let mut complete = 0f64;
@@ -217,6 +368,8 @@ async fn report_complete_percentage(db: DbConn, report: Report) -> Result<f64> {
 
     while let Some(job_result) = jobs.next().await {
         let is_complete = match job_result {
+            // NOTE: excluded games don't count towards the report's progress.
+            Ok(job) if report.excluded_games.iter().any(|g| g.0 == job.game_id.0) => continue,
             Ok(job) => job.is_complete,
             Err(err) => {
                 error!(
@@ -234,25 +387,72 @@ async fn report_complete_percentage(db: DbConn, report: Report) -> Result<f64> {
             incomplete += 1f64;
         }
     }
-    Ok(complete / (complete + incomplete))
+    if complete + incomplete == 0f64 {
+        return Ok(None);
+    }
+    Ok(Some(complete / (complete + incomplete)))
 }
 
-async fn update_report_completeness(db: DbConn, report: Report) -> Result<()> {
+async fn incomplete_games(db: DbConn, report: Report) -> Result<Vec<GameId>> {
+    let jobs: Vec<Job> = Job::find_by_report(db, report).await?.try_collect().await?;
+    let mut games: Vec<GameId> = jobs
+        .into_iter()
+        .filter(|job| !job.is_complete)
+        .map(|job| job.game_id)
+        .collect();
+    games.sort_by(|a, b| a.0.cmp(&b.0));
+    games.dedup_by(|a, b| a.0 == b.0);
+    Ok(games)
+}
+
+async fn update_report_completeness(
+    db: DbConn,
+    report: Report,
+    partial: PartialSubmission,
+    submitter: &Submitter,
+) -> Result<()> {
     let p = "update_report_completeness";
-    let percentage = report_complete_percentage(db.clone(), report.clone()).await?;
-    if percentage >= 1f64 {
-        let updated_report = atomically_update_sent_to_irwin(db, report._id.clone()).await?;
+    let percentage = match report_complete_percentage(db.clone(), report.clone()).await? {
+        Some(percentage) => percentage,
+        None => {
+            info!("{} > Report({:?}) > no games to analyse. Nothing to submit", &p, report._id);
+            return Ok(());
+        }
+    };
+    if percentage >= 1f64 && report.sent_to_irwin && !report.missing_games.is_empty() {
+        if !partial.amend {
+            debug!("{} > Report({:?}) > complete. Not amending partial submission", &p, report._id);
+        } else if let Some(updated_report) =
+            atomically_clear_missing_games(db.clone(), report._id.clone()).await?
+        {
+            info!("{} > Report({:?}) > stragglers complete. Queued amendment", &p, report._id);
+            submitter.submit(updated_report, SubmissionKind::Amendment);
+        }
+    } else if percentage >= 1f64 {
+        let updated_report = atomically_update_sent_to_irwin(db.clone(), report._id.clone()).await?;
         if let Some(updated_report) = updated_report {
-            info!(
-                "{} > Report({:?}) > complete. Submitting to irwin!",
-                &p, updated_report._id
-            );
+            info!("{} > Report({:?}) > complete. Queued submission", &p, report._id);
+            submitter.submit(updated_report, SubmissionKind::Complete);
         } else {
             info!(
                 "{} > Report({:?}) > complete. Already submitted to irwin!",
                 &p, report._id
             );
         }
+    } else if !report.sent_to_irwin && partial.is_due(&report) {
+        let missing_games = incomplete_games(db.clone(), report.clone()).await?;
+        let updated_report =
+            atomically_update_sent_partially_to_irwin(db.clone(), report._id.clone(), missing_games)
+                .await?;
+        if let Some(updated_report) = updated_report {
+            warn!(
+                "{} > Report({:?}) > {:.1}% complete, timed out. Queued partial submission",
+                &p,
+                report._id,
+                percentage * 100f64
+            );
+            submitter.submit(updated_report, SubmissionKind::Partial);
+        }
     } else {
         info!(
             "{} > Report({:?}) > {:.1}% complete!",
@@ -264,7 +464,109 @@ async fn update_report_completeness(db: DbConn, report: Report) -> Result<()> {
     Ok(())
 }
 
-pub async fn fishnet_listener(db: DbConn, tx: broadcast::Sender<FishnetMsg>) {
+/// What a pass of `reconcile_completeness` found.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Reconciled {
+    pub jobs: u64,    // Had their analysis, but weren't marked complete
+    pub reports: u64, // Still open, so their completeness was re-evaluated
+}
+
+/// Recomputes job completeness from the stored analyses, and then report
+/// completeness, for anything we missed. Jobs can be stuck incomplete if they
+/// predate `is_complete` or if the JobCompleted message was dropped.
+///
+/// NOTE: this is also what submits reports that timed out waiting on a
+///       straggler, nothing else looks at reports that aren't progressing.
+pub async fn reconcile_completeness(
+    db: DbConn,
+    partial: PartialSubmission,
+    submitter: &Submitter,
+) -> Result<Reconciled> {
+    let p = "reconcile_completeness >";
+    let mut reconciled = Reconciled::default();
+    let mut jobs = Job::find_incomplete(db.clone()).await?;
+    while let Some(job) = jobs.next().await {
+        let job = job?;
+        if has_complete_analysis(db.clone(), job._id.clone())
+            .await
+            .with_context(|| format!("Job({})", job._id))?
+        {
+            debug!("{} Job({}) > complete", p, job._id);
+            set_complete(db.clone(), job._id.clone())
+                .await
+                .with_context(|| format!("Job({})", job._id))?;
+            reconciled.jobs += 1;
+        }
+    }
+    info!("{} {} jobs were complete", p, reconciled.jobs);
+
+    let mut reports = find_unsent_reports(db.clone()).await?;
+    while let Some(report) = reports.next().await {
+        update_report_completeness(db.clone(), report?, partial, submitter).await?;
+        reconciled.reports += 1;
+    }
+    Ok(reconciled)
+}
+
+/// What the startup recovery pass had to fix up.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Recovery {
+    pub requeued_jobs: u64,
+    pub reconciled: Reconciled,
+    pub resumed_submissions: u64,
+}
+
+/// Picks up where a previous process left off, e.g. after a deploy: takes
+/// back jobs from workers that are gone, catches up on completions we never
+/// heard about, and resumes submissions that were queued but never sent.
+///
+/// NOTE: an interrupted amendment has already cleared its missing games, so
+///       it is resumed as a complete submission.
+/// NOTE: pending submissions are resumed before reconciling, which queues the
+///       reports it finds complete and would otherwise have them sent twice.
+pub async fn recover(
+    db: DbConn,
+    partial: PartialSubmission,
+    submitter: &Submitter,
+) -> Result<Recovery> {
+    let p = "recover >";
+    let requeued_jobs = requeue_stale_jobs(db.clone()).await?;
+    let mut resumed_submissions = 0u64;
+    let mut reports = find_pending_submissions(db.clone()).await?;
+    while let Some(report) = reports.next().await {
+        let report = report?;
+        let kind = if report.missing_games.is_empty() {
+            SubmissionKind::Complete
+        } else {
+            SubmissionKind::Partial
+        };
+        info!("{} Report({:?}) > resuming {} submission", p, report._id, kind);
+        submitter.submit(report, kind);
+        resumed_submissions += 1;
+    }
+    let reconciled = reconcile_completeness(db.clone(), partial, submitter).await?;
+    let recovery = Recovery {
+        requeued_jobs,
+        reconciled,
+        resumed_submissions,
+    };
+    info!(
+        "{} requeued {} stale jobs, completed {} jobs, re-evaluated {} open reports, resumed {} submissions",
+        p,
+        recovery.requeued_jobs,
+        recovery.reconciled.jobs,
+        recovery.reconciled.reports,
+        recovery.resumed_submissions
+    );
+    Ok(recovery)
+}
+
+pub async fn fishnet_listener(
+    db: DbConn,
+    tx: broadcast::Sender<FishnetMsg>,
+    partial: PartialSubmission,
+    submitter: Submitter,
+) {
     let p = "fishnet_listener >";
     let mut should_stop: bool = false;
     let mut rx = tx.subscribe();
@@ -278,7 +580,13 @@ pub async fn fishnet_listener(db: DbConn, tx: broadcast::Sender<FishnetMsg>) {
             } else if let FishnetMsg::JobAborted(id) = msg {
                 handle_job_aborted(db.clone(), id.clone()).await;
             } else if let FishnetMsg::JobCompleted(id) = msg {
-                handle_job_completed(db.clone(), id.clone()).await;
+                let mut span = Span::start("fishnet.job_completed", SpanKind::Internal);
+                span.set("job.id", &id.to_string());
+                instrument(
+                    span,
+                    handle_job_completed(db.clone(), id.clone(), partial, &submitter),
+                )
+                .await;
             }
         } else if let Err(e) = msg {
             match e {
@@ -0,0 +1,153 @@
+// Copyright 2020 Lakin Wecker
+//
+// This file is part of lila-deepq.
+//
+// lila-deepq is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// lila-deepq is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with lila-deepq.  If not, see <https://www.gnu.org/licenses/>.
+//
+// Assembles what irwin gets to see for a completed report.
+//
+use std::collections::HashMap;
+
+use futures::stream::{self, StreamExt, TryStreamExt};
+use log::{debug, warn};
+use serde::Serialize;
+use serde_with::{serde_as, SpaceSeparator, StringWithSeparator};
+use shakmaty::{san::San, CastlingMode, Chess, Position};
+use tokio::task::spawn_blocking;
+
+use crate::db::DbConn;
+use crate::deepq::api::{find_analysis_for_job, find_game, starting_position};
+use crate::deepq::model::{Game, GameAnalysis, GameId, Provenance, Report, Score, UserId};
+use crate::error::{DomainError, Result};
+use crate::fishnet::model::Job;
+
+// NOTE: replaying long games is cpu bound, more than this just contends.
+pub const GAME_ASSEMBLY_CONCURRENCY: usize = 8;
+
+#[serde_as]
+#[derive(Serialize, Debug, Clone)]
+pub struct IrwinGame {
+    #[serde(rename = "_id")]
+    pub id: GameId,
+    pub white: Option<UserId>,
+    pub black: Option<UserId>,
+    #[serde_as(as = "StringWithSeparator::<SpaceSeparator, San>")]
+    pub pgn: Vec<San>,
+    pub emts: Vec<i32>,
+    pub analysis: Vec<Option<Score>>, // One per ply, None where it was skipped
+    pub provenance: Provenance,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct IrwinJob {
+    #[serde(rename = "playerId")]
+    pub player_id: UserId,
+    pub games: Vec<IrwinGame>,
+    #[serde(rename = "missingGames", skip_serializing_if = "Vec::is_empty")]
+    pub missing_games: Vec<GameId>, // Still being analysed, irwin should treat the job as partial
+    #[serde(rename = "excludedGames", skip_serializing_if = "Vec::is_empty")]
+    pub excluded_games: Vec<GameId>, // Left out by a moderator as irrelevant
+    pub amended: bool, // Supersedes an earlier partial submission
+}
+
+/// Replays the game to convert it back to SAN, alongside the analysis scores.
+pub fn irwin_game(game: Game, analysis: Option<GameAnalysis>) -> Result<IrwinGame> {
+    let mut pos: Chess = starting_position(game.clone())
+        .position(CastlingMode::Standard)
+        .map_err(|_| DomainError::IllegalPosition)?;
+    let mut pgn = Vec::with_capacity(game.pgn.len());
+    for uci in game.pgn.iter() {
+        let m = uci.to_move(&pos).map_err(|_| DomainError::IllegalPosition)?;
+        pgn.push(San::from_move(&pos, &m));
+        pos.play_unchecked(&m);
+    }
+    let analysis = analysis
+        .map(|analysis| {
+            analysis
+                .analysis
+                .iter()
+                .map(|ply| ply.as_ref().and_then(|ply| ply.score()))
+                .collect()
+        })
+        .unwrap_or_default();
+    Ok(IrwinGame {
+        id: game._id,
+        white: game.white,
+        black: game.black,
+        pgn,
+        emts: game.emts,
+        analysis,
+        provenance: game.provenance,
+    })
+}
+
+/// Converts the games concurrently, returning them ordered by game id so
+/// the output doesn't depend on which conversion finished first.
+pub async fn assemble_games(
+    games: Vec<(Game, Option<GameAnalysis>)>,
+    concurrency: usize,
+) -> Result<Vec<IrwinGame>> {
+    let mut games: Vec<IrwinGame> = stream::iter(games)
+        .map(|(game, analysis)| async move { spawn_blocking(move || irwin_game(game, analysis)).await? })
+        .buffer_unordered(concurrency.max(1))
+        .try_collect()
+        .await?;
+    games.sort_by(|a, b| a.id.0.cmp(&b.id.0));
+    Ok(games)
+}
+
+async fn game_with_analysis(db: DbConn, job: Job) -> Result<Option<(Game, Option<GameAnalysis>)>> {
+    if let Some(reason) = job.skip_reason {
+        debug!("game_with_analysis > Game({}) > skipped: {}", job.game_id, reason);
+        return Ok(None);
+    }
+    let game = match find_game(db.clone(), job.game_id.clone()).await? {
+        Some(game) => game,
+        None => {
+            warn!("game_with_analysis > Game({}) > missing", job.game_id);
+            return Ok(None);
+        }
+    };
+    Ok(Some((game, find_analysis_for_job(db, job._id).await?)))
+}
+
+pub async fn irwin_job_from_report(db: DbConn, report: Report) -> Result<IrwinJob> {
+    let p = "irwin_job_from_report >";
+    let mut jobs: Vec<Job> = Job::find_by_report(db.clone(), report.clone())
+        .await?
+        .try_collect()
+        .await?;
+    // NOTE: follow up analysis is deeper, so it supersedes the original.
+    jobs.sort_by_key(|job| job.follow_up);
+    let by_game: HashMap<String, (Game, Option<GameAnalysis>)> = stream::iter(jobs)
+        .map(|job| game_with_analysis(db.clone(), job))
+        .buffered(GAME_ASSEMBLY_CONCURRENCY)
+        .try_filter_map(|game| async move { Ok(game) })
+        .map_ok(|(game, analysis)| (game._id.0.clone(), (game, analysis)))
+        .try_collect()
+        .await?;
+    let by_game: Vec<(Game, Option<GameAnalysis>)> = by_game
+        .into_values()
+        .filter(|(game, _)| !report.missing_games.iter().any(|missing| missing.0 == game._id.0))
+        .filter(|(game, _)| !report.excluded_games.iter().any(|excluded| excluded.0 == game._id.0))
+        .collect();
+    debug!("{} Report({}) > {} games", p, report._id, by_game.len());
+    Ok(IrwinJob {
+        player_id: report.user_id,
+        games: assemble_games(by_game, GAME_ASSEMBLY_CONCURRENCY).await?,
+        missing_games: report.missing_games,
+        excluded_games: report.excluded_games,
+        amended: false,
+    })
+}
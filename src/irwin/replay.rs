@@ -0,0 +1,136 @@
+// Copyright 2021 Lakin Wecker
+//
+// This file is part of lila-deepq.
+//
+// lila-deepq is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// lila-deepq is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with lila-deepq.  If not, see <https://www.gnu.org/licenses/>.
+
+//
+// Replays a recorded irwin stream into the ingest pipeline, so that ingest
+// changes can be tried against production-shaped traffic.
+//
+use std::path::Path;
+use std::str::FromStr;
+
+use chrono::prelude::*;
+use log::{info, warn};
+use serde::Deserialize;
+use tokio::fs::File;
+use tokio::io::{AsyncBufReadExt, BufReader};
+use tokio::time::{sleep_until, Duration, Instant};
+
+use crate::db::DbConn;
+use crate::deepq::model::Provenance;
+use crate::error::{DomainError, Error, Result};
+use crate::irwin::api::add_to_queue;
+use crate::irwin::stream::Msg;
+
+/// How much faster than recorded a capture is replayed, e.g. "10x".
+#[derive(Debug, Clone, Copy)]
+pub struct Speed(pub f64);
+
+impl FromStr for Speed {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Speed> {
+        let speed: f64 = s
+            .trim_end_matches('x')
+            .parse()
+            .map_err(|_| DomainError::Invalid(format!("speed {:?}", s)))?;
+        if !speed.is_finite() || speed <= 0f64 {
+            return Err(DomainError::Invalid(format!("speed {:?}", s)).into());
+        }
+        Ok(Speed(speed))
+    }
+}
+
+/// A line of a capture, the message as lila sent it and when it arrived.
+///
+/// NOTE: lines that are bare messages are replayed straight away.
+#[derive(Deserialize)]
+struct Captured {
+    at: DateTime<Utc>,
+    msg: serde_json::Value,
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct ReplaySummary {
+    pub lines: u64,
+    pub requests: u64,
+    pub keep_alives: u64,
+    pub unparseable: u64,
+    pub cooling_down: u64, // Requests skipped, their user was screened recently
+    pub failed: u64, // Requests the ingest pipeline refused
+}
+
+fn parse(line: &str) -> Result<(Option<DateTime<Utc>>, Msg)> {
+    match serde_json::from_str::<Captured>(line) {
+        Ok(captured) => Ok((Some(captured.at), serde_json::from_value(captured.msg)?)),
+        Err(_) => Ok((None, Msg::from_str(line)?)),
+    }
+}
+
+/// Feeds every request in the capture to the ingest pipeline, keeping the
+/// recorded gaps between them, divided by the speed. A request that fails
+/// to ingest is counted and the replay goes on.
+pub async fn replay(
+    db: DbConn,
+    path: &Path,
+    speed: Speed,
+    screening_cooldown: chrono::Duration,
+) -> Result<ReplaySummary> {
+    let p = "replay >";
+    let mut summary = ReplaySummary::default();
+    let mut lines = BufReader::new(File::open(path).await?).lines();
+    let mut started: Option<(DateTime<Utc>, Instant)> = None;
+    while let Some(line) = lines.next_line().await? {
+        if line.trim().is_empty() {
+            continue;
+        }
+        summary.lines += 1;
+        let (at, msg) = match parse(&line) {
+            Ok(parsed) => parsed,
+            Err(err) => {
+                warn!("{} line {} > unparseable: {}", p, summary.lines, err);
+                summary.unparseable += 1;
+                continue;
+            }
+        };
+        if let Some(at) = at {
+            let (first_at, first_instant) = *started.get_or_insert((at, Instant::now()));
+            let recorded = (at - first_at).to_std().unwrap_or_default();
+            sleep_until(first_instant + Duration::from_secs_f64(recorded.as_secs_f64() / speed.0)).await;
+        }
+        let request = match msg {
+            Msg::KeepAlive(_) => {
+                summary.keep_alives += 1;
+                continue;
+            }
+            Msg::Request(request) => request,
+        };
+        summary.requests += 1;
+        let user_id = request.user.id.clone();
+        match add_to_queue(db.clone(), request, Provenance::LichessStream, screening_cooldown).await {
+            Ok(Some(report_id)) => info!("{} line {} > {} > Report({})", p, summary.lines, user_id, report_id),
+            Ok(None) => {
+                info!("{} line {} > {} > cooling down", p, summary.lines, user_id);
+                summary.cooling_down += 1;
+            }
+            Err(err) => {
+                warn!("{} line {} > {} > {}", p, summary.lines, user_id, err);
+                summary.failed += 1;
+            }
+        }
+    }
+    Ok(summary)
+}
@@ -17,12 +17,15 @@
 //
 //
 
-use std::io::{Error as IoError, ErrorKind};
+use std::collections::HashMap;
+use std::io::Error as IoError;
 use std::result::Result as StdResult;
 use std::str::FromStr;
+use std::sync::{Arc, Mutex, MutexGuard};
 
-// use log::debug;
+use chrono::prelude::*;
 use futures::stream::Stream;
+use log::{debug, error, warn};
 use serde::{Deserialize, Serialize};
 use tokio::{io::AsyncBufReadExt, time::Duration};
 use tokio_stream::{wrappers::LinesStream, StreamExt};
@@ -30,6 +33,8 @@ use tokio_util::io::StreamReader;
 
 use crate::error::{Error, Result};
 use crate::irwin::api::Request;
+use crate::metrics::Metrics;
+use crate::telemetry::{self, SpanContext};
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct KeepAlive {
@@ -48,28 +53,155 @@ impl FromStr for Msg {
     type Err = Error;
 
     fn from_str(s: &str) -> StdResult<Self, Self::Err> {
-        Ok(serde_json::from_str(&s)?)
+        Ok(serde_json::from_str(s)?)
     }
 }
 
-pub async fn listener(url: &str, api_key: &str) -> Result<impl Stream<Item = Result<Msg>>> {
+/// How often repeated parse errors are summarized, instead of logged one by one.
+const SUMMARY_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+// NOTE: enough to recognise the message, a malformed line can be huge.
+const MAX_LOGGED_LINE: usize = 500;
+
+// NOTE: past this many kinds of error, the rest are counted together.
+const MAX_FINGERPRINTS: usize = 100;
+
+pub const PARSE_ERRORS_METRIC: &str = "irwin_stream.parse_errors";
+
+#[derive(Debug, Clone)]
+pub struct ParseErrorSeen {
+    pub count: u64, // Since the last summary
+    pub first_seen: DateTime<Utc>,
+    pub last_seen: DateTime<Utc>,
+    pub message: String, // Of the first occurrence
+    summarized: bool,
+}
+
+/// Identifies an error regardless of where in the line it happened or what
+/// the line held, so the same malformed message is recognised on every
+/// reconnect. serde quotes what it found between backticks.
+fn fingerprint(err: &Error) -> u32 {
+    let mut quoted = false;
+    let message: String = err
+        .to_string()
+        .chars()
+        .filter(|&c| {
+            if c == '`' {
+                quoted = !quoted;
+            }
+            !quoted && !c.is_ascii_digit()
+        })
+        .collect();
+    crc32fast::hash(message.as_bytes())
+}
+
+/// The stream's parse errors, counted between summaries. Survives reconnects.
+#[derive(Clone)]
+pub struct ParseErrors {
+    seen: Arc<Mutex<HashMap<u32, ParseErrorSeen>>>, // By fingerprint
+    metrics: Metrics,
+}
+
+impl ParseErrors {
+    pub fn new(metrics: Metrics) -> ParseErrors {
+        ParseErrors {
+            seen: Arc::new(Mutex::new(HashMap::new())),
+            metrics,
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, HashMap<u32, ParseErrorSeen>> {
+        self.seen.lock().expect("parse errors lock poisoned")
+    }
+
+    /// Logs the first occurrence of each kind of parse error in full, and the
+    /// repeats only as counts in the periodic summary.
+    fn record(&self, line: &str, err: &Error) {
+        self.metrics.count(PARSE_ERRORS_METRIC, 1);
+        let now = Utc::now();
+        let mut seen = self.lock();
+        let mut fingerprint = fingerprint(err);
+        if !seen.contains_key(&fingerprint) && seen.len() >= MAX_FINGERPRINTS {
+            fingerprint = 0;
+        }
+        let seen = seen.entry(fingerprint).or_insert_with(|| {
+            let line: String = line.chars().take(MAX_LOGGED_LINE).collect();
+            error!("Error parsing message from lichess: {}\n{}", err, line);
+            ParseErrorSeen {
+                count: 0,
+                first_seen: now,
+                last_seen: now,
+                message: err.to_string(),
+                summarized: false,
+            }
+        });
+        seen.count += 1;
+        seen.last_seen = now;
+        if seen.count > 1 {
+            debug!("Repeated stream parse error ({} times): {}", seen.count, err);
+        }
+    }
+
+    /// Logs the errors repeated since the last summary, then counts afresh.
+    /// Errors that didn't happen since are forgotten.
+    pub fn summarize(&self) {
+        let mut seen = self.lock();
+        seen.retain(|_, seen| seen.count > 0);
+        for seen in seen.values_mut() {
+            // NOTE: a lone first occurrence was already logged in full.
+            if seen.count > 1 || seen.summarized {
+                warn!(
+                    "Stream parse error seen {} times since the last summary, first at {}, last at {}: {}",
+                    seen.count, seen.first_seen, seen.last_seen, seen.message
+                );
+            }
+            seen.count = 0;
+            seen.summarized = true;
+        }
+    }
+
+    pub async fn summarize_periodically(self) {
+        loop {
+            tokio::time::sleep(SUMMARY_INTERVAL).await;
+            self.summarize();
+        }
+    }
+}
+
+/// Lines that don't parse are logged and skipped, only errors reading the
+/// stream itself are passed along.
+pub async fn listener(
+    url: &str,
+    api_key: &str,
+    parse_errors: ParseErrors,
+) -> Result<impl Stream<Item = Result<Msg>>> {
     let client = reqwest::Client::builder()
         .tcp_keepalive(Duration::from_millis(1000))
         .build()?;
-    let response = client
+    let request = client
         .get(url)
         .header("User-Agent", "lila-deepq")
-        .header("Authorization", format!("Bearer {}", api_key))
+        .header("Authorization", format!("Bearer {}", api_key));
+    let response = telemetry::propagate(request, SpanContext::current())
         .send()
         .await?;
 
     let stream = response
         .bytes_stream()
-        .map(|i| i.map_err(|e| IoError::new(ErrorKind::Other, e)));
+        .map(|i| i.map_err(IoError::other));
     let stream = LinesStream::new(StreamReader::new(stream).lines());
-    let stream = Box::new(stream.map(|line| {
-        let line = line?;
-        Ok(FromStr::from_str(&line)?)
+    let stream = Box::new(stream.filter_map(move |line| {
+        let line = match line {
+            Ok(line) => line,
+            Err(err) => return Some(Err(err.into())),
+        };
+        match Msg::from_str(&line) {
+            Ok(msg) => Some(Ok(msg)),
+            Err(err) => {
+                parse_errors.record(&line, &err);
+                None
+            }
+        }
     }));
     Ok(stream)
 }
@@ -0,0 +1,270 @@
+// Copyright 2020 Lakin Wecker
+//
+// This file is part of lila-deepq.
+//
+// lila-deepq is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// lila-deepq is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with lila-deepq.  If not, see <https://www.gnu.org/licenses/>.
+//
+// Assembling a report for irwin reads every game and analysis it covers, so
+// after an incident, when many reports complete at once, doing it inline in
+// the fishnet listener backs everything up. Instead the listener only claims
+// the report and queues it here, to a few workers that take the most urgent
+// report first.
+//
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+use std::sync::atomic::{AtomicUsize, Ordering as AtomicOrdering};
+use std::sync::{Arc, Mutex};
+
+use chrono::prelude::*;
+use log::{error, info, warn};
+use mongodb::bson::{oid::ObjectId, DateTime as BsonDateTime};
+use tokio::sync::Notify;
+use tokio::time::Instant;
+
+use crate::crypto::sha256_hex;
+use crate::db::DbConn;
+use crate::deepq::api::{insert_irwin_submission, mark_submitted};
+use crate::deepq::model::{IrwinSubmission, Report, SubmissionKind};
+use crate::deepq::policy::precedence;
+use crate::deepq::score::{plies_in_perspective, Perspective};
+use crate::error::Result;
+use crate::irwin::job::{irwin_job_from_report, IrwinJob};
+use crate::metrics::Metrics;
+use crate::supervisor::supervise;
+use crate::telemetry::{instrument, Span, SpanKind};
+
+// NOTE: enough to tell errors apart, without storing whole response bodies.
+const ERROR_EXCERPT_CHARS: usize = 500;
+
+#[derive(Debug, Clone)]
+struct Queued {
+    precedence: i32,
+    sequence: u64, // Oldest first, among reports of the same precedence
+    report: Report,
+    kind: SubmissionKind,
+}
+
+impl PartialEq for Queued {
+    fn eq(&self, other: &Queued) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Queued {}
+
+impl PartialOrd for Queued {
+    fn partial_cmp(&self, other: &Queued) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Queued {
+    fn cmp(&self, other: &Queued) -> Ordering {
+        self.precedence
+            .cmp(&other.precedence)
+            .then_with(|| other.sequence.cmp(&self.sequence))
+    }
+}
+
+#[derive(Default)]
+struct Queue {
+    heap: BinaryHeap<Queued>,
+    sequence: u64,
+}
+
+/// A pool of workers submitting reports to irwin, most urgent first.
+#[derive(Clone)]
+pub struct Submitter {
+    queue: Arc<Mutex<Queue>>,
+    notify: Arc<Notify>,
+    idle: Arc<Notify>,         // Signalled when nothing is pending anymore
+    pending: Arc<AtomicUsize>, // Queued or being submitted
+    perspective: Perspective,  // Of the scores irwin gets
+}
+
+impl Submitter {
+    /// Starts `concurrency` workers, recording each submission in `metrics`.
+    pub fn start(db: DbConn, concurrency: usize, perspective: Perspective, metrics: Metrics) -> Submitter {
+        let submitter = Submitter {
+            queue: Arc::new(Mutex::new(Queue::default())),
+            notify: Arc::new(Notify::new()),
+            idle: Arc::new(Notify::new()),
+            pending: Arc::new(AtomicUsize::new(0)),
+            perspective,
+        };
+        for _ in 0..concurrency.max(1) {
+            let submitter = submitter.clone();
+            let (db, metrics) = (db.clone(), metrics.clone());
+            supervise("irwin_submitter", move || {
+                submitter.clone().work(db.clone(), metrics.clone())
+            });
+        }
+        submitter
+    }
+
+    pub fn submit(&self, report: Report, kind: SubmissionKind) {
+        let precedence = precedence(
+            report.origin.clone(),
+            &report.signals.clone().unwrap_or_default(),
+        );
+        {
+            let mut queue = self.queue.lock().expect("submission queue lock poisoned");
+            queue.sequence += 1;
+            let sequence = queue.sequence;
+            queue.heap.push(Queued {
+                precedence,
+                sequence,
+                report,
+                kind,
+            });
+        }
+        self.pending.fetch_add(1, AtomicOrdering::SeqCst);
+        self.notify.notify_one();
+    }
+
+    /// Waits until everything submitted so far has been sent.
+    pub async fn drained(&self) {
+        // NOTE: notify_one keeps a permit when no one is waiting yet, so the
+        //       queue emptying between the check and the wait isn't missed.
+        while self.pending.load(AtomicOrdering::SeqCst) > 0 {
+            self.idle.notified().await;
+        }
+        // NOTE: passes the wake up on to anyone else waiting.
+        self.idle.notify_one();
+    }
+
+    fn pop(&self) -> Option<Queued> {
+        self.queue
+            .lock()
+            .expect("submission queue lock poisoned")
+            .heap
+            .pop()
+    }
+
+    async fn work(self, db: DbConn, metrics: Metrics) {
+        loop {
+            match self.pop() {
+                Some(queued) => {
+                    let _sending = Sending(&self);
+                    send(db.clone(), &metrics, self.perspective, queued).await;
+                }
+                None => self.notify.notified().await,
+            }
+        }
+    }
+}
+
+/// A report being sent, it's no longer pending once sent, or if sending panicked.
+struct Sending<'a>(&'a Submitter);
+
+impl Drop for Sending<'_> {
+    fn drop(&mut self) {
+        if self.0.pending.fetch_sub(1, AtomicOrdering::SeqCst) == 1 {
+            self.0.idle.notify_one();
+        }
+    }
+}
+
+async fn send(db: DbConn, metrics: &Metrics, perspective: Perspective, queued: Queued) {
+    let p = "Submitter::send >";
+    let started = Instant::now();
+    let (report, kind) = (queued.report, queued.kind);
+    let mut span = Span::start("irwin.submit", SpanKind::Client);
+    span.set("report.id", &report._id.to_string());
+    span.set("submission.kind", &kind.to_string());
+    let result = instrument(span, irwin_job_from_report(db.clone(), report.clone()))
+        .await
+        .map(|mut irwin_job| {
+            irwin_job.amended = kind == SubmissionKind::Amendment;
+            for game in irwin_job.games.iter_mut() {
+                game.analysis = plies_in_perspective(std::mem::take(&mut game.analysis), perspective);
+            }
+            irwin_job
+        });
+    metrics.record(
+        format!("irwin.submit.{}", kind),
+        started.elapsed(),
+        result.is_err(),
+    );
+    if let Err(err) = record(db.clone(), &report, kind, started, &result).await {
+        warn!("{} Report({:?}) > unable to record the attempt: {:?}", p, report._id, err);
+    }
+    if result.is_ok() {
+        if let Err(err) = mark_submitted(db, report._id.clone()).await {
+            warn!("{} Report({:?}) > unable to mark as submitted: {:?}", p, report._id, err);
+        }
+    }
+    match result {
+        Err(err) => error!(
+            "{} Report({:?}) > unable to submit {} to irwin: {:?}",
+            p, report._id, kind, err
+        ),
+        Ok(irwin_job) => match kind {
+            SubmissionKind::Complete => info!(
+                "{} Report({:?}) > complete. Submitted {} games to irwin in {:?}!",
+                p,
+                report._id,
+                irwin_job.games.len(),
+                started.elapsed()
+            ),
+            SubmissionKind::Partial => warn!(
+                "{} Report({:?}) > timed out. Submitted {} games to irwin in {:?}, {} missing!",
+                p,
+                report._id,
+                irwin_job.games.len(),
+                started.elapsed(),
+                irwin_job.missing_games.len()
+            ),
+            SubmissionKind::Amendment => info!(
+                "{} Report({:?}) > stragglers complete. Amended with {} games in {:?}!",
+                p,
+                report._id,
+                irwin_job.games.len(),
+                started.elapsed()
+            ),
+        },
+    }
+}
+
+/// Logs the attempt to deepq_irwin_submissions, with a hash of the payload that was built.
+async fn record(
+    db: DbConn,
+    report: &Report,
+    kind: SubmissionKind,
+    started: Instant,
+    result: &Result<IrwinJob>,
+) -> Result<()> {
+    let payload = match result {
+        Ok(irwin_job) => Some(serde_json::to_vec(irwin_job)?),
+        Err(_) => None,
+    };
+    insert_irwin_submission(
+        db,
+        IrwinSubmission {
+            _id: ObjectId::new(),
+            report_id: report._id.clone(),
+            kind,
+            date_attempted: BsonDateTime(Utc::now()),
+            latency_ms: started.elapsed().as_millis() as i64,
+            payload_sha256: payload.as_deref().map(sha256_hex),
+            payload_bytes: payload.map(|payload| payload.len() as i64),
+            response_status: None,
+            error: result
+                .as_ref()
+                .err()
+                .map(|err| err.to_string().chars().take(ERROR_EXCERPT_CHARS).collect()),
+        },
+    )
+    .await
+}
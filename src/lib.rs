@@ -15,10 +15,27 @@
 // You should have received a copy of the GNU Affero General Public License
 // along with lila-deepq.  If not, see <https://www.gnu.org/licenses/>.
 
+#[cfg(feature = "web")]
+pub mod admin;
+pub mod config;
+pub mod crypto;
 pub mod db;
 pub mod deepq;
 pub mod error;
 pub mod fishnet;
+pub mod flags;
 pub mod irwin;
+#[cfg(feature = "web")]
 pub mod http;
 pub mod lichess;
+pub mod metrics;
+pub mod pause;
+pub mod scheduler;
+#[cfg(feature = "cli-admin")]
+pub mod seed;
+#[cfg(feature = "cli-admin")]
+pub mod shell;
+pub mod supervisor;
+pub mod telemetry;
+#[cfg(feature = "embedded-worker")]
+pub mod worker;
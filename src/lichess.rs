@@ -15,3 +15,8 @@
 // You should have received a copy of the GNU Affero General Public License
 // along with lila-deepq.  If not, see <https://www.gnu.org/licenses/>.
 
+//
+pub mod api;
+pub mod backpressure;
+pub mod leaderboard;
+pub mod status;
@@ -0,0 +1,23 @@
+// Copyright 2020 Lakin Wecker
+//
+// This file is part of lila-deepq.
+//
+// lila-deepq is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// lila-deepq is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with lila-deepq.  If not, see <https://www.gnu.org/licenses/>.
+
+// NOTE: lichess hands us irwin requests over the irwin stream, so the intake
+//       lives in irwin::api. These names are kept so integrations that were
+//       written against lichess::api still enqueue work through the same path.
+pub use crate::irwin::api::{
+    add_to_queue, Game as IrwinGame, Request as IrwinRequest, User as IrwinUser,
+};
@@ -0,0 +1,79 @@
+// Copyright 2020 Lakin Wecker
+//
+// This file is part of lila-deepq.
+//
+// lila-deepq is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// lila-deepq is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with lila-deepq.  If not, see <https://www.gnu.org/licenses/>.
+//
+// Tells lila to slow down sending irwin requests while our backlog is past
+// its high watermark, closing the loop on overload control.
+//
+use log::{info, warn};
+use tokio::time::{Duration, Instant};
+
+use crate::deepq::backlog::Advisory;
+use crate::error::Result;
+use crate::telemetry::{self, SpanContext};
+
+// NOTE: lila only needs to hear it once in a while, not once per request.
+const MIN_INTERVAL: Duration = Duration::from_secs(60);
+
+pub struct Backpressure {
+    client: reqwest::Client,
+    url: Option<String>, // None just logs, for lila deployments that can't listen
+    api_key: String,
+    last_signalled: Option<Instant>,
+}
+
+impl Backpressure {
+    pub fn new(url: Option<String>, api_key: String) -> Result<Backpressure> {
+        Ok(Backpressure {
+            client: reqwest::Client::builder()
+                .timeout(std::time::Duration::from_secs(10))
+                .build()?,
+            url,
+            api_key,
+            last_signalled: None,
+        })
+    }
+
+    /// Posts the advisory to lila, at most once every MIN_INTERVAL.
+    pub async fn signal(&mut self, advisory: &Advisory) -> Result<()> {
+        let p = "Backpressure::signal >";
+        if self
+            .last_signalled
+            .is_some_and(|last| last.elapsed() < MIN_INTERVAL)
+        {
+            return Ok(());
+        }
+        self.last_signalled = Some(Instant::now());
+        warn!(
+            "{} {} jobs queued, asking lila to retry after {}s",
+            p, advisory.queued, advisory.retry_after
+        );
+        if let Some(url) = &self.url {
+            let request = self
+                .client
+                .post(url)
+                .header("User-Agent", "lila-deepq")
+                .header("Authorization", format!("Bearer {}", self.api_key))
+                .json(advisory);
+            telemetry::propagate(request, SpanContext::current())
+                .send()
+                .await?
+                .error_for_status()?;
+            info!("{} signalled {}", p, url);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,276 @@
+// Copyright 2020 Lakin Wecker
+//
+// This file is part of lila-deepq.
+//
+// lila-deepq is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// lila-deepq is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with lila-deepq.  If not, see <https://www.gnu.org/licenses/>.
+//
+// Screens the top of the leaderboards periodically, rather than waiting for a
+// moderator to remember to ask.
+//
+use std::str::FromStr;
+
+use chrono::{prelude::*, Duration};
+use log::{debug, info, warn};
+use rand::seq::SliceRandom;
+use serde::Deserialize;
+use shakmaty::san::San;
+use tokio::time::sleep;
+
+use crate::db::DbConn;
+use crate::deepq::api::count_recent_reports_for_user;
+use crate::deepq::model::{GameId, Provenance, ReportOrigin, Score, UserId};
+use crate::error::Result;
+use crate::lichess::api::{add_to_queue, IrwinGame, IrwinRequest, IrwinUser};
+use crate::telemetry::{self, SpanContext};
+
+// NOTE: lichess asks API clients to make one request at a time, and to pause.
+const REQUEST_PAUSE: std::time::Duration = std::time::Duration::from_secs(1);
+
+/// How many recent games to sample from, per player.
+pub const RECENT_GAMES: usize = 30;
+
+#[derive(Deserialize, Debug)]
+struct Leaderboard {
+    users: Vec<LeaderboardUser>,
+}
+
+#[derive(Deserialize, Debug)]
+struct LeaderboardUser {
+    id: String,
+    title: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+struct LightUser {
+    id: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct Player {
+    user: Option<LightUser>,
+}
+
+#[derive(Deserialize, Debug)]
+struct Players {
+    white: Player,
+    black: Player,
+}
+
+#[derive(Deserialize, Debug)]
+struct Clock {
+    increment: i32, // Seconds
+}
+
+#[derive(Deserialize, Debug)]
+struct Eval {
+    eval: Option<i64>,
+    mate: Option<i64>,
+}
+
+#[derive(Deserialize, Debug)]
+struct ExportedGame {
+    id: String,
+    variant: String,
+    moves: String,
+    players: Players,
+    clock: Option<Clock>,
+    clocks: Option<Vec<i32>>, // Remaining centiseconds after each ply
+    analysis: Option<Vec<Eval>>,
+}
+
+/// Move times in centiseconds, from the clock after each ply.
+fn emts(clocks: &[i32], increment: i32) -> Vec<i32> {
+    clocks
+        .iter()
+        .enumerate()
+        .map(|(ply, clock)| match ply.checked_sub(2).and_then(|before| clocks.get(before)) {
+            Some(before) => (before - clock + increment * 100).max(0),
+            None => 0,
+        })
+        .collect()
+}
+
+impl ExportedGame {
+    fn into_irwin_game(self) -> Option<IrwinGame> {
+        if self.variant != "standard" {
+            return None;
+        }
+        let pgn = self
+            .moves
+            .split_whitespace()
+            .map(San::from_str)
+            .collect::<std::result::Result<Vec<San>, _>>()
+            .ok()?;
+        let analysis = self.analysis.and_then(|evals| {
+            evals
+                .into_iter()
+                .map(|eval| match (eval.eval, eval.mate) {
+                    (Some(cp), _) => Some(Score::Cp(cp)),
+                    (None, Some(mate)) => Some(Score::Mate(mate)),
+                    _ => None,
+                })
+                .collect::<Option<Vec<Score>>>()
+        });
+        let increment = self.clock.map(|clock| clock.increment).unwrap_or(0);
+        Some(IrwinGame {
+            id: GameId(self.id),
+            white: UserId::from(self.players.white.user?.id),
+            black: UserId::from(self.players.black.user?.id),
+            emts: self.clocks.map(|clocks| emts(&clocks, increment)),
+            pgn,
+            analysis,
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SweeperOpts {
+    pub api_url: String,
+    pub api_key: Option<String>,
+    pub perf_types: Vec<String>,
+    pub players_per_leaderboard: u32,
+    pub games_per_player: usize,
+    pub screen_every: Duration, // Players screened more recently than this are skipped
+    pub screening_cooldown: Duration,
+}
+
+pub struct Sweeper {
+    opts: SweeperOpts,
+    client: reqwest::Client,
+}
+
+impl Sweeper {
+    pub fn new(opts: SweeperOpts) -> Result<Sweeper> {
+        Ok(Sweeper {
+            opts: SweeperOpts {
+                api_url: opts.api_url.trim_end_matches('/').to_string(),
+                ..opts
+            },
+            client: reqwest::Client::builder()
+                .timeout(std::time::Duration::from_secs(30))
+                .build()?,
+        })
+    }
+
+    fn get(&self, url: String, accept: &str) -> reqwest::RequestBuilder {
+        let request = self
+            .client
+            .get(&url)
+            .header("User-Agent", "lila-deepq")
+            .header("Accept", accept);
+        let request = telemetry::propagate(request, SpanContext::current());
+        match &self.opts.api_key {
+            Some(api_key) => request.header("Authorization", format!("Bearer {}", api_key)),
+            None => request,
+        }
+    }
+
+    async fn leaderboard(&self, perf_type: &str) -> Result<Vec<LeaderboardUser>> {
+        let url = format!(
+            "{}/api/player/top/{}/{}",
+            self.opts.api_url, self.opts.players_per_leaderboard, perf_type
+        );
+        let leaderboard: Leaderboard = self
+            .get(url, "application/vnd.lichess.v3+json")
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        Ok(leaderboard.users)
+    }
+
+    async fn recent_games(&self, user_id: &str, perf_type: &str) -> Result<Vec<IrwinGame>> {
+        let url = format!(
+            "{}/api/games/user/{}?max={}&rated=true&perfType={}&clocks=true&evals=true",
+            self.opts.api_url, user_id, RECENT_GAMES, perf_type
+        );
+        let body = self
+            .get(url, "application/x-ndjson")
+            .send()
+            .await?
+            .error_for_status()?
+            .text()
+            .await?;
+        let mut games = Vec::new();
+        for line in body.lines().filter(|line| !line.trim().is_empty()) {
+            let game: ExportedGame = serde_json::from_str(line)?;
+            match game.into_irwin_game() {
+                Some(game) => games.push(game),
+                None => debug!("Sweeper::recent_games > {} > skipped a game", user_id),
+            }
+        }
+        Ok(games)
+    }
+
+    async fn screen(&self, db: DbConn, user: &LeaderboardUser, perf_type: &str) -> Result<bool> {
+        let user_id = UserId::from(user.id.clone());
+        let since = Utc::now() - self.opts.screen_every;
+        if count_recent_reports_for_user(db.clone(), user_id.clone(), since).await? > 0 {
+            debug!("Sweeper::screen > {} > screened recently", user_id);
+            return Ok(false);
+        }
+        let mut games = self.recent_games(&user.id, perf_type).await?;
+        sleep(REQUEST_PAUSE).await;
+        if games.is_empty() {
+            return Ok(false);
+        }
+        games.shuffle(&mut rand::thread_rng());
+        games.truncate(self.opts.games_per_player);
+        let request = IrwinRequest {
+            t: "request".to_string(),
+            origin: ReportOrigin::Leaderboard,
+            user: IrwinUser {
+                id: user_id.clone(),
+                titled: user.title.is_some(),
+                engine: false,
+                games: games.len() as i32,
+            },
+            games,
+            score: None,
+        };
+        match add_to_queue(db, request, Provenance::LichessExport, self.opts.screening_cooldown).await? {
+            Some(report_id) => {
+                info!("Sweeper::screen > {} > Report({})", user_id, report_id);
+                Ok(true)
+            }
+            None => {
+                debug!("Sweeper::screen > {} > cooling down", user_id);
+                Ok(false)
+            }
+        }
+    }
+
+    /// Queues a Leaderboard report for every player at the top of the
+    /// leaderboards who hasn't been screened recently. Returns how many.
+    pub async fn sweep(&self, db: DbConn) -> Result<usize> {
+        let p = "Sweeper::sweep >";
+        let mut queued = 0;
+        for perf_type in self.opts.perf_types.iter() {
+            let users = self.leaderboard(perf_type).await?;
+            sleep(REQUEST_PAUSE).await;
+            info!("{} {} > {} players", p, perf_type, users.len());
+            for user in users.iter() {
+                match self.screen(db.clone(), user, perf_type).await {
+                    Ok(true) => queued += 1,
+                    Ok(false) => {}
+                    // NOTE: one player's games shouldn't stop the sweep.
+                    Err(err) => warn!("{} {} > {:?}", p, user.id, err),
+                }
+            }
+        }
+        info!("{} queued {} reports", p, queued);
+        Ok(queued)
+    }
+}
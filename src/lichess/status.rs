@@ -0,0 +1,150 @@
+// Copyright 2020 Lakin Wecker
+//
+// This file is part of lila-deepq.
+//
+// lila-deepq is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// lila-deepq is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with lila-deepq.  If not, see <https://www.gnu.org/licenses/>.
+//
+// Games occasionally get deleted, or the player gets caught, before we get
+// around to analysing them. Asking lichess first saves the work. Games we
+// don't have at all are remembered for a while too, so hopeless lookups
+// aren't repeated on every acquire.
+//
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use log::debug;
+use serde::Deserialize;
+use tokio::sync::RwLock;
+use tokio::time::{Duration, Instant};
+
+use crate::db::DbConn;
+use crate::deepq::api::find_game;
+use crate::deepq::model::{Game, GameId, Provenance};
+use crate::error::Result;
+use crate::telemetry::{self, SpanContext};
+
+// NOTE: a game's status rarely changes once it's over.
+const CACHE_TTL: Duration = Duration::from_secs(10 * 60);
+
+/// How long a game that couldn't be found is assumed to still be missing.
+const MISSING_GAME_TTL: Duration = Duration::from_secs(10 * 60);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, strum_macros::Display)]
+#[strum(serialize_all = "snake_case")]
+pub enum SkipReason {
+    Deleted,
+    CheatDetected,
+}
+
+#[derive(Deserialize, Debug)]
+struct ExportedGame {
+    status: String,
+}
+
+/// Whether lichess knows the game: uploaded games and games with ids of our
+/// own making would all look deleted to it.
+pub fn is_lichess_game(game: &Game) -> bool {
+    let id = &game._id.0;
+    game.provenance != Provenance::PgnUpload
+        && id.len() == 8
+        && id.chars().all(|c| c.is_ascii_alphanumeric())
+}
+
+type Cache = HashMap<String, (Instant, Option<SkipReason>)>;
+
+#[derive(Clone)]
+pub struct GameStatusCache {
+    api_url: Option<String>, // Lichess isn't asked when unset
+    client: reqwest::Client,
+    cache: Arc<RwLock<Cache>>,
+    missing: Arc<RwLock<HashMap<String, Instant>>>, // When each game was found missing
+}
+
+impl GameStatusCache {
+    pub fn new(api_url: Option<String>) -> Result<GameStatusCache> {
+        Ok(GameStatusCache {
+            api_url: api_url.map(|api_url| api_url.trim_end_matches('/').to_string()),
+            client: reqwest::Client::builder()
+                .timeout(Duration::from_secs(5))
+                .build()?,
+            cache: Arc::new(RwLock::new(Cache::new())),
+            missing: Arc::new(RwLock::new(HashMap::new())),
+        })
+    }
+
+    async fn fetch(&self, api_url: &str, game_id: &GameId) -> Result<Option<SkipReason>> {
+        let request = self
+            .client
+            .get(&format!("{}/game/export/{}", api_url, game_id))
+            .header("User-Agent", "lila-deepq")
+            .header("Accept", "application/json");
+        let response = telemetry::propagate(request, SpanContext::current())
+            .send()
+            .await?;
+        if response.status() == reqwest::StatusCode::NOT_FOUND {
+            return Ok(Some(SkipReason::Deleted));
+        }
+        let game: ExportedGame = response.error_for_status()?.json().await?;
+        Ok(match game.status.as_str() {
+            "cheat" => Some(SkipReason::CheatDetected),
+            _ => None,
+        })
+    }
+
+    /// Why the game no longer needs analysis, None if it still does.
+    pub async fn skip_reason(&self, game: &Game) -> Result<Option<SkipReason>> {
+        let api_url = match &self.api_url {
+            Some(api_url) if is_lichess_game(game) => api_url,
+            _ => return Ok(None),
+        };
+        let game_id = &game._id;
+        let now = Instant::now();
+        if let Some((fetched, reason)) = self.cache.read().await.get(&game_id.0) {
+            if now.duration_since(*fetched) < CACHE_TTL {
+                return Ok(*reason);
+            }
+        }
+        let reason = self.fetch(api_url, game_id).await?;
+        let mut cache = self.cache.write().await;
+        cache.retain(|_, (fetched, _)| now.duration_since(*fetched) < CACHE_TTL);
+        cache.insert(game_id.0.clone(), (now, reason));
+        Ok(reason)
+    }
+
+    /// The game, unless it was found missing recently.
+    pub async fn find_game(&self, db: DbConn, game_id: GameId) -> Result<Option<Game>> {
+        let known_missing = self
+            .missing
+            .read()
+            .await
+            .get(&game_id.0)
+            .is_some_and(|since| since.elapsed() < MISSING_GAME_TTL);
+        if known_missing {
+            debug!("GameStatusCache::find_game > Game({}) > known missing", game_id);
+            return Ok(None);
+        }
+        let game = find_game(db, game_id.clone()).await?;
+        if game.is_none() {
+            let mut missing = self.missing.write().await;
+            missing.retain(|_, since| since.elapsed() < MISSING_GAME_TTL);
+            missing.insert(game_id.0, Instant::now());
+        }
+        Ok(game)
+    }
+
+    /// Forgets that the game was missing, so the next lookup goes to the database.
+    pub async fn forget_missing(&self, game_id: &GameId) {
+        self.missing.write().await.remove(&game_id.0);
+    }
+}
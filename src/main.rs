@@ -14,13 +14,30 @@
 // You should have received a copy of the GNU Affero General Public License
 // along with lila-deepq.  If not, see <https://www.gnu.org/licenses/>.
 
+#[cfg(feature = "web")]
+pub mod admin;
+pub mod config;
+pub mod crypto;
 pub mod db;
 pub mod deepq;
 pub mod error;
 pub mod fishnet;
+pub mod flags;
+#[cfg(feature = "web")]
 pub mod http;
 pub mod irwin;
 pub mod lichess;
+pub mod metrics;
+pub mod pause;
+pub mod scheduler;
+#[cfg(feature = "cli-admin")]
+pub mod seed;
+#[cfg(feature = "cli-admin")]
+pub mod shell;
+pub mod supervisor;
+pub mod telemetry;
+#[cfg(feature = "embedded-worker")]
+pub mod worker;
 
 extern crate clap;
 extern crate dotenv;
@@ -30,42 +47,244 @@ extern crate pretty_env_logger;
 extern crate serde_json;
 extern crate serde_with;
 
+use std::iter;
+#[cfg(feature = "web")]
 use std::net::SocketAddr;
 use std::result::Result as StdResult;
+use std::sync::Arc;
 
 use dotenv::dotenv;
+#[cfg(feature = "listener")]
 use futures::stream::StreamExt;
-use log::{debug, error, info, warn};
+use log::{debug, error, info};
+#[cfg(any(feature = "web", feature = "listener"))]
+use log::warn;
 use structopt::StructOpt;
-use tokio::time::{sleep, Duration};
+#[cfg(feature = "listener")]
+use tokio::time::sleep;
+#[cfg(any(feature = "embedded-worker", feature = "listener"))]
+use tokio::time::Duration;
+#[cfg(feature = "web")]
 use warp::Filter;
 
 #[derive(Debug, StructOpt)]
 #[structopt(name = "lila-deepq", about = "Analysis Queues for lila.")]
 enum Command {
-    DeepQWebserver(DeepQWebserver),
+    #[cfg(feature = "web")]
+    DeepQWebserver(Box<DeepQWebserver>),
+    #[cfg(feature = "listener")]
     IrwinJobListener(IrwinJobListener),
+    #[cfg(feature = "listener")]
+    ReplayStream(ReplayStream),
+    #[cfg(feature = "cli-admin")]
     FishnetNewUser(FishnetNewUser),
+    Reaper(Reaper),
+    Reconcile(Reconcile),
+    Verifier(Verifier),
+    #[cfg(feature = "cli-admin")]
+    SeedDev(SeedDev),
+    SlaMonitor(SlaMonitor),
+    LeaderboardSweeper(LeaderboardSweeper),
+    Scheduler(Box<Scheduler>),
+    #[cfg(feature = "cli-admin")]
+    NormalizeUsernames(NormalizeUsernames),
+    #[cfg(feature = "cli-admin")]
+    Shell(Shell),
+    Config(Config),
 }
 
 #[derive(Debug, StructOpt, Clone)]
 struct DatabaseOpts {
     #[structopt(long, env = "LILA_DEEPQ_MONGO_URI")]
-    mongo_uri: String,
+    mongo_uri: crypto::Secret, // May carry a password
 
     #[structopt(long, env = "LILA_DEEPQ_MONGO_DATABASE")]
     mongo_database: String,
+
+    /// A separate deployment for admin listings, exports and dashboards, instead of mongo_uri.
+    #[structopt(long, env = "LILA_DEEPQ_MONGO_ANALYTICS_URI")]
+    mongo_analytics_uri: Option<crypto::Secret>,
+
+    /// primary, secondary_preferred, secondary or nearest, for admin listings, exports and dashboards.
+    #[structopt(long, env = "LILA_DEEPQ_MONGO_ANALYTICS_READ_PREFERENCE", default_value = "primary")]
+    mongo_analytics_read_preference: db::AnalyticsReadPreference,
+
+    /// Queries that take longer than this many ms are logged and listed in the admin api.
+    #[structopt(long, env = "LILA_DEEPQ_MONGO_SLOW_QUERY_MS", default_value = "100")]
+    mongo_slow_query_ms: u64,
+}
+
+#[derive(Debug, StructOpt, Clone)]
+struct SubmitterOpts {
+    /// How many reports are assembled for irwin at once.
+    #[structopt(long, env = "LILA_DEEPQ_IRWIN_SUBMISSION_CONCURRENCY", default_value = "4")]
+    irwin_submission_concurrency: usize,
+
+    /// Whose point of view the scores sent to irwin are from, mover or white.
+    #[structopt(long, env = "LILA_DEEPQ_IRWIN_SCORE_PERSPECTIVE", default_value = "mover")]
+    irwin_score_perspective: deepq::score::Perspective,
+}
+
+impl SubmitterOpts {
+    fn start(&self, db: db::DbConn, metrics: metrics::Metrics) -> irwin::submitter::Submitter {
+        irwin::submitter::Submitter::start(
+            db,
+            self.irwin_submission_concurrency,
+            self.irwin_score_perspective,
+            metrics,
+        )
+    }
+}
+
+#[derive(Debug, StructOpt, Clone)]
+struct PartialSubmissionOpts {
+    /// Submit reports to irwin without their stragglers after this long.
+    #[structopt(long, env = "LILA_DEEPQ_PARTIAL_SUBMISSION_TIMEOUT_HOURS")]
+    partial_submission_timeout_hours: Option<i64>,
+
+    /// Resubmit partially submitted reports once the stragglers finish.
+    #[structopt(long, env = "LILA_DEEPQ_AMEND_PARTIAL_SUBMISSIONS")]
+    amend_partial_submissions: bool,
+}
+
+impl From<PartialSubmissionOpts> for irwin::api::PartialSubmission {
+    fn from(opts: PartialSubmissionOpts) -> irwin::api::PartialSubmission {
+        irwin::api::PartialSubmission {
+            timeout: opts.partial_submission_timeout_hours.map(chrono::Duration::hours),
+            amend: opts.amend_partial_submissions,
+        }
+    }
+}
+
+#[derive(Debug, StructOpt, Clone)]
+struct ServiceTimeOpts {
+    /// How many days of completions the expected service times are computed from.
+    #[structopt(long, env = "LILA_DEEPQ_SERVICE_TIME_HISTORY_DAYS", default_value = "7")]
+    service_time_history_days: i64,
+}
+
+impl ServiceTimeOpts {
+    async fn warm_start(&self, db: db::DbConn) -> error::Result<deepq::stats::ServiceTimes> {
+        let history = chrono::Duration::days(self.service_time_history_days);
+        deepq::stats::ServiceTimes::warm_start(db, history).await
+    }
+}
+
+#[cfg(feature = "web")]
+#[derive(Debug, StructOpt, Clone)]
+struct TelemetryOpts {
+    /// Exports trace spans to this OpenTelemetry collector, e.g. http://localhost:4318
+    #[structopt(long, env = "LILA_DEEPQ_OTLP_ENDPOINT", hide_env_values = true)]
+    otlp_endpoint: Option<crypto::Secret>, // May carry credentials
+
+    /// The fraction of traces we start ourselves that are exported.
+    #[structopt(long, env = "LILA_DEEPQ_TRACE_SAMPLE_RATIO", default_value = "0.01")]
+    trace_sample_ratio: f64,
+}
+
+#[cfg(feature = "web")]
+#[derive(Debug, StructOpt, Clone)]
+struct PayloadOpts {
+    /// Analyses larger than this many MB have their pv history truncated.
+    #[structopt(long, env = "LILA_DEEPQ_ANALYSIS_NEAR_LIMIT_MB", default_value = "12")]
+    analysis_near_limit_mb: usize,
+
+    /// How many of the last depths of each pv are kept when truncating.
+    #[structopt(long, env = "LILA_DEEPQ_PV_HISTORY_DEPTHS", default_value = "4")]
+    pv_history_depths: usize,
+
+    /// Analyses near the limit are posted here, at most once an hour.
+    #[structopt(long, env = "LILA_DEEPQ_PAYLOAD_ALERT_WEBHOOK_URL", hide_env_values = true)]
+    payload_alert_webhook_url: Option<crypto::Secret>, // Chat webhooks carry their token in the url
+}
+
+#[cfg(feature = "web")]
+impl PayloadOpts {
+    fn guard(&self, metrics: metrics::Metrics) -> deepq::payload::PayloadGuard {
+        deepq::payload::PayloadGuard::new(
+            deepq::payload::PayloadLimits {
+                near_limit_bytes: self.analysis_near_limit_mb * 1024 * 1024,
+                pv_history_depths: self.pv_history_depths,
+            },
+            metrics,
+            self.payload_alert_webhook_url.as_ref().map(|url| url.expose().to_string()),
+        )
+    }
+}
+
+#[cfg(feature = "embedded-worker")]
+#[derive(Debug, StructOpt, Clone)]
+struct EmbeddedWorkerOpts {
+    /// Analyses jobs in process with the stockfish binary at this path. Off if unset.
+    #[structopt(long, env = "LILA_DEEPQ_EMBEDDED_WORKER_STOCKFISH", parse(from_os_str))]
+    embedded_worker_stockfish: Option<std::path::PathBuf>,
+
+    /// The key the embedded worker claims jobs as, its perms decide which.
+    #[structopt(long, env = "LILA_DEEPQ_EMBEDDED_WORKER_KEY", hide_env_values = true)]
+    embedded_worker_key: Option<crypto::Secret>,
+
+    #[structopt(long, env = "LILA_DEEPQ_EMBEDDED_WORKER_THREADS", default_value = "1")]
+    embedded_worker_threads: u16,
+
+    #[structopt(long, env = "LILA_DEEPQ_EMBEDDED_WORKER_HASH_MB", default_value = "64")]
+    embedded_worker_hash_mb: u32,
+
+    /// How long the embedded worker waits before looking again when the queue is empty.
+    #[structopt(long, env = "LILA_DEEPQ_EMBEDDED_WORKER_IDLE_SECONDS", default_value = "5")]
+    embedded_worker_idle_seconds: u64,
+}
+
+#[cfg(feature = "embedded-worker")]
+impl EmbeddedWorkerOpts {
+    fn worker_opts(&self) -> Option<worker::WorkerOpts> {
+        Some(worker::WorkerOpts {
+            stockfish: self.embedded_worker_stockfish.clone()?,
+            key: self.embedded_worker_key.as_ref()?.expose().to_string().into(),
+            threads: self.embedded_worker_threads,
+            hash_mb: self.embedded_worker_hash_mb,
+            idle: Duration::from_secs(self.embedded_worker_idle_seconds),
+        })
+    }
+}
+
+#[cfg(feature = "web")]
+impl TelemetryOpts {
+    fn init(&self) {
+        if let Some(endpoint) = self.otlp_endpoint.as_ref() {
+            info!("Exporting traces...");
+            telemetry::init(endpoint.expose().to_string(), self.trace_sample_ratio);
+        }
+    }
+}
+
+#[derive(Debug, StructOpt, Clone)]
+struct ScreeningOpts {
+    /// Random and leaderboard screenings of a user screened within this many days are skipped, 0 disables it.
+    #[structopt(long, env = "LILA_DEEPQ_SCREENING_COOLDOWN_DAYS", default_value = "7")]
+    screening_cooldown_days: i64,
+}
+
+impl ScreeningOpts {
+    fn cooldown(&self) -> chrono::Duration {
+        chrono::Duration::days(self.screening_cooldown_days)
+    }
 }
 
 impl From<DatabaseOpts> for db::ConnectionOpts {
     fn from(db_opts: DatabaseOpts) -> db::ConnectionOpts {
         db::ConnectionOpts {
-            mongo_uri: db_opts.mongo_uri,
+            mongo_uri: db_opts.mongo_uri.expose().to_string(),
             mongo_database: db_opts.mongo_database,
+            analytics_uri: db_opts
+                .mongo_analytics_uri
+                .map(|uri| uri.expose().to_string()),
+            analytics_read_preference: db_opts.mongo_analytics_read_preference,
+            slow_query_threshold: std::time::Duration::from_millis(db_opts.mongo_slow_query_ms),
         }
     }
 }
 
+#[cfg(feature = "web")]
 #[derive(Debug, StructOpt)]
 #[structopt(about = "Runs the main lila-deepq webserver.")]
 struct DeepQWebserver {
@@ -75,11 +294,59 @@ struct DeepQWebserver {
     #[structopt(short, long, env = "LILA_DEEPQ_WEBSERVER_PORT")]
     port: u16,
 
+    /// Serves the fishnet routes on their own host:port or unix:/path, instead of host and port.
+    #[structopt(long, env = "LILA_DEEPQ_FISHNET_LISTEN")]
+    fishnet_listen: Option<http::Listen>,
+
+    /// Serves the report intake routes on their own host:port or unix:/path.
+    #[structopt(long, env = "LILA_DEEPQ_INTAKE_LISTEN")]
+    intake_listen: Option<http::Listen>,
+
+    /// Serves the admin routes on their own host:port or unix:/path, e.g. to keep them private.
+    #[structopt(long, env = "LILA_DEEPQ_ADMIN_LISTEN")]
+    admin_listen: Option<http::Listen>,
+
+    #[structopt(long, env = "LILA_DEEPQ_RECEIPT_SECRET", hide_env_values = true)]
+    receipt_secret: crypto::Secret,
+
+    /// Checks games are still worth analysing before handing them out, e.g. https://lichess.org
+    #[structopt(long, env = "LILA_DEEPQ_LICHESS_GAME_STATUS_URL")]
+    lichess_game_status_url: Option<String>,
+
+    #[structopt(flatten)]
+    submitter_opts: SubmitterOpts,
+
+    /// Whose point of view the scores sent to CR result webhooks are from, white or mover.
+    #[structopt(long, env = "LILA_DEEPQ_CR_SCORE_PERSPECTIVE", default_value = "white")]
+    cr_score_perspective: deepq::score::Perspective,
+
+    /// Past this many queued deep jobs, intake responses advise callers to throttle.
+    #[structopt(long, env = "LILA_DEEPQ_HIGH_WATERMARK", default_value = "10000")]
+    high_watermark: i64,
+
+    #[structopt(flatten)]
+    partial_submission_opts: PartialSubmissionOpts,
+
+    #[structopt(flatten)]
+    service_time_opts: ServiceTimeOpts,
+
+    #[structopt(flatten)]
+    telemetry_opts: TelemetryOpts,
+
+    #[structopt(flatten)]
+    payload_opts: PayloadOpts,
+
+    #[cfg(feature = "embedded-worker")]
+    #[structopt(flatten)]
+    embedded_worker_opts: EmbeddedWorkerOpts,
+
     #[structopt(flatten)]
     database_opts: DatabaseOpts,
 }
 
+#[cfg(feature = "web")]
 async fn deepq_web(args: &DeepQWebserver) -> StdResult<(), Box<dyn std::error::Error>> {
+    args.telemetry_opts.init();
     info!("Connecting to database...");
     let conn = db::connection(&args.database_opts.clone().into()).await?;
 
@@ -87,25 +354,142 @@ async fn deepq_web(args: &DeepQWebserver) -> StdResult<(), Box<dyn std::error::E
     info!("Starting Fishnet Actor...");
     let fishnet = fishnet::Actor::new(16);
     info!("Mounting urls...");
-    let app = fishnet.handlers(conn.clone());
+    let secret = args.receipt_secret.clone();
+    let game_status = lichess::status::GameStatusCache::new(args.lichess_game_status_url.clone())?;
+    info!("Estimating service times...");
+    let service_times = args.service_time_opts.warm_start(conn.clone()).await?;
+    let webhooks = fishnet::webhooks::Webhooks;
+    let flags = flags::Flags::new(conn.clone());
+    let pauses = flags.pauses();
+    pauses.refresh().await?;
+    let metrics = metrics::Metrics::new();
+    let payload = args.payload_opts.guard(metrics.clone());
+    for paused in pauses.all().await {
+        warn!(
+            "Paused since {} by {}: {:?} ({})",
+            paused.date_paused.0, paused.operator, paused.analysis_type, paused.reason
+        );
+    }
+    #[cfg(feature = "embedded-worker")]
+    let embedded_worker = args.embedded_worker_opts.worker_opts().map(|opts| {
+        info!("Starting the embedded worker with {:?}...", opts.stockfish);
+        let (worker_conn, worker_tx, worker_pauses) = (conn.clone(), fishnet.tx.clone(), pauses.clone());
+        let worker_payload = payload.clone();
+        supervisor::supervise("embedded_worker", move || {
+            worker::Worker::new(opts.clone(), worker_pauses.clone(), worker_payload.clone())
+                .run(worker_conn.clone(), worker_tx.clone())
+        })
+    });
+    let app = fishnet.handlers(
+        conn.clone(),
+        secret.clone(),
+        fishnet::handlers::Services {
+            game_status: game_status.clone(),
+            service_times: service_times.clone(),
+            webhooks: webhooks.clone(),
+            flags: flags.clone(),
+            payload,
+            metrics: metrics.clone(),
+        },
+    );
+    let backlog = deepq::backlog::Backlog::new(args.high_watermark, service_times.clone());
+    let admin = admin::handlers::mount(
+        conn.clone(),
+        flags,
+        secret.clone(),
+        metrics.clone(),
+        backlog,
+        fishnet.tx.clone(),
+        game_status,
+    );
+    let reports = deepq::handlers::mount(conn.clone(), service_times.clone(), secret);
+    deepq::api::ensure_indexes(conn.clone()).await?;
+    deepq::api::backfill_date_state_changed(conn.clone()).await?;
+
+    let refresh_conn = conn.clone();
+    let service_times_refresh = supervisor::supervise("service_times", move || {
+        service_times.clone().refresh_periodically(refresh_conn.clone())
+    });
+
+    let webhooks_conn = conn.clone();
+    let deadline_warnings = supervisor::supervise("deadline_warnings", move || {
+        webhooks.clone().warn_deadlines_periodically(webhooks_conn.clone())
+    });
+
+    let result_webhooks = deepq::results::ResultWebhooks::new(args.cr_score_perspective);
+    let (results_conn, results_tx) = (conn.clone(), fishnet.tx.clone());
+    let result_webhooks_listener = supervisor::supervise("result_webhooks", move || {
+        result_webhooks
+            .clone()
+            .fishnet_listener(results_conn.clone(), results_tx.clone())
+    });
 
-    let fishnet_listener = tokio::spawn(async move {
+    let tx = fishnet.tx.clone();
+    let partial: irwin::api::PartialSubmission = args.partial_submission_opts.clone().into();
+    let submitter = args.submitter_opts.start(conn.clone(), metrics.clone());
+    info!("Recovering from the previous run...");
+    // NOTE: the next reconcile catches up on whatever this missed, serve anyway.
+    if let Err(err) = irwin::api::recover(conn.clone(), partial, &submitter).await {
+        error!("Unable to recover from the previous run: {:?}", err);
+    }
+    let fishnet_listener = supervisor::supervise("fishnet_listener", move || {
         info!("Starting Irwin Actor...");
-        irwin::api::fishnet_listener(conn.clone(), fishnet.tx.clone()).await;
+        irwin::api::fishnet_listener(conn.clone(), tx.clone(), partial, submitter.clone())
     });
 
-    info!("Starting server...");
+    info!("Starting servers...");
     let address: SocketAddr =
         format!("{host}:{port}", host = args.host, port = args.port).parse()?;
-    warp::serve(warp::path("fishnet").and(app))
-        .run(address)
-        .await;
+    let default = http::Listen::Tcp(address);
+    let groups = vec![
+        (
+            args.fishnet_listen.clone().unwrap_or_else(|| default.clone()),
+            http::Versioned::new("fishnet", http::ApiVersion::V1)
+                .version(http::ApiVersion::V1, http::routes(app))
+                .routes(),
+        ),
+        (
+            args.intake_listen.clone().unwrap_or_else(|| default.clone()),
+            http::Versioned::new("reports", http::ApiVersion::V1)
+                .version(http::ApiVersion::V1, http::routes(reports))
+                .routes(),
+        ),
+        (
+            args.admin_listen.clone().unwrap_or_else(|| default.clone()),
+            http::Versioned::new("admin", http::ApiVersion::V1)
+                .version(http::ApiVersion::V1, http::routes(admin))
+                .routes(),
+        ),
+    ];
+    // NOTE: groups that weren't given a listener of their own share one.
+    let mut listeners: Vec<(http::Listen, http::Routes)> = Vec::new();
+    for (listen, routes) in groups {
+        match listeners.iter_mut().find(|(l, _)| *l == listen) {
+            Some((_, shared)) => *shared = http::routes(shared.clone().or(routes).unify()),
+            None => listeners.push((listen, routes)),
+        }
+    }
+    futures::future::try_join_all(listeners.into_iter().map(|(listen, routes)| {
+        http::serve(
+            listen,
+            http::routes(routes.with(metrics.log())),
+        )
+    }))
+    .await?;
 
     fishnet_listener.await?;
+    #[cfg(feature = "embedded-worker")]
+    if let Some(embedded_worker) = embedded_worker {
+        embedded_worker.await?;
+    }
+    service_times_refresh.await?;
+    deadline_warnings.await?;
+    result_webhooks_listener.await?;
 
     Ok(())
 }
 
+#[cfg(feature = "listener")]
 #[derive(Debug, StructOpt)]
 #[structopt(about = "Listens for irwin jobs from lila")]
 struct IrwinJobListener {
@@ -117,22 +501,63 @@ struct IrwinJobListener {
     )]
     api_url: String,
 
-    #[structopt(short, long, env = "LILA_DEEPQ_IRWIN_LICHESS_API_KEY")]
-    lichess_api_key: String,
+    #[structopt(short, long, env = "LILA_DEEPQ_IRWIN_LICHESS_API_KEY", hide_env_values = true)]
+    lichess_api_key: crypto::Secret,
+
+    /// Where to ask lila to throttle irwin requests, past the high watermark. Only logged if unset.
+    #[structopt(long, env = "LILA_DEEPQ_LICHESS_BACKPRESSURE_URL")]
+    lichess_backpressure_url: Option<String>,
+
+    /// Past this many queued deep jobs, lila is asked to throttle.
+    #[structopt(long, env = "LILA_DEEPQ_HIGH_WATERMARK", default_value = "10000")]
+    high_watermark: i64,
+
+    #[structopt(flatten)]
+    service_time_opts: ServiceTimeOpts,
+
+    #[structopt(flatten)]
+    screening_opts: ScreeningOpts,
 
     #[structopt(flatten)]
     database_opts: DatabaseOpts,
 }
 
+#[cfg(feature = "listener")]
 async fn deepq_irwin_job_listener(
     args: &IrwinJobListener,
 ) -> StdResult<(), Box<dyn std::error::Error>> {
     let conn = db::connection(&args.database_opts.clone().into()).await?;
+    let service_times = args.service_time_opts.warm_start(conn.clone()).await?;
+    let backlog = deepq::backlog::Backlog::new(args.high_watermark, service_times.clone());
+    // NOTE: runs for as long as the listener does, it never returns.
+    let refresh_conn = conn.clone();
+    let _service_times_refresh = supervisor::supervise("service_times", move || {
+        service_times.clone().refresh_periodically(refresh_conn.clone())
+    });
+    let metrics = metrics::Metrics::new();
+    let parse_errors = irwin::stream::ParseErrors::new(metrics.clone());
+    let summarized_errors = parse_errors.clone();
+    let _parse_error_summaries = supervisor::supervise("parse_error_summaries", move || {
+        summarized_errors.clone().summarize_periodically()
+    });
+    let metrics_conn = conn.clone();
+    let _metrics_publisher = supervisor::supervise("metrics_publisher", move || {
+        metrics.clone().publish_periodically(metrics_conn.clone(), "irwin-job-listener")
+    });
+    let mut backpressure = lichess::backpressure::Backpressure::new(
+        args.lichess_backpressure_url.clone(),
+        args.lichess_api_key.expose().to_string(),
+    )?;
 
     info!("Starting up...");
     loop {
         info!("Connecting...");
-        let mut stream = irwin::stream::listener(&args.api_url, &args.lichess_api_key).await?;
+        let mut stream = irwin::stream::listener(
+            &args.api_url,
+            args.lichess_api_key.expose(),
+            parse_errors.clone(),
+        )
+        .await?;
 
         info!("Reading stream...");
         while let Some(msg) = stream.next().await {
@@ -142,12 +567,35 @@ async fn deepq_irwin_job_listener(
                     info!(
                         "{:?} report: {} for {} games",
                         request.origin,
-                        request.user.id.0,
+                        request.user.id,
                         request.games.len()
                     );
-                    irwin::api::add_to_queue(conn.clone(), request).await?;
+                    let precedence = deepq::policy::precedence(
+                        request.origin.clone(),
+                        &deepq::model::ReportSignals::from(&request),
+                    );
+                    let user_id = request.user.id.clone();
+                    let queued = irwin::api::add_to_queue(
+                        conn.clone(),
+                        request,
+                        deepq::model::Provenance::LichessStream,
+                        args.screening_opts.cooldown(),
+                    )
+                    .await?;
+                    if queued.is_none() {
+                        info!("{} was screened recently, skipped", user_id);
+                    }
+                    match backlog.advise(conn.clone(), precedence).await {
+                        Ok(Some(advisory)) => {
+                            if let Err(err) = backpressure.signal(&advisory).await {
+                                warn!("Unable to signal backpressure to lila: {:?}", err);
+                            }
+                        }
+                        Ok(None) => {}
+                        Err(err) => error!("Unable to check the backlog: {:?}", err),
+                    }
                 }
-                Err(e) => error!("Error parsing message from lichess:\n{:?}", e),
+                Err(e) => error!("Error reading stream from lichess:\n{:?}", e),
             }
         }
 
@@ -156,6 +604,54 @@ async fn deepq_irwin_job_listener(
     }
 }
 
+#[cfg(feature = "listener")]
+#[derive(Debug, StructOpt)]
+#[structopt(about = "Feeds a recorded irwin stream capture into the ingest pipeline.")]
+struct ReplayStream {
+    /// One message per line, either as lila sent it or as {"at": <rfc3339>, "msg": <message>}.
+    #[structopt(long, parse(from_os_str))]
+    file: std::path::PathBuf,
+
+    /// How much faster than recorded to replay, e.g. 10x.
+    #[structopt(long, default_value = "1x")]
+    speed: irwin::replay::Speed,
+
+    /// Replay into a database whose name doesn't mention test.
+    #[structopt(long)]
+    allow_any_database: bool,
+
+    #[structopt(flatten)]
+    screening_opts: ScreeningOpts,
+
+    #[structopt(flatten)]
+    database_opts: DatabaseOpts,
+}
+
+#[cfg(feature = "listener")]
+async fn replay_stream(args: &ReplayStream) -> StdResult<(), Box<dyn std::error::Error>> {
+    // NOTE: replayed reports are real reports, keep them out of production.
+    if !args.allow_any_database && !args.database_opts.mongo_database.contains("test") {
+        error!(
+            "Refusing to replay into {:?}, pass --allow-any-database if it really is a test database",
+            args.database_opts.mongo_database
+        );
+        return Err(Box::new(error::Error::InvalidCommandLineArguments));
+    }
+    let conn = db::connection(&args.database_opts.clone().into()).await?;
+    let summary = irwin::replay::replay(conn, &args.file, args.speed, args.screening_opts.cooldown()).await?;
+    info!(
+        "Replayed {} lines: {} requests ({} cooling down, {} failed), {} keepAlives, {} unparseable",
+        summary.lines,
+        summary.requests,
+        summary.cooling_down,
+        summary.failed,
+        summary.keep_alives,
+        summary.unparseable
+    );
+    Ok(())
+}
+
+#[cfg(feature = "cli-admin")]
 #[derive(Debug, StructOpt)]
 #[structopt(about = "Create a new fishnet key.")]
 struct FishnetNewUser {
@@ -174,10 +670,27 @@ struct FishnetNewUser {
     #[structopt(short, long)]
     system_analysis: bool,
 
+    #[structopt(long)]
+    admin: bool,
+
+    #[structopt(long, default_value = "new", possible_values = &["new", "trusted", "core"])]
+    trust: fishnet::model::TrustLevel,
+
+    #[structopt(long)]
+    org: Option<String>,
+
+    #[structopt(long)]
+    committed_jobs_per_hour: Option<i64>,
+
+    /// Only hand the key work from these report origins, e.g. tournament.
+    #[structopt(long, use_delimiter = true)]
+    allowed_origins: Option<Vec<deepq::model::ReportOrigin>>,
+
     #[structopt(flatten)]
     database_opts: DatabaseOpts,
 }
 
+#[cfg(feature = "cli-admin")]
 async fn fishnet_new_user(args: &FishnetNewUser) -> StdResult<(), Box<dyn std::error::Error>> {
     let mut perms = Vec::new();
     if args.system_analysis {
@@ -192,30 +705,668 @@ async fn fishnet_new_user(args: &FishnetNewUser) -> StdResult<(), Box<dyn std::e
     let create_user = fishnet::api::CreateApiUser {
         user: Some(args.username.clone().into()),
         name: args.keyname.clone(),
-        perms: perms,
+        perms,
+        admin: args.admin,
+        trust: args.trust,
+        org: args.org.clone(),
+        committed_jobs_per_hour: args.committed_jobs_per_hour,
+        allowed_origins: args.allowed_origins.clone(),
     };
 
     let conn = db::connection(&args.database_opts.clone().into()).await?;
     let api_user = fishnet::api::create_api_user(conn, create_user).await?;
     info!(
-        "Created key {} for {{user: {:?}, name: {:?}}}",
-        api_user.key.0, api_user.user, api_user.name
+        "Created key {} for {{user: {:?}, name: {:?}, admin: {:?}}}",
+        api_user.key.0, api_user.user, api_user.name, api_user.admin
+    );
+    Ok(())
+}
+
+#[derive(Debug, StructOpt)]
+#[structopt(about = "Removes data that has outlived its retention policy, once, see scheduler.")]
+struct Reaper {
+    #[structopt(flatten)]
+    database_opts: DatabaseOpts,
+}
+
+async fn reaper(args: &Reaper) -> StdResult<(), Box<dyn std::error::Error>> {
+    let conn = db::connection(&args.database_opts.clone().into()).await?;
+
+    // NOTE: the steps are independent, one failing doesn't hold the others back.
+    let mut failed = false;
+    if let Err(err) = deepq::reaper::reap_analyses(conn.clone()).await {
+        error!("Unable to reap analyses: {:?}", err);
+        failed = true;
+    }
+    if let Err(err) = deepq::reaper::purge_deleted(conn.clone()).await {
+        error!("Unable to purge deleted jobs and reports: {:?}", err);
+        failed = true;
+    }
+    // NOTE: a one-off pass, its counts are only logged.
+    if let Err(err) = deepq::reaper::reap_orphan_games(conn, &metrics::Metrics::new()).await {
+        error!("Unable to reap orphaned games: {:?}", err);
+        failed = true;
+    }
+    if failed {
+        return Err("some of the reaping failed, see the log".into());
+    }
+    Ok(())
+}
+
+#[derive(Debug, StructOpt)]
+#[structopt(about = "Recompute job and report completeness from the stored analyses.")]
+struct Reconcile {
+    #[structopt(flatten)]
+    submitter_opts: SubmitterOpts,
+
+    #[structopt(flatten)]
+    partial_submission_opts: PartialSubmissionOpts,
+
+    #[structopt(flatten)]
+    database_opts: DatabaseOpts,
+}
+
+async fn reconcile(args: &Reconcile) -> StdResult<(), Box<dyn std::error::Error>> {
+    let conn = db::connection(&args.database_opts.clone().into()).await?;
+    let submitter = args.submitter_opts.start(conn.clone(), metrics::Metrics::new());
+    irwin::api::reconcile_completeness(
+        conn,
+        args.partial_submission_opts.clone().into(),
+        &submitter,
+    )
+    .await?;
+    submitter.drained().await;
+    Ok(())
+}
+
+#[derive(Debug, StructOpt)]
+#[structopt(about = "Verifies that a sample of the stored analyses replay legally, once, see scheduler.")]
+struct Verifier {
+    #[structopt(long, env = "LILA_DEEPQ_VERIFIER_SAMPLE_SIZE", default_value = "100")]
+    sample_size: i64,
+
+    #[structopt(flatten)]
+    database_opts: DatabaseOpts,
+}
+
+async fn verifier(args: &Verifier) -> StdResult<(), Box<dyn std::error::Error>> {
+    let conn = db::connection(&args.database_opts.clone().into()).await?;
+
+    let verification = deepq::verifier::verify_sample(conn, args.sample_size).await?;
+    info!(
+        "Corruption rate: {:.2}% of {} analyses checked",
+        verification.corrupt as f64 / verification.checked.max(1) as f64 * 100f64,
+        verification.checked
+    );
+    Ok(())
+}
+
+#[derive(Debug, StructOpt)]
+#[structopt(about = "Alerts on providers below their committed throughput, once, see scheduler.")]
+struct SlaMonitor {
+    #[structopt(long, env = "LILA_DEEPQ_SLA_MONITOR_WEBHOOK_URL", hide_env_values = true)]
+    webhook_url: Option<crypto::Secret>, // Chat webhooks carry their token in the url
+
+    #[structopt(flatten)]
+    service_time_opts: ServiceTimeOpts,
+
+    #[structopt(flatten)]
+    database_opts: DatabaseOpts,
+}
+
+async fn sla_monitor(args: &SlaMonitor) -> StdResult<(), Box<dyn std::error::Error>> {
+    let conn = db::connection(&args.database_opts.clone().into()).await?;
+
+    // NOTE: a fresh monitor alerts on every provider below its commitment,
+    //       only the scheduler remembers which it already alerted on.
+    let webhook_url = args.webhook_url.as_ref().map(|url| url.expose().to_string());
+    let service_times = args.service_time_opts.warm_start(conn.clone()).await?;
+    fishnet::sla::Monitor::new(webhook_url, service_times).check(conn).await?;
+    Ok(())
+}
+
+#[derive(Debug, StructOpt, Clone)]
+struct LeaderboardOpts {
+    #[structopt(long, env = "LILA_DEEPQ_LICHESS_API_URL", default_value = "https://lichess.org")]
+    api_url: String,
+
+    #[structopt(long, env = "LILA_DEEPQ_LICHESS_API_KEY", hide_env_values = true)]
+    lichess_api_key: Option<crypto::Secret>,
+
+    #[structopt(
+        long,
+        env = "LILA_DEEPQ_LEADERBOARD_PERF_TYPES",
+        default_value = "bullet,blitz,rapid,classical",
+        use_delimiter = true
+    )]
+    perf_types: Vec<String>,
+
+    #[structopt(long, env = "LILA_DEEPQ_LEADERBOARD_PLAYERS", default_value = "100")]
+    players_per_leaderboard: u32,
+
+    #[structopt(long, env = "LILA_DEEPQ_LEADERBOARD_GAMES_PER_PLAYER", default_value = "10")]
+    games_per_player: usize,
+
+    #[structopt(long, env = "LILA_DEEPQ_LEADERBOARD_SCREEN_EVERY_DAYS", default_value = "30")]
+    screen_every_days: i64,
+}
+
+impl LeaderboardOpts {
+    fn sweeper(&self, screening: &ScreeningOpts) -> error::Result<lichess::leaderboard::Sweeper> {
+        lichess::leaderboard::Sweeper::new(lichess::leaderboard::SweeperOpts {
+            api_url: self.api_url.clone(),
+            api_key: self.lichess_api_key.as_ref().map(|key| key.expose().to_string()),
+            perf_types: self.perf_types.clone(),
+            players_per_leaderboard: self.players_per_leaderboard,
+            games_per_player: self.games_per_player,
+            screen_every: chrono::Duration::days(self.screen_every_days),
+            screening_cooldown: screening.cooldown(),
+        })
+    }
+}
+
+#[derive(Debug, StructOpt)]
+#[structopt(about = "Queues reports for top players who haven't been screened recently, once, see scheduler.")]
+struct LeaderboardSweeper {
+    #[structopt(flatten)]
+    leaderboard_opts: LeaderboardOpts,
+
+    #[structopt(flatten)]
+    screening_opts: ScreeningOpts,
+
+    #[structopt(flatten)]
+    database_opts: DatabaseOpts,
+}
+
+async fn leaderboard_sweeper(args: &LeaderboardSweeper) -> StdResult<(), Box<dyn std::error::Error>> {
+    let conn = db::connection(&args.database_opts.clone().into()).await?;
+    let sweeper = args.leaderboard_opts.sweeper(&args.screening_opts)?;
+
+    sweeper.sweep(conn).await?;
+    Ok(())
+}
+
+#[derive(Debug, StructOpt)]
+#[structopt(about = "Runs the recurring maintenance tasks on cron schedules, one replica at a time.")]
+struct Scheduler {
+    #[structopt(long, env = "LILA_DEEPQ_RETENTION_SCHEDULE", default_value = "0 * * * *")]
+    retention_schedule: String,
+
+    #[structopt(long, env = "LILA_DEEPQ_PURGE_SCHEDULE", default_value = "10 * * * *")]
+    purge_schedule: String,
+
+    #[structopt(long, env = "LILA_DEEPQ_ORPHAN_GAMES_SCHEDULE", default_value = "20 * * * *")]
+    orphan_games_schedule: String,
+
+    #[structopt(long, env = "LILA_DEEPQ_INTEGRITY_SCHEDULE", default_value = "30 4 * * *")]
+    integrity_schedule: String,
+
+    #[structopt(long, env = "LILA_DEEPQ_VERIFIER_SCHEDULE", default_value = "*/10 * * * *")]
+    verifier_schedule: String,
+
+    #[structopt(long, env = "LILA_DEEPQ_VERIFIER_SAMPLE_SIZE", default_value = "100")]
+    verifier_sample_size: i64,
+
+    #[structopt(long, env = "LILA_DEEPQ_SLA_MONITOR_SCHEDULE", default_value = "*/5 * * * *")]
+    sla_monitor_schedule: String,
+
+    #[structopt(long, env = "LILA_DEEPQ_SLA_MONITOR_WEBHOOK_URL", hide_env_values = true)]
+    sla_monitor_webhook_url: Option<crypto::Secret>, // Chat webhooks carry their token in the url
+
+    /// The leaderboard sweep only runs when this is set.
+    #[structopt(long, env = "LILA_DEEPQ_LEADERBOARD_SCHEDULE")]
+    leaderboard_schedule: Option<String>,
+
+    #[structopt(flatten)]
+    leaderboard_opts: LeaderboardOpts,
+
+    /// Each run is delayed by up to this many seconds so replicas don't all race for the lease.
+    #[structopt(long, env = "LILA_DEEPQ_SCHEDULE_JITTER_SECONDS", default_value = "30")]
+    schedule_jitter_seconds: i64,
+
+    #[structopt(flatten)]
+    service_time_opts: ServiceTimeOpts,
+
+    #[structopt(flatten)]
+    screening_opts: ScreeningOpts,
+
+    #[structopt(flatten)]
+    database_opts: DatabaseOpts,
+}
+
+async fn scheduler_command(args: &Scheduler) -> StdResult<(), Box<dyn std::error::Error>> {
+    let conn = db::connection(&args.database_opts.clone().into()).await?;
+    let service_times = args.service_time_opts.warm_start(conn.clone()).await?;
+    let refresh_conn = conn.clone();
+    let refresh_service_times = service_times.clone();
+    // NOTE: runs for as long as the scheduler does, it never returns.
+    let _service_times_refresh = supervisor::supervise("service_times", move || {
+        refresh_service_times.clone().refresh_periodically(refresh_conn.clone())
+    });
+    let metrics = metrics::Metrics::new();
+    let mut scheduler = scheduler::Scheduler::new(
+        conn,
+        chrono::Duration::seconds(args.schedule_jitter_seconds),
+        metrics.clone(),
+    );
+
+    scheduler.add(
+        "reap_analyses",
+        args.retention_schedule.parse()?,
+        chrono::Duration::hours(1),
+        |db| Box::pin(async move { deepq::reaper::reap_analyses(db).await.map(|_| ()) }),
+    );
+    scheduler.add(
+        "purge_deleted",
+        args.purge_schedule.parse()?,
+        chrono::Duration::hours(1),
+        |db| Box::pin(async move { deepq::reaper::purge_deleted(db).await.map(|_| ()) }),
+    );
+    scheduler.add(
+        "reap_orphan_games",
+        args.orphan_games_schedule.parse()?,
+        chrono::Duration::hours(1),
+        move |db| {
+            let metrics = metrics.clone();
+            Box::pin(async move { deepq::reaper::reap_orphan_games(db, &metrics).await.map(|_| ()) })
+        },
+    );
+    scheduler.add(
+        "audit_analysis_links",
+        args.integrity_schedule.parse()?,
+        chrono::Duration::hours(1),
+        |db| Box::pin(async move { deepq::integrity::audit_analysis_links(db).await.map(|_| ()) }),
+    );
+    let sample_size = args.verifier_sample_size;
+    scheduler.add(
+        "verify_sample",
+        args.verifier_schedule.parse()?,
+        chrono::Duration::minutes(10),
+        move |db| {
+            Box::pin(async move {
+                let verification = deepq::verifier::verify_sample(db, sample_size).await?;
+                info!(
+                    "Verified {} analyses, {} corrupt",
+                    verification.checked, verification.corrupt
+                );
+                Ok(())
+            })
+        },
     );
+    // NOTE: the monitor remembers which providers it has already alerted on.
+    let webhook_url = args.sla_monitor_webhook_url.as_ref().map(|url| url.expose().to_string());
+    let monitor = Arc::new(tokio::sync::Mutex::new(fishnet::sla::Monitor::new(
+        webhook_url,
+        service_times,
+    )));
+    scheduler.add(
+        "sla_monitor",
+        args.sla_monitor_schedule.parse()?,
+        chrono::Duration::minutes(5),
+        move |db| {
+            let monitor = monitor.clone();
+            Box::pin(async move { monitor.lock().await.check(db).await })
+        },
+    );
+    if let Some(schedule) = args.leaderboard_schedule.as_deref() {
+        let sweeper = Arc::new(args.leaderboard_opts.sweeper(&args.screening_opts)?);
+        scheduler.add("leaderboard_sweep", schedule.parse()?, chrono::Duration::hours(6), move |db| {
+            let sweeper = sweeper.clone();
+            Box::pin(async move { sweeper.sweep(db).await.map(|_| ()) })
+        });
+    }
+
+    info!("Starting up...");
+    scheduler.run().await;
+    Ok(())
+}
+
+#[cfg(feature = "cli-admin")]
+#[derive(Debug, StructOpt)]
+#[structopt(about = "Populates a local development database with realistic data.")]
+struct SeedDev {
+    #[structopt(flatten)]
+    database_opts: DatabaseOpts,
+}
+
+#[cfg(feature = "cli-admin")]
+async fn seed_dev(args: &SeedDev) -> StdResult<(), Box<dyn std::error::Error>> {
+    let conn = db::connection(&args.database_opts.clone().into()).await?;
+    for api_user in seed::seed(conn).await? {
+        info!(
+            "Created key {} for {{name: {:?}, admin: {:?}, trust: {}}}",
+            api_user.key.0, api_user.name, api_user.admin, api_user.trust
+        );
+    }
+    Ok(())
+}
+
+#[cfg(feature = "cli-admin")]
+#[derive(Debug, StructOpt)]
+#[structopt(about = "Migration: lowercases usernames stored before they were normalized.")]
+struct NormalizeUsernames {
+    #[structopt(flatten)]
+    database_opts: DatabaseOpts,
+}
+
+#[cfg(feature = "cli-admin")]
+async fn normalize_usernames(args: &NormalizeUsernames) -> StdResult<(), Box<dyn std::error::Error>> {
+    let conn = db::connection(&args.database_opts.clone().into()).await?;
+    let normalized = deepq::api::normalize_user_ids(conn).await?;
+    info!("Normalized {} usernames", normalized);
+    Ok(())
+}
+
+#[cfg(feature = "cli-admin")]
+#[derive(Debug, StructOpt)]
+#[structopt(about = "An interactive prompt for listing, inspecting and requeueing jobs.")]
+struct Shell {
+    #[structopt(flatten)]
+    database_opts: DatabaseOpts,
+}
+
+#[cfg(feature = "cli-admin")]
+async fn shell(args: &Shell) -> StdResult<(), Box<dyn std::error::Error>> {
+    let conn = db::connection(&args.database_opts.clone().into()).await?;
+    shell::shell(conn).await?;
+    Ok(())
+}
+
+#[derive(Debug, StructOpt)]
+#[structopt(about = "Inspects the configuration.")]
+enum Config {
+    Check(ConfigCheck),
+}
+
+#[derive(Debug, StructOpt)]
+#[structopt(about = "Validates the configuration of every service, exiting non-zero on problems.")]
+struct ConfigCheck {
+    /// Only checks this subcommand, e.g. reaper.
+    subcommand: Option<String>,
+}
+
+impl config::Validate for SubmitterOpts {
+    fn validate(&self, problems: &mut config::Problems) {
+        problems.range("irwin_submission_concurrency", self.irwin_submission_concurrency, 1, 64);
+    }
+}
+
+impl config::Validate for DatabaseOpts {
+    fn validate(&self, problems: &mut config::Problems) {
+        let uris = iter::once(("mongo_uri", &self.mongo_uri))
+            .chain(self.mongo_analytics_uri.iter().map(|uri| ("mongo_analytics_uri", uri)));
+        for (name, uri) in uris {
+            let uri = uri.expose();
+            problems.check(
+                uri.starts_with("mongodb://") || uri.starts_with("mongodb+srv://"),
+                || format!("{} must be a mongodb:// or mongodb+srv:// uri", name),
+            );
+        }
+        problems.check(!self.mongo_database.is_empty(), || "mongo_database is empty".to_string());
+        problems.positive("mongo_slow_query_ms", self.mongo_slow_query_ms);
+    }
+}
+
+impl config::Validate for ScreeningOpts {
+    fn validate(&self, problems: &mut config::Problems) {
+        problems.check(self.screening_cooldown_days >= 0, || {
+            "screening_cooldown_days must not be negative".to_string()
+        });
+    }
+}
+
+impl config::Validate for ServiceTimeOpts {
+    fn validate(&self, problems: &mut config::Problems) {
+        problems.positive("service_time_history_days", self.service_time_history_days);
+    }
+}
+
+impl config::Validate for PartialSubmissionOpts {
+    fn validate(&self, problems: &mut config::Problems) {
+        if let Some(hours) = self.partial_submission_timeout_hours {
+            problems.positive("partial_submission_timeout_hours", hours);
+        }
+        problems.check(
+            !self.amend_partial_submissions || self.partial_submission_timeout_hours.is_some(),
+            || "amend_partial_submissions requires partial_submission_timeout_hours".to_string(),
+        );
+    }
+}
+
+#[cfg(feature = "web")]
+impl config::Validate for PayloadOpts {
+    fn validate(&self, problems: &mut config::Problems) {
+        problems.range("analysis_near_limit_mb", self.analysis_near_limit_mb, 1, 15);
+        problems.positive("pv_history_depths", self.pv_history_depths);
+        if let Some(url) = self.payload_alert_webhook_url.as_ref() {
+            problems.secret_url("payload_alert_webhook_url", url);
+        }
+    }
+}
+
+#[cfg(feature = "web")]
+impl config::Validate for TelemetryOpts {
+    fn validate(&self, problems: &mut config::Problems) {
+        problems.range("trace_sample_ratio", self.trace_sample_ratio, 0f64, 1f64);
+        if let Some(endpoint) = self.otlp_endpoint.as_ref() {
+            problems.secret_url("otlp_endpoint", endpoint);
+        }
+    }
+}
+
+#[cfg(feature = "embedded-worker")]
+impl config::Validate for EmbeddedWorkerOpts {
+    fn validate(&self, problems: &mut config::Problems) {
+        if let Some(stockfish) = self.embedded_worker_stockfish.as_ref() {
+            problems.check(stockfish.is_file(), || {
+                format!("embedded_worker_stockfish {:?} doesn't exist", stockfish)
+            });
+            problems.check(self.embedded_worker_key.is_some(), || {
+                "embedded_worker_stockfish requires embedded_worker_key".to_string()
+            });
+        }
+        problems.range("embedded_worker_threads", self.embedded_worker_threads, 1, 512);
+        problems.positive("embedded_worker_hash_mb", self.embedded_worker_hash_mb);
+        problems.positive("embedded_worker_idle_seconds", self.embedded_worker_idle_seconds);
+    }
+}
+
+#[cfg(feature = "web")]
+impl config::Validate for DeepQWebserver {
+    fn validate(&self, problems: &mut config::Problems) {
+        problems.positive("port", self.port);
+        let listeners = [&self.fishnet_listen, &self.intake_listen, &self.admin_listen];
+        for listen in listeners.iter().copied().flatten() {
+            if let http::Listen::Unix(path) = listen {
+                problems.check(path.parent().is_none_or(|dir| dir.as_os_str().is_empty() || dir.is_dir()), || {
+                    format!("the directory for {} doesn't exist", listen)
+                });
+            }
+        }
+        self.submitter_opts.validate(problems);
+        problems.positive("high_watermark", self.high_watermark);
+        // NOTE: receipts are only as good as the secret they're signed with.
+        problems.check(self.receipt_secret.expose().len() >= 16, || {
+            "receipt_secret must be at least 16 characters".to_string()
+        });
+        if let Some(url) = self.lichess_game_status_url.as_deref() {
+            problems.url("lichess_game_status_url", url);
+        }
+        self.partial_submission_opts.validate(problems);
+        self.service_time_opts.validate(problems);
+        self.telemetry_opts.validate(problems);
+        self.payload_opts.validate(problems);
+        #[cfg(feature = "embedded-worker")]
+        self.embedded_worker_opts.validate(problems);
+        self.database_opts.validate(problems);
+    }
+}
+
+#[cfg(feature = "listener")]
+impl config::Validate for IrwinJobListener {
+    fn validate(&self, problems: &mut config::Problems) {
+        problems.url("api_url", &self.api_url);
+        if let Some(url) = self.lichess_backpressure_url.as_deref() {
+            problems.url("lichess_backpressure_url", url);
+        }
+        problems.positive("high_watermark", self.high_watermark);
+        self.service_time_opts.validate(problems);
+        self.screening_opts.validate(problems);
+        self.database_opts.validate(problems);
+    }
+}
+
+impl config::Validate for Reaper {
+    fn validate(&self, problems: &mut config::Problems) {
+        self.database_opts.validate(problems);
+    }
+}
+
+impl config::Validate for Reconcile {
+    fn validate(&self, problems: &mut config::Problems) {
+        self.submitter_opts.validate(problems);
+        self.partial_submission_opts.validate(problems);
+        self.database_opts.validate(problems);
+    }
+}
+
+impl config::Validate for Verifier {
+    fn validate(&self, problems: &mut config::Problems) {
+        problems.range("sample_size", self.sample_size, 1, 10_000);
+        self.database_opts.validate(problems);
+    }
+}
+
+impl config::Validate for SlaMonitor {
+    fn validate(&self, problems: &mut config::Problems) {
+        if let Some(url) = self.webhook_url.as_ref() {
+            problems.secret_url("webhook_url", url);
+        }
+        self.service_time_opts.validate(problems);
+        self.database_opts.validate(problems);
+    }
+}
+
+impl config::Validate for LeaderboardOpts {
+    fn validate(&self, problems: &mut config::Problems) {
+        problems.url("api_url", &self.api_url);
+        problems.check(!self.perf_types.is_empty(), || "perf_types is empty".to_string());
+        // NOTE: lichess only serves the top 200 of each leaderboard.
+        problems.range("players_per_leaderboard", self.players_per_leaderboard, 1, 200);
+        problems.range(
+            "games_per_player",
+            self.games_per_player,
+            1,
+            lichess::leaderboard::RECENT_GAMES,
+        );
+        problems.positive("screen_every_days", self.screen_every_days);
+    }
+}
+
+impl config::Validate for LeaderboardSweeper {
+    fn validate(&self, problems: &mut config::Problems) {
+        self.leaderboard_opts.validate(problems);
+        self.screening_opts.validate(problems);
+        self.database_opts.validate(problems);
+    }
+}
+
+impl config::Validate for Scheduler {
+    fn validate(&self, problems: &mut config::Problems) {
+        let schedules = [
+            ("retention_schedule", Some(&self.retention_schedule)),
+            ("purge_schedule", Some(&self.purge_schedule)),
+            ("orphan_games_schedule", Some(&self.orphan_games_schedule)),
+            ("integrity_schedule", Some(&self.integrity_schedule)),
+            ("verifier_schedule", Some(&self.verifier_schedule)),
+            ("sla_monitor_schedule", Some(&self.sla_monitor_schedule)),
+            ("leaderboard_schedule", self.leaderboard_schedule.as_ref()),
+        ];
+        for (name, schedule) in schedules.iter() {
+            if let Some(schedule) = schedule {
+                problems.check(schedule.parse::<scheduler::Schedule>().is_ok(), || {
+                    format!("{} is not a valid cron expression: {:?}", name, schedule)
+                });
+            }
+        }
+        problems.range("verifier_sample_size", self.verifier_sample_size, 1, 10_000);
+        if let Some(url) = self.sla_monitor_webhook_url.as_ref() {
+            problems.secret_url("sla_monitor_webhook_url", url);
+        }
+        if self.leaderboard_schedule.is_some() {
+            self.leaderboard_opts.validate(problems);
+        }
+        problems.check(self.schedule_jitter_seconds >= 0, || {
+            "schedule_jitter_seconds must not be negative".to_string()
+        });
+        self.service_time_opts.validate(problems);
+        self.screening_opts.validate(problems);
+        self.database_opts.validate(problems);
+    }
+}
+
+fn config_check(args: &ConfigCheck) -> StdResult<(), Box<dyn std::error::Error>> {
+    // NOTE: only the services built into this binary are checked.
+    let checks: Vec<(&str, config::Check)> = vec![
+        #[cfg(feature = "web")]
+        ("deep-q-webserver", config::check::<DeepQWebserver>),
+        #[cfg(feature = "listener")]
+        ("irwin-job-listener", config::check::<IrwinJobListener>),
+        ("reaper", config::check::<Reaper>),
+        ("reconcile", config::check::<Reconcile>),
+        ("verifier", config::check::<Verifier>),
+        ("sla-monitor", config::check::<SlaMonitor>),
+        ("leaderboard-sweeper", config::check::<LeaderboardSweeper>),
+        ("scheduler", config::check::<Scheduler>),
+    ];
+    let checks: Vec<_> = checks
+        .into_iter()
+        .filter(|(name, _)| args.subcommand.as_deref().is_none_or(|subcommand| subcommand == *name))
+        .collect();
+    if checks.is_empty() {
+        error!("No such subcommand: {:?}", args.subcommand);
+        std::process::exit(1);
+    }
+    let problems: usize = checks.into_iter().map(|(name, check)| check(name)).sum();
+    if problems > 0 {
+        error!("{} configuration problems", problems);
+        std::process::exit(1);
+    }
+    info!("Configuration is valid");
     Ok(())
 }
 
 #[tokio::main]
 async fn main() -> StdResult<(), Box<dyn std::error::Error>> {
     pretty_env_logger::init();
+    supervisor::install_panic_hook();
 
     debug!("Reading dotenv...");
     dotenv().ok();
 
     let command = Command::from_args();
     match command {
+        #[cfg(feature = "web")]
         Command::DeepQWebserver(args) => deepq_web(&args).await?,
+        #[cfg(feature = "listener")]
         Command::IrwinJobListener(args) => deepq_irwin_job_listener(&args).await?,
+        #[cfg(feature = "listener")]
+        Command::ReplayStream(args) => replay_stream(&args).await?,
+        #[cfg(feature = "cli-admin")]
         Command::FishnetNewUser(args) => fishnet_new_user(&args).await?,
+        Command::Reaper(args) => reaper(&args).await?,
+        Command::Reconcile(args) => reconcile(&args).await?,
+        Command::Verifier(args) => verifier(&args).await?,
+        #[cfg(feature = "cli-admin")]
+        Command::SeedDev(args) => seed_dev(&args).await?,
+        Command::SlaMonitor(args) => sla_monitor(&args).await?,
+        Command::LeaderboardSweeper(args) => leaderboard_sweeper(&args).await?,
+        Command::Scheduler(args) => scheduler_command(&args).await?,
+        #[cfg(feature = "cli-admin")]
+        Command::NormalizeUsernames(args) => normalize_usernames(&args).await?,
+        #[cfg(feature = "cli-admin")]
+        Command::Shell(args) => shell(&args).await?,
+        Command::Config(Config::Check(args)) => config_check(&args)?,
     }
 
     Ok(())
@@ -0,0 +1,312 @@
+// Copyright 2020 Lakin Wecker
+//
+// This file is part of lila-deepq.
+//
+// lila-deepq is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// lila-deepq is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with lila-deepq.  If not, see <https://www.gnu.org/licenses/>.
+//
+// In process request metrics, enough to tell whether we're meeting our
+// latency and error rate objectives without running a metrics stack.
+// Processes without an admin api of their own publish their counters to
+// mongo, so the webserver's admin api can show them too.
+//
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use chrono::{prelude::*, Duration as ChronoDuration};
+use futures::stream::TryStreamExt;
+use log::error;
+use mongodb::bson::{doc, from_document, Bson, DateTime as BsonDateTime, Document};
+use mongodb::options::UpdateOptions;
+use mongodb::Collection;
+use serde::{Deserialize, Serialize};
+#[cfg(feature = "web")]
+use warp::log::{Info, Log};
+
+use crate::db::DbConn;
+use crate::error::{Error, Result};
+use crate::supervisor;
+
+/// Upper bounds of the latency histogram buckets, the last bucket is unbounded.
+pub const LATENCY_BUCKETS_MS: [u64; 10] = [5, 10, 25, 50, 100, 150, 250, 500, 1000, 5000];
+
+const WINDOW_MINUTES: i64 = 60;
+
+const PUBLISH_INTERVAL_SECONDS: u64 = 60;
+
+// NOTE: a process that stopped publishing this long ago is gone.
+const PUBLISHED_STALE_MINUTES: i64 = 10;
+
+#[derive(Serialize, Debug, Clone)]
+pub struct Histogram {
+    pub buckets: Vec<u64>, // Counts per LATENCY_BUCKETS_MS, plus one for slower requests
+    pub count: u64,
+    pub errors: u64,
+    pub total_ms: u64,
+}
+
+impl Default for Histogram {
+    fn default() -> Histogram {
+        Histogram {
+            buckets: vec![0; LATENCY_BUCKETS_MS.len() + 1],
+            count: 0,
+            errors: 0,
+            total_ms: 0,
+        }
+    }
+}
+
+impl Histogram {
+    fn record(&mut self, elapsed: Duration, is_error: bool) {
+        let ms = elapsed.as_millis() as u64;
+        let bucket = LATENCY_BUCKETS_MS
+            .iter()
+            .position(|bound| ms <= *bound)
+            .unwrap_or(LATENCY_BUCKETS_MS.len());
+        self.buckets[bucket] += 1;
+        self.count += 1;
+        self.total_ms += ms;
+        if is_error {
+            self.errors += 1;
+        }
+    }
+
+    fn merge(&mut self, other: &Histogram) {
+        for (bucket, count) in self.buckets.iter_mut().zip(other.buckets.iter()) {
+            *bucket += count;
+        }
+        self.count += other.count;
+        self.errors += other.errors;
+        self.total_ms += other.total_ms;
+    }
+
+    fn within(&self, target_ms: u64) -> u64 {
+        LATENCY_BUCKETS_MS
+            .iter()
+            .zip(self.buckets.iter())
+            .filter(|(bound, _)| **bound <= target_ms)
+            .map(|(_, count)| count)
+            .sum()
+    }
+}
+
+/// The latency objective for a route: `objective` of requests within `target_ms`.
+pub fn slo_for(route: &str) -> (u64, f64) {
+    match route {
+        // NOTE: long polling acquires (?wait=) are expected to miss this.
+        "/fishnet/acquire" => (150, 0.99),
+        "/fishnet/analysis/:id" => (250, 0.99),
+        _ => (500, 0.99),
+    }
+}
+
+const ROUTE_GROUPS: [&str; 3] = ["fishnet", "reports", "admin"];
+
+// NOTE: every segment of a route that isn't an id. Keep in step with the
+//       handlers, a segment missing here is counted as an id.
+const STATIC_SEGMENTS: [&str; 39] = [
+    "abort", "aborts", "acquire", "analysis", "badge", "badge.svg", "bundle", "changes", "commitment",
+    "digest", "eta", "exclude", "flags", "follow-up", "games", "imports", "irwin-submissions",
+    "jobs", "key", "keys", "metrics", "move", "org", "origins", "pause", "presets", "receipts",
+    "refresh", "reports", "result-webhooks", "scheduled-tasks", "search", "slo", "slow-queries",
+    "status", "trust", "undelete", "verify", "webhook",
+];
+
+// NOTE: the deepest route is /admin/reports/:id/follow-up.
+const MAX_SEGMENTS: usize = 4;
+
+fn is_version(segment: &str) -> bool {
+    segment
+        .strip_prefix('v')
+        .is_some_and(|number| !number.is_empty() && number.bytes().all(|b| b.is_ascii_digit()))
+}
+
+/// The route that serves the path, the same whichever api version it came
+/// through and with ids collapsed, so that routes aggregate. Paths no route
+/// serves are all "other", so probing them can't grow the set of routes.
+pub fn route(path: &str) -> String {
+    let mut segments: Vec<&str> = path.split('/').filter(|s| !s.is_empty()).collect();
+    if segments.first().is_some_and(|segment| is_version(segment)) {
+        segments.remove(0);
+    }
+    match segments.split_first() {
+        Some((group, rest)) if ROUTE_GROUPS.contains(group) && rest.len() < MAX_SEGMENTS => {
+            let rest = rest
+                .iter()
+                .map(|segment| if STATIC_SEGMENTS.contains(segment) { *segment } else { ":id" });
+            std::iter::once(*group).chain(rest).fold(String::new(), |route, segment| route + "/" + segment)
+        }
+        _ => "other".to_string(),
+    }
+}
+
+/// The counters a process last published.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PublishedCounters {
+    pub _id: String, // The process, see supervisor::process
+    pub subcommand: String,
+    pub date_published: BsonDateTime,
+    pub counters: HashMap<String, i64>,
+}
+
+impl PublishedCounters {
+    pub fn coll(db: DbConn) -> Collection {
+        db.database.collection("deepq_metrics")
+    }
+}
+
+/// The counters of every process that published lately.
+pub async fn find_published(db: DbConn) -> Result<Vec<PublishedCounters>> {
+    let since = Utc::now() - ChronoDuration::minutes(PUBLISHED_STALE_MINUTES);
+    PublishedCounters::coll(db)
+        .find(doc! {"date_published": {"$gte": since}}, None)
+        .await?
+        .map_err(Error::from)
+        .and_then(|doc| async move { Ok(from_document::<PublishedCounters>(doc)?) })
+        .try_collect()
+        .await
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct SloStatus {
+    pub route: String,
+    pub target_ms: u64,
+    pub objective: f64,
+    pub requests: u64,
+    pub within_target: f64,
+    pub error_rate: f64,
+    pub compliant: bool,
+}
+
+type Window = VecDeque<(i64, Histogram)>; // By minute, oldest first
+
+#[derive(Clone, Default)]
+pub struct Metrics {
+    routes: Arc<Mutex<HashMap<String, Window>>>,
+    counters: Arc<Mutex<HashMap<String, u64>>>, // Running totals since startup
+}
+
+impl Metrics {
+    pub fn new() -> Metrics {
+        Metrics::default()
+    }
+
+    pub fn record(&self, route: String, elapsed: Duration, is_error: bool) {
+        let minute = Utc::now().timestamp() / 60;
+        let mut routes = self.routes.lock().expect("metrics lock poisoned");
+        let window = routes.entry(route).or_default();
+        if window.back().is_none_or(|(m, _)| *m != minute) {
+            window.push_back((minute, Histogram::default()));
+        }
+        while window.front().is_some_and(|(m, _)| *m <= minute - WINDOW_MINUTES) {
+            window.pop_front();
+        }
+        if let Some((_, histogram)) = window.back_mut() {
+            histogram.record(elapsed, is_error);
+        }
+    }
+
+    /// Adds to a running total, e.g. of the work a background task did.
+    pub fn count(&self, name: &str, by: u64) {
+        let mut counters = self.counters.lock().expect("metrics lock poisoned");
+        *counters.entry(name.to_string()).or_insert(0) += by;
+    }
+
+    /// The running totals since startup, by name.
+    pub fn counters(&self) -> HashMap<String, u64> {
+        self.counters.lock().expect("metrics lock poisoned").clone()
+    }
+
+    async fn publish(&self, db: DbConn, subcommand: &str) -> Result<()> {
+        let counters: Document = self
+            .counters()
+            .into_iter()
+            .map(|(name, count)| (name, Bson::Int64(count as i64)))
+            .collect();
+        PublishedCounters::coll(db)
+            .update_one(
+                doc! {"_id": supervisor::process()},
+                doc! {"$set": {
+                    "subcommand": subcommand,
+                    "date_published": Utc::now(),
+                    "counters": counters,
+                }},
+                UpdateOptions::builder().upsert(true).build(),
+            )
+            .await?;
+        Ok(())
+    }
+
+    /// Publishes the counters every minute, for processes without an admin api.
+    pub async fn publish_periodically(self, db: DbConn, subcommand: &'static str) {
+        loop {
+            tokio::time::sleep(tokio::time::Duration::from_secs(PUBLISH_INTERVAL_SECONDS)).await;
+            if let Err(err) = self.publish(db.clone(), subcommand).await {
+                error!("Unable to publish metrics: {:?}", err);
+            }
+        }
+    }
+
+    /// The histograms for the last WINDOW_MINUTES, by route.
+    pub fn snapshot(&self) -> HashMap<String, Histogram> {
+        let oldest = Utc::now().timestamp() / 60 - WINDOW_MINUTES;
+        let routes = self.routes.lock().expect("metrics lock poisoned");
+        routes
+            .iter()
+            .map(|(route, window)| {
+                let mut total = Histogram::default();
+                for (_, histogram) in window.iter().filter(|(m, _)| *m > oldest) {
+                    total.merge(histogram);
+                }
+                (route.clone(), total)
+            })
+            .collect()
+    }
+
+    pub fn slo_summary(&self) -> Vec<SloStatus> {
+        let mut summary: Vec<SloStatus> = self
+            .snapshot()
+            .into_iter()
+            .map(|(route, histogram)| {
+                let (target_ms, objective) = slo_for(&route);
+                let requests = histogram.count.max(1) as f64;
+                let within_target = histogram.within(target_ms) as f64 / requests;
+                SloStatus {
+                    route,
+                    target_ms,
+                    objective,
+                    requests: histogram.count,
+                    within_target,
+                    error_rate: histogram.errors as f64 / requests,
+                    compliant: histogram.count == 0 || within_target >= objective,
+                }
+            })
+            .collect();
+        summary.sort_by(|a, b| a.route.cmp(&b.route));
+        summary
+    }
+
+    /// Records every request that passes through the filter it's applied to.
+    #[cfg(feature = "web")]
+    pub fn log(&self) -> Log<impl Fn(Info) + Clone + Send + Sync> {
+        let metrics = self.clone();
+        warp::log::custom(move |info: Info| {
+            metrics.record(
+                route(info.path()),
+                info.elapsed(),
+                info.status().is_server_error(),
+            )
+        })
+    }
+}
@@ -0,0 +1,187 @@
+// Copyright 2021 Lakin Wecker
+//
+// This file is part of lila-deepq.
+//
+// lila-deepq is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// lila-deepq is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with lila-deepq.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::sync::Arc;
+
+use chrono::prelude::*;
+use log::{debug, error, warn};
+use mongodb::{
+    bson::{doc, from_document, to_document, DateTime},
+    options::ReplaceOptions,
+    Collection,
+};
+use serde::{Deserialize, Serialize};
+use tokio::sync::RwLock;
+use tokio::time::{Duration, Instant};
+
+use crate::db::DbConn;
+use crate::error::Result;
+use crate::fishnet::model::AnalysisType;
+
+// NOTE: the same as the flags, a pause takes effect everywhere within this.
+const REFRESH_INTERVAL: Duration = Duration::from_secs(30);
+
+const PAUSE_SETTINGS_ID: &str = "pause";
+
+/// Nothing of the analysis type, or nothing at all, is handed out while paused.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Pause {
+    pub analysis_type: Option<AnalysisType>, // None pauses the whole queue
+    pub reason: String,
+    pub operator: String, // The admin who paused it
+    pub date_paused: DateTime,
+}
+
+impl Pause {
+    pub fn covers(&self, analysis_type: &AnalysisType) -> bool {
+        self.analysis_type.as_ref().is_none_or(|paused| paused == analysis_type)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct PauseSettings {
+    pub _id: String,
+    pub pauses: Vec<Pause>,
+}
+
+impl PauseSettings {
+    pub fn coll(db: DbConn) -> Collection {
+        db.database.collection("deepq_settings")
+    }
+}
+
+#[derive(Default)]
+struct Cache {
+    pauses: Vec<Pause>,
+    refreshed_at: Option<Instant>,
+}
+
+impl Cache {
+    fn is_stale(&self) -> bool {
+        self.refreshed_at
+            .is_none_or(|refreshed_at| refreshed_at.elapsed() > REFRESH_INTERVAL)
+    }
+}
+
+/// The queue pauses, stored in mongo so that they survive restarts during
+/// long incidents, and cached in process like the flags.
+#[derive(Clone)]
+pub struct Pauses {
+    db: DbConn,
+    cache: Arc<RwLock<Cache>>,
+}
+
+impl Pauses {
+    pub fn new(db: DbConn) -> Pauses {
+        Pauses {
+            db,
+            cache: Arc::new(RwLock::new(Cache::default())),
+        }
+    }
+
+    /// The current pauses, at most REFRESH_INTERVAL out of date.
+    pub async fn all(&self) -> Vec<Pause> {
+        if self.cache.read().await.is_stale() {
+            if let Err(err) = self.refresh().await {
+                // NOTE: keep using whatever we had before, it's better than nothing.
+                error!("Pauses::all > unable to refresh pauses: {:?}", err);
+            }
+        }
+        self.cache.read().await.pauses.clone()
+    }
+
+    /// The pause that keeps jobs of the analysis type from being handed out, if any.
+    pub async fn pause_for(&self, analysis_type: &AnalysisType) -> Option<Pause> {
+        self.all()
+            .await
+            .into_iter()
+            .find(|pause| pause.covers(analysis_type))
+    }
+
+    pub async fn refresh(&self) -> Result<()> {
+        let settings = PauseSettings::coll(self.db.clone())
+            .find_one(doc! {"_id": PAUSE_SETTINGS_ID}, None)
+            .await?
+            .map(from_document::<PauseSettings>)
+            .transpose()?
+            .unwrap_or_default();
+        let mut cache = self.cache.write().await;
+        cache.pauses = settings.pauses;
+        cache.refreshed_at = Some(Instant::now());
+        debug!("Pauses::refresh > {:?}", cache.pauses);
+        Ok(())
+    }
+
+    async fn save(&self, pauses: Vec<Pause>) -> Result<()> {
+        let settings = PauseSettings {
+            _id: PAUSE_SETTINGS_ID.to_string(),
+            pauses,
+        };
+        PauseSettings::coll(self.db.clone())
+            .replace_one(
+                doc! {"_id": PAUSE_SETTINGS_ID},
+                to_document(&settings)?,
+                ReplaceOptions::builder().upsert(true).build(),
+            )
+            .await?;
+        let mut cache = self.cache.write().await;
+        cache.pauses = settings.pauses;
+        cache.refreshed_at = Some(Instant::now());
+        Ok(())
+    }
+
+    /// Pauses the analysis type, or the whole queue, replacing any earlier
+    /// pause of the same scope.
+    pub async fn pause(
+        &self,
+        analysis_type: Option<AnalysisType>,
+        reason: String,
+        operator: String,
+    ) -> Result<Pause> {
+        self.refresh().await?;
+        let pause = Pause {
+            analysis_type,
+            reason,
+            operator,
+            date_paused: DateTime(Utc::now()),
+        };
+        warn!(
+            "Pauses::pause > {:?} by {}: {}",
+            pause.analysis_type, pause.operator, pause.reason
+        );
+        let mut pauses = self.cache.read().await.pauses.clone();
+        pauses.retain(|p| p.analysis_type != pause.analysis_type);
+        pauses.push(pause.clone());
+        self.save(pauses).await?;
+        Ok(pause)
+    }
+
+    /// Lifts the pause of exactly that scope, returning it if there was one.
+    pub async fn resume(&self, analysis_type: Option<AnalysisType>) -> Result<Option<Pause>> {
+        self.refresh().await?;
+        let mut pauses = self.cache.read().await.pauses.clone();
+        let lifted = pauses
+            .iter()
+            .position(|p| p.analysis_type == analysis_type)
+            .map(|i| pauses.remove(i));
+        if lifted.is_some() {
+            warn!("Pauses::resume > {:?}", analysis_type);
+            self.save(pauses).await?;
+        }
+        Ok(lifted)
+    }
+}
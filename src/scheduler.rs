@@ -0,0 +1,396 @@
+// Copyright 2020 Lakin Wecker
+//
+// This file is part of lila-deepq.
+//
+// lila-deepq is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// lila-deepq is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with lila-deepq.  If not, see <https://www.gnu.org/licenses/>.
+//
+// Runs the recurring work, reaping, verification, provider monitoring and
+// leaderboard sweeps, on cron schedules. Each run takes a lease in mongo, so
+// a run that's still going, here or in another scheduler process, is never
+// started again on top of itself, and the outcome of the last run is kept
+// there for the admin API, along with the counters the task exported.
+//
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use chrono::{prelude::*, Duration};
+use futures::future::BoxFuture;
+use futures::stream::TryStreamExt;
+use log::{error, info};
+use mongodb::bson::{doc, from_document, Bson, DateTime as BsonDateTime, Document};
+use mongodb::options::UpdateOptions;
+use mongodb::Collection;
+use rand::{thread_rng, Rng};
+use serde::{Deserialize, Serialize};
+use tokio::time::sleep;
+
+use crate::db::DbConn;
+use crate::error::{Error, Result};
+use crate::metrics::Metrics;
+use crate::supervisor;
+
+// NOTE: enough to tell errors apart, without storing whole backtraces.
+const ERROR_EXCERPT_CHARS: usize = 500;
+
+/// A field of a cron expression, as a bitmask of the values it matches.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Field {
+    matches: u64,
+    any: bool, // A plain *, see Schedule::matches_day
+}
+
+impl Field {
+    fn parse(s: &str, min: u32, max: u32) -> Result<Field> {
+        let invalid = || Error::InvalidCommandLineArguments;
+        let mut matches = 0u64;
+        for part in s.split(',') {
+            let (range, step) = match part.split_once('/') {
+                Some((range, step)) => (range, step.parse::<u32>().map_err(|_| invalid())?),
+                None => (part, 1),
+            };
+            let (from, to) = match range {
+                "*" => (min, max),
+                _ => match range.split_once('-') {
+                    Some((from, to)) => (
+                        from.parse().map_err(|_| invalid())?,
+                        to.parse().map_err(|_| invalid())?,
+                    ),
+                    None => {
+                        let value = range.parse().map_err(|_| invalid())?;
+                        // NOTE: a single value with a step, like 5/15, runs to the end.
+                        (value, if step > 1 { max } else { value })
+                    }
+                },
+            };
+            if step == 0 || from < min || to > max || from > to {
+                return Err(invalid());
+            }
+            for value in (from..=to).step_by(step as usize) {
+                matches |= 1 << value;
+            }
+        }
+        Ok(Field {
+            matches,
+            any: s == "*",
+        })
+    }
+
+    fn matches(&self, value: u32) -> bool {
+        self.matches & (1 << value) != 0
+    }
+}
+
+/// When a task runs, as a cron expression: minute, hour, day of month, month
+/// and day of week, in UTC.
+#[derive(Debug, Clone)]
+pub struct Schedule {
+    expression: String,
+    minute: Field,
+    hour: Field,
+    day_of_month: Field,
+    month: Field,
+    day_of_week: Field,
+}
+
+impl FromStr for Schedule {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Schedule> {
+        let fields: Vec<&str> = s.split_whitespace().collect();
+        match fields.as_slice() {
+            [minute, hour, day_of_month, month, day_of_week] => {
+                let mut day_of_week = Field::parse(day_of_week, 0, 7)?;
+                // NOTE: both 0 and 7 are sunday.
+                if day_of_week.matches(7) {
+                    day_of_week.matches |= 1;
+                }
+                Ok(Schedule {
+                    expression: fields.join(" "),
+                    minute: Field::parse(minute, 0, 59)?,
+                    hour: Field::parse(hour, 0, 23)?,
+                    day_of_month: Field::parse(day_of_month, 1, 31)?,
+                    month: Field::parse(month, 1, 12)?,
+                    day_of_week,
+                })
+            }
+            _ => Err(Error::InvalidCommandLineArguments),
+        }
+    }
+}
+
+impl fmt::Display for Schedule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.expression)
+    }
+}
+
+impl Schedule {
+    /// Like cron, a day matches either day field when both are restricted.
+    fn matches_day(&self, date: NaiveDate) -> bool {
+        let day_of_month = self.day_of_month.matches(date.day());
+        let day_of_week = self.day_of_week.matches(date.weekday().num_days_from_sunday());
+        match (self.day_of_month.any, self.day_of_week.any) {
+            (false, false) => day_of_month || day_of_week,
+            _ => day_of_month && day_of_week,
+        }
+    }
+
+    /// The first minute strictly after `after` that the schedule matches.
+    pub fn next_after(&self, after: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        let start = after.with_second(0)?.with_nanosecond(0)? + Duration::minutes(1);
+        let mut date = start.naive_utc().date();
+        // NOTE: every schedule that matches at all does within 4 years, e.g. feb 29th.
+        for _ in 0..366 * 4 {
+            if self.month.matches(date.month()) && self.matches_day(date) {
+                for hour in 0..24 {
+                    if !self.hour.matches(hour) {
+                        continue;
+                    }
+                    for minute in 0..60 {
+                        if !self.minute.matches(minute) {
+                            continue;
+                        }
+                        let at = Utc.from_utc_datetime(&date.and_hms_opt(hour, minute, 0)?);
+                        if at >= start {
+                            return Some(at);
+                        }
+                    }
+                }
+            }
+            date = date.succ_opt()?;
+        }
+        None
+    }
+}
+
+/// The last run of a task, and whether one is going on right now.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ScheduledTask {
+    pub _id: String, // The task's name
+    pub schedule: Option<String>,
+    pub running_until: Option<BsonDateTime>, // The lease of the current run, if any
+    pub owner: Option<String>,              // The process holding the lease
+    pub next_run: Option<BsonDateTime>,
+    pub last_slot: Option<BsonDateTime>, // When the last run was scheduled for, before jitter
+    pub last_started: Option<BsonDateTime>,
+    pub last_finished: Option<BsonDateTime>,
+    pub last_duration_ms: Option<i64>,
+    pub last_outcome: Option<String>, // ok or failed
+    pub last_error: Option<String>,
+    #[serde(default)]
+    pub counters: HashMap<String, i64>, // Named "<task>.<what>", since the scheduler started
+}
+
+impl ScheduledTask {
+    pub fn coll(db: DbConn) -> Collection {
+        db.database.collection("deepq_scheduled_tasks")
+    }
+}
+
+pub async fn find_all(db: DbConn) -> Result<Vec<ScheduledTask>> {
+    ScheduledTask::coll(db.analytics())
+        .find(doc! {}, None)
+        .await?
+        .map_err(Error::from)
+        .and_then(|doc| async move { Ok(from_document::<ScheduledTask>(doc)?) })
+        .try_collect()
+        .await
+}
+
+/// Takes the task's lease for the run scheduled at `slot`, unless a run that
+/// hasn't outlived its lease holds it, or another scheduler already ran it.
+async fn claim(db: DbConn, name: &str, slot: DateTime<Utc>, lease: Duration) -> Result<bool> {
+    let coll = ScheduledTask::coll(db);
+    coll.update_one(
+        doc! {"_id": name},
+        doc! {"$setOnInsert": {"running_until": Bson::Null}},
+        UpdateOptions::builder().upsert(true).build(),
+    )
+    .await?;
+    let now = Utc::now();
+    let claimed = coll
+        .update_one(
+            doc! {
+                "_id": name,
+                "$and": [
+                    {"$or": [{"running_until": Bson::Null}, {"running_until": {"$lt": now}}]},
+                    {"$or": [{"last_slot": Bson::Null}, {"last_slot": {"$lt": slot}}]},
+                ],
+            },
+            doc! {"$set": {
+                "running_until": now + lease,
+                "owner": supervisor::process(),
+                "last_slot": slot,
+                "last_started": now,
+            }},
+            None,
+        )
+        .await?;
+    Ok(claimed.modified_count > 0)
+}
+
+/// The counters the task exported, so they outlive the scheduler process.
+fn task_counters(metrics: &Metrics, name: &str) -> Document {
+    let prefix = format!("{}.", name);
+    metrics
+        .counters()
+        .into_iter()
+        .filter(|(counter, _)| counter.starts_with(&prefix))
+        .map(|(counter, count)| (counter, Bson::Int64(count as i64)))
+        .collect()
+}
+
+async fn release(
+    db: DbConn,
+    name: &str,
+    started: DateTime<Utc>,
+    result: &Result<()>,
+    counters: Document,
+) -> Result<()> {
+    let now = Utc::now();
+    let error = match result {
+        Ok(()) => Bson::Null,
+        Err(err) => Bson::String(err.to_string().chars().take(ERROR_EXCERPT_CHARS).collect()),
+    };
+    ScheduledTask::coll(db)
+        .update_one(
+            doc! {"_id": name},
+            doc! {"$set": {
+                "running_until": Bson::Null,
+                "last_finished": now,
+                "last_duration_ms": (now - started).num_milliseconds(),
+                "last_outcome": if result.is_ok() { "ok" } else { "failed" },
+                "last_error": error,
+                "counters": counters,
+            }},
+            None,
+        )
+        .await?;
+    Ok(())
+}
+
+async fn set_next_run(db: DbConn, name: &str, schedule: &Schedule, next_run: DateTime<Utc>) -> Result<()> {
+    ScheduledTask::coll(db)
+        .update_one(
+            doc! {"_id": name},
+            doc! {"$set": {"schedule": schedule.to_string(), "next_run": next_run}},
+            UpdateOptions::builder().upsert(true).build(),
+        )
+        .await?;
+    Ok(())
+}
+
+type Run = Arc<dyn Fn(DbConn) -> BoxFuture<'static, Result<()>> + Send + Sync>;
+
+#[derive(Clone)]
+struct Task {
+    name: &'static str,
+    schedule: Schedule,
+    lease: Duration, // How long a run may take before another may start anyway
+    run: Run,
+}
+
+/// The recurring tasks of this process, each started on its own schedule.
+pub struct Scheduler {
+    db: DbConn,
+    jitter: Duration,
+    metrics: Metrics, // Handed to the tasks, for the counts they export
+    tasks: Vec<Task>,
+}
+
+impl Scheduler {
+    /// Each run starts up to `jitter` late, so schedulers don't all hit mongo at once.
+    pub fn new(db: DbConn, jitter: Duration, metrics: Metrics) -> Scheduler {
+        Scheduler {
+            db,
+            jitter,
+            metrics,
+            tasks: Vec::new(),
+        }
+    }
+
+    pub fn add<F>(&mut self, name: &'static str, schedule: Schedule, lease: Duration, run: F)
+    where
+        F: Fn(DbConn) -> BoxFuture<'static, Result<()>> + Send + Sync + 'static,
+    {
+        self.tasks.push(Task {
+            name,
+            schedule,
+            lease,
+            run: Arc::new(run),
+        });
+    }
+
+    /// Runs every task on its schedule, until the process exits.
+    pub async fn run(self) {
+        let Scheduler {
+            db,
+            jitter,
+            metrics,
+            tasks,
+        } = self;
+        let handles: Vec<_> = tasks
+            .into_iter()
+            .map(|task| {
+                let (db, metrics) = (db.clone(), metrics.clone());
+                supervisor::supervise(task.name, move || {
+                    run_task(db.clone(), metrics.clone(), task.clone(), jitter)
+                })
+            })
+            .collect();
+        futures::future::join_all(handles).await;
+    }
+}
+
+async fn run_task(db: DbConn, metrics: Metrics, task: Task, jitter: Duration) {
+    let p = format!("scheduler > {} >", task.name);
+    loop {
+        let slot = match task.schedule.next_after(Utc::now()) {
+            Some(slot) => slot,
+            None => {
+                error!("{} {} never matches", p, task.schedule);
+                return;
+            }
+        };
+        let next_run = slot + Duration::seconds(thread_rng().gen_range(0..=jitter.num_seconds().max(0)));
+        if let Err(err) = set_next_run(db.clone(), task.name, &task.schedule, next_run).await {
+            error!("{} unable to record the next run: {:?}", p, err);
+        }
+        sleep((next_run - Utc::now()).to_std().unwrap_or_default()).await;
+        match claim(db.clone(), task.name, slot, task.lease).await {
+            Ok(true) => {}
+            Ok(false) => {
+                info!("{} still running since the last time, or already ran at {}, skipped", p, slot);
+                continue;
+            }
+            Err(err) => {
+                error!("{} unable to take the lease: {:?}", p, err);
+                continue;
+            }
+        }
+        let started = Utc::now();
+        info!("{} starting", p);
+        let result = (task.run)(db.clone()).await;
+        match &result {
+            Ok(()) => info!("{} finished in {:?}", p, Utc::now() - started),
+            Err(err) => error!("{} failed: {:?}", p, err),
+        }
+        let counters = task_counters(&metrics, task.name);
+        if let Err(err) = release(db.clone(), task.name, started, &result, counters).await {
+            error!("{} unable to record the run: {:?}", p, err);
+        }
+    }
+}
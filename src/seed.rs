@@ -0,0 +1,241 @@
+// Copyright 2020 Lakin Wecker
+//
+// This file is part of lila-deepq.
+//
+// lila-deepq is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// lila-deepq is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with lila-deepq.  If not, see <https://www.gnu.org/licenses/>.
+//
+// Populates a local database with enough data to make the webserver
+// interesting to poke at during development.
+//
+use std::convert::TryFrom;
+
+use chrono::Duration;
+use futures::stream::StreamExt;
+use log::info;
+use serde_json::json;
+use shakmaty::san::San;
+
+use crate::db::DbConn;
+use crate::deepq::units::{NodeBudget, Nodes};
+use crate::deepq::api::{find_game, find_report, upsert_one_game_analysis, CreateGame, UpdateGameAnalysis};
+use crate::deepq::import::read_games;
+use crate::deepq::model::{EngineType, PlyAnalysis, Provenance, ReportId, ReportOrigin};
+use crate::deepq::payload::{PayloadGuard, PayloadLimits};
+use crate::deepq::policy::DEFAULT_SCREENING_COOLDOWN_DAYS;
+use crate::deepq::score::Perspective;
+use crate::error::{Context, DomainError, ProtocolError, Result};
+use crate::fishnet::api::{assign_job, create_api_user, CreateApiUser};
+use crate::fishnet::model::{AnalysisType, ApiUser, Job, TrustLevel};
+use crate::irwin::api::{
+    add_to_queue, reconcile_completeness, Game, PartialSubmission, Request, User,
+};
+use crate::irwin::submitter::Submitter;
+use crate::metrics::Metrics;
+
+const BUNDLED_GAMES: &str = include_str!("seed/games.pgn");
+
+/// The bundled games, validated the same way the irwin intake validates them.
+///
+/// NOTE: they are replayed through the regular irwin intake, so they must be
+///       lichess games, with their id in the Site header, and legal.
+pub fn bundled_games() -> Result<Vec<Game>> {
+    read_games(BUNDLED_GAMES.as_bytes())?
+        .into_iter()
+        .map(|pgn_game| {
+            let id = pgn_game.game_id().ok_or(ProtocolError::Deserialization)?;
+            let player = |color: &str| {
+                pgn_game.headers.get(color).cloned().ok_or(ProtocolError::Deserialization)
+            };
+            let white = player("White")?;
+            let black = player("Black")?;
+            let pgn = pgn_game
+                .sans
+                .iter()
+                .map(|san| san.parse::<San>().map_err(|_| ProtocolError::Deserialization))
+                .collect::<std::result::Result<Vec<San>, ProtocolError>>()?;
+            // NOTE: lichess sends centiseconds, a steady 3 to 10 seconds a move will do.
+            let emts = (0..pgn.len() as i32).map(|i| 300 + (i * 37) % 700).collect();
+            let game = Game {
+                id,
+                white: white.into(),
+                black: black.into(),
+                emts: Some(emts),
+                pgn,
+                analysis: None,
+            };
+            CreateGame::try_from(&game).with_context(|| format!("Game({})", game.id))?;
+            Ok(game)
+        })
+        .collect()
+}
+
+fn request(user: &str, origin: ReportOrigin, score: i32, games: &[Game]) -> Request {
+    let games: Vec<Game> = games
+        .iter()
+        .filter(|g| g.white.as_str() == user || g.black.as_str() == user)
+        .cloned()
+        .collect();
+    Request {
+        t: "request".to_string(),
+        origin,
+        user: User {
+            id: user.to_string().into(),
+            titled: false,
+            engine: false,
+            games: games.len() as i32,
+        },
+        games,
+        score: Some(score),
+    }
+}
+
+/// A plausible analysis that recommends the move that was played at every ply.
+async fn analyse_job(db: DbConn, payload: &PayloadGuard, source: &ApiUser, job: Job) -> Result<()> {
+    let game = find_game(db.clone(), job.game_id.clone())
+        .await?
+        .ok_or(DomainError::NotFound)
+        .with_context(|| format!("Game({})", job.game_id))?;
+    let analysis = game
+        .pgn
+        .iter()
+        .enumerate()
+        .map(|(ply, uci)| {
+            let ply_analysis = json!({
+                "pv": uci.to_string(),
+                "depth": 20,
+                "score": {"cp": if ply % 2 == 0 { 25 } else { -25 }},
+                "time": 1500,
+                "nodes": 2_250_000,
+            });
+            Ok(Some(serde_json::from_value::<PlyAnalysis>(ply_analysis)?))
+        })
+        .collect::<Result<Vec<Option<PlyAnalysis>>>>()?;
+    upsert_one_game_analysis(
+        db,
+        payload,
+        UpdateGameAnalysis {
+            job_id: job._id,
+            game_id: job.game_id,
+            source_id: source.user.clone().unwrap_or_else(|| source.name.clone().into()),
+            analysis,
+            requested_pvs: None,
+            requested_depth: None,
+            requested_nodes: NodeBudget {
+                nnue: Nodes::new(2_250_000),
+                classical: Nodes::new(4_050_000),
+            },
+            engine: EngineType::Stockfish,
+        },
+    )
+    .await?;
+    Ok(())
+}
+
+async fn jobs_for_report(db: DbConn, report_id: ReportId) -> Result<Vec<Job>> {
+    let report = find_report(db.clone(), report_id.clone())
+        .await?
+        .ok_or(DomainError::NotFound)
+        .with_context(|| format!("Report({})", report_id))?;
+    Job::find_by_report(db, report)
+        .await?
+        .collect::<Vec<Result<Job>>>()
+        .await
+        .into_iter()
+        .collect()
+}
+
+/// Seeds keys, games, reports in every state and their analyses.
+pub async fn seed(db: DbConn) -> Result<Vec<ApiUser>> {
+    let p = "seed >";
+    let core = create_api_user(
+        db.clone(),
+        CreateApiUser {
+            user: Some("devcore".to_string().into()),
+            name: "dev-core".to_string(),
+            perms: vec![
+                AnalysisType::UserAnalysis,
+                AnalysisType::SystemAnalysis,
+                AnalysisType::Deep,
+            ],
+            admin: true,
+            trust: TrustLevel::Core,
+            org: Some("dev".to_string()),
+            committed_jobs_per_hour: Some(60),
+            allowed_origins: None,
+        },
+    )
+    .await?;
+    let new = create_api_user(
+        db.clone(),
+        CreateApiUser {
+            user: Some("devnew".to_string().into()),
+            name: "dev-new".to_string(),
+            perms: vec![AnalysisType::Deep],
+            admin: false,
+            trust: TrustLevel::New,
+            org: Some("dev".to_string()),
+            committed_jobs_per_hour: None,
+            allowed_origins: None,
+        },
+    )
+    .await?;
+    info!("{} created keys", p);
+
+    let games = bundled_games()?;
+    let cooldown = Duration::days(DEFAULT_SCREENING_COOLDOWN_DAYS);
+    let complete = add_to_queue(
+        db.clone(),
+        request("devcheater1", ReportOrigin::Moderator, 90, &games),
+        Provenance::PgnUpload, // The bundled games
+        cooldown,
+    )
+    .await?
+    .ok_or_else(|| DomainError::Invalid("seed, devcheater1 was screened recently".to_string()))?;
+    let in_progress = add_to_queue(
+        db.clone(),
+        request("devcheater2", ReportOrigin::Random, 40, &games),
+        Provenance::PgnUpload, // The bundled games
+        cooldown,
+    )
+    .await?
+    .ok_or_else(|| DomainError::Invalid("seed, devcheater2 was screened recently".to_string()))?;
+    add_to_queue(
+        db.clone(),
+        request("devwhite1", ReportOrigin::Leaderboard, 10, &games),
+        Provenance::PgnUpload, // The bundled games
+        cooldown,
+    )
+    .await?;
+    info!("{} queued {} games in 3 reports", p, games.len());
+
+    let metrics = Metrics::new();
+    let payload = PayloadGuard::new(PayloadLimits::default(), metrics.clone(), None);
+
+    for job in jobs_for_report(db.clone(), complete).await? {
+        analyse_job(db.clone(), &payload, &core, job).await?;
+    }
+    let mut in_progress_jobs = jobs_for_report(db.clone(), in_progress).await?.into_iter();
+    if let Some(job) = in_progress_jobs.next() {
+        analyse_job(db.clone(), &payload, &core, job).await?;
+    }
+    // NOTE: leave one job in flight, so that the status pages show an owner.
+    assign_job(db.clone(), new.clone()).await?;
+    let submitter = Submitter::start(db.clone(), 1, Perspective::Mover, metrics);
+    reconcile_completeness(db.clone(), PartialSubmission::default(), &submitter).await?;
+    submitter.drained().await;
+    info!("{} analysed, acquired and reconciled", p);
+
+    Ok(vec![core, new])
+}
+
@@ -0,0 +1,169 @@
+// Copyright 2020 Lakin Wecker
+//
+// This file is part of lila-deepq.
+//
+// lila-deepq is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// lila-deepq is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with lila-deepq.  If not, see <https://www.gnu.org/licenses/>.
+// An interactive prompt for operators, so that poking at the queue doesn't
+// mean hand-writing mongo queries against the production database. Every
+// command goes through the same api functions as the webserver.
+//
+use std::str::FromStr;
+use std::time::Duration;
+
+use futures::stream::{StreamExt, TryStreamExt};
+use log::info;
+use mongodb::bson::{doc, from_document, oid::ObjectId};
+use mongodb::options::FindOptions;
+use tokio::io::{self, AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::time::sleep;
+
+use crate::db::DbConn;
+use crate::deepq::api::{find_report, insert_audit_entry};
+use crate::deepq::model::{AuditEntry, ReportId};
+use crate::error::{DomainError, Error, Result};
+use crate::fishnet::api::{get_job, list_jobs, requeue_job};
+use crate::fishnet::model::{Job, JobId};
+
+const HELP: &str = "\
+commands:
+  jobs [limit]      the most urgent incomplete jobs
+  job <id>          everything about one job
+  requeue <id>      take a job away from its owner
+  report <id>       a report and the state of its jobs
+  tail              follow the audit log, until ctrl-c
+  help              this message
+  quit              leave the shell";
+
+const TAIL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Who to blame in the audit log for changes made from the shell.
+fn actor() -> String {
+    format!("shell:{}", std::env::var("USER").unwrap_or_else(|_| "unknown".to_string()))
+}
+
+fn describe_job(job: &Job) -> String {
+    let state = if job.is_complete {
+        job.skip_reason
+            .as_ref()
+            .map_or("complete".to_string(), |reason| format!("skipped ({})", reason))
+    } else {
+        job.owner
+            .as_ref()
+            .map_or("queued".to_string(), |owner| format!("acquired by {}", owner))
+    };
+    format!(
+        "{} game={} type={:?} precedence={} {}",
+        job._id, job.game_id, job.analysis_type, job.precedence, state
+    )
+}
+
+async fn jobs(db: DbConn, args: &[&str]) -> Result<()> {
+    let limit = args.first().and_then(|limit| limit.parse().ok()).unwrap_or(20);
+    let jobs = list_jobs(db, limit).await?;
+    for job in jobs.iter() {
+        println!("{}", describe_job(job));
+    }
+    println!("{} jobs", jobs.len());
+    Ok(())
+}
+
+async fn job(db: DbConn, id: JobId) -> Result<()> {
+    let job = get_job(db, id).await?.ok_or(DomainError::NotFound)?;
+    println!("{:#?}", job);
+    Ok(())
+}
+
+async fn requeue(db: DbConn, id: JobId) -> Result<()> {
+    let job = requeue_job(db.clone(), id.clone())
+        .await?
+        .ok_or(DomainError::NotFound)?;
+    insert_audit_entry(db, &actor(), "requeue_job", doc! {"job_id": id.0}).await?;
+    println!("requeued {}", describe_job(&job));
+    Ok(())
+}
+
+async fn report(db: DbConn, id: ReportId) -> Result<()> {
+    let report = find_report(db.clone(), id).await?.ok_or(DomainError::NotFound)?;
+    println!("{:#?}", report);
+    let jobs: Vec<Job> = Job::find_by_report(db, report).await?.try_collect().await?;
+    for job in jobs.iter() {
+        println!("  {}", describe_job(job));
+    }
+    Ok(())
+}
+
+/// Prints new audit entries as they're written, until interrupted.
+async fn tail(db: DbConn) -> Result<()> {
+    let mut last_seen = ObjectId::new();
+    println!("following the audit log, ctrl-c to stop");
+    loop {
+        let entries: Vec<AuditEntry> = AuditEntry::coll(db.clone())
+            .find(
+                doc! {"_id": {"$gt": last_seen.clone()}},
+                FindOptions::builder().sort(doc! {"_id": 1}).build(),
+            )
+            .await?
+            .map(|doc| Ok::<_, Error>(from_document::<AuditEntry>(doc?)?))
+            .try_collect()
+            .await?;
+        for entry in entries {
+            println!("{} {} {} {}", entry.date.0, entry.actor, entry.action, entry.details);
+            last_seen = entry._id;
+        }
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => return Ok(()),
+            _ = sleep(TAIL_INTERVAL) => {},
+        }
+    }
+}
+
+async fn run(db: DbConn, command: &str, args: &[&str]) -> Result<()> {
+    let id = || args.first().copied().ok_or(Error::InvalidCommandLineArguments);
+    match command {
+        "jobs" => jobs(db, args).await,
+        "job" => job(db, JobId::from_str(id()?)?).await,
+        "requeue" => requeue(db, JobId::from_str(id()?)?).await,
+        "report" => report(db, ReportId::from_str(id()?)?).await,
+        "tail" => tail(db).await,
+        _ => {
+            println!("{}", HELP);
+            Ok(())
+        }
+    }
+}
+
+/// Reads commands from stdin until it closes or the operator quits.
+pub async fn shell(db: DbConn) -> Result<()> {
+    info!("shell > started by {}", actor());
+    let mut lines = BufReader::new(io::stdin()).lines();
+    let mut stdout = io::stdout();
+    loop {
+        stdout.write_all(b"deepq> ").await?;
+        stdout.flush().await?;
+        let line = match lines.next_line().await? {
+            Some(line) => line,
+            None => return Ok(()),
+        };
+        let words: Vec<&str> = line.split_whitespace().collect();
+        match words.split_first() {
+            None => continue,
+            Some((&"quit", _)) | Some((&"exit", _)) => return Ok(()),
+            Some((command, args)) => {
+                if let Err(err) = run(db.clone(), command, args).await {
+                    println!("error: {}", err);
+                }
+            }
+        }
+    }
+}
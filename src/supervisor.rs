@@ -0,0 +1,98 @@
+// Copyright 2020 Lakin Wecker
+//
+// This file is part of lila-deepq.
+//
+// lila-deepq is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// lila-deepq is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with lila-deepq.  If not, see <https://www.gnu.org/licenses/>.
+//
+// Keeps long running subsystems alive. A panic inside a spawned task only
+// kills that task, so without this the webserver would keep serving with,
+// for example, a dead irwin listener.
+//
+use std::backtrace::Backtrace;
+use std::future::Future;
+use std::panic;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use log::{error, info, warn};
+use tokio::task::JoinHandle;
+use tokio::time::{sleep, Duration, Instant};
+
+const MIN_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+static RESTARTS: AtomicU64 = AtomicU64::new(0);
+
+/// How many times supervised tasks have been restarted since startup.
+pub fn restarts() -> u64 {
+    RESTARTS.load(Ordering::Relaxed)
+}
+
+/// This process, as hostname:pid, to tell whose lease or counters these are.
+pub fn process() -> String {
+    format!(
+        "{}:{}",
+        std::env::var("HOSTNAME").unwrap_or_else(|_| "unknown".to_string()),
+        std::process::id()
+    )
+}
+
+/// Logs panics with a backtrace, the JoinError we get back only has the message.
+pub fn install_panic_hook() {
+    panic::set_hook(Box::new(|info| {
+        error!("panic > {}\n{}", info, Backtrace::force_capture());
+    }));
+}
+
+/// Spawns the task built by `make`, and builds and spawns it again with
+/// exponential backoff whenever it panics. A task that returns is done.
+pub fn supervise<F, Fut>(name: &'static str, make: F) -> JoinHandle<()>
+where
+    F: Fn() -> Fut + Send + 'static,
+    Fut: Future<Output = ()> + Send + 'static,
+{
+    let p = "supervise >";
+    tokio::spawn(async move {
+        let mut backoff = MIN_BACKOFF;
+        loop {
+            info!("{} {} > starting", p, name);
+            let started = Instant::now();
+            match tokio::spawn(make()).await {
+                Ok(()) => {
+                    info!("{} {} > finished", p, name);
+                    return;
+                }
+                Err(err) if err.is_panic() => {
+                    RESTARTS.fetch_add(1, Ordering::Relaxed);
+                    // NOTE: a task that ran for a while before panicking gets a fresh start.
+                    if started.elapsed() > MAX_BACKOFF {
+                        backoff = MIN_BACKOFF;
+                    }
+                    warn!(
+                        "{} {} > panicked, restarting in {:?} ({} restarts)",
+                        p,
+                        name,
+                        backoff,
+                        restarts()
+                    );
+                    sleep(backoff).await;
+                    backoff = (backoff * 2).min(MAX_BACKOFF);
+                }
+                Err(err) => {
+                    error!("{} {} > cancelled: {:?}", p, name, err);
+                    return;
+                }
+            }
+        }
+    })
+}
@@ -0,0 +1,294 @@
+// Copyright 2020 Lakin Wecker
+//
+// This file is part of lila-deepq.
+//
+// lila-deepq is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// lila-deepq is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with lila-deepq.  If not, see <https://www.gnu.org/licenses/>.
+//
+// Exports spans to an OpenTelemetry collector over OTLP/HTTP, so that traces
+// can be stitched together with lila's and irwin's during incidents.
+//
+use std::future::Future;
+use std::sync::OnceLock;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use log::{debug, warn};
+use rand::{thread_rng, Rng};
+use serde_json::{json, Value};
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use tokio::time::{timeout, Duration};
+#[cfg(feature = "web")]
+use warp::http::{Request, Response};
+
+#[cfg(feature = "web")]
+use crate::metrics::route;
+
+pub const TRACEPARENT_HEADER: &str = "traceparent";
+
+const SERVICE_NAME: &str = "lila-deepq";
+const MAX_BATCH_SIZE: usize = 512;
+const EXPORT_INTERVAL_SECONDS: u64 = 5;
+
+struct Tracer {
+    sample_ratio: f64,
+    tx: UnboundedSender<Value>,
+}
+
+static TRACER: OnceLock<Tracer> = OnceLock::new();
+
+tokio::task_local! {
+    static CURRENT: SpanContext;
+}
+
+/// Starts exporting to the collector at `endpoint`. Until this is called
+/// spans are never sampled, so they cost next to nothing.
+pub fn init(endpoint: String, sample_ratio: f64) {
+    let (tx, rx) = mpsc::unbounded_channel();
+    let tracer = Tracer {
+        sample_ratio: sample_ratio.clamp(0f64, 1f64),
+        tx,
+    };
+    if TRACER.set(tracer).is_ok() {
+        tokio::spawn(export(endpoint, rx));
+    }
+}
+
+async fn export(endpoint: String, mut rx: UnboundedReceiver<Value>) {
+    let url = format!("{}/v1/traces", endpoint.trim_end_matches('/'));
+    let client = reqwest::Client::new();
+    let mut open = true;
+    while open {
+        let mut spans = Vec::new();
+        let flush_at = Duration::from_secs(EXPORT_INTERVAL_SECONDS);
+        while spans.len() < MAX_BATCH_SIZE {
+            match timeout(flush_at, rx.recv()).await {
+                Ok(Some(span)) => spans.push(span),
+                Ok(None) => {
+                    open = false;
+                    break;
+                }
+                Err(_) => break,
+            }
+        }
+        if spans.is_empty() {
+            continue;
+        }
+        debug!("telemetry::export > {} spans", spans.len());
+        let body = json!({
+            "resourceSpans": [{
+                "resource": {"attributes": [attribute("service.name", SERVICE_NAME)]},
+                "scopeSpans": [{"scope": {"name": SERVICE_NAME}, "spans": spans}],
+            }]
+        });
+        let result = client
+            .post(&url)
+            .json(&body)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status());
+        if let Err(err) = result {
+            warn!("telemetry::export > dropped {} spans: {}", spans.len(), err);
+        }
+    }
+}
+
+fn attribute(key: &str, value: &str) -> Value {
+    json!({"key": key, "value": {"stringValue": value}})
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn unhex<const N: usize>(s: &str) -> Option<[u8; N]> {
+    if s.len() != N * 2 {
+        return None;
+    }
+    let mut bytes = [0u8; N];
+    for (i, byte) in bytes.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(s.get(i * 2..i * 2 + 2)?, 16).ok()?;
+    }
+    Some(bytes)
+}
+
+fn unix_nanos(time: SystemTime) -> String {
+    time.duration_since(UNIX_EPOCH)
+        .map(|since| since.as_nanos())
+        .unwrap_or(0)
+        .to_string()
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct SpanContext {
+    pub trace_id: [u8; 16],
+    pub span_id: [u8; 8],
+    pub sampled: bool,
+}
+
+impl SpanContext {
+    /// Parses a W3C traceparent header, e.g. from lila.
+    pub fn from_traceparent(header: &str) -> Option<SpanContext> {
+        let mut parts = header.trim().split('-');
+        let (version, trace_id, span_id, flags) =
+            (parts.next()?, parts.next()?, parts.next()?, parts.next()?);
+        if version != "00" {
+            return None;
+        }
+        Some(SpanContext {
+            trace_id: unhex(trace_id)?,
+            span_id: unhex(span_id)?,
+            sampled: unhex::<1>(flags)?[0] & 1 == 1,
+        })
+    }
+
+    pub fn traceparent(&self) -> String {
+        format!(
+            "00-{}-{}-{:02x}",
+            hex(&self.trace_id),
+            hex(&self.span_id),
+            self.sampled as u8
+        )
+    }
+
+    /// The span currently being instrumented on this task, if any.
+    pub fn current() -> Option<SpanContext> {
+        CURRENT.try_with(|context| *context).ok()
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum SpanKind {
+    Internal = 1,
+    Server = 2,
+    Client = 3,
+}
+
+/// Sent to the collector when dropped, if it was sampled.
+pub struct Span {
+    context: SpanContext,
+    parent_span_id: Option<[u8; 8]>,
+    name: String,
+    kind: SpanKind,
+    start: SystemTime,
+    attributes: Vec<Value>,
+}
+
+impl Span {
+    /// A child of the current span, or a new trace.
+    pub fn start(name: &str, kind: SpanKind) -> Span {
+        Span::start_with_parent(name, kind, SpanContext::current())
+    }
+
+    pub fn start_with_parent(name: &str, kind: SpanKind, parent: Option<SpanContext>) -> Span {
+        let mut rng = thread_rng();
+        let context = match parent {
+            Some(parent) => SpanContext {
+                span_id: rng.gen(),
+                ..parent
+            },
+            None => SpanContext {
+                trace_id: rng.gen(),
+                span_id: rng.gen(),
+                sampled: TRACER
+                    .get()
+                    .is_some_and(|tracer| rng.gen::<f64>() < tracer.sample_ratio),
+            },
+        };
+        Span {
+            context,
+            parent_span_id: parent.map(|parent| parent.span_id),
+            name: name.to_string(),
+            kind,
+            start: SystemTime::now(),
+            attributes: Vec::new(),
+        }
+    }
+
+    pub fn context(&self) -> SpanContext {
+        self.context
+    }
+
+    pub fn set(&mut self, key: &str, value: &str) {
+        if self.context.sampled {
+            self.attributes.push(attribute(key, value));
+        }
+    }
+}
+
+impl Drop for Span {
+    fn drop(&mut self) {
+        let tracer = match TRACER.get() {
+            Some(tracer) if self.context.sampled => tracer,
+            _ => return,
+        };
+        let mut span = json!({
+            "traceId": hex(&self.context.trace_id),
+            "spanId": hex(&self.context.span_id),
+            "name": self.name,
+            "kind": self.kind as i32,
+            "startTimeUnixNano": unix_nanos(self.start),
+            "endTimeUnixNano": unix_nanos(SystemTime::now()),
+            "attributes": self.attributes,
+        });
+        if let Some(parent_span_id) = self.parent_span_id {
+            span["parentSpanId"] = Value::String(hex(&parent_span_id));
+        }
+        let _ = tracer.tx.send(span);
+    }
+}
+
+/// Runs the future as the current span, so spans it starts are its children.
+pub async fn instrument<F: Future>(span: Span, f: F) -> F::Output {
+    let context = span.context();
+    let output = CURRENT.scope(context, f).await;
+    drop(span);
+    output
+}
+
+/// Passes the current trace on to the service being called, so its spans
+/// join ours. Spawned tasks don't inherit the current span, they pass the
+/// context they were spawned from.
+pub fn propagate(
+    request: reqwest::RequestBuilder,
+    context: Option<SpanContext>,
+) -> reqwest::RequestBuilder {
+    match context {
+        Some(context) => request.header(TRACEPARENT_HEADER, context.traceparent()),
+        None => request,
+    }
+}
+
+/// Handles the request in a server span, continuing the caller's trace if
+/// they sent one, so the spans the handler starts are its children.
+#[cfg(feature = "web")]
+pub async fn serve<B, R, F>(request: Request<B>, handler: impl FnOnce(Request<B>) -> F) -> Response<R>
+where
+    F: Future<Output = Response<R>>,
+{
+    let parent = request
+        .headers()
+        .get(TRACEPARENT_HEADER)
+        .and_then(|header| header.to_str().ok())
+        .and_then(SpanContext::from_traceparent);
+    let route = route(request.uri().path());
+    let mut span = Span::start_with_parent(
+        &format!("{} {}", request.method(), route),
+        SpanKind::Server,
+        parent,
+    );
+    span.set("http.method", request.method().as_str());
+    span.set("http.route", &route);
+    let response = CURRENT.scope(span.context(), handler(request)).await;
+    span.set("http.status_code", response.status().as_str());
+    response
+}
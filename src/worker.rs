@@ -0,0 +1,319 @@
+// Copyright 2021 Lakin Wecker
+//
+// This file is part of lila-deepq.
+//
+// lila-deepq is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// lila-deepq is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with lila-deepq.  If not, see <https://www.gnu.org/licenses/>.
+
+//
+// An analysis worker inside the webserver, for development and deployments
+// too small to bother with fishnet clients. It claims jobs like a client
+// would, and analyses them with a locally installed stockfish over UCI.
+//
+use std::convert::TryFrom;
+use std::io;
+use std::path::PathBuf;
+use std::process::Stdio;
+
+use log::{debug, error, info, warn};
+use mongodb::bson::doc;
+use serde_json::{json, Map, Value};
+use shakmaty::{fen::Fen, uci::Uci};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader, Lines};
+use tokio::process::{Child, ChildStdin, ChildStdout, Command};
+use tokio::sync::broadcast;
+use tokio::time::{sleep, Duration};
+
+use crate::db::DbConn;
+use crate::deepq::api::{
+    find_game, insert_audit_entry, starting_position, upsert_one_game_analysis, UpdateGameAnalysis,
+};
+use crate::deepq::model::{EngineType, PlyAnalysis, PresetSettings};
+use crate::deepq::payload::PayloadGuard;
+use crate::deepq::preset;
+use crate::error::{Context, DomainError, Error, ProtocolError, Result};
+use crate::fishnet::api::{assign_job, get_api_user, mark_game_missing, set_complete, unassign_job};
+use crate::fishnet::model::{ApiUser, Job, Key};
+use crate::fishnet::FishnetMsg;
+use crate::pause::Pauses;
+
+#[derive(Debug, Clone)]
+pub struct WorkerOpts {
+    pub stockfish: PathBuf,
+    pub key: Key, // The worker claims jobs as this key, with its perms
+    pub threads: u16,
+    pub hash_mb: u32,
+    pub idle: Duration, // How long to wait before looking again when the queue is empty
+}
+
+fn engine_gone() -> Error {
+    ProtocolError::Io(io::Error::new(io::ErrorKind::UnexpectedEof, "stockfish exited")).into()
+}
+
+/// A stockfish process, spoken to over UCI.
+pub struct Engine {
+    // NOTE: kept so the process is killed along with the engine.
+    _child: Child,
+    stdin: ChildStdin,
+    stdout: Lines<BufReader<ChildStdout>>,
+}
+
+impl Engine {
+    pub async fn start(opts: &WorkerOpts) -> Result<Engine> {
+        let mut child = Command::new(&opts.stockfish)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()
+            .with_context(|| format!("stockfish at {:?}", opts.stockfish))?;
+        let stdin = child.stdin.take().ok_or_else(engine_gone)?;
+        let stdout = BufReader::new(child.stdout.take().ok_or_else(engine_gone)?).lines();
+        let mut engine = Engine {
+            _child: child,
+            stdin,
+            stdout,
+        };
+        engine.send("uci").await?;
+        engine.read_until("uciok").await?;
+        engine.send(&format!("setoption name Threads value {}", opts.threads)).await?;
+        engine.send(&format!("setoption name Hash value {}", opts.hash_mb)).await?;
+        engine.send("isready").await?;
+        engine.read_until("readyok").await?;
+        Ok(engine)
+    }
+
+    async fn send(&mut self, command: &str) -> Result<()> {
+        debug!("Engine > {}", command);
+        self.stdin.write_all(format!("{}\n", command).as_bytes()).await?;
+        self.stdin.flush().await?;
+        Ok(())
+    }
+
+    /// The lines the engine writes up to and including the one starting with `prefix`.
+    async fn read_until(&mut self, prefix: &str) -> Result<Vec<String>> {
+        let mut lines = Vec::new();
+        while let Some(line) = self.stdout.next_line().await? {
+            let done = line.starts_with(prefix);
+            lines.push(line);
+            if done {
+                return Ok(lines);
+            }
+        }
+        Err(engine_gone())
+    }
+
+    /// The position after `moves`, as fishnet clients report it.
+    ///
+    /// NOTE: only the best line is searched, multipv presets get a single pv.
+    pub async fn analyse(&mut self, position: &Fen, moves: &[Uci], settings: &PresetSettings) -> Result<Value> {
+        let mut command = format!("position fen {}", position);
+        if !moves.is_empty() {
+            let moves: Vec<String> = moves.iter().map(Uci::to_string).collect();
+            command.push_str(&format!(" moves {}", moves.join(" ")));
+        }
+        self.send(&command).await?;
+        let mut go = format!("go nodes {}", settings.nodes.nnue.get());
+        if let Some(depth) = settings.depth {
+            go.push_str(&format!(" depth {}", depth.get()));
+        }
+        self.send(&go).await?;
+        let lines = self.read_until("bestmove").await?;
+        lines
+            .iter()
+            .rev()
+            .find_map(|line| parse_info(line))
+            .ok_or_else(|| DomainError::Invalid(format!("stockfish output: {:?}", lines.last())).into())
+    }
+}
+
+/// A UCI info line with a score, in the shape fishnet clients send each ply in.
+pub fn parse_info(line: &str) -> Option<Value> {
+    let mut tokens = line.split_whitespace();
+    if tokens.next() != Some("info") {
+        return None;
+    }
+    let mut ply = Map::new();
+    while let Some(token) = tokens.next() {
+        match token {
+            "depth" | "nodes" | "time" | "nps" => {
+                let value: i64 = tokens.next()?.parse().ok()?;
+                ply.insert(token.to_string(), json!(value));
+            }
+            "score" => {
+                let kind = tokens.next()?;
+                let value: i64 = tokens.next()?.parse().ok()?;
+                let mut score = Map::new();
+                score.insert(kind.to_string(), json!(value));
+                ply.insert("score".to_string(), Value::Object(score));
+            }
+            "pv" => {
+                let pv: Vec<&str> = tokens.by_ref().collect();
+                ply.insert("pv".to_string(), json!(pv.join(" ")));
+            }
+            _ => {}
+        }
+    }
+    if !ply.contains_key("score") || !ply.contains_key("depth") {
+        return None;
+    }
+    // NOTE: checkmate and stalemate have no pv, fishnet sends just depth and score.
+    if !ply.contains_key("pv") {
+        for key in &["nodes", "nps", "time"] {
+            ply.remove(*key);
+        }
+    }
+    Some(Value::Object(ply))
+}
+
+pub struct Worker {
+    opts: WorkerOpts,
+    pauses: Pauses,
+    payload: PayloadGuard,
+}
+
+impl Worker {
+    pub fn new(opts: WorkerOpts, pauses: Pauses, payload: PayloadGuard) -> Worker {
+        Worker { opts, pauses, payload }
+    }
+
+    /// Claims and analyses jobs for as long as the webserver runs, restarting
+    /// stockfish whenever it misbehaves.
+    pub async fn run(self, db: DbConn, tx: broadcast::Sender<FishnetMsg>) {
+        let p = "Worker::run >";
+        loop {
+            let mut engine = match Engine::start(&self.opts).await {
+                Ok(engine) => engine,
+                Err(err) => {
+                    error!("{} unable to start stockfish: {}", p, err);
+                    sleep(self.opts.idle).await;
+                    continue;
+                }
+            };
+            info!("{} stockfish is ready", p);
+            loop {
+                match self.work(db.clone(), &tx, &mut engine).await {
+                    Ok(true) => {}
+                    Ok(false) => sleep(self.opts.idle).await,
+                    Err(err) => {
+                        warn!("{} {}", p, err);
+                        sleep(self.opts.idle).await;
+                        break;
+                    }
+                }
+            }
+        }
+    }
+
+    async fn api_user(&self, db: DbConn) -> Result<ApiUser> {
+        let mut api_user = get_api_user(db, self.opts.key.clone())
+            .await?
+            .ok_or(DomainError::NotFound)
+            .context("the embedded worker's key")?;
+        // NOTE: only narrows what the worker may claim, like the acquire route does.
+        let pauses = self.pauses.all().await;
+        api_user.perms.retain(|perm| !pauses.iter().any(|pause| pause.covers(perm)));
+        Ok(api_user)
+    }
+
+    /// Analyses the next job, false if there was none to claim.
+    async fn work(&self, db: DbConn, tx: &broadcast::Sender<FishnetMsg>, engine: &mut Engine) -> Result<bool> {
+        let api_user = self.api_user(db.clone()).await?;
+        if api_user.perms.is_empty() {
+            return Ok(false);
+        }
+        let job = match assign_job(db.clone(), api_user.clone()).await? {
+            Some(job) => job,
+            None => return Ok(false),
+        };
+        let job_id = job._id.clone();
+        match self.analyse(db.clone(), tx, engine, &api_user, job).await {
+            Ok(()) => Ok(true),
+            Err(err) => {
+                // NOTE: someone else may have better luck with it.
+                unassign_job(db, api_user, job_id).await?;
+                Err(err)
+            }
+        }
+    }
+
+    async fn analyse(
+        &self,
+        db: DbConn,
+        tx: &broadcast::Sender<FishnetMsg>,
+        engine: &mut Engine,
+        api_user: &ApiUser,
+        job: Job,
+    ) -> Result<()> {
+        let p = "Worker::analyse >";
+        let game = match find_game(db.clone(), job.game_id.clone()).await? {
+            Some(game) => game,
+            None => {
+                debug!("{} No game for game_id: {:?}", p, job.game_id);
+                mark_game_missing(db.clone(), job._id.clone()).await?;
+                insert_audit_entry(
+                    db,
+                    "embedded_worker",
+                    "mark_game_missing",
+                    doc! {"job_id": job._id.0.clone(), "game_id": job.game_id},
+                )
+                .await?;
+                send(tx, FishnetMsg::JobCompleted(job._id));
+                return Ok(());
+            }
+        };
+        info!("{} Job({}) > Game({})", p, job._id, game._id);
+        send(tx, FishnetMsg::JobAcquired(job._id.clone()));
+        let settings = preset::snapshot_for_job(db.clone(), &job).await?;
+        let skip = preset::skip_positions(&settings, &game);
+        let position = starting_position(game.clone());
+        let mut analysis = Vec::with_capacity(game.pgn.len() + 1);
+        for ply in 0..=game.pgn.len() {
+            let ply_analysis = if u8::try_from(ply).is_ok_and(|ply| skip.contains(&ply)) {
+                json!({"skipped": true})
+            } else {
+                let settings = preset::ply_settings(&settings, &job, ply);
+                engine
+                    .analyse(&position, &game.pgn[..ply], &settings)
+                    .await
+                    .with_context(|| format!("Job({}) ply {}", job._id, ply))?
+            };
+            let ply_analysis: PlyAnalysis = serde_json::from_value(ply_analysis)?;
+            analysis.push(Some(ply_analysis.normalized()));
+        }
+        upsert_one_game_analysis(
+            db.clone(),
+            &self.payload,
+            UpdateGameAnalysis {
+                job_id: job._id.clone(),
+                game_id: job.game_id.clone(),
+                source_id: api_user._id.to_string().into(),
+                analysis,
+                requested_pvs: settings.multipv,
+                requested_depth: settings.depth,
+                requested_nodes: settings.nodes,
+                engine: EngineType::Stockfish,
+            },
+        )
+        .await
+        .with_context(|| format!("Job({})", job._id))?;
+        set_complete(db, job._id.clone()).await?;
+        send(tx, FishnetMsg::JobCompleted(job._id));
+        Ok(())
+    }
+}
+
+fn send(tx: &broadcast::Sender<FishnetMsg>, msg: FishnetMsg) {
+    if let Err(err) = tx.send(msg.clone()) {
+        error!("Worker > unable to send msg: {:?} due to: {:?}", msg, err);
+    }
+}
@@ -0,0 +1,117 @@
+// Copyright 2020 Lakin Wecker
+//
+// This file is part of lila-deepq.
+//
+// lila-deepq is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// lila-deepq is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with lila-deepq.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::str::FromStr;
+
+use serde_json::json;
+use shakmaty::uci::Uci;
+
+use lila_deepq::deepq::anomaly::{score_game, CRITICAL_GAP_CP};
+use lila_deepq::deepq::model::{Game, GameId, PlyAnalysis, Provenance, UserId};
+
+const PLIES: usize = 60;
+const TOP_MOVE: &str = "e2e4";
+const OTHER_MOVE: &str = "d2d4";
+
+fn game(emt: i32) -> Game {
+    Game {
+        _id: GameId("abcdefgh".to_string()),
+        emts: vec![emt; PLIES],
+        pgn: vec![Uci::from_str(TOP_MOVE).unwrap(); PLIES],
+        white: Some(UserId::from("Suspect".to_string())),
+        black: Some(UserId::from("opponent".to_string())),
+        provenance: Provenance::Unknown,
+        date_stored: None,
+        not_analysable: false,
+    }
+}
+
+fn multipv(best: i64, second: i64) -> Option<PlyAnalysis> {
+    Some(
+        serde_json::from_value(json!({
+            "pv": [[[TOP_MOVE, "e7e5"]], [[OTHER_MOVE, "d7d5"]]],
+            "score": [[{"cp": best}], [{"cp": second}]],
+            "depth": 20,
+            "nodes": 1000000,
+            "time": 1000,
+        }))
+        .unwrap(),
+    )
+}
+
+fn single_pv(best: i64) -> Option<PlyAnalysis> {
+    Some(
+        serde_json::from_value(json!({
+            "pv": format!("{} e7e5", TOP_MOVE),
+            "score": {"cp": best},
+            "depth": 20,
+            "time": 1000,
+            "nodes": 1000000,
+            "nps": 1000000,
+        }))
+        .unwrap(),
+    )
+}
+
+fn suspect() -> UserId {
+    UserId::from("suspect".to_string())
+}
+
+#[test]
+fn instant_only_moves_are_anomalous() {
+    let analysis = vec![multipv(50, 50 - CRITICAL_GAP_CP - 50); PLIES];
+    let anomaly = score_game(&game(10), &analysis, &suspect()).unwrap();
+    assert_eq!(anomaly.critical_moves, 22); // White's moves past the 16 opening plies
+    assert_eq!(anomaly.instant_top_moves, 22);
+    assert!(anomaly.score >= 0.75);
+}
+
+#[test]
+fn balanced_positions_with_many_good_moves_are_not_critical() {
+    let analysis = vec![multipv(20, 10); PLIES];
+    let anomaly = score_game(&game(10), &analysis, &suspect()).unwrap();
+    assert_eq!(anomaly.critical_moves, 0);
+    assert_eq!(anomaly.score, 0.0);
+}
+
+#[test]
+fn losing_a_decided_position_by_more_is_not_critical() {
+    let analysis = vec![multipv(3000, 1500); PLIES];
+    let anomaly = score_game(&game(10), &analysis, &suspect()).unwrap();
+    assert_eq!(anomaly.critical_moves, 0);
+}
+
+#[test]
+fn single_pv_analysis_cannot_tell_what_is_critical() {
+    let analysis = vec![single_pv(0); PLIES];
+    let anomaly = score_game(&game(10), &analysis, &suspect()).unwrap();
+    assert_eq!(anomaly.critical_moves, 0);
+}
+
+#[test]
+fn slow_only_moves_are_not_anomalous() {
+    let analysis = vec![multipv(50, -200); PLIES];
+    let anomaly = score_game(&game(3000), &analysis, &suspect()).unwrap();
+    assert_eq!(anomaly.instant_top_moves, 0);
+    assert!(anomaly.score < 0.1);
+}
+
+#[test]
+fn games_the_player_did_not_play_are_not_scored() {
+    let analysis = vec![multipv(50, -200); PLIES];
+    assert!(score_game(&game(10), &analysis, &UserId::from("someone".to_string())).is_none());
+}
@@ -0,0 +1,59 @@
+// Copyright 2020 Lakin Wecker
+//
+// This file is part of lila-deepq.
+//
+// lila-deepq is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// lila-deepq is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with lila-deepq.  If not, see <https://www.gnu.org/licenses/>.
+
+#![cfg(feature = "web")]
+
+use lila_deepq::deepq::model::{Game, GameId, Provenance};
+use lila_deepq::lichess::status::{is_lichess_game, GameStatusCache, SkipReason};
+use warp::Filter;
+
+fn game(id: &str, provenance: Provenance) -> Game {
+    Game {
+        _id: GameId(id.to_string()),
+        emts: Vec::new(),
+        pgn: Vec::new(),
+        black: None,
+        white: None,
+        provenance,
+        date_stored: None,
+        not_analysable: false,
+    }
+}
+
+/// A lichess that has never heard of any game.
+fn lichess_without_games() -> String {
+    let not_found = warp::any().map(|| warp::reply::with_status("", warp::http::StatusCode::NOT_FOUND));
+    let (addr, server) = warp::serve(not_found).bind_ephemeral(([127, 0, 0, 1], 0));
+    tokio::spawn(server);
+    format!("http://{}", addr)
+}
+
+#[test]
+fn only_lichess_games_are_checked() {
+    assert!(is_lichess_game(&game("abcd1234", Provenance::LichessStream)));
+    assert!(!is_lichess_game(&game("abcd1234", Provenance::PgnUpload)));
+    assert!(!is_lichess_game(&game("0123456789abcdef", Provenance::Unknown)));
+}
+
+#[tokio::test]
+async fn uploaded_games_are_handed_out() {
+    let game_status = GameStatusCache::new(Some(lichess_without_games())).unwrap();
+    let uploaded = game("0123456789abcdef", Provenance::PgnUpload);
+    assert_eq!(game_status.skip_reason(&uploaded).await.unwrap(), None);
+    let deleted = game("abcd1234", Provenance::LichessStream);
+    assert_eq!(game_status.skip_reason(&deleted).await.unwrap(), Some(SkipReason::Deleted));
+}
@@ -0,0 +1,54 @@
+// Copyright 2021 Lakin Wecker
+//
+// This file is part of lila-deepq.
+//
+// lila-deepq is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// lila-deepq is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with lila-deepq.  If not, see <https://www.gnu.org/licenses/>.
+
+#![cfg(feature = "web")]
+
+use std::collections::HashMap;
+
+use lila_deepq::deepq::import::{movetext_id, PgnGame};
+
+fn game(site: &str, sans: &[&str]) -> PgnGame {
+    let mut headers = HashMap::new();
+    headers.insert("Site".to_string(), site.to_string());
+    PgnGame {
+        headers,
+        sans: sans.iter().map(|san| san.to_string()).collect(),
+    }
+}
+
+#[test]
+fn lichess_games_keep_their_id() {
+    let game = game("https://lichess.org/abcdefgh", &["e4", "e5"]);
+    assert_eq!(game.game_id().map(|id| id.0), Some("abcdefgh".to_string()));
+}
+
+#[test]
+fn other_games_are_identified_by_their_moves() {
+    let a = game("https://example.com/1", &["e4", "e5", "Nf3"]);
+    let b = game("https://example.com/2", &["e4", "e5", "Nf3"]);
+    let c = game("https://example.com/1", &["d4", "d5", "c4"]);
+    let id = |game: &PgnGame| game.game_id().map(|id| id.0);
+    assert_eq!(id(&a), id(&b));
+    assert_ne!(id(&a), id(&c));
+    assert_eq!(id(&a).map(|id| id.len()), Some(16));
+    assert_eq!(id(&a), Some(movetext_id(&a.sans).0));
+}
+
+#[test]
+fn games_without_moves_or_a_lichess_site_have_no_id() {
+    assert!(game("?", &[]).game_id().is_none());
+}
@@ -0,0 +1,47 @@
+// Copyright 2020 Lakin Wecker
+//
+// This file is part of lila-deepq.
+//
+// lila-deepq is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// lila-deepq is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with lila-deepq.  If not, see <https://www.gnu.org/licenses/>.
+
+use chrono::prelude::*;
+use mongodb::bson::{doc, from_document, oid::ObjectId};
+
+use lila_deepq::fishnet::model::{Job, Key};
+
+/// A job that expired on oldkey and was acquired by newkey since.
+fn reacquired_job() -> Job {
+    from_document(doc! {
+        "_id": ObjectId::new(),
+        "game_id": "abcd1234",
+        "analysis_type": "deep",
+        "precedence": 0,
+        "owner": "newkey",
+        "handed_to": ["oldkey", "newkey"],
+        "date_last_updated": Utc::now(),
+    })
+    .unwrap()
+}
+
+#[test]
+fn previous_owners_were_handed_the_job() {
+    let job = reacquired_job();
+    assert!(job.was_handed_to(&Key("OldKey".to_string())));
+    assert!(job.was_handed_to(&Key("newkey".to_string())));
+}
+
+#[test]
+fn other_keys_were_not_handed_the_job() {
+    assert!(!reacquired_job().was_handed_to(&Key("stranger".to_string())));
+}
@@ -0,0 +1,119 @@
+// Copyright 2020 Lakin Wecker
+//
+// This file is part of lila-deepq.
+//
+// lila-deepq is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// lila-deepq is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with lila-deepq.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::convert::TryFrom;
+
+use mongodb::bson::{doc, from_document, to_document, Bson};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use lila_deepq::deepq::model::PlyAnalysis;
+use lila_deepq::deepq::units::Nodes;
+
+// Deep searches routinely pass 2^31 nodes, and time in ms and nps can too.
+const DEEP_NODES: u64 = 5_000_000_000;
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+struct Stored {
+    nodes: Nodes,
+}
+
+#[test]
+fn nodes_beyond_i32_are_accepted_from_json() {
+    let nodes: Nodes = serde_json::from_value(json!(DEEP_NODES)).unwrap();
+    assert_eq!(nodes.get(), DEEP_NODES);
+}
+
+#[test]
+fn nodes_within_i32_are_accepted_from_json() {
+    let nodes: Nodes = serde_json::from_value(json!(2_250_000i32)).unwrap();
+    assert_eq!(nodes.get(), 2_250_000);
+}
+
+#[test]
+fn negative_nodes_are_rejected() {
+    assert!(serde_json::from_value::<Nodes>(json!(-1)).is_err());
+    assert!(Nodes::try_from(-1i64).is_err());
+}
+
+#[test]
+fn nodes_beyond_i32_are_stored_as_int64() {
+    let stored = Stored {
+        nodes: Nodes::new(DEEP_NODES),
+    };
+    let document = to_document(&stored).unwrap();
+    assert_eq!(document.get("nodes"), Some(&Bson::Int64(DEEP_NODES as i64)));
+    assert_eq!(from_document::<Stored>(document).unwrap(), stored);
+}
+
+#[test]
+fn nodes_stored_as_int32_are_still_readable() {
+    let stored: Stored = from_document(doc! {"nodes": 2_250_000i32}).unwrap();
+    assert_eq!(stored.nodes.get(), 2_250_000);
+}
+
+#[test]
+fn best_move_beyond_i32_round_trips() {
+    let submitted = json!({
+        "pv": "e2e4 e7e5",
+        "depth": 40,
+        "score": {"cp": 25},
+        "time": 3_000_000_000i64,
+        "nodes": DEEP_NODES,
+        "nps": 2_500_000_000i64,
+    });
+    let analysis: PlyAnalysis = serde_json::from_value(submitted.clone()).unwrap();
+    assert_eq!(serde_json::to_value(&analysis).unwrap(), submitted);
+
+    let document = to_document(&analysis).unwrap();
+    assert_eq!(document.get("nodes"), Some(&Bson::Int64(DEEP_NODES as i64)));
+    assert_eq!(document.get("time"), Some(&Bson::Int64(3_000_000_000)));
+    let stored: PlyAnalysis = from_document(document).unwrap();
+    assert_eq!(serde_json::to_value(&stored).unwrap(), submitted);
+}
+
+#[test]
+fn best_move_from_a_32_bit_client_is_accepted() {
+    let stored: PlyAnalysis = from_document(doc! {
+        "pv": "e2e4",
+        "depth": 20i32,
+        "score": {"cp": 25i32},
+        "time": 1500i32,
+        "nodes": 2_250_000i32,
+        "nps": 1_500_000i32,
+    })
+    .unwrap();
+    let value = serde_json::to_value(&stored).unwrap();
+    assert_eq!(value["nodes"], json!(2_250_000));
+    assert_eq!(value["time"], json!(1500));
+}
+
+#[test]
+fn matrix_analysis_beyond_i32_round_trips() {
+    let submitted = json!({
+        "pv": [[["e2e4", "e7e5"]]],
+        "score": [[{"cp": 25}]],
+        "depth": 40,
+        "nodes": DEEP_NODES,
+        "time": 3_000_000_000i64,
+        "nps": 2_500_000_000i64,
+    });
+    let analysis: PlyAnalysis = serde_json::from_value(submitted.clone()).unwrap();
+    assert_eq!(serde_json::to_value(&analysis).unwrap(), submitted);
+    let stored: PlyAnalysis = from_document(to_document(&analysis).unwrap()).unwrap();
+    assert_eq!(serde_json::to_value(&stored).unwrap(), submitted);
+}
@@ -0,0 +1,75 @@
+// Copyright 2021 Lakin Wecker
+//
+// This file is part of lila-deepq.
+//
+// lila-deepq is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// lila-deepq is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with lila-deepq.  If not, see <https://www.gnu.org/licenses/>.
+
+use shakmaty::{fen::Fen, uci::Uci};
+
+use lila_deepq::deepq::model::PhaseSettings;
+use lila_deepq::deepq::phase::{phases, skip_positions, Phase};
+
+const START: &str = "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1";
+
+fn fen(fen: &str) -> Fen {
+    fen.parse().expect("valid fen")
+}
+
+fn moves(moves: &str) -> Vec<Uci> {
+    moves.split(' ').map(|uci| uci.parse().expect("valid uci")).collect()
+}
+
+fn settings(book_plies: u8, endgame_pieces: Option<u8>) -> PhaseSettings {
+    PhaseSettings {
+        book_plies,
+        endgame_pieces,
+    }
+}
+
+#[test]
+fn quiet_openings_last_the_book_depth() {
+    let phases = phases(&fen(START), &moves("e2e4 e7e5 g1f3 b8c6 f1b5 a7a6"), &settings(4, None));
+    assert_eq!(phases.len(), 7);
+    assert!(phases[..4].iter().all(|phase| *phase == Phase::Opening));
+    assert!(phases[4..].iter().all(|phase| *phase == Phase::Middlegame));
+}
+
+#[test]
+fn captures_leave_book_early() {
+    let phases = phases(&fen(START), &moves("e2e4 d7d5 e4d5 d8d5 b1c3"), &settings(16, None));
+    assert_eq!(
+        phases,
+        vec![
+            Phase::Opening,
+            Phase::Opening,
+            Phase::Opening,
+            Phase::Middlegame,
+            Phase::Middlegame,
+            Phase::Middlegame,
+        ]
+    );
+}
+
+#[test]
+fn few_pieces_are_an_endgame() {
+    let rook_ending = fen("4k3/r7/8/8/8/8/4P3/R3K3 w - - 0 1");
+    let phases = phases(&rook_ending, &moves("a1a7"), &settings(0, Some(1)));
+    assert_eq!(phases, vec![Phase::Middlegame, Phase::Endgame]);
+}
+
+#[test]
+fn skips_everything_but_the_middlegame() {
+    let phases = vec![Phase::Opening, Phase::Middlegame, Phase::Middlegame, Phase::Endgame];
+    assert_eq!(skip_positions(&phases), vec![0, 3]);
+}
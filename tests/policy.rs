@@ -0,0 +1,106 @@
+// Copyright 2020 Lakin Wecker
+//
+// This file is part of lila-deepq.
+//
+// lila-deepq is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// lila-deepq is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with lila-deepq.  If not, see <https://www.gnu.org/licenses/>.
+
+use lila_deepq::deepq::model::{ReportOrigin, ReportSignals};
+use lila_deepq::deepq::policy::{
+    decayed_precedence, max_precedence, precedence, precedence_for_origin, screening_cooldown,
+    DEFAULT_SCREENING_COOLDOWN_DAYS,
+};
+
+fn suspicious() -> ReportSignals {
+    ReportSignals {
+        score: Some(100),
+        titled: true,
+        engine: false,
+    }
+}
+
+#[test]
+fn suspicion_raises_random_reports_too() {
+    let quiet = precedence(ReportOrigin::Random, &ReportSignals::default());
+    let signals = |score| ReportSignals {
+        score: Some(score),
+        ..ReportSignals::default()
+    };
+    assert!(precedence(ReportOrigin::Random, &signals(50)) > quiet);
+    assert_eq!(precedence(ReportOrigin::Random, &signals(100)), 2 * quiet);
+}
+
+#[test]
+fn first_request_is_not_decayed() {
+    let p = precedence(ReportOrigin::Moderator, &suspicious());
+    assert_eq!(decayed_precedence(ReportOrigin::Moderator, p, 0), p);
+}
+
+#[test]
+fn repeated_requests_decay() {
+    let p = precedence(ReportOrigin::Leaderboard, &ReportSignals::default());
+    let decayed: Vec<i32> = (0..4)
+        .map(|recent| decayed_precedence(ReportOrigin::Leaderboard, p, recent))
+        .collect();
+    assert_eq!(decayed, vec![p, p / 2, p / 4, p / 8]);
+}
+
+#[test]
+fn decay_is_bounded() {
+    let p = precedence(ReportOrigin::Moderator, &ReportSignals::default());
+    assert_eq!(
+        decayed_precedence(ReportOrigin::Moderator, p, 1000),
+        decayed_precedence(ReportOrigin::Moderator, p, 3)
+    );
+}
+
+#[test]
+fn flapping_moderator_requests_still_outrank_other_origins() {
+    let flapping = decayed_precedence(
+        ReportOrigin::Moderator,
+        precedence(ReportOrigin::Moderator, &ReportSignals::default()),
+        1000,
+    );
+    let leaderboard = precedence(ReportOrigin::Leaderboard, &suspicious());
+    assert!(flapping > leaderboard);
+}
+
+#[test]
+fn flapping_requests_dont_monopolize_their_origin() {
+    let flapping = decayed_precedence(
+        ReportOrigin::Tournament,
+        precedence(ReportOrigin::Tournament, &suspicious()),
+        3,
+    );
+    let fresh = precedence(ReportOrigin::Tournament, &ReportSignals::default());
+    assert!(flapping < fresh);
+}
+
+#[test]
+fn precedence_never_exceeds_the_ceiling() {
+    for origin in ReportOrigin::all().iter() {
+        let p = precedence(origin.clone(), &suspicious());
+        assert!(decayed_precedence(origin.clone(), p, 0) <= max_precedence(origin.clone()));
+        assert!(max_precedence(origin.clone()) > precedence_for_origin(origin.clone()));
+    }
+}
+
+#[test]
+fn only_automatic_screenings_cool_down() {
+    let cooldown = chrono::Duration::days(DEFAULT_SCREENING_COOLDOWN_DAYS);
+    assert_eq!(screening_cooldown(ReportOrigin::Random, cooldown), Some(cooldown));
+    assert_eq!(screening_cooldown(ReportOrigin::Leaderboard, cooldown), Some(cooldown));
+    assert_eq!(screening_cooldown(ReportOrigin::Moderator, cooldown), None);
+    assert_eq!(screening_cooldown(ReportOrigin::Random, chrono::Duration::zero()), None);
+    assert_eq!(screening_cooldown(ReportOrigin::Tournament, cooldown), None);
+}
@@ -0,0 +1,39 @@
+// Copyright 2020 Lakin Wecker
+//
+// This file is part of lila-deepq.
+//
+// lila-deepq is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// lila-deepq is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with lila-deepq.  If not, see <https://www.gnu.org/licenses/>.
+
+#![cfg(feature = "web")]
+
+use chrono::{prelude::*, Duration};
+
+use lila_deepq::fishnet::filters::{ReplayCache, BODY_AUTH_WINDOW_SECONDS};
+
+#[tokio::test]
+async fn replayed_nonces_are_rejected() {
+    let cache = ReplayCache::new();
+    let now = Utc::now();
+    assert!(cache.accept("nonce".to_string(), now).await);
+    assert!(!cache.accept("nonce".to_string(), now).await);
+    assert!(cache.accept("another nonce".to_string(), now).await);
+}
+
+#[tokio::test]
+async fn stale_timestamps_are_rejected() {
+    let cache = ReplayCache::new();
+    let window = Duration::seconds(BODY_AUTH_WINDOW_SECONDS + 1);
+    assert!(!cache.accept("old".to_string(), Utc::now() - window).await);
+    assert!(!cache.accept("early".to_string(), Utc::now() + window).await);
+}
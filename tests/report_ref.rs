@@ -0,0 +1,35 @@
+// Copyright 2021 Lakin Wecker
+//
+// This file is part of lila-deepq.
+//
+// lila-deepq is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// lila-deepq is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with lila-deepq.  If not, see <https://www.gnu.org/licenses/>.
+
+use lila_deepq::deepq::model::{ReportRef, ReportSlug};
+use lila_deepq::error::{Error, ProtocolError};
+
+#[test]
+fn reports_are_referred_to_by_id_or_slug() {
+    let by_id: ReportRef = "5fd4c38c00a8e67d00d2a5a1".parse().expect("an ObjectId");
+    assert!(matches!(by_id, ReportRef::Id(_)));
+    let by_slug: ReportRef = "K7QM-x2dn".parse().expect("a slug");
+    assert!(matches!(by_slug, ReportRef::Slug(ReportSlug(slug)) if slug == "k7qm-x2dn"));
+}
+
+#[test]
+fn malformed_references_are_invalid_ids() {
+    for malformed in &["", "k7qm", "k7qm-x2d", "k7qm-x2dn-abcd", "k1qm-x2dn", "../reports"] {
+        let err = malformed.parse::<ReportRef>().expect_err(malformed);
+        assert!(matches!(err, Error::Protocol(ProtocolError::InvalidId(_))), "{}", malformed);
+    }
+}
@@ -0,0 +1,81 @@
+// Copyright 2020 Lakin Wecker
+//
+// This file is part of lila-deepq.
+//
+// lila-deepq is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// lila-deepq is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with lila-deepq.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::str::FromStr;
+
+use lila_deepq::deepq::model::Score;
+use lila_deepq::deepq::score::{in_perspective, plies_in_perspective, Perspective};
+
+#[test]
+fn flipping_negates_cp_mate_and_q() {
+    assert_eq!(Score::Cp(35).flipped(), Score::Cp(-35));
+    assert_eq!(Score::Mate(-3).flipped(), Score::Mate(3));
+    assert_eq!(Score::Q(0.25).flipped(), Score::Q(-0.25));
+}
+
+#[test]
+fn flipping_swaps_wins_and_losses() {
+    assert_eq!(Score::Wdl([600, 300, 100]).flipped(), Score::Wdl([100, 300, 600]));
+}
+
+#[test]
+fn malformed_wdl_is_rejected() {
+    assert!(serde_json::from_str::<Score>(r#"{"wdl": [600, 300]}"#).is_err());
+    assert_eq!(
+        serde_json::from_str::<Score>(r#"{"wdl": [600, 300, 100]}"#).unwrap(),
+        Score::Wdl([600, 300, 100])
+    );
+}
+
+#[test]
+fn flipping_twice_is_the_original() {
+    let score = Score::Cp(-120);
+    assert_eq!(score.flipped().flipped(), score);
+}
+
+#[test]
+fn mover_perspective_keeps_engine_scores() {
+    for ply in 0..4 {
+        assert_eq!(in_perspective(&Score::Cp(50), ply, Perspective::Mover), Score::Cp(50));
+    }
+}
+
+#[test]
+fn white_perspective_flips_when_black_is_to_move() {
+    assert_eq!(in_perspective(&Score::Cp(50), 0, Perspective::White), Score::Cp(50));
+    assert_eq!(in_perspective(&Score::Cp(50), 1, Perspective::White), Score::Cp(-50));
+    assert_eq!(in_perspective(&Score::Mate(2), 3, Perspective::White), Score::Mate(-2));
+    assert_eq!(in_perspective(&Score::Mate(2), 4, Perspective::White), Score::Mate(2));
+}
+
+#[test]
+fn plies_keep_their_gaps() {
+    let scores = vec![Some(Score::Cp(20)), None, Some(Score::Cp(30)), Some(Score::Cp(-40))];
+    assert_eq!(plies_in_perspective(scores.clone(), Perspective::Mover), scores);
+    assert_eq!(
+        plies_in_perspective(scores, Perspective::White),
+        vec![Some(Score::Cp(20)), None, Some(Score::Cp(30)), Some(Score::Cp(40))]
+    );
+}
+
+#[test]
+fn perspectives_parse_from_the_command_line() {
+    assert_eq!(Perspective::from_str("mover").unwrap(), Perspective::Mover);
+    assert_eq!(Perspective::from_str("white").unwrap(), Perspective::White);
+    assert!(Perspective::from_str("black").is_err());
+    assert_eq!(Perspective::default(), Perspective::Mover);
+}
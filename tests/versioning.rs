@@ -0,0 +1,81 @@
+// Copyright 2021 Lakin Wecker
+//
+// This file is part of lila-deepq.
+//
+// lila-deepq is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// lila-deepq is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with lila-deepq.  If not, see <https://www.gnu.org/licenses/>.
+
+#![cfg(feature = "web")]
+
+use warp::Filter;
+
+use lila_deepq::http::{routes, ApiVersion, Routes, Versioned};
+use lila_deepq::metrics::Metrics;
+
+fn fishnet() -> Routes {
+    Versioned::new("fishnet", ApiVersion::V1)
+        .version(ApiVersion::V1, routes(warp::path("ping").map(|| "pong")))
+        .routes()
+}
+
+#[tokio::test]
+async fn versions_are_served_with_and_without_a_prefix() {
+    for path in &["/v1/fishnet/ping", "/fishnet/ping"] {
+        let res = warp::test::request().path(path).reply(&fishnet()).await;
+        assert_eq!(res.status(), 200, "{}", path);
+        assert_eq!(res.headers()["Api-Version"], "v1", "{}", path);
+        assert_eq!(res.body(), "pong");
+    }
+    let res = warp::test::request().path("/fishnet/ping").reply(&fishnet()).await;
+    assert_eq!(res.headers()["Link"], "</v1/fishnet>; rel=\"successor-version\"");
+}
+
+#[tokio::test]
+async fn unknown_versions_are_bad_requests() {
+    let res = warp::test::request()
+        .path("/fishnet/ping")
+        .header("Api-Version", "v9")
+        .reply(&fishnet())
+        .await;
+    assert_eq!(res.status(), 400);
+    let res = warp::test::request().path("/admin/ping").reply(&fishnet()).await;
+    assert_eq!(res.status(), 404);
+}
+
+#[tokio::test]
+async fn deprecated_versions_say_so() {
+    let fishnet = Versioned::new("fishnet", ApiVersion::V1)
+        .version(ApiVersion::V1, routes(warp::path("ping").map(|| "pong")))
+        .deprecate(ApiVersion::V1, Some("Sat, 01 Jan 2022 00:00:00 GMT"))
+        .routes();
+    let res = warp::test::request().path("/v1/fishnet/ping").reply(&fishnet).await;
+    assert_eq!(res.headers()["Deprecation"], "true");
+    assert_eq!(res.headers()["Sunset"], "Sat, 01 Jan 2022 00:00:00 GMT");
+}
+
+#[tokio::test]
+async fn versions_share_their_slo() {
+    let metrics = Metrics::new();
+    let fishnet = Versioned::new("fishnet", ApiVersion::V1)
+        .version(ApiVersion::V1, routes(warp::path("acquire").map(|| "")))
+        .routes()
+        .with(metrics.log());
+    for path in &["/v1/fishnet/acquire", "/fishnet/acquire"] {
+        warp::test::request().method("POST").path(path).reply(&fishnet).await;
+    }
+    let summary = metrics.slo_summary();
+    assert_eq!(summary.len(), 1);
+    assert_eq!(summary[0].route, "/fishnet/acquire");
+    assert_eq!(summary[0].requests, 2);
+    assert_eq!(summary[0].target_ms, 150);
+}
@@ -0,0 +1,81 @@
+// Copyright 2020 Lakin Wecker
+//
+// This file is part of lila-deepq.
+//
+// lila-deepq is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// lila-deepq is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with lila-deepq.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::net::IpAddr;
+
+use tokio::time::Duration;
+
+use lila_deepq::error::{Error, ProtocolError};
+use lila_deepq::fishnet::webhooks::{is_public, post, validate_url};
+
+fn public(ip: &str) -> bool {
+    is_public(ip.parse::<IpAddr>().unwrap())
+}
+
+#[test]
+fn internal_addresses_are_not_public() {
+    for ip in &[
+        "127.0.0.1",
+        "10.1.2.3",
+        "172.16.0.1",
+        "192.168.1.1",
+        "169.254.169.254",
+        "100.64.0.1",
+        "0.0.0.0",
+        "::1",
+        "::",
+        "fd00:ec2::254",
+        "fe80::1",
+        "::ffff:127.0.0.1",
+        "224.0.0.1",
+        "239.255.255.250",
+        "255.255.255.255",
+        "::ffff:10.0.0.1",
+        "::10.0.0.1",
+        "64:ff9b::a9fe:a9fe",
+        "64:ff9b::7f00:1",
+        "64:ff9b:1::1",
+        "2002:c0a8:101::1",
+        "ff02::1",
+    ] {
+        assert!(!public(ip), "{}", ip);
+    }
+    assert!(public("1.1.1.1"));
+    assert!(public("2606:4700:4700::1111"));
+    assert!(public("64:ff9b::101:101"));
+    assert!(public("2002:101:101::1"));
+}
+
+#[tokio::test]
+async fn webhooks_to_internal_hosts_are_refused() {
+    assert!(validate_url("http://1.1.1.1/hook").await.is_err());
+    assert!(validate_url("https://127.0.0.1/hook").await.is_err());
+    assert!(validate_url("https://[::1]:8443/hook").await.is_err());
+    assert!(validate_url("https://169.254.169.254/latest/meta-data").await.is_err());
+    assert!(validate_url("https://localhost/hook").await.is_err());
+    assert!(validate_url("https://1.1.1.1/hook").await.is_ok());
+}
+
+#[tokio::test]
+async fn deliveries_to_internal_hosts_are_refused_before_connecting() {
+    for url in &["https://localhost:1/hook", "http://1.1.1.1/hook"] {
+        let err = post(url, &[], b"{}".to_vec(), Duration::from_secs(1), None)
+            .await
+            .unwrap_err();
+        assert!(matches!(err.root(), Error::Protocol(ProtocolError::InvalidUrl(_))), "{}", url);
+    }
+}
@@ -0,0 +1,41 @@
+// Copyright 2021 Lakin Wecker
+//
+// This file is part of lila-deepq.
+//
+// lila-deepq is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// lila-deepq is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with lila-deepq.  If not, see <https://www.gnu.org/licenses/>.
+
+#![cfg(feature = "embedded-worker")]
+
+use serde_json::json;
+
+use lila_deepq::deepq::model::PlyAnalysis;
+use lila_deepq::worker::parse_info;
+
+#[test]
+fn info_lines_become_fishnet_plies() {
+    let line = "info depth 18 seldepth 24 multipv 1 score cp 31 nodes 2250112 nps 1500074 time 1500 pv e2e4 e7e5 g1f3";
+    let ply = parse_info(line).expect("a scored info line");
+    assert_eq!(
+        ply,
+        json!({"depth": 18, "score": {"cp": 31}, "nodes": 2250112, "nps": 1500074, "time": 1500, "pv": "e2e4 e7e5 g1f3"})
+    );
+    assert!(matches!(serde_json::from_value(ply), Ok(PlyAnalysis::Best(_))));
+}
+
+#[test]
+fn finished_games_have_no_pv() {
+    let ply = parse_info("info depth 0 score mate 0").expect("a scored info line");
+    assert!(matches!(serde_json::from_value(ply), Ok(PlyAnalysis::Empty(_))));
+    assert!(parse_info("info string NNUE evaluation using nn-62ef826d1a6d.nnue enabled").is_none());
+}